@@ -12,6 +12,7 @@ cargo check --locked
 cargo build --release
 cargo doc
 cargo test
+cargo test --features usb-host
 
 # features
 sets=(
@@ -19,6 +20,7 @@ sets=(
     "nvme-mi"
     "pldm"
     "nvme-mi,pldm"
+    "usb-host"
 )
 
 for features in "${sets[@]}"
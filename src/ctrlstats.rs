@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Rate limiting and counters for the control protocol listener. All tasks
+ * share one executor, so a peer flooding us with control requests could
+ * otherwise starve the other listeners. Each peer EID has a token bucket;
+ * requests beyond its rate are dropped without a response.
+ */
+
+use log::warn;
+use mctp::Eid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/* minimum interval between warnings about dropped requests */
+const DROP_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default control request rate per peer, in requests per second
+pub const DEFAULT_RATE: u32 = 100;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Per-peer token buckets, allowing `rate` requests per second, with bursts
+/// of up to one second's worth
+pub struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<u8, Bucket>,
+    // drops since the last warning, and when it was logged
+    warned: (u64, Option<Instant>),
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            buckets: HashMap::new(),
+            warned: (0, None),
+        }
+    }
+
+    /// Take a token for a request from `peer`, returning false if the
+    /// request should be dropped
+    pub fn allow(&mut self, peer: Eid) -> bool {
+        let now = Instant::now();
+        let rate = self.rate;
+        let b = self.buckets.entry(peer.0).or_insert(Bucket {
+            tokens: rate,
+            last: now,
+        });
+        let elapsed = now.duration_since(b.last).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * rate).min(rate);
+        b.last = now;
+
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            return true;
+        }
+
+        let (drops, at) = &mut self.warned;
+        *drops += 1;
+        if at.is_none_or(|t| now.duration_since(t) >= DROP_WARN_INTERVAL) {
+            warn!(
+                "Dropped {drops} control requests over the rate limit, \
+                 latest from {peer}"
+            );
+            self.warned = (0, Some(now));
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    by_command: BTreeMap<u8, u64>,
+    dropped: u64,
+}
+
+/// Control request counters, by command code
+#[derive(Default)]
+pub struct ControlStats {
+    counters: Mutex<Counters>,
+}
+
+impl ControlStats {
+    pub fn request(&self, cmd: u8) {
+        let mut c = self.counters.lock().unwrap();
+        *c.by_command.entry(cmd).or_default() += 1;
+    }
+
+    pub fn dropped(&self) {
+        self.counters.lock().unwrap().dropped += 1;
+    }
+}
+
+impl std::fmt::Display for ControlStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let c = self.counters.lock().unwrap();
+        write!(f, "{} rate limited", c.dropped)?;
+        for (cmd, count) in &c.by_command {
+            write!(f, ", cmd {cmd:#04x}: {count}")?;
+        }
+        Ok(())
+    }
+}
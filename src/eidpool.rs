@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* EID pool management for bridge mode. The bus owner allocates a pool of
+ * EIDs to the bridge with Allocate Endpoint IDs; we then assign EIDs from
+ * that pool to the endpoint on the downstream port, using Get Endpoint ID
+ * and Set Endpoint ID requests to the null EID.
+ *
+ * The downstream transports are point-to-point, so there is a single
+ * endpoint to assign. An endpoint that doesn't respond is marked stale, and
+ * the assignment retried periodically.
+ */
+
+use log::{debug, info, warn};
+use mctp::{AsyncReqChannel, Eid};
+use mctp_estack::router::Router;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{
+    Routes, MCTP_CTRL_CC_ERROR_INVALID_DATA, MCTP_CTRL_CC_SUCCESS,
+    MCTP_CTRL_CMD_GET_ENDPOINT_ID, MCTP_CTRL_CMD_SET_ENDPOINT_ID,
+};
+
+pub const MCTP_CTRL_CMD_ALLOCATE_ENDPOINT_IDS: u8 = 0x08;
+
+/* Allocate Endpoint IDs operations */
+const ALLOCATE_OP_ALLOCATE: u8 = 0b00;
+const ALLOCATE_OP_FORCE: u8 = 0b01;
+const ALLOCATE_OP_GET_INFO: u8 = 0b10;
+
+/* Allocate Endpoint IDs status */
+const ALLOCATE_ACCEPTED: u8 = 0b00;
+const ALLOCATE_REJECTED: u8 = 0b01;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+const STALE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Assignment state of the downstream endpoint
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Assignment {
+    /// no pool has been allocated
+    #[default]
+    Idle,
+    /// waiting to assign an EID
+    Pending,
+    /// the endpoint has accepted an EID
+    Assigned(u8),
+    /// the endpoint didn't respond, or rejected the assignment
+    Stale { attempts: u32 },
+    /// no EIDs are left in the pool
+    Exhausted,
+}
+
+#[derive(Default)]
+struct PoolState {
+    pool: Option<RangeInclusive<u8>>,
+    bus_owner: Option<u8>,
+    assignment: Assignment,
+    allocations: u64,
+}
+
+/// The EID pool allocated to the bridge, and its downstream assignment
+pub struct EidPool {
+    state: Mutex<PoolState>,
+    changed_tx: async_channel::Sender<()>,
+    changed_rx: async_channel::Receiver<()>,
+}
+
+impl Default for EidPool {
+    fn default() -> Self {
+        let (changed_tx, changed_rx) = async_channel::bounded(1);
+        Self {
+            state: Mutex::new(PoolState::default()),
+            changed_tx,
+            changed_rx,
+        }
+    }
+}
+
+impl EidPool {
+    /// Handle an Allocate Endpoint IDs request from `bus_owner`, returning
+    /// the response.
+    pub fn allocate(&self, req: &[u8], bus_owner: Eid) -> Vec<u8> {
+        let mut resp = vec![req[0] & 0x1f, MCTP_CTRL_CMD_ALLOCATE_ENDPOINT_IDS];
+        let [op, size, start, ..] = req[2..] else {
+            resp.push(MCTP_CTRL_CC_ERROR_INVALID_DATA);
+            return resp;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let status = match op & 0x03 {
+            ALLOCATE_OP_GET_INFO => ALLOCATE_ACCEPTED,
+            // only the owner of the current pool may replace it
+            ALLOCATE_OP_ALLOCATE
+                if state.pool.is_some()
+                    && state.bus_owner != Some(bus_owner.0) =>
+            {
+                info!("Rejecting EID pool from {bus_owner}, already allocated");
+                ALLOCATE_REJECTED
+            }
+            ALLOCATE_OP_ALLOCATE | ALLOCATE_OP_FORCE => {
+                let end = start.checked_add(size.saturating_sub(1));
+                match end {
+                    Some(end) if size > 0 && start != 0 && end < 0xff => {
+                        info!(
+                            "EID pool {start}-{end} allocated by {bus_owner}"
+                        );
+                        state.pool = Some(start..=end);
+                        state.bus_owner = Some(bus_owner.0);
+                        state.assignment = Assignment::Pending;
+                        state.allocations += 1;
+                        let _ = self.changed_tx.try_send(());
+                        ALLOCATE_ACCEPTED
+                    }
+                    _ => {
+                        warn!(
+                            "Invalid EID pool {start}+{size} from {bus_owner}"
+                        );
+                        ALLOCATE_REJECTED
+                    }
+                }
+            }
+            _ => {
+                resp.push(MCTP_CTRL_CC_ERROR_INVALID_DATA);
+                return resp;
+            }
+        };
+
+        let (size, start) = match &state.pool {
+            Some(p) => (p.end() - p.start() + 1, *p.start()),
+            None => (0, 0),
+        };
+        resp.extend_from_slice(&[MCTP_CTRL_CC_SUCCESS, status, size, start]);
+        resp
+    }
+
+    /// The allocated pool, for routing to the downstream port
+    pub fn pool(&self) -> Option<RangeInclusive<u8>> {
+        self.state.lock().unwrap().pool.clone()
+    }
+
+    fn set_assignment(&self, assignment: Assignment) {
+        self.state.lock().unwrap().assignment = assignment;
+    }
+}
+
+impl std::fmt::Display for EidPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        match &state.pool {
+            Some(p) => write!(f, "pool {}-{}", p.start(), p.end())?,
+            None => write!(f, "no pool")?,
+        }
+        write!(
+            f,
+            ", allocations {}, downstream {:?}",
+            state.allocations, state.assignment
+        )
+    }
+}
+
+// Send a control request to `chan`, returning the response body after the
+// completion code, or None if there was no valid response
+async fn request(
+    chan: &mut impl AsyncReqChannel,
+    iid: &mut u8,
+    cmd: u8,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    *iid = (*iid + 1) & 0x1f;
+    let mut req = vec![0x80 | *iid, cmd];
+    req.extend_from_slice(data);
+    if let Err(e) = chan.send(mctp::MCTP_TYPE_CONTROL, &req).await {
+        debug!("downstream request failed: {e}");
+        return None;
+    }
+
+    let mut buf = [0u8; 64];
+    let resp = futures::future::select(
+        Box::pin(chan.recv(&mut buf)),
+        smol::Timer::after(RESPONSE_TIMEOUT),
+    )
+    .await;
+    let futures::future::Either::Left((Ok((_typ, _ic, msg)), _)) = resp else {
+        return None;
+    };
+    match msg {
+        [hdr, c, 0, rest @ ..] if hdr & 0x1f == *iid && *c == cmd => {
+            Some(rest.to_vec())
+        }
+        [_, _, cc, ..] => {
+            debug!("downstream request {cmd:#04x} failed, cc {cc:#04x}");
+            None
+        }
+        _ => None,
+    }
+}
+
+// Assign `eid` from `pool` to the downstream endpoint
+async fn assign(
+    router: &Router<'_>,
+    iid: &mut u8,
+    pool: &RangeInclusive<u8>,
+    eid: u8,
+) -> Option<Assignment> {
+    let mut chan = router.req(Eid(0));
+
+    // the endpoint may already have an EID from this pool, from an earlier
+    // assignment that we didn't see the response to
+    let resp =
+        request(&mut chan, iid, MCTP_CTRL_CMD_GET_ENDPOINT_ID, &[]).await?;
+    if let Some(eid) = resp.first().filter(|e| pool.contains(e)) {
+        return Some(Assignment::Assigned(*eid));
+    }
+
+    let resp = request(
+        &mut chan,
+        iid,
+        MCTP_CTRL_CMD_SET_ENDPOINT_ID,
+        &[0x00 /* set */, eid],
+    )
+    .await?;
+    match resp[..] {
+        [status, eid, ..] if (status >> 4) & 0x03 == 0 => {
+            Some(Assignment::Assigned(eid))
+        }
+        _ => {
+            warn!("Downstream endpoint rejected EID {eid}");
+            None
+        }
+    }
+}
+
+/// Assign EIDs to the downstream port from the pool allocated by the bus
+/// owner, retrying stale assignments periodically.
+pub async fn manage(
+    router: &Router<'_>,
+    routes: &Routes,
+    pool: &EidPool,
+    downstream: u8,
+) -> std::io::Result<()> {
+    let mut iid = 0;
+    loop {
+        let assignment = pool.state.lock().unwrap().assignment;
+        let retry = match assignment {
+            Assignment::Pending => Duration::ZERO,
+            Assignment::Stale { .. } => STALE_RETRY_INTERVAL,
+            _ => Duration::MAX,
+        };
+        futures::future::select(
+            Box::pin(pool.changed_rx.recv()),
+            smol::Timer::after(retry),
+        )
+        .await;
+
+        let Some(range) = pool.pool() else {
+            continue;
+        };
+        let attempts = match pool.state.lock().unwrap().assignment {
+            Assignment::Pending => 0,
+            Assignment::Stale { attempts } => attempts,
+            _ => continue,
+        };
+        routes.set_pool_route(Some(range.clone()), downstream);
+
+        // EIDs already reachable through another port can't be used
+        let free = range
+            .clone()
+            .find(|e| routes.lookup(*e).is_none_or(|p| p == downstream));
+        let Some(eid) = free else {
+            warn!("EID pool exhausted, can't assign downstream endpoint");
+            pool.set_assignment(Assignment::Exhausted);
+            continue;
+        };
+
+        // the unassigned endpoint is reached through the null EID
+        routes.set_null_route(Some(downstream));
+        let r = assign(router, &mut iid, &range, eid).await;
+        routes.set_null_route(None);
+
+        match r {
+            Some(a @ Assignment::Assigned(eid)) => {
+                info!("Assigned EID {eid} on port {downstream}");
+                pool.set_assignment(a);
+            }
+            _ => {
+                let attempts = attempts + 1;
+                warn!(
+                    "Downstream endpoint didn't accept an EID, attempt \
+                     {attempts}, retrying in {}s",
+                    STALE_RETRY_INTERVAL.as_secs()
+                );
+                pool.set_assignment(Assignment::Stale { attempts });
+            }
+        }
+    }
+}
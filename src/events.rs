@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Distribution of MCTP control events (such as EID assignment) to the tasks
+ * that act on them. Each subscriber has its own queue, so one slow
+ * subscriber can't cause events to be lost by the others.
+ */
+
+use log::{debug, warn};
+use mctp::Eid;
+use mctp_estack::control::ControlEvent;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/* events queued per subscriber, before further events are dropped */
+const SUBSCRIBER_QUEUE_DEPTH: usize = 16;
+
+/// A change of endpoint state
+#[derive(Debug)]
+pub enum EventKind {
+    /// from the control protocol handler, such as an EID assignment
+    Control(ControlEvent),
+    /// our EID was reset to its static value by `bus_owner`
+    EidReset { eid: Eid, bus_owner: Eid },
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Control(ev) => write!(f, "{ev:?}"),
+            Self::EidReset { eid, bus_owner } => {
+                write!(f, "EID reset to {eid} by {bus_owner}")
+            }
+        }
+    }
+}
+
+/// A control event, and when it occurred
+#[derive(Debug)]
+pub struct Event {
+    pub time: Instant,
+    pub event: EventKind,
+}
+
+struct Subscriber {
+    name: &'static str,
+    sender: async_channel::Sender<Arc<Event>>,
+    dropped: u64,
+}
+
+/// Broadcasts control events to any number of subscribers
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    /// Receive all further events. `name` identifies the subscriber in
+    /// logs.
+    pub fn subscribe(
+        &self,
+        name: &'static str,
+    ) -> async_channel::Receiver<Arc<Event>> {
+        let (sender, receiver) = async_channel::bounded(SUBSCRIBER_QUEUE_DEPTH);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            name,
+            sender,
+            dropped: 0,
+        });
+        receiver
+    }
+
+    /// Send `event` to all subscribers. Subscribers with a full queue miss
+    /// the event, and subscribers that have gone away are removed.
+    pub fn publish(&self, event: EventKind) {
+        debug!("control event: {event}");
+        let event = Arc::new(Event {
+            time: Instant::now(),
+            event,
+        });
+
+        self.subscribers.lock().unwrap().retain_mut(|s| {
+            match s.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(async_channel::TrySendError::Full(_)) => {
+                    s.dropped += 1;
+                    warn!(
+                        "{} is lagging, dropped {} control events",
+                        s.name, s.dropped
+                    );
+                    true
+                }
+                Err(async_channel::TrySendError::Closed(_)) => {
+                    debug!("{} unsubscribed from control events", s.name);
+                    false
+                }
+            }
+        });
+    }
+}
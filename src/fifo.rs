@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* A pair of named pipes, one for each direction, forming a bidirectional
+ * stream for the MCTP serial framing.
+ *
+ * The peer may close and reopen its ends of the pipes. We hold the receive
+ * pipe open for writing too, so it never reports EOF: without a peer writer,
+ * it just has no data. While there is no reader on the transmit pipe,
+ * outbound data is discarded.
+ */
+
+use anyhow::{Context, Result};
+use futures::{AsyncRead, AsyncWrite};
+use log::{debug, info};
+use smol::Async;
+use std::fs::File;
+use std::os::unix::fs::OpenOptionsExt;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+const FIFO_OPEN_DELAY: Duration = Duration::from_millis(500);
+
+pub struct FifoPair {
+    tx_path: String,
+    rx: Async<File>,
+    tx: Option<Async<File>>,
+}
+
+// Open the receive side. This is opened read-write, so that there is always a
+// writer: a non-blocking read from a fifo without writers returns EOF
+// immediately, rather than waiting for one to appear.
+fn open_rx(path: &str) -> std::io::Result<Async<File>> {
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    Async::new(f)
+}
+
+// Open the transmit side. Returns None if there is no reader present.
+fn open_tx(path: &str) -> std::io::Result<Option<Async<File>>> {
+    let r = std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    match r {
+        Ok(f) => Async::new(f).map(Some),
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl FifoPair {
+    /// Open the fifos at `rx_path` and `tx_path`, waiting for the peer to
+    /// open the reading end of `tx_path`.
+    pub fn open(rx_path: &str, tx_path: &str) -> Result<Self> {
+        let rx = open_rx(rx_path)
+            .with_context(|| format!("Can't open fifo {rx_path}"))?;
+
+        let tx = loop {
+            let tx = open_tx(tx_path)
+                .with_context(|| format!("Can't open fifo {tx_path}"))?;
+            if let Some(tx) = tx {
+                break tx;
+            }
+            info!("No reader on {tx_path}, retrying");
+            std::thread::sleep(FIFO_OPEN_DELAY);
+        };
+
+        Ok(Self {
+            tx_path: tx_path.to_string(),
+            rx,
+            tx: Some(tx),
+        })
+    }
+}
+
+impl AsyncRead for FifoPair {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.rx).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FifoPair {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.tx.is_none() {
+            self.tx = open_tx(&self.tx_path)?;
+            if self.tx.is_some() {
+                info!("fifo {} reopened", self.tx_path);
+            }
+        }
+
+        let Some(tx) = &mut self.tx else {
+            debug!("no reader on {}, discarding", self.tx_path);
+            return Poll::Ready(Ok(buf.len()));
+        };
+
+        match Pin::new(tx).poll_write(cx, buf) {
+            Poll::Ready(Err(e))
+                if e.kind() == std::io::ErrorKind::BrokenPipe =>
+            {
+                info!("fifo {} closed by peer", self.tx_path);
+                self.tx = None;
+                Poll::Ready(Ok(buf.len()))
+            }
+            r => r,
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.tx {
+            Some(tx) => Pin::new(tx).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.tx {
+            Some(tx) => Pin::new(tx).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
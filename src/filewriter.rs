@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Writes received file data on a blocking thread, so a slow disk doesn't
+ * stall the listeners sharing the executor. Data is passed to the thread
+ * through a queue of up to WRITE_QUEUE chunks; queueing only waits once
+ * the writer has fallen that far behind.
+ */
+
+// only used by the PLDM file transfer requester and firmware device
+#![cfg_attr(not(feature = "pldm"), allow(dead_code))]
+
+use std::fs::File;
+use std::io::{self, Write};
+
+const WRITE_QUEUE: usize = 64;
+
+pub struct FileWriter {
+    tx: async_channel::Sender<Vec<u8>>,
+    task: smol::Task<io::Result<()>>,
+}
+
+impl FileWriter {
+    pub fn new(mut file: File) -> Self {
+        let (tx, rx) = async_channel::bounded::<Vec<u8>>(WRITE_QUEUE);
+        let task = smol::unblock(move || {
+            while let Ok(data) = rx.recv_blocking() {
+                file.write_all(&data)?;
+            }
+            file.sync_all()
+        });
+        Self { tx, task }
+    }
+
+    /// Queue `data`. Returns false if the writer has failed; the error is
+    /// returned by `finish`.
+    pub async fn write(&self, data: &[u8]) -> bool {
+        self.tx.send(data.to_vec()).await.is_ok()
+    }
+
+    /// Wait for the queued data to be written and synced
+    pub async fn finish(self) -> io::Result<()> {
+        self.tx.close();
+        self.task.await
+    }
+}
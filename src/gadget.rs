@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* USB gadget transport, via the Linux FunctionFS interface. This presents
+ * the same MCTP-over-USB interface as the usbredir transport, but to a real
+ * USB host, through a device controller.
+ *
+ * The FunctionFS instance needs to be mounted, and the function linked into
+ * a gadget configuration. Once we have written the descriptors, the gadget
+ * can be bound to a UDC.
+ */
+
+use anyhow::{Context, Result};
+use futures::future::{self, Either, LocalBoxFuture};
+use futures::{select, AsyncReadExt, FutureExt};
+use log::{debug, info, trace, warn};
+use smol::{Async, Task};
+use std::fs::File;
+use std::io::{Read as _, Write as _};
+use std::sync::Arc;
+
+use crate::linkdump;
+use crate::pcap::Direction;
+use crate::transport::{MctpTransport, BINDING_USB};
+use crate::usbredir::{
+    mctp_interface, LinkUp, MctpUsbXfer, UsbSpeed, UsbStats, IFACE_STRING,
+    USB_MTU, USB_XFER_SIZE,
+};
+
+const FUNCTIONFS_DESCRIPTORS_MAGIC_V2: u32 = 3;
+const FUNCTIONFS_STRINGS_MAGIC: u32 = 2;
+const FUNCTIONFS_HAS_FS_DESC: u32 = 1;
+const FUNCTIONFS_HAS_HS_DESC: u32 = 2;
+
+const FUNCTIONFS_BIND: u8 = 0;
+const FUNCTIONFS_UNBIND: u8 = 1;
+const FUNCTIONFS_ENABLE: u8 = 2;
+const FUNCTIONFS_DISABLE: u8 = 3;
+const FUNCTIONFS_SETUP: u8 = 4;
+const FUNCTIONFS_SUSPEND: u8 = 5;
+const FUNCTIONFS_RESUME: u8 = 6;
+
+/* struct usb_functionfs_event: setup request, type, padding */
+const EVENT_SIZE: usize = 12;
+
+const USB_DIR_IN: u8 = 0x80;
+
+const LANG_EN_US: u16 = 0x0409;
+
+/* a blocking endpoint transfer, returning the transfer buffer */
+type XferTask = Task<(std::io::Result<usize>, Vec<u8>)>;
+
+// FunctionFS descriptor block: interface and endpoints, for full- and
+// high-speed
+fn descriptors() -> Vec<u8> {
+    let mut descs = Vec::new();
+    let mut count = 0;
+    for speed in [UsbSpeed::Full, UsbSpeed::High] {
+        let mut iface = mctp_interface(speed, false);
+        /* string indices are local to the function */
+        iface.interface_idx = 1;
+        iface.write(&mut descs);
+        count = 1 + iface.endpoints.len() as u32;
+    }
+
+    let flags = FUNCTIONFS_HAS_FS_DESC | FUNCTIONFS_HAS_HS_DESC;
+    let len = 5 * 4 + descs.len() as u32;
+
+    let mut v = Vec::new();
+    for w in [FUNCTIONFS_DESCRIPTORS_MAGIC_V2, len, flags, count, count] {
+        v.extend_from_slice(&w.to_le_bytes());
+    }
+    v.extend_from_slice(&descs);
+    v
+}
+
+// FunctionFS strings block, with a single language
+fn strings() -> Vec<u8> {
+    let mut strs = Vec::new();
+    strs.extend_from_slice(&LANG_EN_US.to_le_bytes());
+    strs.extend_from_slice(IFACE_STRING.as_bytes());
+    strs.push(0);
+
+    let len = 4 * 4 + strs.len() as u32;
+
+    let mut v = Vec::new();
+    for w in [FUNCTIONFS_STRINGS_MAGIC, len, 1, 1] {
+        v.extend_from_slice(&w.to_le_bytes());
+    }
+    v.extend_from_slice(&strs);
+    v
+}
+
+enum Event {
+    Ep0(std::io::Result<usize>),
+    RxDone(std::io::Result<usize>, Vec<u8>),
+    TxDone(std::io::Result<usize>),
+    Tx(Result<Vec<u8>, async_channel::RecvError>),
+}
+
+/* contains the FunctionFS state, and handles async processing */
+pub struct MctpGadgetPort {
+    ep0: Async<File>,
+
+    /* the data endpoints don't support poll, so transfers are performed
+     * on the blocking threadpool. At most one transfer is in progress in
+     * each direction.
+     */
+    ep_out: Arc<File>,
+    ep_in: Arc<File>,
+    rx_task: Option<XferTask>,
+    tx_task: Option<XferTask>,
+
+    enabled: bool,
+    suspended: bool,
+
+    /* usb transfer interactions, connected to the higher-level objects */
+    xfer_tx_chan: async_channel::Receiver<Vec<u8>>,
+    xfer_rx_chan: async_channel::Sender<Vec<u8>>,
+    xfer_reset_chan: async_channel::Sender<()>,
+
+    stats: Arc<UsbStats>,
+    /* notified when the host enables the function */
+    link_up: LinkUp,
+}
+
+pub struct MctpGadget {
+    xfer: MctpUsbXfer,
+
+    /* FunctionFS processing, until taken by background() */
+    port: Option<MctpGadgetPort>,
+}
+
+impl MctpGadget {
+    /// Create a gadget transport on the FunctionFS instance mounted at
+    /// `path`. Transfers are counted in `stats`, and `link_up` is notified
+    /// when the host enables the function.
+    pub fn new(
+        path: &str,
+        stats: Arc<UsbStats>,
+        link_up: LinkUp,
+    ) -> Result<Self> {
+        let ep0_path = format!("{path}/ep0");
+        let mut ep0 = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&ep0_path)
+            .with_context(|| format!("Can't open {ep0_path}"))?;
+
+        ep0.write_all(&descriptors())
+            .context("Can't write FunctionFS descriptors")?;
+        ep0.write_all(&strings())
+            .context("Can't write FunctionFS strings")?;
+
+        /* endpoint files are numbered in descriptor order */
+        let open_ep = |n: usize| {
+            let p = format!("{path}/ep{n}");
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&p)
+                .with_context(|| format!("Can't open {p}"))
+        };
+        let ep_out = open_ep(1)?;
+        let ep_in = open_ep(2)?;
+
+        let (xfer, xfer_tx_chan, xfer_rx_chan, xfer_reset_chan) =
+            MctpUsbXfer::new(stats.clone());
+        let port = MctpGadgetPort {
+            ep0: Async::new(ep0)?,
+            ep_out: Arc::new(ep_out),
+            ep_in: Arc::new(ep_in),
+            rx_task: None,
+            tx_task: None,
+            enabled: false,
+            suspended: false,
+            xfer_tx_chan,
+            xfer_rx_chan,
+            xfer_reset_chan,
+            stats,
+            link_up,
+        };
+
+        Ok(Self {
+            xfer,
+            port: Some(port),
+        })
+    }
+}
+
+impl MctpGadgetPort {
+    fn active(&self) -> bool {
+        self.enabled && !self.suspended
+    }
+
+    async fn process_one(&mut self) -> mctp::Result<()> {
+        let active = self.active();
+
+        // keep a read pending on the OUT endpoint while the data path is
+        // active.
+        if active && self.rx_task.is_none() {
+            let ep = self.ep_out.clone();
+            self.rx_task = Some(smol::unblock(move || {
+                let mut buf = vec![0u8; USB_XFER_SIZE];
+                let r = (&*ep).read(&mut buf);
+                (r, buf)
+            }));
+        }
+
+        // we only take outgoing transfers from the MCTP stack once the
+        // previous transfer has completed. While inactive, these remain
+        // queued.
+        let tx_fut = if active && self.tx_task.is_none() {
+            Either::Right(self.xfer_tx_chan.recv())
+        } else {
+            Either::Left(future::pending())
+        };
+
+        let rx_done_fut = match &mut self.rx_task {
+            Some(t) => Either::Right(t),
+            None => Either::Left(future::pending()),
+        };
+        let tx_done_fut = match &mut self.tx_task {
+            Some(t) => Either::Right(t),
+            None => Either::Left(future::pending()),
+        };
+
+        let mut ev_buf = [0u8; EVENT_SIZE * 4];
+
+        let ev = select!(
+            r = self.ep0.read(&mut ev_buf).fuse() => Event::Ep0(r),
+            r = rx_done_fut.fuse() => Event::RxDone(r.0, r.1),
+            r = tx_done_fut.fuse() => Event::TxDone(r.0),
+            r = tx_fut.fuse() => Event::Tx(r),
+        );
+
+        match ev {
+            Event::Ep0(r) => {
+                let len = r.map_err(|e| {
+                    warn!("ep0 read error {e:?}");
+                    mctp::Error::RxFailure
+                })?;
+                for e in ev_buf[..len].chunks_exact(EVENT_SIZE) {
+                    self.event(e);
+                }
+            }
+            Event::RxDone(r, xfer) => {
+                self.rx_task = None;
+                match r {
+                    Ok(len) => {
+                        trace!("rx xfer: {:02x?}", &xfer[..len]);
+                        linkdump::dump("gadget", Direction::In, &xfer[..len]);
+                        UsbStats::add(&self.stats.out_xfers, 1);
+                        UsbStats::add(&self.stats.out_bytes, len);
+                        let _ =
+                            self.xfer_rx_chan.send(xfer[..len].to_vec()).await;
+                    }
+                    // expected when the function is disabled
+                    Err(e) => debug!("OUT transfer failed: {e}"),
+                }
+            }
+            Event::TxDone(r) => {
+                self.tx_task = None;
+                match r {
+                    Ok(len) => {
+                        UsbStats::add(&self.stats.in_xfers, 1);
+                        UsbStats::add(&self.stats.in_bytes, len);
+                    }
+                    Err(e) => debug!("IN transfer failed: {e}"),
+                }
+            }
+            Event::Tx(r) => {
+                let Ok(xfer) = r else {
+                    warn!("tx/xfer failure: {r:?}");
+                    return Err(mctp::Error::TxFailure);
+                };
+                trace!("tx xfer: {xfer:02x?}");
+                linkdump::dump("gadget", Direction::Out, &xfer);
+                let ep = self.ep_in.clone();
+                self.tx_task = Some(smol::unblock(move || {
+                    let r = (&*ep).write(&xfer);
+                    (r, xfer)
+                }));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn event(&mut self, ev: &[u8]) {
+        match ev[8] {
+            FUNCTIONFS_BIND => info!("gadget bound to UDC"),
+            FUNCTIONFS_UNBIND => {
+                info!("gadget unbound from UDC");
+                self.disable();
+            }
+            FUNCTIONFS_ENABLE => {
+                info!("gadget enabled");
+                self.enabled = true;
+                self.suspended = false;
+                self.link_up.notify();
+            }
+            FUNCTIONFS_DISABLE => {
+                info!("gadget disabled");
+                self.disable();
+            }
+            FUNCTIONFS_SUSPEND => {
+                debug!("gadget suspended");
+                self.suspended = true;
+            }
+            FUNCTIONFS_RESUME => {
+                debug!("gadget resumed");
+                self.suspended = false;
+            }
+            FUNCTIONFS_SETUP => self.stall(ev),
+            t => debug!("unknown FunctionFS event {t}"),
+        }
+    }
+
+    fn disable(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.enabled = false;
+
+        // outbound packets were for the previous host configuration; the
+        // host will re-enumerate
+        let mut dropped = 0;
+        while self.xfer_tx_chan.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("dropped {dropped} outbound packets on disable");
+        }
+        let _ = self.xfer_reset_chan.try_send(());
+    }
+
+    // MCTP over USB has no class-specific control requests. A transfer in
+    // the opposite direction to the request stalls ep0.
+    fn stall(&mut self, ev: &[u8]) {
+        let (req_type, req) = (ev[0], ev[1]);
+        debug!("unsupported setup request {req_type:02x}:{req:02x}, stalling");
+        let mut ep0 = self.ep0.get_ref();
+        let _ = if req_type & USB_DIR_IN != 0 {
+            ep0.read(&mut [])
+        } else {
+            ep0.write(&[])
+        };
+    }
+
+    pub async fn process(&mut self) -> mctp::Result<()> {
+        loop {
+            self.process_one().await?;
+        }
+    }
+}
+
+impl MctpTransport for MctpGadget {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(self.xfer.recv())
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(self.xfer.send(pkt))
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(USB_MTU)
+    }
+
+    fn binding(&self) -> u8 {
+        BINDING_USB
+    }
+
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        let mut port = self.port.take()?;
+        Some(Box::pin(async move { port.process().await }))
+    }
+}
@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* MCTP over SMBus/I2C (DSP0237), using Linux i2c-dev for transmit, and the
+ * kernel's i2c slave-mqueue backend for receive.
+ *
+ * The slave-mqueue backend needs to be instantiated on the bus, at our
+ * address, before starting:
+ *
+ *   echo slave-mqueue 0x1010 > /sys/bus/i2c/devices/i2c-1/new_device
+ */
+
+use anyhow::{bail, Context, Result};
+use futures::future::LocalBoxFuture;
+use log::{debug, trace, warn};
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::transport::{MctpTransport, BINDING_SMBUS};
+
+const SMBUS_CMD_MCTP: u8 = 0x0f;
+const I2C_SLAVE_FORCE: libc::c_ulong = 0x0706;
+
+// dest address, command code, byte count, source address
+const SMBUS_HDR_LEN: usize = 4;
+const SMBUS_PEC_LEN: usize = 1;
+const SMBUS_MAX_BLOCK: usize = 255;
+
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct MctpI2c {
+    dev: Arc<std::fs::File>,
+    mqueue: std::fs::File,
+    addr: u8,
+
+    /* i2c addresses of peers, learnt from received packets */
+    peers: HashMap<u8, u8>,
+    default_peer: Option<u8>,
+
+    rx_buf: [u8; SMBUS_HDR_LEN + SMBUS_MAX_BLOCK + SMBUS_PEC_LEN],
+    rx_len: usize,
+
+    pec_errors: u64,
+}
+
+/* SMBus PEC: CRC-8, polynomial x^8 + x^2 + x + 1 */
+fn pec(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[derive(Debug)]
+pub enum SmbusError {
+    Short,
+    NotMctp,
+    Length,
+    Pec,
+}
+
+/// Validate the SMBus framing of a received MCTP block write. `buf` contains
+/// the full write, including the destination address byte and PEC. Returns
+/// the 7-bit source address, and the range of the MCTP packet within `buf`.
+pub fn smbus_decode(
+    buf: &[u8],
+) -> Result<(u8, std::ops::Range<usize>), SmbusError> {
+    if buf.len() < SMBUS_HDR_LEN + SMBUS_PEC_LEN {
+        return Err(SmbusError::Short);
+    }
+
+    if buf[1] != SMBUS_CMD_MCTP {
+        return Err(SmbusError::NotMctp);
+    }
+
+    // byte count covers the source address and the MCTP packet
+    let count = buf[2] as usize;
+    if count + 3 + SMBUS_PEC_LEN != buf.len() {
+        return Err(SmbusError::Length);
+    }
+
+    let (data, rx_pec) = buf.split_at(buf.len() - SMBUS_PEC_LEN);
+    if pec(data) != rx_pec[0] {
+        return Err(SmbusError::Pec);
+    }
+
+    Ok((buf[3] >> 1, SMBUS_HDR_LEN..buf.len() - SMBUS_PEC_LEN))
+}
+
+/// Build an SMBus block write for an MCTP packet, from 7-bit address
+/// `src_addr` to `dest_addr`. The result includes the destination address
+/// byte and PEC.
+pub fn smbus_encode(
+    dest_addr: u8,
+    src_addr: u8,
+    pkt: &[u8],
+) -> mctp::Result<Vec<u8>> {
+    if pkt.len() < 4 {
+        return Err(mctp::Error::BadArgument);
+    }
+    if pkt.len() + 1 > SMBUS_MAX_BLOCK {
+        return Err(mctp::Error::NoSpace);
+    }
+
+    let mut buf = Vec::with_capacity(pkt.len() + SMBUS_HDR_LEN + 1);
+    buf.extend_from_slice(&[
+        dest_addr << 1,
+        SMBUS_CMD_MCTP,
+        (pkt.len() + 1) as u8,
+        (src_addr << 1) | 1,
+    ]);
+    buf.extend_from_slice(pkt);
+    buf.push(pec(&buf));
+
+    Ok(buf)
+}
+
+impl MctpI2c {
+    /// Create an I2C transport on the i2c-dev device `dev`, at 7-bit
+    /// address `addr`. Received data is read from the slave-mqueue file at
+    /// `mqueue`, or the default sysfs location for the bus and address.
+    pub fn new(
+        dev: &str,
+        addr: u8,
+        mqueue: Option<&str>,
+        default_peer: Option<u8>,
+    ) -> Result<Self> {
+        if addr > 0x7f {
+            bail!("Invalid 7-bit i2c address {addr:#x}");
+        }
+
+        let bus = dev
+            .strip_prefix("/dev/i2c-")
+            .context("i2c device should be a /dev/i2c-N path")?;
+
+        let mqueue = match mqueue {
+            Some(m) => m.to_string(),
+            None => format!(
+                "/sys/bus/i2c/devices/{bus}-{:04x}/slave-mqueue",
+                0x1000 | addr as u16
+            ),
+        };
+
+        let devf = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dev)
+            .with_context(|| format!("Can't open i2c device {dev}"))?;
+        let mqueuef = std::fs::File::open(&mqueue)
+            .with_context(|| format!("Can't open slave mqueue {mqueue}"))?;
+
+        Ok(Self {
+            dev: Arc::new(devf),
+            mqueue: mqueuef,
+            addr,
+            peers: HashMap::new(),
+            default_peer,
+            rx_buf: [0u8; SMBUS_HDR_LEN + SMBUS_MAX_BLOCK + SMBUS_PEC_LEN],
+            rx_len: 0,
+            pec_errors: 0,
+        })
+    }
+
+    // Read one message from the slave mqueue into rx_buf, normalised to
+    // include our own (destination) address byte. Returns false if no
+    // message is available.
+    fn read_mqueue(&mut self) -> mctp::Result<bool> {
+        let (hdr, data) = self.rx_buf.split_at_mut(1);
+        let n = self.mqueue.read_at(data, 0).map_err(|e| {
+            warn!("slave mqueue read failed: {e}");
+            mctp::Error::RxFailure
+        })?;
+
+        if n == 0 {
+            return Ok(false);
+        }
+
+        if data[0] == SMBUS_CMD_MCTP {
+            // mqueue has given us the data without the address byte
+            hdr[0] = self.addr << 1;
+            self.rx_len = n + 1;
+        } else {
+            self.rx_buf.copy_within(1..n + 1, 0);
+            self.rx_len = n;
+        }
+
+        Ok(true)
+    }
+
+    // Validate the SMBus framing in rx_buf, returning the range of the MCTP
+    // packet
+    fn decode(&mut self) -> Option<std::ops::Range<usize>> {
+        let buf = &self.rx_buf[..self.rx_len];
+
+        trace!("i2c rx: {buf:02x?}");
+
+        let (src_addr, pkt) = match smbus_decode(buf) {
+            Ok(r) => r,
+            Err(SmbusError::Pec) => {
+                self.pec_errors += 1;
+                debug!("i2c rx: bad PEC ({} errors)", self.pec_errors);
+                return None;
+            }
+            Err(e) => {
+                debug!("i2c rx: invalid message: {e:?}");
+                return None;
+            }
+        };
+
+        // learn the peer address from the MCTP source EID
+        if let Some(&src_eid) = buf.get(pkt.start + 2) {
+            self.peers.insert(src_eid, src_addr);
+        }
+
+        Some(pkt)
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        loop {
+            if !self.read_mqueue()? {
+                smol::Timer::after(RX_POLL_INTERVAL).await;
+                continue;
+            }
+
+            if let Some(pkt) = self.decode() {
+                return Ok(&self.rx_buf[pkt]);
+            }
+        }
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        if pkt.len() < 4 {
+            return Err(mctp::Error::BadArgument);
+        }
+
+        let dest_eid = pkt[1];
+        let Some(dest_addr) =
+            self.peers.get(&dest_eid).copied().or(self.default_peer)
+        else {
+            warn!("i2c tx: no known address for EID {dest_eid}, dropping");
+            return Err(mctp::Error::TxFailure);
+        };
+
+        let buf = smbus_encode(dest_addr, self.addr, pkt)?;
+
+        trace!("i2c tx: {buf:02x?}");
+
+        // i2c transfers are slow; perform the write off the executor
+        let dev = self.dev.clone();
+        smol::unblock(move || {
+            // SAFETY: I2C_SLAVE_FORCE takes the address as an integer arg
+            let rc = unsafe {
+                libc::ioctl(
+                    dev.as_raw_fd(),
+                    I2C_SLAVE_FORCE as _,
+                    dest_addr as libc::c_ulong,
+                )
+            };
+            if rc < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // address byte is provided by the adapter. This needs to be a
+            // single write, to form one i2c transaction.
+            let n = (&*dev).write(&buf[1..])?;
+            if n != buf.len() - 1 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "short i2c write",
+                ));
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!("i2c write failed: {e}");
+            mctp::Error::TxFailure
+        })
+    }
+}
+
+impl MctpTransport for MctpI2c {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpI2c::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpI2c::send(self, pkt))
+    }
+
+    fn binding(&self) -> u8 {
+        BINDING_SMBUS
+    }
+}
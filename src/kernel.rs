@@ -0,0 +1,465 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* AF_MCTP transport: exchange messages with the Linux kernel MCTP stack.
+ *
+ * The kernel sockets operate on whole messages, but our router expects
+ * packets. So, inbound messages are split into packets for the router, and
+ * outbound packets are reassembled into messages before sending.
+ *
+ * Tags for our own requests are preallocated from the kernel, and mapped
+ * to/from the router's tag values.
+ */
+
+use anyhow::{bail, Result};
+use futures::future::LocalBoxFuture;
+use log::{debug, trace, warn};
+use mctp::{Eid, MsgType};
+use smol::Async;
+use std::collections::{HashMap, VecDeque};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::transport::MctpTransport;
+
+const AF_MCTP: libc::c_int = 45;
+const MCTP_NET_ANY: u32 = 0;
+const MCTP_ADDR_ANY: u8 = 0xff;
+const MCTP_TAG_MASK: u8 = 0x07;
+const MCTP_TAG_OWNER: u8 = 0x08;
+const SIOCMCTPALLOCTAG: libc::c_ulong = 0x89e0;
+
+#[repr(C)]
+#[derive(Default)]
+struct SockaddrMctp {
+    smctp_family: libc::sa_family_t,
+    smctp_pad0: u16,
+    smctp_network: u32,
+    smctp_addr: u8,
+    smctp_type: u8,
+    smctp_tag: u8,
+    smctp_pad1: u8,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MctpIocTagCtl {
+    peer_addr: u8,
+    tag: u8,
+    flags: u16,
+}
+
+// MCTP packet header fields
+const HDR_LEN: usize = 4;
+const HDR_VER: u8 = 1;
+const FLAG_SOM: u8 = 0x80;
+const FLAG_EOM: u8 = 0x40;
+const FLAG_TO: u8 = 0x08;
+const SEQ_SHIFT: u8 = 4;
+
+// packet payload size used when passing inbound messages to the router
+const RX_PKT_PAYLOAD: usize = 255;
+
+const MAX_MSG: usize = 64 * 1024;
+
+struct KernelSocket {
+    typ: MsgType,
+    sock: Async<OwnedFd>,
+}
+
+pub struct MctpKernel {
+    net: u32,
+    socks: Vec<KernelSocket>,
+    rx_buf: Vec<u8>,
+
+    /* packets built from the last inbound message */
+    rx_pkts: VecDeque<Vec<u8>>,
+    rx_cur: Vec<u8>,
+
+    /* outbound message reassembly, keyed by (dest, tag, tag owner) */
+    tx_msgs: HashMap<(u8, u8, bool), Vec<u8>>,
+
+    /* preallocated kernel tags for our requests, keyed by
+     * (type, peer, router tag)
+     */
+    req_tags: HashMap<(u8, u8, u8), u8>,
+}
+
+impl SockaddrMctp {
+    fn new(net: u32, addr: u8, typ: u8, tag: u8) -> Self {
+        Self {
+            smctp_family: AF_MCTP as libc::sa_family_t,
+            smctp_network: net,
+            smctp_addr: addr,
+            smctp_type: typ,
+            smctp_tag: tag,
+            ..Default::default()
+        }
+    }
+}
+
+impl KernelSocket {
+    fn bind(net: u32, typ: MsgType) -> Result<Self> {
+        // SAFETY: plain socket() call, fd checked below
+        let fd = unsafe {
+            libc::socket(
+                AF_MCTP,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            bail!(
+                "Can't create AF_MCTP socket: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // SAFETY: we have just created this fd
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let addr = SockaddrMctp::new(net, MCTP_ADDR_ANY, typ.0, 0);
+        // SAFETY: addr is a valid sockaddr_mctp for the provided length
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const SockaddrMctp as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrMctp>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            bail!(
+                "Can't bind AF_MCTP socket for type {:#04x}: {}",
+                typ.0,
+                std::io::Error::last_os_error()
+            );
+        }
+
+        Ok(Self {
+            typ,
+            sock: Async::new(fd)?,
+        })
+    }
+
+    fn recvfrom(
+        fd: &OwnedFd,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, SockaddrMctp)> {
+        let mut addr = SockaddrMctp::default();
+        let mut addrlen =
+            std::mem::size_of::<SockaddrMctp>() as libc::socklen_t;
+        // SAFETY: buf and addr are valid for their provided lengths
+        let rc = unsafe {
+            libc::recvfrom(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_TRUNC,
+                &mut addr as *mut SockaddrMctp as *mut libc::sockaddr,
+                &mut addrlen,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok((rc as usize, addr))
+    }
+
+    fn sendto(&self, addr: &SockaddrMctp, buf: &[u8]) -> std::io::Result<()> {
+        // SAFETY: buf and addr are valid for their provided lengths
+        let rc = unsafe {
+            libc::sendto(
+                self.sock.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                addr as *const SockaddrMctp as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrMctp>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn alloc_tag(&self, peer: u8) -> std::io::Result<u8> {
+        let mut ctl = MctpIocTagCtl {
+            peer_addr: peer,
+            ..Default::default()
+        };
+        // SAFETY: ctl is a valid mctp_ioc_tag_ctl for this ioctl
+        let rc = unsafe {
+            libc::ioctl(
+                self.sock.as_raw_fd(),
+                SIOCMCTPALLOCTAG as _,
+                &mut ctl as *mut MctpIocTagCtl,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(ctl.tag)
+    }
+}
+
+impl MctpKernel {
+    /// Create a kernel transport, binding one socket for each of the message
+    /// `types` on MCTP network `net` (or any network if `None`).
+    pub fn new(net: Option<u32>, types: &[MsgType]) -> Result<Self> {
+        let net = net.unwrap_or(MCTP_NET_ANY);
+        let socks = types
+            .iter()
+            .map(|t| KernelSocket::bind(net, *t))
+            .collect::<Result<Vec<_>>>()?;
+
+        if socks.is_empty() {
+            bail!("No message types to bind");
+        }
+
+        Ok(Self {
+            net,
+            socks,
+            rx_buf: vec![0u8; MAX_MSG],
+            rx_pkts: VecDeque::new(),
+            rx_cur: Vec::new(),
+            tx_msgs: HashMap::new(),
+            req_tags: HashMap::new(),
+        })
+    }
+
+    async fn recv_msg(&mut self) -> mctp::Result<()> {
+        let futs = self.socks.iter().enumerate().map(|(i, s)| {
+            Box::pin(async move { (i, s.sock.readable().await) })
+        });
+        let ((idx, res), _, _) = futures::future::select_all(futs).await;
+        if let Err(e) = res {
+            warn!("AF_MCTP socket error: {e}");
+            return Err(mctp::Error::RxFailure);
+        }
+
+        let sock = &self.socks[idx];
+        let (len, addr) =
+            match KernelSocket::recvfrom(sock.sock.get_ref(), &mut self.rx_buf)
+            {
+                Ok(r) => r,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("AF_MCTP recv failed: {e}");
+                    return Err(mctp::Error::RxFailure);
+                }
+            };
+
+        if len > self.rx_buf.len() {
+            warn!("AF_MCTP message truncated ({len} bytes), dropping");
+            return Ok(());
+        }
+
+        let peer = addr.smctp_addr;
+        let mut tag = addr.smctp_tag & MCTP_TAG_MASK;
+        let owner = addr.smctp_tag & MCTP_TAG_OWNER != 0;
+
+        if !owner {
+            // a response to one of our requests: map back to the router's tag
+            let rtag = self.req_tags.iter().find_map(|((t, p, rt), kt)| {
+                (*t == sock.typ.0 && *p == peer && kt & MCTP_TAG_MASK == tag)
+                    .then_some(*rt)
+            });
+            match rtag {
+                Some(t) => tag = t,
+                None => {
+                    debug!("AF_MCTP response from {peer} with unknown tag");
+                    return Ok(());
+                }
+            }
+        }
+
+        trace!(
+            "AF_MCTP rx from {peer}, type {:#04x}, len {len}",
+            addr.smctp_type
+        );
+
+        // message data includes the type byte, as the router expects
+        let msg = &self.rx_buf[..len];
+
+        let flags = tag | if owner { FLAG_TO } else { 0 };
+        let nchunks = msg.chunks(RX_PKT_PAYLOAD).count();
+        for (i, chunk) in msg.chunks(RX_PKT_PAYLOAD).enumerate() {
+            let mut f = flags | (((i & 0x3) as u8) << SEQ_SHIFT);
+            if i == 0 {
+                f |= FLAG_SOM;
+            }
+            if i == nchunks - 1 {
+                f |= FLAG_EOM;
+            }
+            let mut pkt = Vec::with_capacity(HDR_LEN + chunk.len());
+            // destination of 0: the local EID is owned by the kernel
+            pkt.extend_from_slice(&[HDR_VER, 0, peer, f]);
+            pkt.extend_from_slice(chunk);
+            self.rx_pkts.push_back(pkt);
+        }
+
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        while self.rx_pkts.is_empty() {
+            self.recv_msg().await?;
+        }
+        // unwrap: checked for non-empty above
+        self.rx_cur = self.rx_pkts.pop_front().unwrap();
+        Ok(&self.rx_cur)
+    }
+
+    /// Add an outbound packet to its message. Returns the socket index,
+    /// address and message data once the message is complete.
+    fn tx_packet(
+        &mut self,
+        pkt: &[u8],
+    ) -> mctp::Result<Option<(usize, SockaddrMctp, Vec<u8>)>> {
+        if pkt.len() < HDR_LEN {
+            return Err(mctp::Error::BadArgument);
+        }
+        let dest = pkt[1];
+        let flags = pkt[3];
+        let tag = flags & MCTP_TAG_MASK;
+        let owner = flags & FLAG_TO != 0;
+        let key = (dest, tag, owner);
+
+        if flags & FLAG_SOM != 0 {
+            self.tx_msgs.insert(key, Vec::new());
+        }
+
+        let Some(msg) = self.tx_msgs.get_mut(&key) else {
+            debug!("AF_MCTP tx: continuation without start, dropping");
+            return Ok(None);
+        };
+        msg.extend_from_slice(&pkt[HDR_LEN..]);
+
+        if flags & FLAG_EOM == 0 {
+            return Ok(None);
+        }
+
+        // unwrap: present, checked above
+        let msg = self.tx_msgs.remove(&key).unwrap();
+        // the type byte selects the socket, but is sent as part of the data
+        let Some(&typ) = msg.first() else {
+            return Ok(None);
+        };
+        let typ = typ & 0x7f;
+
+        let Some(idx) = self.socks.iter().position(|s| s.typ.0 == typ) else {
+            warn!("AF_MCTP tx: no socket for message type {typ:#04x}");
+            return Err(mctp::Error::TxFailure);
+        };
+
+        let ktag = if owner {
+            let k = (typ, dest, tag);
+            match self.req_tags.get(&k) {
+                Some(t) => *t,
+                None => {
+                    let t = self.socks[idx].alloc_tag(dest).map_err(|e| {
+                        warn!("AF_MCTP tag allocation failed: {e}");
+                        mctp::Error::TxFailure
+                    })?;
+                    self.req_tags.insert(k, t);
+                    t
+                }
+            }
+        } else {
+            tag
+        };
+
+        let addr = SockaddrMctp::new(self.net, dest, typ, ktag);
+        Ok(Some((idx, addr, msg)))
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        let Some((idx, addr, msg)) = self.tx_packet(pkt)? else {
+            return Ok(());
+        };
+        trace!(
+            "AF_MCTP tx to {}, type {:#04x}",
+            Eid(addr.smctp_addr),
+            addr.smctp_type
+        );
+        self.socks[idx].sendto(&addr, &msg).map_err(|e| {
+            warn!("AF_MCTP send failed: {e}");
+            mctp::Error::TxFailure
+        })
+    }
+}
+
+impl MctpTransport for MctpKernel {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpKernel::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpKernel::send(self, pkt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A kernel transport over a datagram socketpair standing in for the
+     * AF_MCTP socket. Returns the transport, and the other end of the pair.
+     */
+    fn stub(typ: MsgType) -> (MctpKernel, std::os::unix::net::UnixDatagram) {
+        let (a, b) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let sock = KernelSocket {
+            typ,
+            sock: Async::new(OwnedFd::from(a)).unwrap(),
+        };
+        let k = MctpKernel {
+            net: MCTP_NET_ANY,
+            socks: vec![sock],
+            rx_buf: vec![0u8; MAX_MSG],
+            rx_pkts: VecDeque::new(),
+            rx_cur: Vec::new(),
+            tx_msgs: HashMap::new(),
+            req_tags: HashMap::new(),
+        };
+        (k, b)
+    }
+
+    // Inbound message data, including the type byte, is passed to the
+    // router unchanged
+    #[test]
+    fn rx_bytes() {
+        let (mut k, peer) = stub(MsgType(0x7e));
+        /* the stub peer has no MCTP address, so this appears as a response
+         * from EID 0 to our earlier request with router tag 3
+         */
+        k.req_tags.insert((0x7e, 0, 3), 0);
+        let msg = [0x7e, 0x01, 0x02, 0x03];
+        peer.send(&msg).unwrap();
+
+        let pkt = smol::block_on(k.recv()).unwrap();
+        assert_eq!(
+            pkt,
+            [HDR_VER, 0, 0, FLAG_SOM | FLAG_EOM | 3, 0x7e, 1, 2, 3]
+        );
+    }
+
+    // Outbound packets are reassembled to the full message, type byte and
+    // all, and the type selects the socket
+    #[test]
+    fn tx_bytes() {
+        let (mut k, _peer) = stub(MsgType(0x7e));
+        let first = [HDR_VER, 9, 8, FLAG_SOM | 2, 0xfe, 0x01];
+        let last = [HDR_VER, 9, 8, FLAG_EOM | (1 << SEQ_SHIFT) | 2, 0x02];
+        assert!(k.tx_packet(&first).unwrap().is_none());
+        let (idx, addr, msg) = k.tx_packet(&last).unwrap().unwrap();
+        assert_eq!(idx, 0);
+        assert_eq!(addr.smctp_addr, 9);
+        assert_eq!(addr.smctp_type, 0x7e);
+        assert_eq!(addr.smctp_tag, 2);
+        assert_eq!(msg, [0xfe, 0x01, 0x02]);
+    }
+}
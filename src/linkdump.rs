@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Hexdumps of link-level traffic: the raw bytes of serial streams and USB
+ * transfers, below the MCTP packet layer. Disabled by default, and enabled
+ * at runtime with --dump-link.
+ */
+
+use log::info;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::pcap::Direction;
+
+const BYTES_PER_LINE: usize = 16;
+
+/* maximum number of bytes to dump per transfer; zero when disabled */
+static DUMP_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Enable link dumps, showing at most `max_len` bytes of each transfer
+pub fn enable(max_len: usize) {
+    DUMP_LEN.store(max_len.max(1), Ordering::Relaxed);
+}
+
+/// Log a hexdump of `data`, transferred by `link` in direction `dir`
+pub fn dump(link: &str, dir: Direction, data: &[u8]) {
+    let max = DUMP_LEN.load(Ordering::Relaxed);
+    if max == 0 || data.is_empty() {
+        return;
+    }
+
+    let dir = match dir {
+        Direction::In => "in",
+        Direction::Out => "out",
+    };
+    let shown = &data[..data.len().min(max)];
+    let mut s = format!("{link} {dir}: {} bytes", data.len());
+    if shown.len() < data.len() {
+        let _ = write!(s, ", first {}", shown.len());
+    }
+
+    for (i, chunk) in shown.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(s, "\n  {:04x}: ", i * BYTES_PER_LINE);
+        for j in 0..BYTES_PER_LINE {
+            match chunk.get(j) {
+                Some(b) => {
+                    let _ = write!(s, "{b:02x} ");
+                }
+                None => s.push_str("   "),
+            }
+        }
+        s.push(' ');
+        s.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+    }
+
+    info!("{s}");
+}
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Loopback transport, for exercising the local stack without any external
+ * software.
+ *
+ * Outbound packets are returned as inbound packets, with the source and
+ * destination EIDs swapped. So, a request we send to a peer EID is received
+ * by our own responders as a request from that peer, and their responses
+ * are received back as responses from the peer.
+ *
+ * The self test issues a fixed sequence of requests over the loopback, and
+ * checks the responses.
+ */
+
+use anyhow::{bail, Context, Result};
+use futures::future::LocalBoxFuture;
+use log::{info, trace};
+use mctp::{AsyncReqChannel, Eid, MsgType};
+use mctp_estack::router::Router;
+use std::time::Duration;
+
+use crate::transport::MctpTransport;
+
+// the EID that our self test requests appear to originate from
+const PEER_EID: Eid = Eid(8);
+// the EID we assign to ourselves
+const LOCAL_EID: Eid = Eid(9);
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const CONTROL_RQ: u8 = 0x80;
+const CONTROL_SET_EID: u8 = 0x01;
+const CONTROL_GET_MSG_TYPES: u8 = 0x05;
+
+// vendor subtype handled by the echo listener
+const VENDOR_SUBTYPE_ECHO: [u8; 3] = [0xcc, 0xde, 0xf0];
+
+pub struct MctpLoopback {
+    tx: async_channel::Sender<Vec<u8>>,
+    rx: async_channel::Receiver<Vec<u8>>,
+    rx_cur: Vec<u8>,
+}
+
+impl MctpLoopback {
+    pub fn new() -> Self {
+        let (tx, rx) = async_channel::unbounded();
+        Self {
+            tx,
+            rx,
+            rx_cur: Vec::new(),
+        }
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        self.rx_cur = self.rx.recv().await.or(Err(mctp::Error::RxFailure))?;
+        Ok(&self.rx_cur)
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        if pkt.len() < 4 {
+            return Err(mctp::Error::BadArgument);
+        }
+        let mut pkt = pkt.to_vec();
+        pkt.swap(1, 2);
+        trace!("loopback: {pkt:02x?}");
+        self.tx.send(pkt).await.or(Err(mctp::Error::TxFailure))
+    }
+}
+
+// Send a request, and wait for the response, with a timeout
+async fn request<'f>(
+    chan: &mut impl AsyncReqChannel,
+    typ: MsgType,
+    req: &[u8],
+    buf: &'f mut [u8],
+) -> Result<&'f [u8]> {
+    chan.send(typ, req).await.context("request send failed")?;
+
+    let resp = futures::future::select(
+        Box::pin(chan.recv(buf)),
+        smol::Timer::after(RESPONSE_TIMEOUT),
+    )
+    .await;
+
+    let (rtyp, _ic, msg) = match resp {
+        futures::future::Either::Left((r, _)) => {
+            r.context("response receive failed")?
+        }
+        futures::future::Either::Right(_) => bail!("no response"),
+    };
+
+    if rtyp != typ {
+        bail!("unexpected response type {:#04x}", rtyp.0);
+    }
+
+    Ok(msg)
+}
+
+// Send a control request, and check the response header and completion code.
+// Returns the response data following the completion code.
+async fn control<'f>(
+    chan: &mut impl AsyncReqChannel,
+    iid: u8,
+    cmd: u8,
+    data: &[u8],
+    buf: &'f mut [u8],
+) -> Result<&'f [u8]> {
+    let mut req = vec![CONTROL_RQ | iid, cmd];
+    req.extend_from_slice(data);
+
+    let resp = request(chan, mctp::MCTP_TYPE_CONTROL, &req, buf).await?;
+
+    let [hdr, rcmd, cc, rest @ ..] = resp else {
+        bail!("short control response");
+    };
+    if *hdr != iid || *rcmd != cmd {
+        bail!("mismatched control response header {hdr:#04x} {rcmd:#04x}");
+    }
+    if *cc != 0 {
+        bail!("control command {cmd:#04x} failed, completion code {cc:#04x}");
+    }
+
+    Ok(rest)
+}
+
+async fn test_set_eid(chan: &mut impl AsyncReqChannel) -> Result<()> {
+    let mut buf = [0u8; 64];
+    let resp =
+        control(chan, 1, CONTROL_SET_EID, &[0x00, LOCAL_EID.0], &mut buf)
+            .await?;
+
+    let [status, eid, ..] = resp else {
+        bail!("short Set Endpoint ID response");
+    };
+    if status & 0x30 != 0 {
+        bail!("EID assignment rejected, status {status:#04x}");
+    }
+    if *eid != LOCAL_EID.0 {
+        bail!("EID {eid} assigned, expected {}", LOCAL_EID.0);
+    }
+
+    Ok(())
+}
+
+async fn test_msg_types(
+    chan: &mut impl AsyncReqChannel,
+    types: &[MsgType],
+) -> Result<()> {
+    let mut buf = [0u8; 64];
+    let resp = control(chan, 2, CONTROL_GET_MSG_TYPES, &[], &mut buf).await?;
+
+    let Some((count, reported)) = resp.split_first() else {
+        bail!("short Get Message Type Support response");
+    };
+    if reported.len() != *count as usize {
+        bail!("message type count {count} doesn't match response length");
+    }
+
+    for t in types {
+        if !reported.contains(&t.0) {
+            bail!("message type {:#04x} not reported", t.0);
+        }
+    }
+
+    Ok(())
+}
+
+async fn test_echo(chan: &mut impl AsyncReqChannel) -> Result<()> {
+    let mut req = VENDOR_SUBTYPE_ECHO.to_vec();
+    req.extend_from_slice(b"mctp-dev loopback");
+
+    let mut buf = [0u8; 64];
+    let resp =
+        request(chan, mctp::MCTP_TYPE_VENDOR_PCIE, &req, &mut buf).await?;
+
+    if resp != req {
+        bail!("echo response mismatch: {resp:02x?}");
+    }
+
+    Ok(())
+}
+
+/// Run a sequence of requests over the loopback transport, checking the
+/// responses from our own responders. `types` are the message types that
+/// should be reported as supported.
+pub async fn selftest(router: &Router<'_>, types: &[MsgType]) -> Result<()> {
+    let mut chan = router.req(PEER_EID);
+
+    test_set_eid(&mut chan).await.context("Set Endpoint ID")?;
+    info!("loopback: Set Endpoint ID passed");
+
+    test_msg_types(&mut chan, types)
+        .await
+        .context("Get Message Type Support")?;
+    info!("loopback: Get Message Type Support passed");
+
+    test_echo(&mut chan).await.context("Vendor echo")?;
+    info!("loopback: Vendor echo passed");
+
+    Ok(())
+}
+
+impl MctpTransport for MctpLoopback {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpLoopback::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpLoopback::send(self, pkt))
+    }
+}
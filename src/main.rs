@@ -1,24 +1,70 @@
 // SPDX-License-Identifier: GPL-3.0
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use argh::FromArgs;
 use futures::{select, FutureExt};
 use log::{debug, info, warn, LevelFilter};
-use mctp::{AsyncListener, AsyncRespChannel, Eid};
+use mctp::{AsyncListener, AsyncReqChannel, AsyncRespChannel, Eid};
 use mctp_estack::{
     control::{ControlEvent, MctpControl},
     router::{Port, PortId, PortLookup, PortTop, Router},
 };
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use transport::MctpTransport;
 
+mod ctrlstats;
+mod eidpool;
+mod events;
+mod fifo;
+mod filewriter;
+mod gadget;
+mod i2c;
+mod kernel;
+mod linkdump;
+mod loopback;
+mod monitor;
 #[cfg(feature = "nvme-mi")]
-use nvme_mi_dev::{
-    CommandEffectError, ManagementEndpoint, PciePort, PortType, Subsystem,
-    SubsystemInfo, TwoWirePort,
-};
-
+mod nvme;
+#[cfg(feature = "nvme-mi")]
+mod nvmeconfig;
+mod nvmectl;
+mod nvmefault;
+#[cfg(feature = "nvme-mi")]
+mod nvmelink;
+mod nvmepass;
+mod nvmetrace;
+mod pcap;
+mod pldmbase;
+#[cfg(feature = "pldm")]
+mod pldmfile;
+#[cfg(feature = "pldm")]
+mod pldmfw;
+#[cfg(feature = "pldm")]
+mod pldmplatform;
+#[cfg(feature = "pldm")]
+mod pldmupload;
+mod pty;
+mod qemu_i2c;
+mod replay;
 mod serial;
+mod signals;
+mod systemd;
+mod transport;
+mod tty;
+mod typefilter;
+mod udp;
+mod usbdesc;
+#[cfg(feature = "usb-host")]
+mod usbhost;
 mod usbredir;
+mod vendor;
+mod vsock;
 
 #[derive(FromArgs)]
 /// Run an emulated MCTP device
@@ -26,293 +72,3162 @@ struct Options {
     /// MCTP transport to use
     #[argh(subcommand)]
     transport: TransportSubcommand,
+
+    /// additional transport, as a transport command line, eg.
+    /// "serial /dev/ttyS1". May be specified multiple times; each transport
+    /// is a separate port.
+    #[argh(option, long = "transport")]
+    extra_transports: Vec<String>,
+
+    /// write all packets to a pcapng capture file at this path
+    #[argh(option)]
+    pcap: Option<String>,
+
+    /// listen on a unix socket at this path for monitor connections, to
+    /// send requests to the local responders
+    #[argh(option)]
+    monitor: Option<String>,
+
+    /// EID that monitor requests originate from (default 254)
+    #[argh(option, from_str_fn(parse_int), default = "254")]
+    monitor_eid: u8,
+
+    /// static EID for the endpoint, used until a bus owner assigns one. By
+    /// default, the endpoint has no EID until assigned
+    #[argh(option, from_str_fn(parse_int))]
+    eid: Option<u8>,
+
+    /// with --eid, reject Set Endpoint ID requests from bus owners
+    #[argh(switch)]
+    eid_static_only: bool,
+
+    /// interval between Discovery Notify attempts after a USB link comes
+    /// up, in milliseconds, doubling on each attempt; 0 to disable (default
+    /// 1000)
+    #[argh(option, default = "1000")]
+    discovery_interval: u64,
+
+    /// answer an example set of vendor-defined control commands, under
+    /// PCI vendor ID 0xccde
+    #[argh(switch)]
+    vendor_control: bool,
+
+    /// control requests accepted per second from each peer, with excess
+    /// requests dropped; 0 for no limit (default 100)
+    #[argh(option, default = "ctrlstats::DEFAULT_RATE")]
+    control_rate: u32,
+
+    /// endpoint UUID, reported by the control protocol (default: random)
+    #[argh(option)]
+    uuid: Option<uuid::Uuid>,
+
+    /// drop inbound messages of these types, as a comma-separated list
+    #[argh(option, from_str_fn(typefilter::parse_types))]
+    deny_types: Option<Vec<u8>>,
+
+    /// drop inbound messages of types other than these, as a
+    /// comma-separated list
+    #[argh(option, from_str_fn(typefilter::parse_types))]
+    allow_types: Option<Vec<u8>>,
+
+    /// seconds after which a route learnt from inbound packets expires, if
+    /// no further packets are received from that EID; 0 to keep routes
+    /// indefinitely (default 600). Bus owners' routes don't expire.
+    #[argh(option, default = "600")]
+    route_timeout: u64,
+
+    /// network ID, reported by Get Network ID (default: derived from the
+    /// endpoint UUID)
+    #[argh(option)]
+    network_id: Option<uuid::Uuid>,
+
+    /// file to load the endpoint UUID from, or to store a new random UUID
+    /// in if it does not exist, so the UUID persists across restarts
+    #[argh(option)]
+    uuid_file: Option<String>,
+
+    /// EID of the PLDM file transfer peer, to start the transfer without
+    /// waiting for a bus owner to assign our EID
+    #[argh(option, from_str_fn(parse_int))]
+    pldm_peer: Option<u8>,
+
+    /// file to write the PLDM file transfer data to. By default the data is
+    /// only hashed.
+    #[argh(option)]
+    pldm_output: Option<String>,
+
+    /// replace an existing --pldm-output file
+    #[argh(switch)]
+    pldm_overwrite: bool,
+
+    /// only transfer PLDM files with names matching this regular expression
+    #[argh(option)]
+    pldm_file_name: Option<FileNamePattern>,
+
+    /// only transfer the PLDM file with this file identifier
+    #[argh(option, from_str_fn(parse_int))]
+    pldm_file_id: Option<u16>,
+
+    /// exit after the first PLDM file transfer session, with a non-zero
+    /// status if it failed
+    #[argh(switch)]
+    pldm_oneshot: bool,
+
+    /// with --pldm-oneshot, fail if the session has not completed within
+    /// this many seconds, including the wait for an EID assignment
+    #[argh(option)]
+    pldm_timeout: Option<u64>,
+
+    /// attempts at a PLDM session before waiting for the next EID
+    /// assignment, retrying timeouts and transfer failures (default 5)
+    #[argh(option, default = "5")]
+    pldm_retries: u32,
+
+    /// delay before the first PLDM session retry, in milliseconds, doubling
+    /// for each further retry (default 500)
+    #[argh(option, default = "500")]
+    pldm_retry_delay: u64,
+
+    /// multipart transfer size to negotiate for PLDM file transfers, in
+    /// bytes, at least 256 (default 512)
+    #[argh(option, from_str_fn(parse_int), default = "512")]
+    pldm_xfer_size: u16,
+
+    /// size of each DfRead of a PLDM file, in bytes. A retried transfer
+    /// resumes after the last complete read. (default 65536)
+    #[argh(option, from_str_fn(parse_int), default = "65536")]
+    pldm_read_chunk: u32,
+
+    /// TOML file of simulated numeric sensors, served by a PLDM platform
+    /// monitoring responder
+    #[argh(option)]
+    pldm_sensors: Option<String>,
+
+    /// PLDM terminus ID reported by the PLDM responder, until changed by
+    /// SetTID (default 1)
+    #[argh(option, from_str_fn(parse_int), default = "pldmbase::DEFAULT_TID")]
+    pldm_tid: u8,
+
+    /// serve a local file by PLDM file transfer, as NAME=PATH, with a File
+    /// Descriptor PDR. May be repeated.
+    #[argh(option)]
+    serve_file: Vec<String>,
+
+    /// upload a local file to the PLDM file transfer peer, to the first file
+    /// its File Descriptor PDRs mark as writable
+    #[argh(option)]
+    pldm_upload: Option<String>,
+
+    /// TOML file of firmware descriptors and components, served by a PLDM
+    /// firmware update device
+    #[argh(option)]
+    pldm_fw: Option<String>,
+
+    /// directory to write component images received by the PLDM firmware
+    /// update device to (default: the current directory)
+    #[argh(option)]
+    pldm_fw_dir: Option<String>,
+
+    /// inject a failure into PLDM firmware component updates: "verify" or
+    /// "apply" to fail that step, or "stall" to stop the transfer halfway
+    #[argh(option)]
+    pldm_fw_fail: Option<String>,
+
+    /// log a summary of the USB transfer and serial frame counters at this
+    /// interval, in seconds
+    #[argh(option)]
+    stats: Option<u64>,
+
+    /// log hexdumps of link-level traffic: serial data and USB transfers
+    #[argh(switch)]
+    dump_link: bool,
+
+    /// maximum number of bytes to dump per transfer (default 256)
+    #[argh(option, default = "256")]
+    dump_link_len: usize,
+
+    /// MTU for the transport ports, overriding the transport's default
+    #[argh(option)]
+    mtu: Option<usize>,
+
+    /// maximum size of received MCTP control messages (default 1024)
+    #[argh(option, default = "Limits::default().control")]
+    max_control_msg: usize,
+
+    /// maximum size of received echo messages (default 1024)
+    #[argh(option, default = "Limits::default().echo")]
+    max_echo_msg: usize,
+
+    /// maximum size of received NVMe-MI messages (default 4352)
+    #[argh(option, default = "Limits::default().nvme_mi")]
+    max_nvme_mi_msg: usize,
+
+    /// disable the NVMe-MI responder
+    #[argh(switch)]
+    no_nvme_mi: bool,
+
+    /// NVMe-MI subsystem topology, as a TOML file of ports, controllers and
+    /// namespaces (default: one controller with two namespaces)
+    #[argh(option)]
+    nvme_config: Option<String>,
+
+    /// inject a fault into NVMe-MI responses, as "delay MS", "drop K" or
+    /// "status CODE", optionally followed by "mi|admin OPCODE" and a count.
+    /// May be specified multiple times.
+    #[argh(option, long = "nvme-inject")]
+    nvme_inject: Vec<nvmefault::Rule>,
+
+    /// forward NVMe-MI Identify and SMART log requests to this local NVMe
+    /// device, eg. /dev/nvme0
+    #[argh(option)]
+    nvme_passthrough: Option<String>,
+
+    /// log each NVMe-MI request and response at debug level, with the
+    /// decoded command, status and size
+    #[argh(switch)]
+    trace_nvme: bool,
+
+    /// disable the PLDM file transfer requester and the PLDM responder
+    #[argh(switch)]
+    no_pldm: bool,
+}
+
+/// Receive buffer sizes for the local responders. Larger messages are
+/// dropped.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    control: usize,
+    echo: usize,
+    nvme_mi: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            control: 1024,
+            echo: 1024,
+            // a 4KiB data payload, plus the largest NVMe-MI request header
+            // (an admin command, 68 bytes with the MIC) and headroom
+            nvme_mi: 4352,
+        }
+    }
+}
+
+// Log a failed listener receive. Messages that don't fit the buffer are
+// reported with the option to increase it, as they would otherwise be
+// silently dropped.
+fn listener_recv_error(e: mctp::Error, typ: &str, limit: usize, opt: &str) {
+    match e {
+        mctp::Error::NoSpace => warn!(
+            "{typ} message exceeds the {limit} byte receive buffer, \
+             dropping; the limit can be raised with {opt}"
+        ),
+        e => debug!("{typ} recv() failed: {e}"),
+    }
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum TransportSubcommand {
     Serial(SerialSubcommand),
+    Bridge(BridgeSubcommand),
+    Fifo(FifoSubcommand),
+    Gadget(GadgetSubcommand),
+    I2c(I2cSubcommand),
+    Kernel(KernelSubcommand),
+    Loopback(LoopbackSubcommand),
+    QemuI2c(QemuI2cSubcommand),
+    Replay(ReplaySubcommand),
+    Tcp(TcpSubcommand),
+    Stdio(StdioSubcommand),
+    Udp(UdpSubcommand),
+    Unix(UnixSubcommand),
     Usb(UsbRedirSubcommand),
+    UsbHost(UsbHostSubcommand),
+    Vsock(VsockSubcommand),
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "serial")]
 /// Serial transport
 struct SerialSubcommand {
-    /// TTY device
+    /// TTY device. Multiple devices may be given, each creating a separate
+    /// port
     #[argh(positional)]
-    tty: String,
-}
+    tty: Vec<String>,
 
-#[derive(FromArgs)]
-#[argh(subcommand, name = "usb")]
-/// USB redir transport
-struct UsbRedirSubcommand {
-    /// path to socket
-    #[argh(positional)]
-    path: String,
-}
+    /// allocate a new pty for the transport, rather than using a TTY device
+    #[argh(switch)]
+    pty: bool,
+
+    /// with --pty, create a symlink to the pty device at this path
+    #[argh(option)]
+    link: Option<String>,
+
+    /// reopen the TTY device if it is disconnected, rather than exiting
+    #[argh(switch)]
+    reconnect: bool,
+
+    /// baud rate for the TTY device (default: unchanged)
+    #[argh(option)]
+    baud: Option<u32>,
+
+    /// flow control for the TTY device: none or rtscts (default none)
+    #[argh(option, default = "tty::FlowControl::None")]
+    flow: tty::FlowControl,
+
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped; 0 to wait indefinitely (default 500)
+    #[argh(option)]
+    send_timeout: Option<u64>,
+
+    /// assert the DTR line of the TTY device
+    #[argh(switch)]
+    assert_dtr: bool,
+
+    /// deassert the DTR line of the TTY device
+    #[argh(switch)]
+    deassert_dtr: bool,
+
+    /// assert the RTS line of the TTY device
+    #[argh(switch)]
+    assert_rts: bool,
 
-#[allow(clippy::large_enum_variant)]
-enum Transport {
-    Serial(serial::MctpSerial),
-    Usb(usbredir::MctpUsbRedir),
+    /// deassert the RTS line of the TTY device
+    #[argh(switch)]
+    deassert_rts: bool,
+
+    /// wait for the DSR line of the TTY device to be asserted before
+    /// receiving
+    #[argh(switch)]
+    wait_dsr: bool,
 }
 
-impl Transport {
-    async fn recv(&mut self) -> mctp::Result<&[u8]> {
-        match self {
-            Self::Serial(s) => s.recv().await,
-            Self::Usb(u) => u.recv().await,
+impl SerialSubcommand {
+    // Split into one subcommand per TTY device, with the same settings
+    fn split(self) -> Vec<Self> {
+        if self.tty.len() <= 1 {
+            return vec![self];
         }
+        self.tty
+            .iter()
+            .map(|tty| Self {
+                tty: vec![tty.clone()],
+                pty: self.pty,
+                link: self.link.clone(),
+                reconnect: self.reconnect,
+                baud: self.baud,
+                flow: self.flow,
+                send_timeout: self.send_timeout,
+                assert_dtr: self.assert_dtr,
+                deassert_dtr: self.deassert_dtr,
+                assert_rts: self.assert_rts,
+                deassert_rts: self.deassert_rts,
+                wait_dsr: self.wait_dsr,
+            })
+            .collect()
     }
 
-    async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
-        match self {
-            Self::Serial(s) => s.send(pkt).await,
-            Self::Usb(u) => u.send(pkt).await,
-        }
+    // Line settings for the TTY devices
+    fn tty_config(&self) -> Result<tty::TtyConfig> {
+        let line = |assert, deassert, name| match (assert, deassert) {
+            (true, true) => bail!("Can't both assert and deassert {name}"),
+            (true, false) => Ok(Some(true)),
+            (false, true) => Ok(Some(false)),
+            (false, false) => Ok(None),
+        };
+        Ok(tty::TtyConfig {
+            baud: self.baud,
+            flow: self.flow,
+            dtr: line(self.assert_dtr, self.deassert_dtr, "DTR")?,
+            rts: line(self.assert_rts, self.deassert_rts, "RTS")?,
+        })
     }
 }
 
-struct Routes {}
+#[derive(FromArgs)]
+#[argh(subcommand, name = "bridge")]
+/// Bridge between two transports, forwarding packets between them
+struct BridgeSubcommand {
+    /// upstream transport (port 0), as a transport command line, eg.
+    /// "serial /dev/ttyS0"
+    #[argh(positional)]
+    upstream: String,
 
-impl PortLookup for Routes {
-    fn by_eid(
-        &self,
-        _eid: Eid,
-        source_port: Option<PortId>,
-    ) -> (Option<PortId>, Option<usize>) {
-        // we're an endpoint device, don't forward packets from other ports
-        if source_port.is_some() {
-            return (None, None);
-        }
-        (Some(PortId(0)), None)
-    }
+    /// downstream transport (port 1), as a transport command line
+    #[argh(positional)]
+    downstream: String,
+
+    /// static route, as EID[-EID]:PORT. May be specified multiple times.
+    #[argh(option, from_str_fn(parse_route))]
+    route: Vec<Route>,
 }
 
-async fn update_router_time(router: &Router<'_>, start_time: Instant) {
-    let ms = (Instant::now() - start_time).as_millis() as u64;
-    let r = router.update_time(ms).await;
-    if let Err(e) = r {
-        warn!("time update failure: {e}");
-    }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "fifo")]
+/// Named pipe transport, using serial framing over a pair of fifos
+struct FifoSubcommand {
+    /// fifo to receive from
+    #[argh(option)]
+    rx: String,
+
+    /// fifo to transmit on
+    #[argh(option)]
+    tx: String,
+
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped (default: wait indefinitely)
+    #[argh(option)]
+    send_timeout: Option<u64>,
 }
 
-async fn run(
-    mut transport: Transport,
-    mut port: Port<'_>,
-    router: &Router<'_>,
-) -> std::io::Result<()> {
-    let portid = PortId(0);
-    let start_time = Instant::now();
-    loop {
-        select!(
-            r = transport.recv().fuse() => {
-                update_router_time(router, start_time).await;
-                let pkt = r?;
-                router.inbound(pkt, portid).await;
-            }
-            (pkt, _dest) = port.outbound().fuse() => {
-                update_router_time(router, start_time).await;
-                let _ = transport.send(pkt).await;
-                port.outbound_done();
-            }
-        );
-    }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "gadget")]
+/// USB gadget transport, via a Linux FunctionFS instance
+struct GadgetSubcommand {
+    /// FunctionFS mount point
+    #[argh(positional)]
+    path: String,
 }
 
-#[allow(unused)]
-async fn echo<'a>(router: &'a Router<'a>) -> std::io::Result<()> {
-    const VENDOR_SUBTYPE_ECHO: [u8; 3] = [0xcc, 0xde, 0xf0];
-    let mut l = router.listener(mctp::MCTP_TYPE_VENDOR_PCIE)?;
+#[derive(FromArgs)]
+#[argh(subcommand, name = "i2c")]
+/// I2C/SMBus transport, via i2c-dev and the i2c slave-mqueue backend
+struct I2cSubcommand {
+    /// i2c-dev device, as /dev/i2c-N
+    #[argh(positional)]
+    dev: String,
 
-    info!("echo server listening");
-    let mut buf = [0u8; 100];
-    loop {
-        let Ok((_typ, _ic, msg, mut resp)) = l.recv(&mut buf).await else {
-            continue;
-        };
+    /// our 7-bit i2c address
+    #[argh(positional, from_str_fn(parse_int))]
+    addr: u8,
 
-        if !msg.starts_with(&VENDOR_SUBTYPE_ECHO) {
-            continue;
-        }
+    /// path to the slave-mqueue file (default: sysfs path for dev and addr)
+    #[argh(option)]
+    mqueue: Option<String>,
 
-        if let Err(_e) = resp.send(msg).await {
-            debug!("listener reply fail");
-        }
-    }
+    /// 7-bit i2c address of the bus owner, used before any packets have
+    /// been received
+    #[argh(option, from_str_fn(parse_int))]
+    peer_addr: Option<u8>,
 }
 
-async fn control(
-    router: &Router<'_>,
-    ctrl_ev_sender: async_channel::Sender<ControlEvent>,
-) -> std::io::Result<()> {
-    let mut l = router.listener(mctp::MCTP_TYPE_CONTROL)?;
-    let mut c = MctpControl::new(router);
-    let u = uuid::Uuid::new_v4();
-
-    let types = [
-        mctp::MCTP_TYPE_CONTROL,
-        #[cfg(feature = "pldm")]
-        mctp::MCTP_TYPE_PLDM,
-        #[cfg(feature = "nvme-mi")]
-        mctp::MCTP_TYPE_NVME,
-    ];
+#[derive(FromArgs)]
+#[argh(subcommand, name = "kernel")]
+/// Linux kernel AF_MCTP transport
+struct KernelSubcommand {
+    /// MCTP network to bind to (default any)
+    #[argh(option)]
+    net: Option<u32>,
+}
 
-    c.set_message_types(&types)?;
-    c.set_uuid(&u);
+#[derive(FromArgs)]
+#[argh(subcommand, name = "loopback")]
+/// Loopback transport, running a self test against the local responders.
+/// Exits once the test is complete.
+struct LoopbackSubcommand {}
 
-    info!("MCTP Control Protocol server listening");
-    let mut buf = [0u8; 256];
-    loop {
-        let Ok((_typ, _ic, msg, resp)) = l.recv(&mut buf).await else {
-            continue;
-        };
+#[derive(FromArgs)]
+#[argh(subcommand, name = "qemu-i2c")]
+/// I2C/SMBus transport, via a qemu i2c socket
+struct QemuI2cSubcommand {
+    /// path to socket
+    #[argh(positional)]
+    path: String,
 
-        let r = c.handle_async(msg, resp).await;
+    /// our 7-bit i2c address
+    #[argh(positional, from_str_fn(parse_int))]
+    addr: u8,
 
-        match r {
-            Err(e) => info!("control handler failure: {e}"),
-            Ok(Some(ev)) => {
-                let _ = ctrl_ev_sender.force_send(ev);
-            }
-            Ok(None) => (),
-        }
-    }
+    /// 7-bit i2c address of the bus owner, used before any packets have
+    /// been received
+    #[argh(option, from_str_fn(parse_int))]
+    peer_addr: Option<u8>,
 }
 
-#[cfg(feature = "nvme-mi")]
-async fn nvme_mi(router: &Router<'_>) -> std::io::Result<()> {
-    let mut l = router.listener(mctp::MCTP_TYPE_NVME)?;
-
-    let mut subsys = Subsystem::new(SubsystemInfo::environment());
-    let ppid = subsys
-        .add_port(PortType::Pcie(PciePort::new()))
-        .expect("Unable to create PCIe port");
-    let ctlrid = subsys
-        .add_controller(ppid)
-        .expect("Unable to create controller");
-    let nsid = subsys
-        .add_namespace(1024)
-        .expect("Unable to create namespace");
-    subsys
-        .add_namespace(2048)
-        .expect("Unable to create namespace");
-    subsys
-        .controller_mut(ctlrid)
-        .attach_namespace(nsid)
-        .unwrap_or_else(|_| {
-            panic!(
-                "Unable to attach namespace {nsid:?} to controller {ctlrid:?}"
-            )
-        });
-    let twpid = subsys
-        .add_port(PortType::TwoWire(TwoWirePort::new()))
-        .expect("Unable to create TwoWire port");
-    let mut mep = ManagementEndpoint::new(twpid);
+#[derive(FromArgs)]
+#[argh(subcommand, name = "replay")]
+/// Replay inbound packets from a pcap or pcapng capture file. Exits once all
+/// packets have been replayed.
+struct ReplaySubcommand {
+    /// path to capture file
+    #[argh(positional)]
+    path: String,
 
-    debug!("NVMe-MI endpoint listening");
+    /// delay packets according to their capture timestamps
+    #[argh(switch)]
+    realtime: bool,
 
-    let mut buf = [0u8; 4224];
-    loop {
-        let Ok((_typ, ic, msg, resp)) = l.recv(&mut buf).await else {
-            debug!("recv() failed");
-            continue;
-        };
+    /// compare sent packets against those in the capture
+    #[argh(switch)]
+    compare: bool,
+}
 
-        debug!("Handling NVMe-MI message: {msg:x?}");
-        mep.handle_async(&mut subsys, msg, ic, resp, async |ce| match ce {
-            nvme_mi_dev::CommandEffect::SetMtu { port_id, mtus } => {
-                if port_id != twpid {
-                    warn!("NVMe-MI: Bad Port ID for Set MTU: {port_id:?}");
-                    return Err(CommandEffectError::InternalError);
-                }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stdio")]
+/// stdin/stdout transport, using serial framing. Logs are sent to stderr.
+struct StdioSubcommand {
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped (default: wait indefinitely)
+    #[argh(option)]
+    send_timeout: Option<u64>,
+}
 
-                if mtus != 64 {
-                    warn!("NVMe-MI: Application lacks support for MTU ({mtus}) != BTU (64)");
-                    return Err(CommandEffectError::Unsupported);
-                }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "tcp")]
+/// TCP transport, using serial framing
+struct TcpSubcommand {
+    /// remote address, as host:port
+    #[argh(positional)]
+    addr: String,
 
-                Ok(())
-            }
-            nvme_mi_dev::CommandEffect::SetSmbusFreq { port_id: _, freq } => {
-                use nvme_mi_dev::smbus::BusFrequency;
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped (default: wait indefinitely)
+    #[argh(option)]
+    send_timeout: Option<u64>,
+}
 
-                if freq != BusFrequency::Freq100Khz {
-                    warn!("NVMe-MI: Application lacks support for I2C bus frequency {:?}", freq);
-                    return Err(CommandEffectError::Unsupported);
-                }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "udp")]
+/// UDP transport, with one MCTP packet per datagram
+struct UdpSubcommand {
+    /// local address to bind to, as addr:port
+    #[argh(positional)]
+    local: String,
 
-                Ok(())
-            }
-        })
-        .await;
-    }
-}
-#[cfg(not(feature = "nvme-mi"))]
-async fn nvme_mi(_router: &Router<'_>) -> std::io::Result<()> {
-    futures::future::pending().await
+    /// remote peer address, as host:port
+    #[argh(positional)]
+    remote: String,
+
+    /// maximum datagram size, including the MCTP packet header
+    #[argh(option, default = "1024")]
+    mtu: usize,
 }
 
-#[cfg(feature = "pldm")]
-mod pldm;
-#[cfg(not(feature = "pldm"))]
-mod pldm {
-    pub async fn pldm(
-        _router: &super::Router<'_>,
-        _recv: async_channel::Receiver<super::ControlEvent>,
-    ) -> std::io::Result<()> {
-        futures::future::pending().await
-    }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "unix")]
+/// Unix socket transport, using serial framing
+struct UnixSubcommand {
+    /// path to socket
+    #[argh(positional)]
+    path: String,
+
+    /// number of connection attempts while waiting for the socket
+    #[argh(option, default = "10")]
+    attempts: u32,
+
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped (default: wait indefinitely)
+    #[argh(option)]
+    send_timeout: Option<u64>,
 }
 
-fn main() -> Result<()> {
-    let opts: Options = argh::from_env();
+#[derive(FromArgs)]
+#[argh(subcommand, name = "usb")]
+/// USB redir transport
+struct UsbRedirSubcommand {
+    /// path to socket
+    #[argh(positional)]
+    path: String,
 
-    let conf = simplelog::ConfigBuilder::new().build();
-    simplelog::SimpleLogger::init(LevelFilter::Debug, conf)?;
+    /// create and listen on the socket, rather than opening an existing path
+    #[argh(switch)]
+    listen: bool,
 
-    let eid = Eid(0);
+    /// exit when the usbredir peer disconnects, rather than waiting for a
+    /// new session
+    #[argh(switch)]
+    no_reconnect: bool,
 
-    let mut port_top = PortTop::new();
-    let routes = Routes {};
-    let mut router = Router::new(eid, &routes, 0);
-    let port_id = router.add_port(&mut port_top)?;
-    let port = router.port(port_id)?;
+    /// USB vendor ID to report (default 0x0000)
+    #[argh(option, from_str_fn(parse_int))]
+    usb_vid: Option<u16>,
 
-    let (transport, mut t_port) = match opts.transport {
-        TransportSubcommand::Serial(s) => {
-            let serial = serial::MctpSerial::new(&s.tty)?;
-            info!("Created MCTP Serial transport on {}", s.tty);
-            let t = Transport::Serial(serial);
-            (t, None)
+    /// USB product ID to report (default 0x0000)
+    #[argh(option, from_str_fn(parse_int))]
+    usb_pid: Option<u16>,
+
+    /// USB manufacturer string (default "mctp-dev")
+    #[argh(option)]
+    usb_manufacturer: Option<String>,
+
+    /// USB product string (default "MCTP over USB device")
+    #[argh(option)]
+    usb_product: Option<String>,
+
+    /// USB serial number string (default: the MCTP endpoint UUID)
+    #[argh(option)]
+    usb_serial: Option<String>,
+
+    /// USB device speed: full, high or super (default high)
+    #[argh(option, default = "usbredir::UsbSpeed::High")]
+    usb_speed: usbredir::UsbSpeed,
+
+    /// number of received OUT transfers to queue, before failing further
+    /// transfers (default 64)
+    #[argh(option)]
+    usb_out_queue: Option<usize>,
+
+    /// add an interrupt IN endpoint, notifying the host when outbound data
+    /// is available
+    #[argh(switch)]
+    usb_int_ep: bool,
+
+    /// fail OUT transfers that have invalid MCTP USB headers, rather than
+    /// completing them and discarding the invalid data
+    #[argh(switch)]
+    strict_usb: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "usb-host")]
+/// Minimal usbredir host, for testing: connects to a usb transport, and
+/// queries its EID. Requires the usb-host feature
+#[cfg_attr(not(feature = "usb-host"), allow(dead_code))]
+struct UsbHostSubcommand {
+    /// path to the device's usbredir socket
+    #[argh(positional)]
+    path: String,
+
+    /// EID to send the Get Endpoint ID request to (default 0, the null EID)
+    #[argh(option, from_str_fn(parse_int), default = "0")]
+    dest_eid: u8,
+
+    /// fail unless the device reports this EID
+    #[argh(option, from_str_fn(parse_int))]
+    expect_eid: Option<u8>,
+}
+
+impl UsbRedirSubcommand {
+    fn device_info(&self, uuid: &uuid::Uuid) -> usbredir::UsbDeviceInfo {
+        // the serial number allows the host to correlate the USB device with
+        // the endpoint's Get Endpoint UUID response
+        let mut info = usbredir::UsbDeviceInfo {
+            vid: self.usb_vid,
+            pid: self.usb_pid,
+            speed: self.usb_speed,
+            serial: uuid.to_string(),
+            int_ep: self.usb_int_ep,
+            strict: self.strict_usb,
+            ..Default::default()
+        };
+        if let Some(s) = &self.usb_manufacturer {
+            info.manufacturer = s.clone();
         }
-        TransportSubcommand::Usb(u) => {
-            let (usbredir, t_port) = usbredir::MctpUsbRedir::new(&u.path)?;
-            info!("Created MCTP USB transport on {}", u.path);
-            let t = Transport::Usb(usbredir);
-            (t, Some(t_port))
+        if let Some(s) = &self.usb_product {
+            info.product = s.clone();
         }
-    };
+        if let Some(s) = &self.usb_serial {
+            info.serial = s.clone();
+        }
+        if let Some(n) = self.usb_out_queue {
+            info.out_queue = n;
+        }
+        info
+    }
+}
 
-    let fut = match t_port {
-        Some(ref mut p) => futures::future::Either::Left(p.process()),
-        None => futures::future::Either::Right(futures::future::pending()),
-    };
+#[derive(FromArgs)]
+#[argh(subcommand, name = "vsock")]
+/// vsock transport, using serial framing
+struct VsockSubcommand {
+    /// remote CID to connect to (default: host)
+    #[argh(option, default = "2")]
+    cid: u32,
 
-    let (ctrl_ev_tx, ctrl_ev_rx) = async_channel::bounded(1);
+    /// vsock port
+    #[argh(positional)]
+    port: u32,
 
-    smol::block_on(async {
-        select!(
-            _ = fut.fuse() => (),
-            _ = run(transport, port, &router).fuse() => (),
-            _ = control(&router, ctrl_ev_tx).fuse() => (),
-            _ = nvme_mi(&router).fuse() => (),
-            _ = pldm::pldm(&router, ctrl_ev_rx).fuse() => (),
-        );
-        Ok::<_, anyhow::Error>(())
-    })?;
+    /// listen for a connection on the port, rather than connecting
+    #[argh(switch)]
+    listen: bool,
 
-    Ok(())
+    /// time allowed to send each frame in milliseconds, after which the
+    /// frame is dropped (default: wait indefinitely)
+    #[argh(option)]
+    send_timeout: Option<u64>,
+}
+
+// Parse an integer argument, allowing a 0x prefix for hex
+fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let v = match s.strip_prefix("0x") {
+        Some(h) => u64::from_str_radix(h, 16),
+        None => s.parse::<u64>(),
+    }
+    .map_err(|e| e.to_string())?;
+    T::try_from(v).map_err(|_| format!("value {v} out of range"))
+}
+
+// Parse a static route, as EID[-EID]:PORT
+fn parse_route(s: &str) -> Result<Route, String> {
+    let (eids, port) = s
+        .split_once(':')
+        .ok_or_else(|| "route should be EID[-EID]:PORT".to_string())?;
+    let (start, end) = match eids.split_once('-') {
+        Some((s, e)) => (parse_int(s)?, parse_int(e)?),
+        None => {
+            let e = parse_int(eids)?;
+            (e, e)
+        }
+    };
+    if start > end {
+        return Err(format!("invalid EID range {eids}"));
+    }
+    Ok(Route {
+        eids: start..=end,
+        port: parse_int(port)?,
+    })
+}
+
+// Parse a transport specified as a single argument, eg.
+// "usb --listen /tmp/usbredir.sock"
+fn parse_transport(spec: &str) -> Result<TransportSubcommand> {
+    let args = spec.split_whitespace().collect::<Vec<_>>();
+    let Some((name, args)) = args.split_first() else {
+        bail!("Empty transport specification");
+    };
+    let t = TransportSubcommand::from_args(&[*name], args).map_err(|e| {
+        anyhow::anyhow!("Invalid transport '{spec}': {}", e.output.trim())
+    })?;
+    match t {
+        TransportSubcommand::Bridge(_) => {
+            bail!("A bridge can't be used as a transport here")
+        }
+        TransportSubcommand::UsbHost(_) => {
+            bail!("usb-host can't be used as a transport")
+        }
+        _ => (),
+    }
+    Ok(t)
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    eids: RangeInclusive<u8>,
+    port: u8,
+}
+
+#[derive(Default)]
+struct BusOwners {
+    // bus owner EID, by port index
+    by_port: HashMap<u8, u8>,
+    // the bus owner that most recently assigned our EID
+    current: Option<u8>,
+    // number of EID assignments, to detect new assignments
+    assignments: u64,
+}
+
+/* Get Routing Table Entries entry, with no physical address */
+type RoutingEntry = [u8; 6];
+
+/* MCTP baseline transmission unit, the minimum MTU */
+const MCTP_BTU: usize = 64;
+
+/* routing table entry types */
+const ROUTING_ENTRY_ENDPOINT: u8 = 0b00;
+const ROUTING_ENTRY_BRIDGE: u8 = 0b10;
+/* EID range behind a bridge, not including the bridge itself */
+const ROUTING_ENTRY_RANGE: u8 = 0b11;
+
+/* A port learnt from the source of inbound packets */
+struct Learnt {
+    port: u8,
+    seen: Instant,
+}
+
+struct Routes {
+    // whether we forward packets between ports, rather than acting only as
+    // an endpoint
+    bridge: bool,
+    static_routes: Vec<Route>,
+    // EID to port, learnt from the source of inbound packets
+    learnt: Mutex<HashMap<u8, Learnt>>,
+    // idle period after which learnt routes expire, other than bus owners'
+    learnt_timeout: Option<Duration>,
+    bus_owners: Mutex<BusOwners>,
+    // port index to MTU, for ports with a limited MTU
+    mtus: Mutex<HashMap<u8, usize>>,
+    // port index to the MTU the port was created with, the upper limit for
+    // MTUs negotiated by peers
+    max_mtus: Mutex<HashMap<u8, usize>>,
+    // port index to DSP0239 transport binding, for the transport ports
+    bindings: Mutex<HashMap<u8, u8>>,
+    // EID pool allocated to the bridge, routed to the downstream port
+    pool_route: Mutex<Option<Route>>,
+    // port for locally-originated packets to the null EID, while assigning
+    // EIDs downstream
+    null_route: Mutex<Option<u8>>,
+    // ports on which we have been discovered by the bus owner
+    discovered: Mutex<HashSet<u8>>,
+    // our EID, to recognise inbound packets that aren't forwarded
+    local_eid: AtomicU8,
+}
+
+impl Routes {
+    fn new(
+        bridge: bool,
+        static_routes: Vec<Route>,
+        learnt_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            bridge,
+            static_routes,
+            learnt: Mutex::new(HashMap::new()),
+            learnt_timeout,
+            bus_owners: Mutex::new(BusOwners::default()),
+            mtus: Mutex::new(HashMap::new()),
+            max_mtus: Mutex::new(HashMap::new()),
+            bindings: Mutex::new(HashMap::new()),
+            pool_route: Mutex::new(None),
+            null_route: Mutex::new(None),
+            discovered: Mutex::new(HashSet::new()),
+            local_eid: AtomicU8::new(0),
+        }
+    }
+
+    fn set_local_eid(&self, eid: Eid) {
+        self.local_eid.store(eid.0, Ordering::Relaxed);
+    }
+
+    // Whether an inbound packet is delivered locally, rather than forwarded
+    fn is_local(&self, pkt: &[u8]) -> bool {
+        let Some(&dest) = pkt.get(1) else {
+            return false;
+        };
+        !self.bridge
+            || dest == 0
+            || dest == 0xff
+            || dest == self.local_eid.load(Ordering::Relaxed)
+    }
+
+    fn set_discovered(&self, port: u8, discovered: bool) {
+        let mut ports = self.discovered.lock().unwrap();
+        let changed = if discovered {
+            ports.insert(port)
+        } else {
+            ports.remove(&port)
+        };
+        if changed {
+            debug!("Port {port} discovered: {discovered}");
+        }
+    }
+
+    fn discovered(&self, port: u8) -> bool {
+        self.discovered.lock().unwrap().contains(&port)
+    }
+
+    fn set_pool_route(&self, eids: Option<RangeInclusive<u8>>, port: u8) {
+        *self.pool_route.lock().unwrap() =
+            eids.map(|eids| Route { eids, port });
+    }
+
+    fn set_null_route(&self, port: Option<u8>) {
+        *self.null_route.lock().unwrap() = port;
+    }
+
+    fn set_binding(&self, port: PortId, binding: u8) {
+        self.bindings.lock().unwrap().insert(port.0, binding);
+    }
+
+    // Routing table entries, in the Get Routing Table Entries format: our
+    // own EID, then in bridge mode, the static and learnt routes. Ports
+    // without a transport binding (the monitor) are omitted.
+    fn routing_table(&self, own: Eid, own_static: bool) -> Vec<RoutingEntry> {
+        let bindings = self.bindings.lock().unwrap();
+        let entry = |start: u8, len: u8, typ: u8, is_static: bool, port| {
+            let binding = *bindings.get(&port)?;
+            Some([
+                len,
+                start,
+                typ << 6 | u8::from(is_static) << 5 | (port & 0x1f),
+                binding,
+                0, /* physical media type: unspecified */
+                0, /* physical address size: none */
+            ])
+        };
+
+        let own_port = self.bus_owner_port().unwrap_or(0);
+        let own_type = if self.bridge {
+            ROUTING_ENTRY_BRIDGE
+        } else {
+            ROUTING_ENTRY_ENDPOINT
+        };
+        let mut entries = Vec::new();
+        entries.extend(entry(own.0, 1, own_type, own_static, own_port));
+        if !self.bridge {
+            return entries;
+        }
+
+        for r in &self.static_routes {
+            let len = (r.eids.end() - r.eids.start()).saturating_add(1);
+            let typ = if len == 1 {
+                ROUTING_ENTRY_ENDPOINT
+            } else {
+                ROUTING_ENTRY_RANGE
+            };
+            entries.extend(entry(*r.eids.start(), len, typ, true, r.port));
+        }
+
+        if let Some(r) = &*self.pool_route.lock().unwrap() {
+            let len = r.eids.end() - r.eids.start() + 1;
+            entries.extend(entry(
+                *r.eids.start(),
+                len,
+                ROUTING_ENTRY_RANGE,
+                false,
+                r.port,
+            ));
+        }
+
+        for (eid, port, _) in self.learnt_routes() {
+            let routed =
+                self.static_routes.iter().any(|r| r.eids.contains(&eid))
+                    || self.pool_route_port(eid).is_some();
+            if eid != own.0 && !routed {
+                entries.extend(entry(
+                    eid,
+                    1,
+                    ROUTING_ENTRY_ENDPOINT,
+                    false,
+                    port,
+                ));
+            }
+        }
+        entries
+    }
+
+    fn set_mtu(&self, port: PortId, mtu: usize) {
+        self.mtus.lock().unwrap().insert(port.0, mtu);
+        self.max_mtus.lock().unwrap().insert(port.0, mtu);
+    }
+
+    // Set the MTU of `port` as negotiated by a peer, within the MTU that
+    // the port was created with. Returns false if `mtu` is out of range.
+    fn negotiate_mtu(&self, port: u8, mtu: usize) -> bool {
+        let max = self.max_mtus.lock().unwrap().get(&port).copied();
+        if mtu < MCTP_BTU || max.is_some_and(|m| mtu > m) {
+            return false;
+        }
+        self.mtus.lock().unwrap().insert(port, mtu);
+        debug!("Port {port} MTU {mtu}");
+        true
+    }
+
+    // Route to `port`, with its MTU
+    fn route(&self, port: u8) -> (Option<PortId>, Option<usize>) {
+        let mtu = self.mtus.lock().unwrap().get(&port).copied();
+        (Some(PortId(port)), mtu)
+    }
+
+    // Record a bus owner assigning our EID. This will have been received on
+    // the port that the owner was last seen on.
+    //
+    // The router has a single local EID, so an assignment from any port
+    // applies to all ports; we track the owner of each port separately, and
+    // default to the port of the most recent owner for outbound traffic.
+    fn set_bus_owner(&self, owner: Eid) {
+        let port = self.learnt.lock().unwrap().get(&owner.0).map(|l| l.port);
+        let mut owners = self.bus_owners.lock().unwrap();
+        owners.assignments += 1;
+        let Some(port) = port else {
+            warn!("No known port for bus owner {owner}");
+            return;
+        };
+        owners.by_port.insert(port, owner.0);
+        owners.current = Some(owner.0);
+        info!("Bus owner {owner} on port {port}");
+    }
+
+    fn eid_assignments(&self) -> u64 {
+        self.bus_owners.lock().unwrap().assignments
+    }
+
+    // The port that the current bus owner was last seen on
+    fn bus_owner_port(&self) -> Option<u8> {
+        let owner = self.bus_owners.lock().unwrap().current?;
+        self.learnt.lock().unwrap().get(&owner).map(|l| l.port)
+    }
+
+    fn is_bus_owner(&self, eid: u8) -> bool {
+        let owners = self.bus_owners.lock().unwrap();
+        owners.current == Some(eid)
+            || owners.by_port.values().any(|o| *o == eid)
+    }
+
+    // Whether a learnt route has been idle for longer than the timeout.
+    // Bus owners may be idle indefinitely, but must still be reachable.
+    fn expired(&self, eid: u8, l: &Learnt, now: Instant) -> bool {
+        self.learnt_timeout
+            .is_some_and(|t| now.duration_since(l.seen) > t)
+            && !self.is_bus_owner(eid)
+    }
+
+    fn learnt_port(&self, eid: u8) -> Option<u8> {
+        let learnt = self.learnt.lock().unwrap();
+        let l = learnt.get(&eid)?;
+        (!self.expired(eid, l, Instant::now())).then_some(l.port)
+    }
+
+    // Learnt routes that have not expired, as (EID, port, idle time), in EID
+    // order
+    fn learnt_routes(&self) -> Vec<(u8, u8, Duration)> {
+        let now = Instant::now();
+        let learnt = self.learnt.lock().unwrap();
+        let mut routes = learnt
+            .iter()
+            .filter(|(e, l)| !self.expired(**e, l, now))
+            .map(|(e, l)| (*e, l.port, now.duration_since(l.seen)))
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|r| r.0);
+        routes
+    }
+
+    // Summary of the learnt routes, for the stats dump
+    fn learnt_summary(&self) -> String {
+        let routes = self.learnt_routes();
+        if routes.is_empty() {
+            return "none".to_string();
+        }
+        routes
+            .iter()
+            .map(|(eid, port, idle)| {
+                format!("{eid} port {port} ({}s idle)", idle.as_secs())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    // Record the port that the source EID of an inbound packet is reachable
+    // through
+    fn learn(&self, pkt: &[u8], port: PortId) {
+        let Some(&src) = pkt.get(2) else {
+            return;
+        };
+        // null and broadcast EIDs don't identify a peer
+        if src == 0 || src == 0xff {
+            return;
+        }
+        let now = Instant::now();
+        let prev = self.learnt.lock().unwrap().insert(
+            src,
+            Learnt {
+                port: port.0,
+                seen: now,
+            },
+        );
+        if prev.is_none_or(|p| p.port != port.0) {
+            debug!("EID {src} reachable via port {}", port.0);
+        }
+
+        // drop expired routes, so the table doesn't grow with departed
+        // peers
+        let mut learnt = self.learnt.lock().unwrap();
+        let expired = learnt
+            .iter()
+            .filter(|(e, l)| self.expired(**e, l, now))
+            .map(|(e, _)| *e)
+            .collect::<Vec<_>>();
+        for eid in expired {
+            debug!("Route to EID {eid} expired");
+            learnt.remove(&eid);
+        }
+    }
+
+    fn pool_route_port(&self, eid: u8) -> Option<u8> {
+        let route = self.pool_route.lock().unwrap();
+        route
+            .as_ref()
+            .filter(|r| r.eids.contains(&eid))
+            .map(|r| r.port)
+    }
+
+    fn lookup(&self, eid: u8) -> Option<u8> {
+        self.static_routes
+            .iter()
+            .find(|r| r.eids.contains(&eid))
+            .map(|r| r.port)
+            .or_else(|| self.pool_route_port(eid))
+            .or_else(|| self.learnt_port(eid))
+    }
+}
+
+impl PortLookup for Routes {
+    fn by_eid(
+        &self,
+        eid: Eid,
+        source_port: Option<PortId>,
+    ) -> (Option<PortId>, Option<usize>) {
+        // Packets for our own EID are delivered locally by the router, so we
+        // only see packets to other destinations here.
+        let Some(source) = source_port else {
+            // locally-originated; responses go out through the port that
+            // the requester was seen on, otherwise default to the bus
+            // owner's port, or the upstream port
+            let null_route = match eid.0 {
+                0 => *self.null_route.lock().unwrap(),
+                _ => None,
+            };
+            let port = null_route
+                .or_else(|| self.lookup(eid.0))
+                .or_else(|| self.bus_owner_port())
+                .unwrap_or(0);
+            return self.route(port);
+        };
+
+        // we're an endpoint device, don't forward packets from other ports
+        if !self.bridge {
+            return (None, None);
+        }
+
+        // unknown destinations are forwarded to the other side of the bridge
+        let port =
+            self.lookup(eid.0)
+                .unwrap_or(if source.0 == 0 { 1 } else { 0 });
+
+        // never forward back out of the ingress port
+        if port == source.0 {
+            return (None, None);
+        }
+        self.route(port)
+    }
+}
+
+async fn update_router_time(router: &Router<'_>, start_time: Instant) {
+    let ms = (Instant::now() - start_time).as_millis() as u64;
+    let r = router.update_time(ms).await;
+    if let Err(e) = r {
+        warn!("time update failure: {e}");
+    }
+}
+
+async fn run(
+    mut transport: impl MctpTransport,
+    mut port: Port<'_>,
+    portid: PortId,
+    router: &Router<'_>,
+    routes: &Routes,
+    capture: &pcap::Capture,
+    filter: &typefilter::TypeFilter,
+) -> std::io::Result<()> {
+    let start_time = Instant::now();
+    loop {
+        select!(
+            r = transport.recv().fuse() => {
+                update_router_time(router, start_time).await;
+                let pkt = match r {
+                    Ok(pkt) => pkt,
+                    Err(_) if transport.closed() => {
+                        info!("Transport closed by peer");
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                routes.learn(pkt, portid);
+                capture.packet(portid.0, pcap::Direction::In, pkt);
+                if routes.is_local(pkt) && !filter.accept(pkt) {
+                    continue;
+                }
+                router.inbound(pkt, portid).await;
+            }
+            (pkt, _dest) = port.outbound().fuse() => {
+                update_router_time(router, start_time).await;
+                capture.packet(portid.0, pcap::Direction::Out, pkt);
+                let _ = transport.send(pkt).await;
+                port.outbound_done();
+            }
+        );
+    }
+}
+
+async fn echo<'a>(
+    router: &'a Router<'a>,
+    limits: &Limits,
+) -> std::io::Result<()> {
+    const VENDOR_SUBTYPE_ECHO: [u8; 3] = [0xcc, 0xde, 0xf0];
+    let mut l = router.listener(mctp::MCTP_TYPE_VENDOR_PCIE)?;
+
+    info!("echo server listening");
+    let mut buf = vec![0u8; limits.echo];
+    loop {
+        let (_typ, _ic, msg, mut resp) = match l.recv(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                listener_recv_error(e, "echo", limits.echo, "--max-echo-msg");
+                continue;
+            }
+        };
+
+        if !msg.starts_with(&VENDOR_SUBTYPE_ECHO) {
+            continue;
+        }
+
+        if let Err(_e) = resp.send(msg).await {
+            debug!("listener reply fail");
+        }
+    }
+}
+
+/// Message types of the enabled subsystems, reported by Get Message Type
+/// Support. Each subsystem registers its types before the listeners start.
+#[derive(Default)]
+struct MessageTypes {
+    types: Vec<mctp::MsgType>,
+}
+
+impl MessageTypes {
+    // The types of the control protocol, and of the subsystems enabled at
+    // runtime
+    fn enabled(echo: bool, nvme_mi: bool, pldm: bool) -> Self {
+        let mut types = Self::default();
+        types.register(mctp::MCTP_TYPE_CONTROL, "control");
+        if echo {
+            types.register(mctp::MCTP_TYPE_VENDOR_PCIE, "echo");
+        }
+        if nvme_mi {
+            types.register(mctp::MCTP_TYPE_NVME, "NVMe-MI");
+        }
+        if pldm {
+            types.register(mctp::MCTP_TYPE_PLDM, "PLDM");
+        }
+        types
+    }
+
+    fn register(&mut self, typ: mctp::MsgType, subsystem: &str) {
+        if !self.types.contains(&typ) {
+            debug!("{subsystem} handles message type {:#04x}", typ.0);
+            self.types.push(typ);
+        }
+    }
+
+    fn all(&self) -> &[mctp::MsgType] {
+        &self.types
+    }
+
+    // Types other than control, for the application listeners
+    fn app(&self) -> Vec<mctp::MsgType> {
+        self.types
+            .iter()
+            .filter(|t| **t != mctp::MCTP_TYPE_CONTROL)
+            .copied()
+            .collect()
+    }
+}
+
+const MCTP_CTRL_CMD_SET_ENDPOINT_ID: u8 = 0x01;
+const MCTP_CTRL_CMD_GET_ENDPOINT_ID: u8 = 0x02;
+const MCTP_CTRL_CMD_GET_ROUTING_TABLE: u8 = 0x0a;
+const MCTP_CTRL_CMD_PREPARE_DISCOVERY: u8 = 0x0b;
+const MCTP_CTRL_CMD_ENDPOINT_DISCOVERY: u8 = 0x0c;
+const MCTP_CTRL_CMD_GET_NETWORK_ID: u8 = 0x0e;
+
+const MCTP_CTRL_CC_SUCCESS: u8 = 0x00;
+const MCTP_CTRL_CC_ERROR_INVALID_DATA: u8 = 0x02;
+const MCTP_CTRL_CC_ERROR_INVALID_LENGTH: u8 = 0x03;
+const MCTP_CTRL_CC_ERROR_UNSUPPORTED_CMD: u8 = 0x05;
+
+/* entries per Get Routing Table Entries response, to fit a baseline MTU */
+const ROUTING_ENTRIES_PER_RESPONSE: usize = 8;
+
+// Build a Get Routing Table Entries response for `req`, returning the
+// entries from the requested entry handle onwards
+fn routing_table_response(req: &[u8], entries: &[RoutingEntry]) -> Vec<u8> {
+    let mut resp = vec![req[0] & 0x1f, MCTP_CTRL_CMD_GET_ROUTING_TABLE];
+    let start = req.get(2).map(|h| *h as usize);
+    let Some(start) = start.filter(|s| *s < entries.len()) else {
+        resp.push(MCTP_CTRL_CC_ERROR_INVALID_DATA);
+        return resp;
+    };
+
+    let end = (start + ROUTING_ENTRIES_PER_RESPONSE).min(entries.len());
+    let next = if end < entries.len() { end as u8 } else { 0xff };
+    resp.extend_from_slice(&[MCTP_CTRL_CC_SUCCESS, next, (end - start) as u8]);
+    for e in &entries[start..end] {
+        resp.extend_from_slice(e);
+    }
+    resp
+}
+
+/// An EID assigned on the command line
+#[derive(Clone, Copy, Debug)]
+struct StaticEid {
+    eid: Eid,
+    /// reject reassignment by a bus owner
+    fixed: bool,
+}
+
+/* Set Endpoint ID operations */
+const SET_EID_OP_SET: u8 = 0b00;
+const SET_EID_OP_FORCE: u8 = 0b01;
+const SET_EID_OP_RESET: u8 = 0b10;
+const SET_EID_OP_SET_DISCOVERED: u8 = 0b11;
+
+/* Set Endpoint ID status: accepted or rejected, not using an EID pool */
+const SET_EID_ACCEPTED: u8 = 0x00;
+const SET_EID_REJECTED: u8 = 0x10;
+
+/* mixed into the endpoint UUID for the default network ID */
+const NETWORK_ID_SALT: [u8; 16] = *b"mctp-dev network";
+
+// A network ID derived from the endpoint UUID, so that it is stable when
+// the endpoint UUID is, but distinct from it
+fn default_network_id(uuid: &uuid::Uuid) -> uuid::Uuid {
+    let mut bytes = *uuid.as_bytes();
+    for (b, s) in bytes.iter_mut().zip(NETWORK_ID_SALT) {
+        *b ^= s;
+    }
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+// Build a Get Network ID response, for a request with header byte `hdr`
+fn network_id_response(hdr: u8, network_id: &uuid::Uuid) -> Vec<u8> {
+    let mut resp = vec![
+        hdr & 0x1f,
+        MCTP_CTRL_CMD_GET_NETWORK_ID,
+        MCTP_CTRL_CC_SUCCESS,
+    ];
+    resp.extend_from_slice(network_id.as_bytes());
+    resp
+}
+
+fn get_eid_response(
+    hdr: u8,
+    current: Eid,
+    static_eid: Option<StaticEid>,
+    bridge: bool,
+) -> Vec<u8> {
+    // dynamic EID, or static EID and whether it is current
+    let eid_type = match static_eid {
+        None => 0b00,
+        Some(s) if s.eid == current => 0b10,
+        Some(_) => 0b11,
+    };
+    // simple endpoint, or bridge
+    let endpoint_type = if bridge { 0b01 } else { 0b00 };
+    vec![
+        hdr & 0x1f,
+        MCTP_CTRL_CMD_GET_ENDPOINT_ID,
+        MCTP_CTRL_CC_SUCCESS,
+        current.0,
+        endpoint_type << 4 | eid_type,
+        0, /* medium-specific */
+    ]
+}
+
+/// Handling of a Set Endpoint ID request
+enum SetEid {
+    /// respond with this reply
+    Reply(Vec<u8>),
+    /// set the discovered flag, and respond with this reply
+    Discovered(Vec<u8>),
+    /// pass to the control handler
+    Assign,
+    /// pass this assignment of our static EID to the control handler
+    Reset(Vec<u8>),
+}
+
+fn set_eid_request(
+    req: &[u8],
+    current: Eid,
+    static_eid: Option<StaticEid>,
+) -> SetEid {
+    let hdr = req[0] & 0x1f;
+    let error =
+        |cc| SetEid::Reply(vec![hdr, MCTP_CTRL_CMD_SET_ENDPOINT_ID, cc]);
+    let status = |status| {
+        vec![
+            hdr,
+            MCTP_CTRL_CMD_SET_ENDPOINT_ID,
+            MCTP_CTRL_CC_SUCCESS,
+            status,
+            current.0,
+            0, /* pool size */
+        ]
+    };
+
+    let (Some(op), Some(&eid)) = (req.get(2), req.get(3)) else {
+        return error(MCTP_CTRL_CC_ERROR_INVALID_LENGTH);
+    };
+    match op & 0x03 {
+        // null and broadcast EIDs can't be assigned
+        SET_EID_OP_SET | SET_EID_OP_FORCE if eid == 0 || eid == 0xff => {
+            error(MCTP_CTRL_CC_ERROR_INVALID_DATA)
+        }
+        SET_EID_OP_SET | SET_EID_OP_FORCE => match static_eid {
+            Some(s) if s.fixed => {
+                info!("Rejecting Set Endpoint ID, our EID is static");
+                SetEid::Reply(status(SET_EID_REJECTED))
+            }
+            _ => SetEid::Assign,
+        },
+        // reset only applies to endpoints with a static EID
+        SET_EID_OP_RESET => match static_eid {
+            Some(s) => SetEid::Reset(vec![
+                req[0],
+                MCTP_CTRL_CMD_SET_ENDPOINT_ID,
+                SET_EID_OP_SET,
+                s.eid.0,
+            ]),
+            None => error(MCTP_CTRL_CC_ERROR_INVALID_DATA),
+        },
+        SET_EID_OP_SET_DISCOVERED => {
+            SetEid::Discovered(status(SET_EID_ACCEPTED))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Shared state and options for the control protocol task
+struct ControlEnv<'a> {
+    routes: &'a Routes,
+    events: &'a events::EventBus,
+    uuid: &'a uuid::Uuid,
+    network_id: &'a uuid::Uuid,
+    limits: &'a Limits,
+    static_eid: Option<StaticEid>,
+    eid_pool: Option<&'a eidpool::EidPool>,
+    vendor: &'a vendor::VendorControl,
+    // control requests per second from each peer, 0 for no limit
+    rate: u32,
+    stats: &'a ctrlstats::ControlStats,
+    msg_types: &'a MessageTypes,
+}
+
+async fn control(
+    router: &Router<'_>,
+    env: ControlEnv<'_>,
+) -> std::io::Result<()> {
+    let ControlEnv {
+        routes,
+        events,
+        uuid,
+        network_id,
+        limits,
+        static_eid,
+        eid_pool,
+        vendor,
+        rate,
+        stats,
+        msg_types,
+    } = env;
+    let mut l = router.listener(mctp::MCTP_TYPE_CONTROL)?;
+    let mut c = MctpControl::new(router);
+
+    c.set_message_types(msg_types.all())?;
+    c.set_uuid(uuid);
+
+    // our current EID, tracked for Get Endpoint ID responses
+    let mut current = static_eid.map_or(Eid(0), |s| s.eid);
+
+    let mut limiter = (rate > 0).then(|| ctrlstats::RateLimiter::new(rate));
+
+    info!("MCTP Control Protocol server listening");
+    let mut buf = vec![0u8; limits.control];
+    loop {
+        let (_typ, _ic, msg, mut resp) = match l.recv(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                listener_recv_error(
+                    e,
+                    "control",
+                    limits.control,
+                    "--max-control-msg",
+                );
+                continue;
+            }
+        };
+
+        let is_request =
+            |cmd| msg.len() >= 2 && msg[0] & 0x80 != 0 && msg[1] == cmd;
+
+        // The message has been fully received, so dropping it here leaves
+        // no partial state behind for later requests.
+        if let Some(l) = &mut limiter {
+            if !l.allow(resp.remote_eid()) {
+                stats.dropped();
+                continue;
+            }
+        }
+        if msg.len() >= 2 && msg[0] & 0x80 != 0 {
+            stats.request(msg[1]);
+        }
+
+        // the port the requester was seen on, for the discovered flag
+        let peer_port = routes.lookup(resp.remote_eid().0).unwrap_or(0);
+
+        // The control handler reports a dynamic EID and handles only EID
+        // assignment, so we respond to Get Endpoint ID, the other Set
+        // Endpoint ID operations and the endpoint discovery commands
+        // ourselves. A reset is passed on as an assignment of our static
+        // EID.
+        let mut forward = None;
+        let reply = if is_request(MCTP_CTRL_CMD_GET_ENDPOINT_ID) {
+            Some(get_eid_response(msg[0], current, static_eid, routes.bridge))
+        } else if is_request(MCTP_CTRL_CMD_SET_ENDPOINT_ID) {
+            let r = set_eid_request(msg, current, static_eid);
+            match &r {
+                SetEid::Discovered(_) => routes.set_discovered(peer_port, true),
+                SetEid::Reset(req) => forward = Some(req.clone()),
+                _ => (),
+            }
+            match r {
+                SetEid::Reply(reply) | SetEid::Discovered(reply) => Some(reply),
+                SetEid::Assign | SetEid::Reset(_) => None,
+            }
+        } else if is_request(MCTP_CTRL_CMD_GET_NETWORK_ID) {
+            Some(network_id_response(msg[0], network_id))
+        } else if is_request(MCTP_CTRL_CMD_PREPARE_DISCOVERY) {
+            debug!("Prepare for Endpoint Discovery on port {peer_port}");
+            routes.set_discovered(peer_port, false);
+            Some(vec![
+                msg[0] & 0x1f,
+                MCTP_CTRL_CMD_PREPARE_DISCOVERY,
+                MCTP_CTRL_CC_SUCCESS,
+            ])
+        } else if is_request(MCTP_CTRL_CMD_ENDPOINT_DISCOVERY) {
+            // only undiscovered endpoints respond
+            if routes.discovered(peer_port) {
+                continue;
+            }
+            Some(vec![
+                msg[0] & 0x1f,
+                MCTP_CTRL_CMD_ENDPOINT_DISCOVERY,
+                MCTP_CTRL_CC_SUCCESS,
+            ])
+        } else {
+            None
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = resp.send(&reply).await {
+                debug!("control reply failed: {e}");
+            }
+            continue;
+        }
+
+        if let Some(pool) = eid_pool {
+            if is_request(eidpool::MCTP_CTRL_CMD_ALLOCATE_ENDPOINT_IDS) {
+                let reply = pool.allocate(msg, resp.remote_eid());
+                if let Err(e) = resp.send(&reply).await {
+                    debug!("control reply failed: {e}");
+                }
+                continue;
+            }
+        }
+
+        // with no vendor sets registered, Get Vendor Defined Message Support
+        // and unknown commands are left to the control handler
+        let vendor_reply = if msg.len() >= 2 && msg[0] & 0x80 != 0 {
+            Some(vendor)
+                .filter(|v| !v.is_empty())
+                .and_then(|v| v.handle(msg))
+        } else {
+            None
+        };
+        if let Some(reply) = vendor_reply {
+            if let Err(e) = resp.send(&reply).await {
+                debug!("control reply failed: {e}");
+            }
+            continue;
+        }
+
+        if is_request(MCTP_CTRL_CMD_GET_ROUTING_TABLE) {
+            let own_static = static_eid.is_some_and(|s| s.eid == current);
+            let entries = routes.routing_table(current, own_static);
+            let reply = routing_table_response(msg, &entries);
+            if let Err(e) = resp.send(&reply).await {
+                debug!("control reply failed: {e}");
+            }
+            continue;
+        }
+
+        let req = forward.as_deref().unwrap_or(msg);
+        let reset = forward.is_some();
+
+        // the EID requested by Set Endpoint ID, applied if accepted
+        let set_eid = if is_request(MCTP_CTRL_CMD_SET_ENDPOINT_ID) {
+            req.get(3).map(|e| Eid(*e))
+        } else {
+            None
+        };
+
+        let r = c.handle_async(req, resp).await;
+
+        match r {
+            Err(e) => info!("control handler failure: {e}"),
+            Ok(Some(ControlEvent::SetEndpointId { bus_owner, .. }))
+                if reset =>
+            {
+                routes.set_bus_owner(bus_owner);
+                routes.set_discovered(peer_port, true);
+                // unwrap: a reset is only forwarded with a static EID
+                let eid = set_eid.unwrap();
+                current = eid;
+                routes.set_local_eid(eid);
+                info!("EID reset to {eid} by {bus_owner}");
+                events.publish(events::EventKind::EidReset { eid, bus_owner });
+            }
+            Ok(Some(ev)) => {
+                if let ControlEvent::SetEndpointId { bus_owner, .. } = &ev {
+                    routes.set_bus_owner(*bus_owner);
+                    routes.set_discovered(peer_port, true);
+                    if let Some(eid) = set_eid {
+                        current = eid;
+                        routes.set_local_eid(eid);
+                    }
+                }
+                events.publish(events::EventKind::Control(ev));
+            }
+            Ok(None) => (),
+        }
+    }
+}
+
+const MCTP_CTRL_CMD_DISCOVERY_NOTIFY: u8 = 0x0d;
+
+const DISCOVERY_INTERVAL_MAX: Duration = Duration::from_secs(30);
+
+// Send Discovery Notify to the bus owner each time a USB link comes up, as
+// required for discoverable bindings. Attempts are retried with backoff
+// until a response is received, or our EID is assigned.
+async fn discovery(
+    router: &Router<'_>,
+    routes: &Routes,
+    link_up: async_channel::Receiver<PortId>,
+    interval: Duration,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; 64];
+    while let Ok(port) = link_up.recv().await {
+        let assignments = routes.eid_assignments();
+        let mut chan = router.req(Eid(0));
+        let mut delay = interval;
+
+        // the bus owner is reached through the null EID, on the port that
+        // came up
+        routes.set_null_route(Some(port.0));
+        for attempt in 1u32.. {
+            if routes.eid_assignments() != assignments {
+                info!("EID assigned, stopping Discovery Notify");
+                break;
+            }
+
+            info!(
+                "Sending Discovery Notify on port {}, attempt {attempt}",
+                port.0
+            );
+            // request, with an instance ID per attempt
+            let req = [
+                0x80 | (attempt & 0x1f) as u8,
+                MCTP_CTRL_CMD_DISCOVERY_NOTIFY,
+            ];
+            if let Err(e) = chan.send(mctp::MCTP_TYPE_CONTROL, &req).await {
+                debug!("Discovery Notify send failed: {e}");
+            } else {
+                let resp = futures::future::select(
+                    Box::pin(chan.recv(&mut buf)),
+                    smol::Timer::after(delay),
+                )
+                .await;
+                if let futures::future::Either::Left((Ok(_), _)) = resp {
+                    info!("Discovery Notify acknowledged");
+                    break;
+                }
+            }
+
+            delay = (delay * 2).min(DISCOVERY_INTERVAL_MAX);
+        }
+        routes.set_null_route(None);
+    }
+
+    // the USB transports have been dropped
+    futures::future::pending().await
+}
+
+/// A --pldm-file-name pattern, only compiled with the pldm feature
+#[cfg(feature = "pldm")]
+type FileNamePattern = regex::Regex;
+#[cfg(not(feature = "pldm"))]
+type FileNamePattern = String;
+
+/// Options for the PLDM file transfer requester
+#[cfg_attr(not(feature = "pldm"), allow(dead_code))]
+struct PldmOptions {
+    peer: Option<Eid>,
+    output: Option<String>,
+    overwrite: bool,
+    file_name: Option<FileNamePattern>,
+    file_id: Option<u16>,
+    retries: u32,
+    retry_delay: Duration,
+    xfer_size: u16,
+    read_chunk: usize,
+    oneshot: bool,
+    timeout: Option<Duration>,
+    upload: Option<String>,
+}
+
+/// PLDM session counters, for the stats dump
+#[derive(Default)]
+struct PldmStats {
+    attempts: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl std::fmt::Display for PldmStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} attempts, {} succeeded, {} failed",
+            self.attempts.load(Ordering::Relaxed),
+            self.successes.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Shared state and options for the NVMe-MI device
+#[cfg_attr(not(feature = "nvme-mi"), allow(dead_code))]
+struct NvmeEnv<'a> {
+    routes: &'a Routes,
+    limits: &'a Limits,
+    faults: &'a nvmefault::NvmeFaults,
+    control: &'a nvmectl::NvmeControl,
+    passthrough: Option<&'a nvmepass::Passthrough>,
+    trace: &'a nvmetrace::NvmeTrace,
+}
+
+#[cfg(not(feature = "nvme-mi"))]
+mod nvme {
+    pub struct NvmeDevice;
+
+    impl NvmeDevice {
+        pub fn build(
+            _topology: &super::nvmeconfig::Topology,
+        ) -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+
+        pub async fn run(
+            self,
+            _router: &super::Router<'_>,
+            _env: super::NvmeEnv<'_>,
+            _events: async_channel::Receiver<
+                std::sync::Arc<super::events::Event>,
+            >,
+        ) -> std::io::Result<()> {
+            futures::future::pending().await
+        }
+    }
+}
+
+#[cfg(not(feature = "nvme-mi"))]
+mod nvmeconfig {
+    #[derive(Default)]
+    pub struct Topology;
+
+    pub fn load(_path: &str) -> anyhow::Result<Topology> {
+        anyhow::bail!("--nvme-config requires the nvme-mi feature")
+    }
+}
+
+#[cfg(not(feature = "pldm"))]
+mod pldmplatform {
+    use std::sync::Arc;
+
+    pub enum Platform {}
+
+    impl Platform {
+        pub fn responder(&self) -> Box<dyn super::pldmbase::TypeResponder> {
+            match *self {}
+        }
+
+        pub async fn events(self, _router: &super::Router<'_>) {
+            match self {}
+        }
+    }
+
+    pub fn load(
+        _path: &str,
+        _terminus: Arc<super::pldmbase::Terminus>,
+    ) -> anyhow::Result<Platform> {
+        anyhow::bail!("--pldm-sensors requires the pldm feature")
+    }
+}
+
+#[cfg(not(feature = "pldm"))]
+mod pldmfile {
+    use super::pldmplatform::Platform;
+    use std::sync::Arc;
+
+    pub enum FileServer {}
+
+    impl FileServer {
+        pub fn publish(
+            &self,
+            _platform: Option<Platform>,
+            _terminus: Arc<super::pldmbase::Terminus>,
+        ) -> Platform {
+            match *self {}
+        }
+
+        pub fn responder(self) -> Box<dyn super::pldmbase::TypeResponder> {
+            match self {}
+        }
+    }
+
+    pub fn load(_args: &[String]) -> anyhow::Result<FileServer> {
+        anyhow::bail!("--serve-file requires the pldm feature")
+    }
+}
+
+#[cfg(not(feature = "pldm"))]
+mod pldmfw {
+    pub enum FirmwareDevice {}
+
+    impl FirmwareDevice {
+        pub fn responder(&self) -> Box<dyn super::pldmbase::TypeResponder> {
+            match *self {}
+        }
+
+        pub async fn run(self, _router: &super::Router<'_>) {
+            match self {}
+        }
+    }
+
+    pub fn load(
+        _path: &str,
+        _dir: &str,
+        _fault: Option<&str>,
+    ) -> anyhow::Result<FirmwareDevice> {
+        anyhow::bail!("--pldm-fw requires the pldm feature")
+    }
+}
+
+#[cfg(feature = "pldm")]
+mod pldm;
+#[cfg(not(feature = "pldm"))]
+mod pldm {
+    pub async fn pldm(
+        _router: &super::Router<'_>,
+        _events: async_channel::Receiver<std::sync::Arc<super::events::Event>>,
+        _opts: super::PldmOptions,
+        _stats: &super::PldmStats,
+        _terminus: &super::pldmbase::Terminus,
+    ) -> anyhow::Result<()> {
+        futures::future::pending().await
+    }
+}
+
+// Apply a --send-timeout option, in milliseconds, to a serial-framed
+// transport. 0 waits indefinitely; without the option, the transport's
+// default applies.
+fn set_send_timeout(serial: &mut serial::MctpSerial, ms: Option<u64>) {
+    if let Some(ms) = ms {
+        let timeout = (ms > 0).then(|| Duration::from_millis(ms));
+        serial.set_send_timeout(timeout);
+    }
+}
+
+// Create the transport, plus any background processing it requires
+fn create_transport(
+    transport: TransportSubcommand,
+    usb_faults: &usbredir::UsbFaults,
+    usb_stats: &Arc<usbredir::UsbStats>,
+    uuid: &uuid::Uuid,
+    link_up: &usbredir::LinkUp,
+    msg_types: &MessageTypes,
+) -> Result<Box<dyn MctpTransport>> {
+    let t: Box<dyn MctpTransport> = match transport {
+        TransportSubcommand::Fifo(f) => {
+            let mut fifo = serial::MctpSerial::fifo(&f.rx, &f.tx)?;
+            set_send_timeout(&mut fifo, f.send_timeout);
+            info!("Created MCTP fifo transport on {}, {}", f.rx, f.tx);
+            Box::new(fifo)
+        }
+        TransportSubcommand::Gadget(g) => {
+            let gadget = gadget::MctpGadget::new(
+                &g.path,
+                usb_stats.clone(),
+                link_up.clone(),
+            )?;
+            info!("Created MCTP USB gadget transport on {}", g.path);
+            Box::new(gadget)
+        }
+        TransportSubcommand::I2c(i) => {
+            let i2c = i2c::MctpI2c::new(
+                &i.dev,
+                i.addr,
+                i.mqueue.as_deref(),
+                i.peer_addr,
+            )?;
+            info!("Created MCTP I2C transport on {}, {:#04x}", i.dev, i.addr);
+            Box::new(i2c)
+        }
+        TransportSubcommand::Kernel(k) => {
+            let kernel = kernel::MctpKernel::new(k.net, &msg_types.app())?;
+            info!("Created MCTP kernel transport");
+            Box::new(kernel)
+        }
+        TransportSubcommand::Loopback(_) => {
+            info!("Created MCTP loopback transport");
+            Box::new(loopback::MctpLoopback::new())
+        }
+        TransportSubcommand::Bridge(_) => bail!("Can't nest bridge transports"),
+        TransportSubcommand::UsbHost(_) => bail!("usb-host is not a transport"),
+        TransportSubcommand::Serial(s) => {
+            let mut serial = match (s.tty.as_slice(), s.pty) {
+                ([tty], false) => {
+                    let config = s.tty_config()?;
+                    let serial = if s.reconnect {
+                        serial::MctpSerial::reconnecting(tty, &config)?
+                    } else {
+                        serial::MctpSerial::new(tty, &config)?
+                    };
+                    info!("Created MCTP Serial transport on {tty}");
+                    serial
+                }
+                ([], true) => {
+                    let (serial, path) =
+                        serial::MctpSerial::pty(s.link.as_deref())?;
+                    info!(
+                        "Created MCTP Serial transport on pty {}",
+                        path.display()
+                    );
+                    serial
+                }
+                _ => bail!("serial requires either a TTY device or --pty"),
+            };
+            if s.wait_dsr {
+                if s.reconnect {
+                    bail!("--wait-dsr can't be used with --reconnect");
+                }
+                serial.set_wait_dsr(true);
+            }
+            set_send_timeout(&mut serial, s.send_timeout);
+            Box::new(serial)
+        }
+        TransportSubcommand::QemuI2c(q) => {
+            let i2c = qemu_i2c::MctpQemuI2c::new(&q.path, q.addr, q.peer_addr)?;
+            info!(
+                "Created MCTP qemu I2C transport on {}, {:#04x}",
+                q.path, q.addr
+            );
+            Box::new(i2c)
+        }
+        TransportSubcommand::Replay(r) => {
+            let replay =
+                replay::MctpReplay::new(&r.path, r.realtime, r.compare)?;
+            info!("Created MCTP replay transport from {}", r.path);
+            Box::new(replay)
+        }
+        TransportSubcommand::Stdio(s) => {
+            let mut stdio = serial::MctpSerial::stdio()?;
+            set_send_timeout(&mut stdio, s.send_timeout);
+            info!("Created MCTP stdio transport");
+            Box::new(stdio)
+        }
+        TransportSubcommand::Tcp(t) => {
+            let mut tcp = match systemd::take_fd() {
+                Some(fd) => serial::MctpSerial::activated(fd)?,
+                None => serial::MctpSerial::tcp(&t.addr)?,
+            };
+            set_send_timeout(&mut tcp, t.send_timeout);
+            info!("Created MCTP TCP transport to {}", t.addr);
+            Box::new(tcp)
+        }
+        TransportSubcommand::Udp(u) => {
+            let udp = udp::MctpUdp::new(&u.local, &u.remote, u.mtu)?;
+            info!("Created MCTP UDP transport on {}, to {}", u.local, u.remote);
+            Box::new(udp)
+        }
+        TransportSubcommand::Unix(u) => {
+            let mut unix = match systemd::take_fd() {
+                Some(fd) => serial::MctpSerial::activated(fd)?,
+                None => serial::MctpSerial::unix(&u.path, u.attempts)?,
+            };
+            set_send_timeout(&mut unix, u.send_timeout);
+            info!("Created MCTP unix socket transport on {}", u.path);
+            Box::new(unix)
+        }
+        TransportSubcommand::Usb(u) => {
+            let info = usbredir::UsbDeviceInfo {
+                faults: usb_faults.clone(),
+                stats: usb_stats.clone(),
+                link_up: Some(link_up.clone()),
+                ..u.device_info(uuid)
+            };
+            let mut usbredir = match systemd::take_fd() {
+                Some(fd) => usbredir::MctpUsbRedir::activated(fd, info)?,
+                None if u.listen => {
+                    usbredir::MctpUsbRedir::listen(&u.path, info)?
+                }
+                None => usbredir::MctpUsbRedir::new(&u.path, info)?,
+            };
+            if u.no_reconnect {
+                usbredir.disable_reconnect();
+            }
+            info!("Created MCTP USB transport on {}", u.path);
+            Box::new(usbredir)
+        }
+        TransportSubcommand::Vsock(v) => {
+            let mut vsock = serial::MctpSerial::vsock(v.cid, v.port, v.listen)?;
+            set_send_timeout(&mut vsock, v.send_timeout);
+            info!("Created MCTP vsock transport on port {}", v.port);
+            Box::new(vsock)
+        }
+    };
+    Ok(t)
+}
+
+// Load a UUID from `path`, or create a new random UUID and store it there if
+// the file does not exist
+fn load_uuid(path: &str) -> Result<uuid::Uuid> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid UUID in {path}: '{}'", s.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let uuid = uuid::Uuid::new_v4();
+            let mut f = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .with_context(|| format!("Can't create UUID file {path}"))?;
+            writeln!(f, "{uuid}")
+                .with_context(|| format!("Can't write UUID file {path}"))?;
+            info!("Stored new UUID in {path}");
+            Ok(uuid)
+        }
+        Err(e) => {
+            Err(e).with_context(|| format!("Can't read UUID file {path}"))
+        }
+    }
+}
+
+// Run the usbredir host against a device, on the socket at `h.path` or a
+// socket passed by systemd
+#[cfg(feature = "usb-host")]
+fn usb_host(h: &UsbHostSubcommand) -> Result<()> {
+    let conf = simplelog::ConfigBuilder::new().build();
+    simplelog::SimpleLogger::init(LevelFilter::Debug, conf)?;
+
+    let sock = match systemd::take_fd() {
+        Some(fd) if systemd::is_listening(&fd)? => {
+            bail!("usb-host requires a connected socket")
+        }
+        Some(fd) => std::os::unix::net::UnixStream::from(fd),
+        None => std::os::unix::net::UnixStream::connect(&h.path)
+            .with_context(|| format!("Can't connect to {}", h.path))?,
+    };
+    usbhost::run(sock, h.dest_eid, h.expect_eid)
+}
+
+#[cfg(not(feature = "usb-host"))]
+fn usb_host(_h: &UsbHostSubcommand) -> Result<()> {
+    bail!("usb-host requires the usb-host feature")
+}
+
+fn main() -> Result<()> {
+    let opts: Options = argh::from_env();
+
+    if let TransportSubcommand::UsbHost(h) = &opts.transport {
+        return usb_host(h);
+    }
+
+    // before any threads are started, so the signals are blocked in all
+    let signals = signals::Signals::new(&[libc::SIGINT, libc::SIGTERM])?;
+
+    let (mut transports, bridge, static_routes) = match opts.transport {
+        TransportSubcommand::Bridge(b) => {
+            let transports = vec![
+                parse_transport(&b.upstream)?,
+                parse_transport(&b.downstream)?,
+            ];
+            (transports, true, b.route)
+        }
+        t => (vec![t], false, Vec::new()),
+    };
+
+    for t in &opts.extra_transports {
+        transports.push(parse_transport(t)?);
+    }
+
+    // a serial transport with multiple TTY devices has a port per device
+    let multi_serial = transports.iter().any(
+        |t| matches!(t, TransportSubcommand::Serial(s) if s.tty.len() > 1),
+    );
+    if multi_serial {
+        if bridge {
+            bail!("A bridge requires a single TTY device per serial transport");
+        }
+        transports = transports
+            .into_iter()
+            .flat_map(|t| match t {
+                TransportSubcommand::Serial(s) => s
+                    .split()
+                    .into_iter()
+                    .map(TransportSubcommand::Serial)
+                    .collect(),
+                t => vec![t],
+            })
+            .collect();
+    }
+
+    if let Some(r) = static_routes
+        .iter()
+        .find(|r| r.port as usize >= transports.len())
+    {
+        bail!("Invalid port {} for route {:?}", r.port, r.eids);
+    }
+
+    // the monitor has its own loopback port, following the transports
+    let mut static_routes = static_routes;
+    let mut nports = transports.len();
+    let monitor_eid = Eid(opts.monitor_eid);
+    if opts.monitor.is_some() {
+        static_routes.push(Route {
+            eids: monitor_eid.0..=monitor_eid.0,
+            port: nports as u8,
+        });
+        nports += 1;
+    }
+    let learnt_timeout = (opts.route_timeout > 0)
+        .then(|| Duration::from_secs(opts.route_timeout));
+    let routes = Routes::new(bridge, static_routes, learnt_timeout);
+
+    let conf = simplelog::ConfigBuilder::new().build();
+    if transports
+        .iter()
+        .any(|t| matches!(t, TransportSubcommand::Stdio(_)))
+    {
+        // keep stdout free for the transport
+        simplelog::WriteLogger::init(
+            LevelFilter::Debug,
+            conf,
+            std::io::stderr(),
+        )?;
+    } else {
+        simplelog::SimpleLogger::init(LevelFilter::Debug, conf)?;
+    }
+
+    if opts.dump_link {
+        linkdump::enable(opts.dump_link_len);
+    }
+
+    let static_eid = match opts.eid {
+        Some(e) if !(8..=0xfe).contains(&e) => bail!("Invalid EID {e}"),
+        Some(e) => Some(StaticEid {
+            eid: Eid(e),
+            fixed: opts.eid_static_only,
+        }),
+        None if opts.eid_static_only => {
+            bail!("--eid-static-only requires --eid")
+        }
+        None => None,
+    };
+    let eid = static_eid.map_or(Eid(0), |s| s.eid);
+    routes.set_local_eid(eid);
+
+    // the kernel stack handles the MCTP control protocol itself, and owns
+    // our local EID
+    let control_enabled = !transports
+        .iter()
+        .any(|t| matches!(t, TransportSubcommand::Kernel(_)));
+
+    // run the self test, and the echo server it uses, on loopback
+    let loopback = transports
+        .iter()
+        .any(|t| matches!(t, TransportSubcommand::Loopback(_)));
+
+    let nvme_mi_enabled = cfg!(feature = "nvme-mi") && !opts.no_nvme_mi;
+    let nvme_device = if nvme_mi_enabled {
+        let topology = match &opts.nvme_config {
+            Some(path) => nvmeconfig::load(path)?,
+            None => nvmeconfig::Topology::default(),
+        };
+        Some(nvme::NvmeDevice::build(&topology)?)
+    } else {
+        None
+    };
+    let pldm_enabled = cfg!(feature = "pldm") && !opts.no_pldm;
+    if !opts.serve_file.is_empty() && !pldm_enabled {
+        bail!("--serve-file requires the pldm feature, without --no-pldm");
+    }
+    if opts.pldm_upload.is_some() && !pldm_enabled {
+        bail!("--pldm-upload requires the pldm feature, without --no-pldm");
+    }
+    if opts.pldm_oneshot && !pldm_enabled {
+        bail!("--pldm-oneshot requires the pldm feature, without --no-pldm");
+    }
+    if opts.pldm_sensors.is_some() && !pldm_enabled {
+        bail!("--pldm-sensors requires the pldm feature, without --no-pldm");
+    }
+    if opts.pldm_fw.is_some() && !pldm_enabled {
+        bail!("--pldm-fw requires the pldm feature, without --no-pldm");
+    }
+    if (opts.pldm_fw_dir.is_some() || opts.pldm_fw_fail.is_some())
+        && opts.pldm_fw.is_none()
+    {
+        bail!("--pldm-fw-dir and --pldm-fw-fail require --pldm-fw");
+    }
+    if opts.pldm_timeout.is_some() && !opts.pldm_oneshot {
+        bail!("--pldm-timeout requires --pldm-oneshot");
+    }
+    if opts.pldm_xfer_size < 256 {
+        bail!("--pldm-xfer-size must be at least 256 bytes");
+    }
+    if opts.pldm_read_chunk == 0 {
+        bail!("--pldm-read-chunk must be non-zero");
+    }
+
+    let msg_types =
+        MessageTypes::enabled(loopback, nvme_mi_enabled, pldm_enabled);
+
+    if opts.allow_types.is_some() && opts.deny_types.is_some() {
+        bail!("Can't use both --allow-types and --deny-types");
+    }
+    let type_filter = typefilter::TypeFilter::new(
+        msg_types.all().iter().map(|t| t.0).collect(),
+        opts.allow_types.clone(),
+        opts.deny_types.clone().unwrap_or_default(),
+    );
+
+    let capture = pcap::Capture::new(opts.pcap.as_deref(), nports)?;
+
+    let mut port_tops = (0..nports).map(|_| PortTop::new()).collect::<Vec<_>>();
+    let mut router = Router::new(eid, &routes, 0);
+    let port_ids = port_tops
+        .iter_mut()
+        .map(|t| router.add_port(t))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // reported by the control protocol, and as the USB serial number
+    let uuid = match (opts.uuid, &opts.uuid_file) {
+        (Some(_), Some(_)) => bail!("Can't use both --uuid and --uuid-file"),
+        (Some(u), None) => u,
+        (None, Some(path)) => load_uuid(path)?,
+        (None, None) => uuid::Uuid::new_v4(),
+    };
+    info!("Endpoint UUID {uuid}");
+    let network_id =
+        opts.network_id.unwrap_or_else(|| default_network_id(&uuid));
+    debug!("Network ID {network_id}");
+
+    // fault injection for the usb transports, driven by the monitor
+    let usb_faults = usbredir::UsbFaults::default();
+    let usb_stats = Arc::new(usbredir::UsbStats::default());
+
+    // fault injection for NVMe-MI responses, from the command line and the
+    // monitor
+    let nvme_faults = nvmefault::NvmeFaults::default();
+    for rule in opts.nvme_inject.iter().cloned() {
+        nvme_faults.inject(rule);
+    }
+    // runtime subsystem changes, from the monitor
+    let nvme_control = nvmectl::NvmeControl::default();
+    let nvme_passthrough = opts
+        .nvme_passthrough
+        .as_deref()
+        .map(nvmepass::Passthrough::open)
+        .transpose()?;
+    let nvme_trace = nvmetrace::NvmeTrace::new(opts.trace_nvme);
+
+    // notified by the USB transports when the host configures the device,
+    // with room for a notification from each port
+    let (link_up_tx, link_up_rx) = async_channel::bounded(nports.max(1));
+
+    let mut runs = Vec::new();
+    let mut background = Vec::new();
+    let mut port_ids = port_ids.into_iter();
+    for (t, port_id) in transports.into_iter().zip(port_ids.by_ref()) {
+        let link_up = usbredir::LinkUp {
+            chan: link_up_tx.clone(),
+            port: port_id,
+        };
+        let mut transport = create_transport(
+            t,
+            &usb_faults,
+            &usb_stats,
+            &uuid,
+            &link_up,
+            &msg_types,
+        )?;
+        let port = router.port(port_id)?;
+        if let Some(mtu) = opts.mtu.or(transport.mtu()) {
+            debug!("Port {} MTU {mtu}", port_id.0);
+            routes.set_mtu(port_id, mtu);
+        }
+        routes.set_binding(port_id, transport.binding());
+        background.extend(transport.background());
+        runs.push((transport, port, port_id));
+    }
+
+    let monitor = match &opts.monitor {
+        Some(path) => {
+            let listener = monitor::listen(path)?;
+            // unwrap: we have allocated a port for the monitor
+            let port_id = port_ids.next().unwrap();
+            let port = router.port(port_id)?;
+            let transport: Box<dyn MctpTransport> =
+                Box::new(loopback::MctpLoopback::new());
+            runs.push((transport, port, port_id));
+            futures::future::Either::Left(monitor::monitor(
+                &router,
+                listener,
+                monitor_eid,
+                &usb_faults,
+                &usb_stats,
+                &nvme_faults,
+                nvme_mi_enabled.then_some(&nvme_control),
+            ))
+        }
+        None => futures::future::Either::Right(futures::future::pending()),
+    };
+
+    let fut = if background.is_empty() {
+        futures::future::Either::Right(futures::future::pending())
+    } else {
+        futures::future::Either::Left(futures::future::select_all(background))
+    };
+
+    let runs = futures::future::select_all(runs.into_iter().map(
+        |(transport, port, port_id)| {
+            Box::pin(run(
+                transport,
+                port,
+                port_id,
+                &router,
+                &routes,
+                &capture,
+                &type_filter,
+            ))
+        },
+    ))
+    .map(|(r, _, _)| r);
+
+    let limits = Limits {
+        control: opts.max_control_msg,
+        echo: opts.max_echo_msg,
+        nvme_mi: opts.max_nvme_mi_msg,
+    };
+
+    // allocated by the bus owner, and assigned to the downstream port
+    let eid_pool = bridge.then(eidpool::EidPool::default);
+    let pool_manager = match &eid_pool {
+        Some(pool) if control_enabled => futures::future::Either::Left(
+            eidpool::manage(&router, &routes, pool, 1),
+        ),
+        _ => futures::future::Either::Right(futures::future::pending()),
+    };
+
+    let control_stats = ctrlstats::ControlStats::default();
+
+    let mut vendor = vendor::VendorControl::default();
+    if opts.vendor_control {
+        vendor.register(vendor::example());
+    }
+
+    let events = events::EventBus::default();
+    let pldm_stats = PldmStats::default();
+    let pldm_opts = PldmOptions {
+        peer: opts.pldm_peer.map(Eid),
+        output: opts.pldm_output.clone(),
+        overwrite: opts.pldm_overwrite,
+        file_name: opts.pldm_file_name.clone(),
+        file_id: opts.pldm_file_id,
+        retries: opts.pldm_retries.max(1),
+        retry_delay: Duration::from_millis(opts.pldm_retry_delay),
+        xfer_size: opts.pldm_xfer_size,
+        read_chunk: opts.pldm_read_chunk as usize,
+        oneshot: opts.pldm_oneshot,
+        timeout: opts.pldm_timeout.map(Duration::from_secs),
+        upload: opts.pldm_upload.clone(),
+    };
+
+    // disabled subsystems don't subscribe to events, so aren't left with a
+    // growing queue
+    let nvme_mi = match nvme_device {
+        Some(dev) => {
+            let env = NvmeEnv {
+                routes: &routes,
+                limits: &limits,
+                faults: &nvme_faults,
+                control: &nvme_control,
+                passthrough: nvme_passthrough.as_ref(),
+                trace: &nvme_trace,
+            };
+            let events = events.subscribe("NVMe-MI");
+            futures::future::Either::Left(dev.run(&router, env, events))
+        }
+        None => futures::future::Either::Right(futures::future::pending()),
+    };
+    let terminus = Arc::new(pldmbase::Terminus::new(opts.pldm_tid));
+    let pldm = if pldm_enabled {
+        let events = events.subscribe("PLDM");
+        futures::future::Either::Left(pldm::pldm(
+            &router,
+            events,
+            pldm_opts,
+            &pldm_stats,
+            &terminus,
+        ))
+    } else {
+        futures::future::Either::Right(futures::future::pending())
+    };
+    let mut platform = opts
+        .pldm_sensors
+        .as_deref()
+        .map(|path| pldmplatform::load(path, terminus.clone()))
+        .transpose()?;
+    let files = (!opts.serve_file.is_empty())
+        .then(|| pldmfile::load(&opts.serve_file))
+        .transpose()?;
+    // the file PDRs are served by the platform responder
+    if let Some(f) = &files {
+        platform = Some(f.publish(platform, terminus.clone()));
+    }
+    let firmware = opts
+        .pldm_fw
+        .as_deref()
+        .map(|path| {
+            let dir = opts.pldm_fw_dir.as_deref().unwrap_or(".");
+            pldmfw::load(path, dir, opts.pldm_fw_fail.as_deref())
+        })
+        .transpose()?;
+    let pldm_responder = if pldm_enabled {
+        let mut base = pldmbase::PldmBase::new(terminus.clone());
+        if let Some(p) = &platform {
+            base.register(p.responder());
+        }
+        if let Some(f) = files {
+            base.register(f.responder());
+        }
+        if let Some(f) = &firmware {
+            base.register(f.responder());
+        }
+        futures::future::Either::Left(pldmbase::responder(&router, base))
+    } else {
+        futures::future::Either::Right(futures::future::pending())
+    };
+    let pldm_events = match platform {
+        Some(p) => futures::future::Either::Left(p.events(&router)),
+        None => futures::future::Either::Right(futures::future::pending()),
+    };
+    let pldm_fw = match firmware {
+        Some(f) => futures::future::Either::Left(f.run(&router)),
+        None => futures::future::Either::Right(futures::future::pending()),
+    };
+
+    let discovery = if control_enabled && opts.discovery_interval > 0 {
+        futures::future::Either::Left(discovery(
+            &router,
+            &routes,
+            link_up_rx,
+            Duration::from_millis(opts.discovery_interval),
+        ))
+    } else {
+        futures::future::Either::Right(futures::future::pending())
+    };
+
+    let ctrl = if control_enabled {
+        let env = ControlEnv {
+            routes: &routes,
+            events: &events,
+            uuid: &uuid,
+            network_id: &network_id,
+            limits: &limits,
+            static_eid,
+            eid_pool: eid_pool.as_ref(),
+            vendor: &vendor,
+            rate: opts.control_rate,
+            stats: &control_stats,
+            msg_types: &msg_types,
+        };
+        futures::future::Either::Left(control(&router, env))
+    } else {
+        futures::future::Either::Right(futures::future::pending())
+    };
+
+    let (selftest, echo) = if loopback {
+        (
+            futures::future::Either::Left(loopback::selftest(
+                &router,
+                msg_types.all(),
+            )),
+            futures::future::Either::Left(echo(&router, &limits)),
+        )
+    } else {
+        (
+            futures::future::Either::Right(futures::future::pending()),
+            futures::future::Either::Right(futures::future::pending()),
+        )
+    };
+
+    let stats = match opts.stats {
+        Some(secs) => futures::future::Either::Left(async {
+            loop {
+                smol::Timer::after(Duration::from_secs(secs.max(1))).await;
+                debug!("USB stats: {usb_stats}");
+                debug!("serial stats: {}", serial::stats());
+                debug!("control stats: {control_stats}");
+                debug!("learnt routes: {}", routes.learnt_summary());
+                debug!("message types: {type_filter}");
+                if nvme_mi_enabled {
+                    debug!("NVMe-MI requests: {nvme_trace}");
+                }
+                if pldm_enabled {
+                    debug!("PLDM sessions: {pldm_stats}");
+                }
+                if let Some(pool) = &eid_pool {
+                    debug!("EID pool: {pool}");
+                }
+            }
+        }),
+        None => futures::future::Either::Right(futures::future::pending()),
+    };
+
+    smol::block_on(async {
+        select!(
+            (r, _, _) = fut.fuse() => {
+                if let Err(e) = r {
+                    warn!("Transport stopped: {e}");
+                }
+            }
+            r = runs.fuse() => r?,
+            r = signals.recv().fuse() => info!("Exiting on signal {}", r?),
+            r = selftest.fuse() => {
+                r.context("Loopback self test failed")?;
+                info!("Loopback self test passed");
+            }
+            _ = echo.fuse() => (),
+            r = monitor.fuse() => r.context("Monitor failed")?,
+            _ = ctrl.fuse() => (),
+            _ = discovery.fuse() => (),
+            _ = pool_manager.fuse() => (),
+            _ = stats.fuse() => (),
+            _ = nvme_mi.fuse() => (),
+            r = pldm.fuse() => {
+                r.context("PLDM file transfer failed")?;
+                info!("PLDM file transfer complete, exiting");
+            }
+            r = pldm_responder.fuse() => r.context("PLDM responder failed")?,
+            _ = pldm_events.fuse() => (),
+            _ = pldm_fw.fuse() => (),
+        );
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_EID: Eid = Eid(10);
+
+    // Deliver the packets sent from `port` to `peer`
+    async fn link(mut port: Port<'_>, router: &Router<'_>, peer: &Router<'_>) {
+        let start = Instant::now();
+        loop {
+            let (pkt, _dest) = port.outbound().await;
+            update_router_time(router, start).await;
+            update_router_time(peer, start).await;
+            peer.inbound(pkt, PortId(0)).await;
+            port.outbound_done();
+        }
+    }
+
+    // Send a control request from a host to a device running the control
+    // task, with `msg_types` registered. Returns the response.
+    fn control_request(msg_types: &MessageTypes, req: &[u8]) -> Vec<u8> {
+        let static_eid = StaticEid {
+            eid: DEVICE_EID,
+            fixed: false,
+        };
+        let mut resps = control_requests(msg_types, Some(static_eid), &[req]);
+        // unwrap: all requests sent here are answered
+        resps.pop().unwrap().unwrap()
+    }
+
+    // Send control requests in turn to a device with `static_eid`,
+    // returning each response, or None for requests that were not
+    // answered. Set Endpoint ID is always answered, and its response gives
+    // the EID for later requests. Other requests are followed by a Get
+    // Endpoint ID, by which time any response to them has arrived.
+    fn control_requests(
+        msg_types: &MessageTypes,
+        static_eid: Option<StaticEid>,
+        reqs: &[&[u8]],
+    ) -> Vec<Option<Vec<u8>>> {
+        let dev_routes = Routes::new(false, Vec::new(), None);
+        let host_routes = Routes::new(false, Vec::new(), None);
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [dev_top, host_top] = &mut tops;
+        let mut device = Router::new(DEVICE_EID, &dev_routes, 0);
+        let mut host = Router::new(Eid(8), &host_routes, 0);
+        let dev_port = device.add_port(dev_top).unwrap();
+        let host_port = host.add_port(host_top).unwrap();
+        let dev_port = device.port(dev_port).unwrap();
+        let host_port = host.port(host_port).unwrap();
+
+        let limits = Limits::default();
+        let events = events::EventBus::default();
+        let uuid = uuid::Uuid::new_v4();
+        let vendor = vendor::VendorControl::default();
+        let stats = ctrlstats::ControlStats::default();
+        let ctrl = control(
+            &device,
+            ControlEnv {
+                routes: &dev_routes,
+                events: &events,
+                uuid: &uuid,
+                network_id: &uuid,
+                limits: &limits,
+                static_eid,
+                eid_pool: None,
+                vendor: &vendor,
+                rate: 0,
+                stats: &stats,
+                msg_types,
+            },
+        );
+
+        let request = async {
+            let mut dest = static_eid.map_or(DEVICE_EID, |s| s.eid);
+            let mut buf = [0u8; 256];
+            let mut resps = Vec::new();
+            for req in reqs {
+                let mut chan = host.req(dest);
+                chan.send(mctp::MCTP_TYPE_CONTROL, req).await.unwrap();
+
+                if req[1] == MCTP_CTRL_CMD_SET_ENDPOINT_ID {
+                    let (_typ, _ic, resp) = chan.recv(&mut buf).await.unwrap();
+                    // accepted: the EID in use follows
+                    if resp.len() == 6 && resp[3] & 0x30 == 0 {
+                        dest = Eid(resp[4]);
+                    }
+                    resps.push(Some(resp.to_vec()));
+                    continue;
+                }
+
+                let mut probe = host.req(dest);
+                probe
+                    .send(mctp::MCTP_TYPE_CONTROL, &[0x9f, 0x02])
+                    .await
+                    .unwrap();
+                probe.recv(&mut buf).await.unwrap();
+                let resp = chan.recv(&mut buf).now_or_never();
+                resps.push(resp.map(|r| r.unwrap().2.to_vec()));
+            }
+            resps
+        };
+
+        smol::block_on(async {
+            select!(
+                r = request.fuse() => r,
+                r = ctrl.fuse() => panic!("control failed: {r:?}"),
+                _ = link(dev_port, &device, &host).fuse() => unreachable!(),
+                _ = link(host_port, &host, &device).fuse() => unreachable!(),
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("request timed out")
+                }
+            )
+        })
+    }
+
+    // Get Message Type Support reports exactly the types registered for
+    // the enabled subsystems
+    #[test]
+    fn message_type_support() {
+        for (echo, nvme_mi, pldm) in [
+            (false, false, false),
+            (true, false, false),
+            (false, true, false),
+            (false, false, true),
+            (true, true, true),
+        ] {
+            let msg_types = MessageTypes::enabled(echo, nvme_mi, pldm);
+            let types = msg_types.all();
+            assert_eq!(types.contains(&mctp::MCTP_TYPE_VENDOR_PCIE), echo);
+            assert_eq!(types.contains(&mctp::MCTP_TYPE_NVME), nvme_mi);
+            assert_eq!(types.contains(&mctp::MCTP_TYPE_PLDM), pldm);
+
+            // request, instance ID 1
+            let resp = control_request(&msg_types, &[0x81, 0x05]);
+            assert_eq!(
+                resp[..4],
+                [0x01, 0x05, MCTP_CTRL_CC_SUCCESS, types.len() as u8]
+            );
+            let mut reported = resp[4..].to_vec();
+            let mut expected = types.iter().map(|t| t.0).collect::<Vec<_>>();
+            reported.sort();
+            expected.sort();
+            assert_eq!(reported, expected);
+        }
+    }
+
+    // Each Set Endpoint ID operation, with the EID reported after it
+    #[test]
+    fn set_endpoint_id_operations() {
+        let msg_types = MessageTypes::enabled(false, false, false);
+        let static_eid = StaticEid {
+            eid: DEVICE_EID,
+            fixed: false,
+        };
+        let resps = control_requests(
+            &msg_types,
+            Some(static_eid),
+            &[
+                // set EID 20, then get: static EID, not current
+                &[0x81, 0x01, 0x00, 20],
+                &[0x82, 0x02],
+                // force EID 21
+                &[0x83, 0x01, 0x01, 21],
+                // reset, then get: static EID, current
+                &[0x84, 0x01, 0x02, 0x00],
+                &[0x85, 0x02],
+                // set discovered flag
+                &[0x86, 0x01, 0x03, 0x00],
+            ],
+        );
+        let expected: [&[u8]; 6] = [
+            &[0x01, 0x01, MCTP_CTRL_CC_SUCCESS, SET_EID_ACCEPTED, 20, 0],
+            &[0x02, 0x02, MCTP_CTRL_CC_SUCCESS, 20, 0b11, 0],
+            &[0x03, 0x01, MCTP_CTRL_CC_SUCCESS, SET_EID_ACCEPTED, 21, 0],
+            &[0x04, 0x01, MCTP_CTRL_CC_SUCCESS, SET_EID_ACCEPTED, 10, 0],
+            &[0x05, 0x02, MCTP_CTRL_CC_SUCCESS, 10, 0b10, 0],
+            &[0x06, 0x01, MCTP_CTRL_CC_SUCCESS, SET_EID_ACCEPTED, 10, 0],
+        ];
+        assert_eq!(resps.len(), expected.len());
+        for (resp, expected) in resps.iter().zip(expected) {
+            assert_eq!(resp.as_deref(), Some(expected));
+        }
+    }
+
+    // Set Endpoint ID requests that are malformed, or not allowed for our
+    // EID configuration
+    #[test]
+    fn set_endpoint_id_errors() {
+        let msg_types = MessageTypes::enabled(false, false, false);
+        let mut static_eid = StaticEid {
+            eid: DEVICE_EID,
+            fixed: false,
+        };
+        let resps = control_requests(
+            &msg_types,
+            Some(static_eid),
+            &[
+                // truncated
+                &[0x81, 0x01, 0x00],
+                // set the null EID, force the broadcast EID
+                &[0x82, 0x01, 0x00, 0x00],
+                &[0x83, 0x01, 0x01, 0xff],
+            ],
+        );
+        assert_eq!(
+            resps,
+            [
+                Some(vec![0x01, 0x01, MCTP_CTRL_CC_ERROR_INVALID_LENGTH]),
+                Some(vec![0x02, 0x01, MCTP_CTRL_CC_ERROR_INVALID_DATA]),
+                Some(vec![0x03, 0x01, MCTP_CTRL_CC_ERROR_INVALID_DATA]),
+            ]
+        );
+
+        // reset without a static EID
+        let resps =
+            control_requests(&msg_types, None, &[&[0x84, 0x01, 0x02, 0x00]]);
+        assert_eq!(
+            resps,
+            [Some(vec![0x04, 0x01, MCTP_CTRL_CC_ERROR_INVALID_DATA])]
+        );
+
+        // assignment with --eid-static-only
+        static_eid.fixed = true;
+        let resps = control_requests(
+            &msg_types,
+            Some(static_eid),
+            &[&[0x85, 0x01, 0x00, 20]],
+        );
+        assert_eq!(
+            resps,
+            [Some(vec![
+                0x05,
+                0x01,
+                MCTP_CTRL_CC_SUCCESS,
+                SET_EID_REJECTED,
+                10,
+                0
+            ])]
+        );
+    }
+
+    // Endpoint Discovery is answered only until the discovered flag is set,
+    // and again after Prepare for Endpoint Discovery
+    #[test]
+    fn endpoint_discovery() {
+        let msg_types = MessageTypes::enabled(false, false, false);
+        let static_eid = StaticEid {
+            eid: DEVICE_EID,
+            fixed: false,
+        };
+        let resps = control_requests(
+            &msg_types,
+            Some(static_eid),
+            &[
+                &[0x81, 0x0b],
+                &[0x82, 0x0c],
+                &[0x83, 0x01, 0x03, 0x00],
+                &[0x84, 0x0c],
+                &[0x85, 0x0b],
+                &[0x86, 0x0c],
+            ],
+        );
+        assert_eq!(
+            resps,
+            [
+                Some(vec![0x01, 0x0b, MCTP_CTRL_CC_SUCCESS]),
+                Some(vec![0x02, 0x0c, MCTP_CTRL_CC_SUCCESS]),
+                Some(vec![
+                    0x03,
+                    0x01,
+                    MCTP_CTRL_CC_SUCCESS,
+                    SET_EID_ACCEPTED,
+                    DEVICE_EID.0,
+                    0
+                ]),
+                None,
+                Some(vec![0x05, 0x0b, MCTP_CTRL_CC_SUCCESS]),
+                Some(vec![0x06, 0x0c, MCTP_CTRL_CC_SUCCESS]),
+            ]
+        );
+    }
+
+    // A bridge's routing table, with more entries than fit in a response,
+    // is returned in full by following the next entry handles
+    #[test]
+    fn routing_table_pagination() {
+        let mut static_routes = (20..32)
+            .map(|e| Route {
+                eids: e..=e,
+                port: 1,
+            })
+            .collect::<Vec<_>>();
+        static_routes.push(Route {
+            eids: 40..=49,
+            port: 1,
+        });
+        let routes = Routes::new(true, static_routes, None);
+        routes.set_binding(PortId(0), transport::BINDING_SERIAL);
+        routes.set_binding(PortId(1), transport::BINDING_USB);
+
+        let entries = routes.routing_table(Eid(10), true);
+        assert_eq!(entries.len(), 14);
+        // ourselves: a bridge with a static EID, on port 0
+        assert_eq!(
+            entries[0],
+            [1, 10, 0b1010_0000, transport::BINDING_SERIAL, 0, 0]
+        );
+        // a single endpoint, then the range, on port 1
+        assert_eq!(
+            entries[1],
+            [1, 20, 0b0010_0001, transport::BINDING_USB, 0, 0]
+        );
+        assert_eq!(
+            entries[13],
+            [10, 40, 0b1110_0001, transport::BINDING_USB, 0, 0]
+        );
+
+        let mut handle = 0;
+        let mut fetched = Vec::new();
+        for (count, next) in [(8, 8), (6, 0xff)] {
+            // request, instance ID 3
+            let resp = routing_table_response(&[0x83, 0x0a, handle], &entries);
+            assert_eq!(
+                resp[..5],
+                [0x03, 0x0a, MCTP_CTRL_CC_SUCCESS, next, count]
+            );
+            assert_eq!(resp.len(), 5 + count as usize * 6);
+            fetched.extend(
+                resp[5..]
+                    .chunks(6)
+                    .map(|e| RoutingEntry::try_from(e).unwrap()),
+            );
+            handle = next;
+        }
+        assert_eq!(fetched, entries);
+
+        // a handle beyond the last entry
+        let resp = routing_table_response(&[0x83, 0x0a, 14], &entries);
+        assert_eq!(resp, [0x03, 0x0a, MCTP_CTRL_CC_ERROR_INVALID_DATA]);
+    }
+
+    // A Set MTU of 256 applies to the requester's port, within the MTU the
+    // port was created with, and later messages are fragmented to it
+    #[test]
+    fn negotiated_mtu() {
+        let routes = Routes::new(false, Vec::new(), None);
+        routes.set_mtu(PortId(0), 1024);
+        assert!(routes.negotiate_mtu(0, 256));
+        assert_eq!(routes.route(0), (Some(PortId(0)), Some(256)));
+        // below the baseline, or above the port's MTU
+        assert!(!routes.negotiate_mtu(0, MCTP_BTU - 1));
+        assert!(!routes.negotiate_mtu(0, 1025));
+        assert_eq!(routes.route(0), (Some(PortId(0)), Some(256)));
+
+        let mut top = PortTop::new();
+        let mut router = Router::new(DEVICE_EID, &routes, 0);
+        let port = router.add_port(&mut top).unwrap();
+        let mut port = router.port(port).unwrap();
+
+        // a 600 byte message, and its type byte
+        let msg = [0x5a; 600];
+        let send = async {
+            let mut chan = router.req(Eid(8));
+            chan.send(mctp::MCTP_TYPE_VENDOR_PCIE, &msg).await.unwrap();
+        };
+        let payloads = async {
+            let mut sizes = Vec::new();
+            while sizes.iter().sum::<usize>() < msg.len() + 1 {
+                let (pkt, _dest) = port.outbound().await;
+                // less the 4 byte MCTP header
+                sizes.push(pkt.len() - 4);
+                port.outbound_done();
+            }
+            sizes
+        };
+        let (_, sizes) =
+            smol::block_on(async { futures::join!(send, payloads) });
+        assert_eq!(sizes, [256, 256, 89]);
+    }
+
+    // An NVMe-MI request over the size limit is answered with an Invalid
+    // Command Size error
+    #[cfg(feature = "nvme-mi")]
+    #[test]
+    fn nvme_mi_oversized_request() {
+        let dev_routes = Routes::new(false, Vec::new(), None);
+        let host_routes = Routes::new(false, Vec::new(), None);
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [dev_top, host_top] = &mut tops;
+        let mut device = Router::new(DEVICE_EID, &dev_routes, 0);
+        let mut host = Router::new(Eid(8), &host_routes, 0);
+        let dev_port = device.add_port(dev_top).unwrap();
+        let host_port = host.add_port(host_top).unwrap();
+        let dev_port = device.port(dev_port).unwrap();
+        let host_port = host.port(host_port).unwrap();
+
+        let limits = Limits {
+            nvme_mi: 64,
+            ..Limits::default()
+        };
+        let events = events::EventBus::default();
+        let topology = nvmeconfig::Topology::default();
+        let faults = nvmefault::NvmeFaults::default();
+        let control = nvmectl::NvmeControl::default();
+        let trace = nvmetrace::NvmeTrace::new(false);
+        let env = NvmeEnv {
+            routes: &dev_routes,
+            limits: &limits,
+            faults: &faults,
+            control: &control,
+            passthrough: None,
+            trace: &trace,
+        };
+        let nvme = nvme::NvmeDevice::build(&topology).unwrap().run(
+            &device,
+            env,
+            events.subscribe("NVMe-MI"),
+        );
+
+        // a 100 byte MI command, with its MIC
+        let mut req = vec![0x08, 0, 0, 0x00];
+        req.resize(100, 0);
+        nvmefault::update_mic(&mut req, true);
+        let request = async {
+            let mut chan = host.req(DEVICE_EID);
+            chan.send_vectored(
+                mctp::MCTP_TYPE_NVME,
+                mctp::MsgIC(true),
+                &[&req],
+            )
+            .await
+            .unwrap();
+            let mut buf = [0u8; 64];
+            let (typ, ic, resp) = chan.recv(&mut buf).await.unwrap();
+            assert_eq!(typ, mctp::MCTP_TYPE_NVME);
+            assert!(ic.0);
+            resp.to_vec()
+        };
+
+        let resp = smol::block_on(async {
+            select!(
+                r = request.fuse() => r,
+                r = nvme.fuse() => panic!("NVMe-MI failed: {r:?}"),
+                _ = link(dev_port, &device, &host).fuse() => unreachable!(),
+                _ = link(host_port, &host, &device).fuse() => unreachable!(),
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("request timed out")
+                }
+            )
+        });
+
+        // Invalid Command Size
+        let mut expected = vec![0x88, 0, 0, 0x05, 0, 0, 0];
+        expected.resize(expected.len() + 4, 0);
+        nvmefault::update_mic(&mut expected, true);
+        assert_eq!(resp, expected);
+    }
+
+    // Get Network ID responds with command 0x0e and the 16-byte ID
+    #[test]
+    fn network_id_response_encoding() {
+        let id = uuid::uuid!("00112233-4455-6677-8899-aabbccddeeff");
+        // request, instance ID 5
+        let resp = network_id_response(0x85, &id);
+        assert_eq!(resp.len(), 19);
+        assert_eq!(resp[..3], [0x05, 0x0e, MCTP_CTRL_CC_SUCCESS]);
+        assert_eq!(resp[3..], *id.as_bytes());
+    }
+
+    // Deliver the packets sent from `port` to `peer`
+    #[cfg(feature = "nvme-mi")]
+    async fn link(mut port: Port<'_>, router: &Router<'_>, peer: &Router<'_>) {
+        let start = Instant::now();
+        loop {
+            let (pkt, _dest) = port.outbound().await;
+            let ms = start.elapsed().as_millis() as u64;
+            let _ = router.update_time(ms).await;
+            let _ = peer.update_time(ms).await;
+            peer.inbound(pkt, PortId(0)).await;
+            port.outbound_done();
+        }
+    }
+
+    // A slow NVMe-MI response, here from an injected delay, holds only the
+    // NVMe-MI listener: a control request sent after it is answered while
+    // the NVMe-MI response is still held. The delay outlasts the test, so
+    // the order doesn't depend on timing.
+    #[cfg(feature = "nvme-mi")]
+    #[test]
+    fn slow_nvme_mi_does_not_delay_control() {
+        const DEVICE_EID: Eid = Eid(10);
+        const NVME_DELAY: Duration = Duration::from_secs(3600);
+
+        let dev_routes = Routes::new(false, Vec::new(), None);
+        dev_routes.set_local_eid(DEVICE_EID);
+        let host_routes = Routes::new(false, Vec::new(), None);
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [dev_top, host_top] = &mut tops;
+        let mut device = Router::new(DEVICE_EID, &dev_routes, 0);
+        let mut host = Router::new(Eid(8), &host_routes, 0);
+        let dev_port = device.add_port(dev_top).unwrap();
+        let host_port = host.add_port(host_top).unwrap();
+        let dev_port = device.port(dev_port).unwrap();
+        let host_port = host.port(host_port).unwrap();
+
+        let mut msg_types = MessageTypes::default();
+        msg_types.register(mctp::MCTP_TYPE_CONTROL, "control");
+        msg_types.register(mctp::MCTP_TYPE_NVME, "NVMe-MI");
+        let limits = Limits::default();
+        let events = events::EventBus::default();
+        let uuid = uuid::Uuid::new_v4();
+        let vendor = vendor::VendorControl::default();
+        let stats = ctrlstats::ControlStats::default();
+        let ctrl = control(
+            &device,
+            ControlEnv {
+                routes: &dev_routes,
+                events: &events,
+                uuid: &uuid,
+                network_id: &uuid,
+                limits: &limits,
+                static_eid: Some(StaticEid {
+                    eid: DEVICE_EID,
+                    fixed: false,
+                }),
+                eid_pool: None,
+                vendor: &vendor,
+                rate: 0,
+                stats: &stats,
+                msg_types: &msg_types,
+            },
+        );
+
+        let faults = nvmefault::NvmeFaults::default();
+        faults.inject(nvmefault::Rule {
+            action: nvmefault::Action::Delay(NVME_DELAY),
+            command: None,
+            count: None,
+        });
+        let nvme_control = nvmectl::NvmeControl::default();
+        let trace = nvmetrace::NvmeTrace::new(false);
+        let dev = nvme::NvmeDevice::build(&Default::default()).unwrap();
+        let nvme_mi = dev.run(
+            &device,
+            NvmeEnv {
+                routes: &dev_routes,
+                limits: &limits,
+                faults: &faults,
+                control: &nvme_control,
+                passthrough: None,
+                trace: &trace,
+            },
+            events.subscribe("NVMe-MI"),
+        );
+
+        let requests = async {
+            // Read NVMe-MI Data Structure, for the subsystem information
+            let mut req = vec![0x08, 0, 0, 0x00];
+            req.extend_from_slice(&[0u8; 12]);
+            let mut mic = vec![0x84];
+            mic.extend_from_slice(&req);
+            req.extend_from_slice(&nvmefault::crc32c(&mic).to_le_bytes());
+            let mut nvme = host.req(DEVICE_EID);
+            nvme.send_vectored(
+                mctp::MCTP_TYPE_NVME,
+                mctp::MsgIC(true),
+                &[&req],
+            )
+            .await
+            .unwrap();
+
+            // Get Endpoint ID
+            let get_eid = async {
+                let mut chan = host.req(DEVICE_EID);
+                chan.send(mctp::MCTP_TYPE_CONTROL, &[0x80, 0x02])
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 64];
+                let (_typ, _ic, resp) = chan.recv(&mut buf).await.unwrap();
+                assert_eq!(resp[..4], [0x00, 0x02, 0x00, DEVICE_EID.0]);
+            };
+
+            let mut buf = vec![0u8; limits.nvme_mi];
+            select!(
+                _ = get_eid.fuse() => (),
+                _ = nvme.recv(&mut buf).fuse() => {
+                    panic!("NVMe-MI response not delayed")
+                }
+            );
+        };
+
+        smol::block_on(async {
+            select!(
+                r = requests.fuse() => r,
+                r = ctrl.fuse() => panic!("control failed: {r:?}"),
+                r = nvme_mi.fuse() => panic!("NVMe-MI failed: {r:?}"),
+                _ = link(dev_port, &device, &host).fuse() => unreachable!(),
+                _ = link(host_port, &host, &device).fuse() => unreachable!(),
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("requests timed out")
+                }
+            )
+        });
+        // the NVMe-MI response was held when the control response arrived
+        let faults = faults.to_string();
+        assert!(faults.contains("delayed 1,"), "faults: {faults}");
+    }
 }
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Monitor socket, for issuing hand-crafted requests to the local responders.
+ *
+ * Each line received on a monitor connection is a MCTP message in hex: the
+ * message type byte (including the IC bit), followed by the message body.
+ * The request is sent from the monitor EID, and the response is returned as
+ * a line in the same format, or as a line starting with "error:".
+ *
+ * The monitor EID is routed to a loopback port, so requests appear to
+ * arrive from that EID on a separate port to the transports.
+ *
+ * Lines starting with "usb" inject failures into the usb transport's bulk
+ * transfers:
+ *
+ *   usb stall|error in|out [count]
+ *   usb clear
+ *
+ * Lines starting with "nvme" inject faults into NVMe-MI responses, with
+ * rules as described in nvmefault:
+ *
+ *   nvme <rule>
+ *   nvme clear
+ *
+ * and "nvme attach" and "nvme add-controller" change the subsystem, as
+ * described in nvmectl.
+ *
+ * "stats" returns the USB transfer counters, "stats serial" returns the
+ * serial framing counters, and "stats nvme" returns the NVMe-MI injection
+ * counters.
+ *
+ * A stall also halts the endpoint, until the host clears the halt. Failed
+ * OUT transfers are discarded, so a partially transferred message is not
+ * completed; the router drops it once its reassembly times out. Failed IN
+ * transfers lose no data, as the packets are sent in a later transfer.
+ */
+
+use anyhow::{bail, Context, Result};
+use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+use log::{info, warn};
+use mctp::{AsyncReqChannel, Eid, MsgIC, MsgType};
+use mctp_estack::router::Router;
+use smol::Async;
+use std::fmt::Write as _;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+
+use crate::nvmectl::{Change, NvmeControl};
+use crate::nvmefault::NvmeFaults;
+use crate::usbredir::{UsbFault, UsbFaults, UsbStats};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_RESPONSE: usize = 4224;
+
+/// Create the monitor socket at `path`, replacing any stale socket
+pub fn listen(path: &str) -> Result<Async<UnixListener>> {
+    if let Ok(m) = std::fs::metadata(path) {
+        if m.file_type().is_socket() {
+            std::fs::remove_file(path)
+                .context("Can't remove existing monitor socket")?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Can't bind monitor socket {path}"))?;
+    info!("Monitor listening on {path}");
+    Ok(Async::new(listener)?)
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let s = s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    if !s.is_ascii() {
+        bail!("invalid hex");
+    }
+    if s.len() & 1 != 0 {
+        bail!("odd number of hex digits");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .with_context(|| format!("invalid hex '{}'", &s[i..i + 2]))
+        })
+        .collect()
+}
+
+fn format_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+// Send a single request, returning the response in the monitor line format
+async fn request(
+    chan: &mut impl AsyncReqChannel,
+    line: &str,
+) -> Result<String> {
+    let msg = parse_hex(line)?;
+    let Some((&typ, body)) = msg.split_first() else {
+        bail!("empty request");
+    };
+    let ic = MsgIC(typ & 0x80 != 0);
+    let typ = MsgType(typ & 0x7f);
+
+    chan.send_vectored(typ, ic, &[body])
+        .await
+        .context("request send failed")?;
+
+    let mut buf = vec![0u8; MAX_RESPONSE];
+    let resp = futures::future::select(
+        Box::pin(chan.recv(&mut buf)),
+        smol::Timer::after(RESPONSE_TIMEOUT),
+    )
+    .await;
+
+    let (rtyp, ric, rmsg) = match resp {
+        futures::future::Either::Left((r, _)) => {
+            r.context("response receive failed")?
+        }
+        futures::future::Either::Right(_) => bail!("no response"),
+    };
+
+    let hdr = rtyp.0 | if ric.0 { 0x80 } else { 0 };
+    Ok(format!("{hdr:02x}{}", format_hex(rmsg)))
+}
+
+// Handle a "usb" fault injection command
+fn usb_command(faults: &UsbFaults, args: &str) -> Result<String> {
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    let fault = match args.first() {
+        Some(&"clear") if args.len() == 1 => {
+            faults.clear();
+            return Ok("ok".to_string());
+        }
+        Some(&"stall") => UsbFault::Stall,
+        Some(&"error") => UsbFault::Error,
+        _ => bail!("expected usb stall|error in|out [count], or usb clear"),
+    };
+    let is_in = match args.get(1) {
+        Some(&"in") => true,
+        Some(&"out") => false,
+        _ => bail!("expected endpoint direction in or out"),
+    };
+    let count = match args.get(2) {
+        Some(c) => c.parse().context("invalid count")?,
+        None => 1,
+    };
+    if args.len() > 3 {
+        bail!("too many arguments");
+    }
+    faults.inject(is_in, fault, count);
+    Ok("ok".to_string())
+}
+
+// Handle a "nvme" fault injection or subsystem change command
+async fn nvme_command(
+    faults: &NvmeFaults,
+    control: Option<&NvmeControl>,
+    args: &str,
+) -> Result<String> {
+    match args.split_whitespace().next() {
+        Some("clear") => faults.clear(),
+        Some("attach" | "add-controller") => {
+            let Some(control) = control else {
+                bail!("NVMe-MI is disabled");
+            };
+            return control.request(args.parse::<Change>()?).await;
+        }
+        _ => faults.inject(args.parse().map_err(anyhow::Error::msg)?),
+    }
+    Ok("ok".to_string())
+}
+
+async fn session(
+    router: &Router<'_>,
+    stream: Async<std::os::unix::net::UnixStream>,
+    eid: Eid,
+    usb_faults: &UsbFaults,
+    usb_stats: &UsbStats,
+    nvme_faults: &NvmeFaults,
+    nvme_control: Option<&NvmeControl>,
+) -> std::io::Result<()> {
+    let mut lines = futures::io::BufReader::new(&stream).lines();
+    let mut w = &stream;
+    let mut chan = router.req(eid);
+
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let resp = if line.trim() == "stats" {
+            Ok(usb_stats.to_string())
+        } else if line.trim() == "stats serial" {
+            Ok(crate::serial::stats().to_string())
+        } else if line.trim() == "stats nvme" {
+            Ok(nvme_faults.to_string())
+        } else if let Some(args) = line.trim().strip_prefix("usb") {
+            usb_command(usb_faults, args)
+        } else if let Some(args) = line.trim().strip_prefix("nvme") {
+            nvme_command(nvme_faults, nvme_control, args).await
+        } else {
+            request(&mut chan, &line).await
+        };
+        let resp = match resp {
+            Ok(r) => r,
+            Err(e) => format!("error: {e:#}"),
+        };
+        w.write_all(resp.as_bytes()).await?;
+        w.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+/// Serve monitor connections on `listener`, one at a time. Requests are
+/// sent from `eid`, and fault injection commands apply to `usb_faults` and
+/// `nvme_faults`. Subsystem changes are sent to `nvme_control`, if NVMe-MI
+/// is enabled.
+pub async fn monitor(
+    router: &Router<'_>,
+    listener: Async<UnixListener>,
+    eid: Eid,
+    usb_faults: &UsbFaults,
+    usb_stats: &UsbStats,
+    nvme_faults: &NvmeFaults,
+    nvme_control: Option<&NvmeControl>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        info!("Monitor connection");
+        if let Err(e) = session(
+            router,
+            stream,
+            eid,
+            usb_faults,
+            usb_stats,
+            nvme_faults,
+            nvme_control,
+        )
+        .await
+        {
+            warn!("Monitor connection failed: {e}");
+        }
+        info!("Monitor connection closed");
+    }
+}
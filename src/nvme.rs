@@ -0,0 +1,560 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* The emulated NVMe-MI device: a subsystem built from the topology, its
+ * management endpoints, and the listener loop passing requests to them.
+ *
+ * Requests are handled by the management endpoint for the MCTP port they
+ * arrive on. Runtime changes from the monitor are applied between requests,
+ * as the device owns the subsystem.
+ */
+
+use anyhow::{anyhow, Result};
+use futures::{select, FutureExt};
+use log::{debug, info, warn};
+use mctp::{AsyncListener, AsyncRespChannel};
+use mctp_estack::{control::ControlEvent, router::Router};
+use nvme_mi_dev::{
+    CommandEffect, CommandEffectError, ControllerId, ManagementEndpoint,
+    NamespaceId, PciePort, PortId, PortType, Subsystem, SubsystemInfo,
+    TwoWirePort,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::nvmeconfig::{PortKind, Topology};
+use crate::nvmelink::{self, PcieLink};
+use crate::{events, listener_recv_error, nvmectl, nvmefault, NvmeEnv, Routes};
+
+/* NVMe-MI status for a request larger than we accept */
+const STATUS_INVALID_COMMAND_SIZE: u8 = 0x05;
+
+// An NVMe-MI error response to `req`, a message following the type byte
+fn error_response(req: &[u8], ic: bool, status: u8) -> Vec<u8> {
+    // the request header with the response bit set, the status, and an
+    // empty management response
+    let hdr = req.first().map_or(0, |h| h | 0x80);
+    let mut resp = vec![hdr, 0, 0, status, 0, 0, 0];
+    if ic {
+        resp.extend_from_slice(&[0; 4]);
+        nvmefault::update_mic(&mut resp, ic);
+    }
+    resp
+}
+
+// The subsystem port of a management endpoint, and the MCTP port it serves
+#[derive(Clone, Copy)]
+struct MepPort {
+    mctp_port: Option<u8>,
+    port: PortId,
+    twowire: bool,
+}
+
+impl MepPort {
+    // Apply the effect of a command received by this management endpoint,
+    // from a requester on MCTP port `port`
+    fn command_effect(
+        &self,
+        routes: &Routes,
+        port: u8,
+        ce: CommandEffect,
+    ) -> Result<(), CommandEffectError> {
+        match ce {
+            CommandEffect::SetMtu { port_id, mtus } => {
+                if port_id != self.port {
+                    warn!("NVMe-MI: Bad Port ID for Set MTU: {port_id:?}");
+                    return Err(CommandEffectError::InternalError);
+                }
+
+                // an MTU set by the requester applies to the port it is on
+                if !routes.negotiate_mtu(port, mtus as usize) {
+                    warn!(
+                        "NVMe-MI: MTU {mtus} is outside the range of port \
+                         {port}"
+                    );
+                    return Err(CommandEffectError::Unsupported);
+                }
+
+                info!("NVMe-MI: MTU set to {mtus} on port {port}");
+                Ok(())
+            }
+            CommandEffect::SetSmbusFreq { port_id, freq } => {
+                use nvme_mi_dev::smbus::BusFrequency;
+
+                if port_id != self.port || !self.twowire {
+                    warn!(
+                        "NVMe-MI: Bad Port ID for SMBus frequency: \
+                         {port_id:?}"
+                    );
+                    return Err(CommandEffectError::InternalError);
+                }
+
+                // The bus clock is set by the bus master, and i2c-dev gives
+                // us no control of it, so accepted frequencies are only
+                // recorded by the responder, for Configuration Get.
+                match freq {
+                    BusFrequency::Freq100Khz
+                    | BusFrequency::Freq400Khz
+                    | BusFrequency::Freq1Mhz => {
+                        info!("NVMe-MI: I2C bus frequency set to {freq:?}");
+                        Ok(())
+                    }
+                    _ => {
+                        warn!(
+                            "NVMe-MI: Application lacks support for I2C bus \
+                             frequency {freq:?}"
+                        );
+                        Err(CommandEffectError::Unsupported)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The emulated NVMe-MI device
+pub struct NvmeDevice {
+    subsys: Subsystem,
+    port_kinds: Vec<PortKind>,
+    ports: Vec<PortId>,
+    // namespace ID in the topology to subsystem namespace
+    nsids: HashMap<u32, NamespaceId>,
+    // in creation order, as numbered by the monitor
+    ctlrids: Vec<ControllerId>,
+    meps: Vec<(MepPort, ManagementEndpoint)>,
+    // PCIe link attributes, by port
+    links: Vec<PcieLink>,
+    // reported to the host in health polls, until cleared
+    change_flags: nvmectl::ChangeFlags,
+}
+
+impl NvmeDevice {
+    /// Build the subsystem and management endpoints for `topology`
+    pub fn build(topology: &Topology) -> Result<Self> {
+        let mut subsys = Subsystem::new(SubsystemInfo::environment());
+        let mut ports = Vec::new();
+        for kind in &topology.ports {
+            let port = match kind {
+                PortKind::Pcie => PortType::Pcie(PciePort::new()),
+                PortKind::TwoWire => PortType::TwoWire(TwoWirePort::new()),
+            };
+            let pid = subsys
+                .add_port(port)
+                .map_err(|_| anyhow!("Unable to create port"))?;
+            ports.push(pid);
+        }
+
+        let mut nsids = HashMap::new();
+        for ns in &topology.namespaces {
+            let nsid = subsys
+                .add_namespace(ns.size)
+                .map_err(|_| anyhow!("Unable to create namespace"))?;
+            if ns.eui64.is_some() {
+                warn!("NVMe-MI: namespace EUI64 is not supported, ignoring");
+            }
+            nsids.insert(ns.id, nsid);
+        }
+
+        let mut ctlrids = Vec::new();
+        for c in &topology.controllers {
+            let ctlrid = subsys
+                .add_controller(ports[c.port])
+                .map_err(|_| anyhow!("Unable to create controller"))?;
+            ctlrids.push(ctlrid);
+            for id in &c.namespaces {
+                let nsid = nsids[id];
+                subsys
+                    .controller_mut(ctlrid)
+                    .attach_namespace(nsid)
+                    .map_err(|_| {
+                        anyhow!(
+                            "Unable to attach namespace {nsid:?} to \
+                             controller {ctlrid:?}"
+                        )
+                    })?;
+            }
+        }
+
+        // management endpoints, with the MCTP port each serves. By default
+        // there is one, on the first two-wire port.
+        let mut meps = Vec::new();
+        for m in &topology.meps {
+            let mp = MepPort {
+                mctp_port: m.mctp_port,
+                port: ports[m.port],
+                twowire: topology.ports[m.port] == PortKind::TwoWire,
+            };
+            meps.push((mp, ManagementEndpoint::new(mp.port)));
+        }
+        if meps.is_empty() {
+            let twpid = match topology
+                .ports
+                .iter()
+                .position(|k| *k == PortKind::TwoWire)
+            {
+                Some(i) => ports[i],
+                None => subsys
+                    .add_port(PortType::TwoWire(TwoWirePort::new()))
+                    .map_err(|_| anyhow!("Unable to create TwoWire port"))?,
+            };
+            let mp = MepPort {
+                mctp_port: None,
+                port: twpid,
+                twowire: true,
+            };
+            meps.push((mp, ManagementEndpoint::new(twpid)));
+        }
+
+        Ok(Self {
+            subsys,
+            port_kinds: topology.ports.clone(),
+            ports,
+            nsids,
+            ctlrids,
+            meps,
+            links: topology.links.clone(),
+            change_flags: nvmectl::ChangeFlags::default(),
+        })
+    }
+
+    // Apply a runtime change from the monitor, returning the reply
+    fn apply(&mut self, change: &nvmectl::Change) -> Result<String, String> {
+        match *change {
+            nvmectl::Change::Attach {
+                controller,
+                namespace,
+            } => {
+                match (self.ctlrids.get(controller), self.nsids.get(&namespace))
+                {
+                    (Some(&ctlrid), Some(&nsid)) => self
+                        .subsys
+                        .controller_mut(ctlrid)
+                        .attach_namespace(nsid)
+                        .map(|_| {
+                            self.change_flags.latch(nvmectl::CCS_NAC);
+                            "ok".to_string()
+                        })
+                        .map_err(|_| {
+                            format!(
+                                "can't attach namespace {namespace} to \
+                             controller {controller}"
+                            )
+                        }),
+                    (None, _) => Err(format!("no controller {controller}")),
+                    (_, None) => Err(format!("no namespace {namespace}")),
+                }
+            }
+            nvmectl::Change::AddController { port } => {
+                match self.port_kinds.get(port) {
+                    Some(PortKind::Pcie) => {
+                        let ctlrid = self
+                            .subsys
+                            .add_controller(self.ports[port])
+                            .map_err(|_| "can't create controller")?;
+                        self.ctlrids.push(ctlrid);
+                        Ok(format!("controller {}", self.ctlrids.len() - 1))
+                    }
+                    Some(_) => Err(format!("port {port} is not a PCIe port")),
+                    None => Err(format!("no port {port}")),
+                }
+            }
+        }
+    }
+
+    // The management endpoint serving MCTP port `port`, or the default
+    fn mep_for(&self, port: u8) -> Option<usize> {
+        self.meps
+            .iter()
+            .position(|(m, _)| m.mctp_port == Some(port))
+            .or_else(|| {
+                self.meps.iter().position(|(m, _)| m.mctp_port.is_none())
+            })
+    }
+
+    /// Serve NVMe-MI requests, until the listener fails
+    pub async fn run(
+        mut self,
+        router: &Router<'_>,
+        env: NvmeEnv<'_>,
+        events: async_channel::Receiver<Arc<events::Event>>,
+    ) -> std::io::Result<()> {
+        let mut l = router.listener(mctp::MCTP_TYPE_NVME)?;
+
+        debug!("NVMe-MI endpoint listening");
+
+        // received into a buffer larger than the limit, so that oversized
+        // requests can be answered with an error
+        let scratch = env.limits.nvme_mi * 2;
+        let mut buf = vec![0u8; scratch];
+        // requesters that have sent oversized requests, logged once each
+        let mut oversized = HashSet::new();
+        loop {
+            let r = select!(
+                r = l.recv(&mut buf).fuse() => r,
+                req = env.control.rx.recv().fuse() => {
+                    let Ok(req) = req else {
+                        continue;
+                    };
+                    let r = self.apply(&req.change);
+                    match &r {
+                        Ok(_) => info!("NVMe-MI: applied {:?}", req.change),
+                        Err(e) => warn!("NVMe-MI: {e}"),
+                    }
+                    let _ = req.reply.try_send(r);
+                    continue;
+                }
+                ev = events.recv().fuse() => {
+                    let eid_changed = ev.as_ref().is_ok_and(|ev| matches!(
+                        ev.event,
+                        events::EventKind::Control(
+                            ControlEvent::SetEndpointId { .. }
+                        ) | events::EventKind::EidReset { .. }
+                    ));
+                    if let (true, Ok(ev)) = (eid_changed, ev) {
+                        info!(
+                            "NVMe-MI: EID changed {:.1?} ago: {:?}",
+                            ev.time.elapsed(),
+                            ev.event
+                        );
+                    }
+                    continue;
+                }
+            );
+            let (_typ, ic, msg, resp) = match r {
+                Ok(r) => r,
+                Err(e) => {
+                    listener_recv_error(
+                        e,
+                        "NVMe-MI",
+                        scratch,
+                        "--max-nvme-mi-msg",
+                    );
+                    continue;
+                }
+            };
+
+            let mut resp = crate::nvmetrace::Traced {
+                cmd: env.trace.request(resp.remote_eid(), msg),
+                inner: resp,
+                trace: env.trace,
+            };
+
+            if msg.len() > env.limits.nvme_mi {
+                let eid = resp.remote_eid();
+                if oversized.insert(eid.0) {
+                    warn!(
+                        "NVMe-MI: {} byte request from {eid} exceeds the {} \
+                         byte limit; the limit can be raised with \
+                         --max-nvme-mi-msg",
+                        msg.len(),
+                        env.limits.nvme_mi
+                    );
+                }
+                let reply =
+                    error_response(msg, ic.0, STATUS_INVALID_COMMAND_SIZE);
+                if let Err(e) = resp.send_vectored(ic, &[&reply]).await {
+                    debug!("NVMe-MI reply failed: {e}");
+                }
+                continue;
+            }
+
+            if let Some(p) = env.passthrough {
+                if let Some(reply) = p.handle(msg, ic.0).await {
+                    if let Err(e) = resp.send_vectored(ic, &[&reply]).await {
+                        debug!("NVMe-MI reply failed: {e}");
+                    }
+                    continue;
+                }
+            }
+
+            let port = env.routes.lookup(resp.remote_eid().0).unwrap_or(0);
+            let Some(idx) = self.mep_for(port) else {
+                debug!("NVMe-MI: no management endpoint for port {port}");
+                continue;
+            };
+            let (mp, mep) = &mut self.meps[idx];
+            let mp = *mp;
+
+            debug!("Handling NVMe-MI message on port {port}: {msg:x?}");
+            let resp = nvmefault::Injected {
+                action: env.faults.take(msg),
+                inner: nvmectl::Latched {
+                    inner: nvmelink::Linked {
+                        inner: resp,
+                        links: &self.links,
+                        req: msg,
+                    },
+                    flags: &self.change_flags,
+                    req: msg,
+                },
+            };
+            let routes = env.routes;
+            mep.handle_async(&mut self.subsys, msg, ic, resp, async |ce| {
+                mp.command_effect(routes, port, ce)
+            })
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvmeconfig::Mep;
+
+    // The default topology has one management endpoint, on a two-wire port
+    // added for it, serving every MCTP port
+    #[test]
+    fn build_default() {
+        let mut dev = NvmeDevice::build(&Topology::default()).unwrap();
+        assert_eq!(dev.ports.len(), 1);
+        assert_eq!(dev.ctlrids.len(), 1);
+        assert_eq!(dev.meps.len(), 1);
+        let mp = dev.meps[0].0;
+        assert_eq!(mp.mctp_port, None);
+        assert!(mp.twowire);
+        assert!(mp.port != dev.ports[0]);
+        assert_eq!(dev.mep_for(0), Some(0));
+        assert_eq!(dev.mep_for(3), Some(0));
+
+        let add = nvmectl::Change::AddController { port: 0 };
+        assert_eq!(dev.apply(&add), Ok("controller 1".to_string()));
+        let attach = nvmectl::Change::Attach {
+            controller: 1,
+            namespace: 2,
+        };
+        assert_eq!(dev.apply(&attach), Ok("ok".to_string()));
+    }
+
+    // Management endpoints from the topology serve their own MCTP port,
+    // with an unassigned endpoint serving the rest
+    #[test]
+    fn build_meps() {
+        let topology = Topology {
+            ports: vec![PortKind::Pcie, PortKind::TwoWire],
+            links: vec![PcieLink::default(); 2],
+            meps: vec![
+                Mep {
+                    port: 1,
+                    mctp_port: Some(1),
+                },
+                Mep {
+                    port: 0,
+                    mctp_port: None,
+                },
+            ],
+            ..Topology::default()
+        };
+        let dev = NvmeDevice::build(&topology).unwrap();
+        assert_eq!(dev.meps.len(), 2);
+        assert!(dev.meps[0].0.twowire);
+        assert_eq!(dev.meps[0].0.port, dev.ports[1]);
+        assert!(!dev.meps[1].0.twowire);
+        assert_eq!(dev.meps[1].0.port, dev.ports[0]);
+        assert_eq!(dev.mep_for(1), Some(0));
+        assert_eq!(dev.mep_for(0), Some(1));
+        assert_eq!(dev.mep_for(2), Some(1));
+
+        // without an unassigned endpoint, other MCTP ports aren't served
+        let topology = Topology {
+            meps: vec![Mep {
+                port: 1,
+                mctp_port: Some(1),
+            }],
+            ..topology
+        };
+        let dev = NvmeDevice::build(&topology).unwrap();
+        assert_eq!(dev.mep_for(1), Some(0));
+        assert_eq!(dev.mep_for(0), None);
+    }
+
+    // Runtime changes naming missing or unsuitable parts are refused
+    #[test]
+    fn apply_errors() {
+        let topology = Topology {
+            ports: vec![PortKind::Pcie, PortKind::TwoWire],
+            links: vec![PcieLink::default(); 2],
+            ..Topology::default()
+        };
+        let mut dev = NvmeDevice::build(&topology).unwrap();
+        let errors = [
+            (
+                nvmectl::Change::AddController { port: 1 },
+                "not a PCIe port",
+            ),
+            (nvmectl::Change::AddController { port: 2 }, "no port 2"),
+            (
+                nvmectl::Change::Attach {
+                    controller: 5,
+                    namespace: 1,
+                },
+                "no controller 5",
+            ),
+            (
+                nvmectl::Change::Attach {
+                    controller: 0,
+                    namespace: 9,
+                },
+                "no namespace 9",
+            ),
+        ];
+        for (change, msg) in errors {
+            let e = dev.apply(&change).unwrap_err();
+            assert!(e.contains(msg), "{change:?}: {e}");
+        }
+        assert_eq!(dev.ctlrids.len(), 1);
+    }
+
+    // Set SMBus frequency is only accepted by a two-wire management
+    // endpoint, for its own port
+    #[test]
+    fn pcie_mep_rejects_smbus_freq() {
+        use nvme_mi_dev::smbus::BusFrequency;
+
+        let mut subsys = Subsystem::new(SubsystemInfo::environment());
+        let pcie = subsys.add_port(PortType::Pcie(PciePort::new())).unwrap();
+        let twowire = subsys
+            .add_port(PortType::TwoWire(TwoWirePort::new()))
+            .unwrap();
+        let pcie_mep = MepPort {
+            mctp_port: Some(0),
+            port: pcie,
+            twowire: false,
+        };
+        let twowire_mep = MepPort {
+            mctp_port: Some(1),
+            port: twowire,
+            twowire: true,
+        };
+
+        let routes = Routes::new(false, Vec::new(), None);
+        let set_freq = |mep: MepPort, port_id| {
+            let ce = CommandEffect::SetSmbusFreq {
+                port_id,
+                freq: BusFrequency::Freq400Khz,
+            };
+            mep.command_effect(&routes, 0, ce)
+        };
+        assert!(matches!(
+            set_freq(pcie_mep, pcie),
+            Err(CommandEffectError::InternalError)
+        ));
+        assert!(set_freq(twowire_mep, twowire).is_ok());
+        // a two-wire endpoint, for another endpoint's port
+        assert!(matches!(
+            set_freq(twowire_mep, pcie),
+            Err(CommandEffectError::InternalError)
+        ));
+    }
+
+    // An error response echoes the request header with the response bit,
+    // and has a valid MIC
+    #[test]
+    fn error_response_encoding() {
+        let resp = error_response(&[0x08, 0, 0, 0x00], true, 0x05);
+        assert_eq!(resp[..7], [0x88, 0, 0, 0x05, 0, 0, 0]);
+        let mut check = resp.clone();
+        nvmefault::update_mic(&mut check, true);
+        assert_eq!(resp, check);
+        assert_eq!(error_response(&[0x08], false, 0x05).len(), 7);
+    }
+}
@@ -0,0 +1,349 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* NVMe-MI subsystem topology, from a TOML file given with --nvme-config:
+ *
+ *   [[port]]
+ *   type = "pcie"          # or "twowire"
+ *   count = 1
+ *   link-speed = "gen4"    # PCIe only, gen1 to gen6
+ *   link-width = 4         # PCIe only, lanes
+ *   mps = 256              # PCIe only, max payload size in bytes
+ *
+ *   [[namespace]]
+ *   id = 1
+ *   size = 1024
+ *   eui64 = "0011223344556677"
+ *
+ *   [[controller]]
+ *   id = 0
+ *   port = 0               # index into the ports, in order
+ *   namespaces = [1]
+ *
+ *   [[mep]]
+ *   port = 1               # index into the ports
+ *   mctp-port = 0          # optional, MCTP port the MEP serves
+ *
+ * Namespace and controller IDs are labels within the file; the subsystem
+ * allocates its own. Without any [[mep]] entries, a single management
+ * endpoint uses the first two-wire port, which is added if none is
+ * configured.
+ *
+ * Requests are handled by the management endpoint for the MCTP port they
+ * arrive on, or otherwise by the endpoint with no mctp-port.
+ *
+ * The PCIe link attributes are reported in Port Information, in place of
+ * nvme-mi-dev's defaults; see nvmelink.
+ */
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use toml::Spanned;
+
+use crate::nvmelink::PcieLink;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortKind {
+    Pcie,
+    TwoWire,
+}
+
+fn one() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct PortConfig {
+    #[serde(rename = "type")]
+    kind: PortKind,
+    #[serde(default = "one")]
+    count: u32,
+    link_speed: Option<Spanned<String>>,
+    link_width: Option<Spanned<u8>>,
+    mps: Option<Spanned<u16>>,
+}
+
+// Build the PCIe link attributes of a port, returning the error and the
+// offset of the offending value
+fn port_link(p: &PortConfig) -> Result<PcieLink, (String, usize)> {
+    if p.kind != PortKind::Pcie {
+        let attr = [
+            p.link_speed
+                .as_ref()
+                .map(|s| ("link-speed", s.span().start)),
+            p.link_width
+                .as_ref()
+                .map(|w| ("link-width", w.span().start)),
+            p.mps.as_ref().map(|m| ("mps", m.span().start)),
+        ];
+        if let Some((name, offset)) = attr.into_iter().flatten().next() {
+            return Err((
+                format!("{name} is only valid for PCIe ports"),
+                offset,
+            ));
+        }
+    }
+
+    let mut link = PcieLink::default();
+    if let Some(s) = &p.link_speed {
+        link = link.speed(s.get_ref()).map_err(|e| (e, s.span().start))?;
+    }
+    if let Some(w) = &p.link_width {
+        link = link.width(*w.get_ref()).map_err(|e| (e, w.span().start))?;
+    }
+    if let Some(m) = &p.mps {
+        link = link.mps(*m.get_ref()).map_err(|e| (e, m.span().start))?;
+    }
+    Ok(link)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamespaceConfig {
+    id: Spanned<u32>,
+    size: u64,
+    eui64: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControllerConfig {
+    id: Spanned<u32>,
+    port: Spanned<usize>,
+    #[serde(default)]
+    namespaces: Vec<Spanned<u32>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct MepConfig {
+    port: Spanned<usize>,
+    mctp_port: Option<Spanned<u8>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default, rename = "port")]
+    ports: Vec<PortConfig>,
+    #[serde(default, rename = "namespace")]
+    namespaces: Vec<NamespaceConfig>,
+    #[serde(default, rename = "controller")]
+    controllers: Vec<ControllerConfig>,
+    #[serde(default, rename = "mep")]
+    meps: Vec<MepConfig>,
+}
+
+#[derive(Debug)]
+pub struct Namespace {
+    pub id: u32,
+    pub size: u64,
+    pub eui64: Option<[u8; 8]>,
+}
+
+#[derive(Debug)]
+pub struct Controller {
+    /// index into the topology's ports
+    pub port: usize,
+    /// IDs of the namespaces to attach
+    pub namespaces: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct Mep {
+    /// index into the topology's ports
+    pub port: usize,
+    /// MCTP port served; if unset, ports not served by other endpoints
+    pub mctp_port: Option<u8>,
+}
+
+/// A validated subsystem topology
+#[derive(Debug)]
+pub struct Topology {
+    pub ports: Vec<PortKind>,
+    /// the link attributes of each port
+    pub links: Vec<PcieLink>,
+    pub namespaces: Vec<Namespace>,
+    pub controllers: Vec<Controller>,
+    /// if empty, a single management endpoint on a two-wire port
+    pub meps: Vec<Mep>,
+}
+
+impl Default for Topology {
+    /// One PCIe port with a controller, and two namespaces, the first
+    /// attached to the controller
+    fn default() -> Self {
+        Self {
+            ports: vec![PortKind::Pcie],
+            links: vec![PcieLink::default()],
+            namespaces: vec![
+                Namespace {
+                    id: 1,
+                    size: 1024,
+                    eui64: None,
+                },
+                Namespace {
+                    id: 2,
+                    size: 2048,
+                    eui64: None,
+                },
+            ],
+            controllers: vec![Controller {
+                port: 0,
+                namespaces: vec![1],
+            }],
+            meps: Vec::new(),
+        }
+    }
+}
+
+fn parse_eui64(s: &str) -> Option<[u8; 8]> {
+    let s = s.replace([':', '-'], "");
+    if s.len() != 16 {
+        return None;
+    }
+    let mut eui = [0u8; 8];
+    for (i, b) in eui.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(eui)
+}
+
+// Validate the parsed config, returning the error message and the offset
+// of the offending value
+fn validate(config: Config) -> Result<Topology, (String, usize)> {
+    let mut ports = Vec::new();
+    let mut links = Vec::new();
+    for p in &config.ports {
+        let link = port_link(p)?;
+        ports.extend(std::iter::repeat_n(p.kind, p.count as usize));
+        links.extend(std::iter::repeat_n(link, p.count as usize));
+    }
+
+    let mut namespaces = Vec::new();
+    for ns in config.namespaces {
+        let id = *ns.id.get_ref();
+        if namespaces.iter().any(|n: &Namespace| n.id == id) {
+            return Err((
+                format!("duplicate namespace id {id}"),
+                ns.id.span().start,
+            ));
+        }
+        let eui64 = match &ns.eui64 {
+            Some(e) => match parse_eui64(e.get_ref()) {
+                Some(eui) => Some(eui),
+                None => {
+                    return Err((
+                        format!("invalid EUI64 '{}'", e.get_ref()),
+                        e.span().start,
+                    ))
+                }
+            },
+            None => None,
+        };
+        namespaces.push(Namespace {
+            id,
+            size: ns.size,
+            eui64,
+        });
+    }
+
+    let mut ids = HashSet::new();
+    let mut controllers = Vec::new();
+    for c in config.controllers {
+        let id = *c.id.get_ref();
+        if !ids.insert(id) {
+            return Err((
+                format!("duplicate controller id {id}"),
+                c.id.span().start,
+            ));
+        }
+        let port = *c.port.get_ref();
+        match ports.get(port) {
+            Some(PortKind::Pcie) => (),
+            Some(_) => {
+                return Err((
+                    format!("controller {id}: port {port} is not a PCIe port"),
+                    c.port.span().start,
+                ))
+            }
+            None => {
+                return Err((
+                    format!("controller {id}: no port {port}"),
+                    c.port.span().start,
+                ))
+            }
+        }
+        for ns in &c.namespaces {
+            let nsid = *ns.get_ref();
+            if !namespaces.iter().any(|n| n.id == nsid) {
+                return Err((
+                    format!("controller {id}: no namespace {nsid}"),
+                    ns.span().start,
+                ));
+            }
+        }
+        controllers.push(Controller {
+            port,
+            namespaces: c.namespaces.iter().map(|n| *n.get_ref()).collect(),
+        });
+    }
+
+    let mut meps: Vec<Mep> = Vec::new();
+    for m in config.meps {
+        let port = *m.port.get_ref();
+        if port >= ports.len() {
+            return Err((format!("mep: no port {port}"), m.port.span().start));
+        }
+        let mctp_port = m.mctp_port.as_ref().map(|p| *p.get_ref());
+        if meps.iter().any(|e| e.mctp_port == mctp_port) {
+            let (msg, offset) = match &m.mctp_port {
+                Some(p) => (
+                    format!("duplicate mep for MCTP port {}", p.get_ref()),
+                    p.span().start,
+                ),
+                None => (
+                    "more than one mep without mctp-port".to_string(),
+                    m.port.span().start,
+                ),
+            };
+            return Err((msg, offset));
+        }
+        meps.push(Mep { port, mctp_port });
+    }
+
+    Ok(Topology {
+        ports,
+        links,
+        namespaces,
+        controllers,
+        meps,
+    })
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// Load and validate a topology file. Errors include the line of the
+/// offending entry.
+pub fn load(path: &str) -> Result<Topology> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Can't read NVMe config {path}"))?;
+
+    let config: Config = match toml::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => match e.span() {
+            Some(span) => {
+                bail!("{path}:{}: {}", line_of(&text, span.start), e.message())
+            }
+            None => bail!("{path}: {}", e.message()),
+        },
+    };
+
+    validate(config).map_err(|(msg, offset)| {
+        anyhow::anyhow!("{path}:{}: {msg}", line_of(&text, offset))
+    })
+}
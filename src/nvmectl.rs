@@ -0,0 +1,237 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Runtime changes to the NVMe-MI subsystem, requested from the monitor:
+ *
+ *   nvme attach <controller> <namespace>
+ *   nvme add-controller <port>
+ *
+ * Controllers are numbered in the order they were created, starting with
+ * those from the topology, namespaces by their ID in the topology, and
+ * ports by their index. The subsystem is owned by the NVMe-MI task, so
+ * changes are sent to it and applied between MI requests.
+ *
+ * Attaching a namespace latches the Namespace Attribute Changed flag in the
+ * Composite Controller Status of NVM Subsystem Health Status Poll
+ * responses. The flag is reported until a poll with Clear Status set.
+ */
+
+// only used by the NVMe-MI device, with the nvme-mi feature
+#![cfg_attr(not(feature = "nvme-mi"), allow(dead_code))]
+
+use anyhow::{bail, Context, Result};
+use std::cell::Cell;
+
+use crate::nvmefault;
+
+/* NVM Subsystem Health Status Poll request and response fields, following
+ * the message type byte */
+const NMIMT_SHIFT: u8 = 3;
+const NMIMT_MASK: u8 = 0x0f;
+const NMIMT_MI_COMMAND: u8 = 1;
+const MI_OPCODE_SUBSYS_HEALTH_POLL: u8 = 0x01;
+const OPCODE_OFFSET: usize = 3;
+const STATUS_OFFSET: usize = 3;
+// Clear Status, bit 31 of DWORD1
+const CS_OFFSET: usize = 14;
+const CS_BIT: u8 = 0x80;
+// Composite Controller Status, in the health data following the status
+// and the management response
+const CCS_OFFSET: usize = 11;
+
+/// Composite Controller Status: Namespace Attribute Changed
+pub const CCS_NAC: u16 = 1 << 6;
+
+#[derive(Debug)]
+pub enum Change {
+    Attach { controller: usize, namespace: u32 },
+    AddController { port: usize },
+}
+
+impl std::str::FromStr for Change {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let args = s.split_whitespace().collect::<Vec<_>>();
+        let change = match args[..] {
+            ["attach", c, ns] => Change::Attach {
+                controller: c.parse().context("invalid controller")?,
+                namespace: ns.parse().context("invalid namespace")?,
+            },
+            ["add-controller", p] => Change::AddController {
+                port: p.parse().context("invalid port")?,
+            },
+            _ => bail!(
+                "expected attach <controller> <namespace>, or \
+                 add-controller <port>"
+            ),
+        };
+        Ok(change)
+    }
+}
+
+/// A change, and where to send its result
+pub struct Request {
+    pub change: Change,
+    pub reply: async_channel::Sender<Result<String, String>>,
+}
+
+/// Channel from the monitor to the NVMe-MI task
+#[derive(Clone)]
+pub struct NvmeControl {
+    tx: async_channel::Sender<Request>,
+    pub rx: async_channel::Receiver<Request>,
+}
+
+impl Default for NvmeControl {
+    fn default() -> Self {
+        let (tx, rx) = async_channel::bounded(1);
+        Self { tx, rx }
+    }
+}
+
+impl NvmeControl {
+    /// Apply `change`, returning the result from the NVMe-MI task
+    pub async fn request(&self, change: Change) -> Result<String> {
+        let (reply, result) = async_channel::bounded(1);
+        self.tx
+            .send(Request { change, reply })
+            .await
+            .context("NVMe-MI task has exited")?;
+        let result = result.recv().await.context("no reply")?;
+        result.map_err(anyhow::Error::msg)
+    }
+}
+
+/// Composite Controller Status change flags, latched by subsystem changes
+/// until the host clears them
+#[derive(Default)]
+pub struct ChangeFlags(Cell<u16>);
+
+impl ChangeFlags {
+    pub fn latch(&self, flags: u16) {
+        self.0.set(self.0.get() | flags);
+    }
+
+    /// Report the latched flags in the response `resp` to `req`, if it is a
+    /// successful NVM Subsystem Health Status Poll, clearing them if the
+    /// poll requests it. Both messages follow the message type byte.
+    pub fn apply(&self, req: &[u8], resp: &mut [u8], ic: bool) {
+        let nmimt = req.first().map(|h| (h >> NMIMT_SHIFT) & NMIMT_MASK);
+        let poll = nmimt == Some(NMIMT_MI_COMMAND)
+            && req.get(OPCODE_OFFSET) == Some(&MI_OPCODE_SUBSYS_HEALTH_POLL);
+        if !poll || resp.get(STATUS_OFFSET) != Some(&0) {
+            return;
+        }
+        let Some(ccs) = resp.get_mut(CCS_OFFSET..CCS_OFFSET + 2) else {
+            return;
+        };
+        let flags = self.0.get();
+        if flags != 0 {
+            let v = u16::from_le_bytes([ccs[0], ccs[1]]) | flags;
+            ccs.copy_from_slice(&v.to_le_bytes());
+            nvmefault::update_mic(resp, ic);
+        }
+        if req.get(CS_OFFSET).is_some_and(|b| b & CS_BIT != 0) {
+            self.0.set(0);
+        }
+    }
+}
+
+/// A response channel reporting latched change flags in the response
+pub struct Latched<'f, 'r, R> {
+    pub inner: R,
+    pub flags: &'f ChangeFlags,
+    pub req: &'r [u8],
+}
+
+impl<R: mctp::AsyncRespChannel> mctp::AsyncRespChannel for Latched<'_, '_, R> {
+    type ReqChannel<'a>
+        = R::ReqChannel<'a>
+    where
+        Self: 'a;
+
+    async fn send_vectored(
+        &mut self,
+        ic: mctp::MsgIC,
+        bufs: &[&[u8]],
+    ) -> mctp::Result<()> {
+        let mut msg = bufs.concat();
+        self.flags.apply(self.req, &mut msg, ic.0);
+        self.inner.send_vectored(ic, &[&msg]).await
+    }
+
+    fn remote_eid(&self) -> mctp::Eid {
+        self.inner.remote_eid()
+    }
+
+    fn req_channel(&self) -> mctp::Result<Self::ReqChannel<'_>> {
+        self.inner.req_channel()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NVM Subsystem Health Status Poll, with Clear Status if `clear`
+    fn poll(clear: bool) -> Vec<u8> {
+        let mut req = vec![0x08, 0, 0, MI_OPCODE_SUBSYS_HEALTH_POLL];
+        req.resize(15 + 4, 0);
+        if clear {
+            req[CS_OFFSET] |= CS_BIT;
+        }
+        nvmefault::update_mic(&mut req, true);
+        req
+    }
+
+    // A response to `req` with `status`, and ready set in the Composite
+    // Controller Status. Returns the response after applying `flags`.
+    fn respond(flags: &ChangeFlags, req: &[u8], status: u8) -> Vec<u8> {
+        let mut resp = vec![0x88, 0, 0, status, 0, 0, 0, 0, 0, 0, 0];
+        resp.extend_from_slice(&1u16.to_le_bytes());
+        resp.resize(15 + 4, 0);
+        nvmefault::update_mic(&mut resp, true);
+        flags.apply(req, &mut resp, true);
+
+        let mut check = resp.clone();
+        nvmefault::update_mic(&mut check, true);
+        assert_eq!(resp, check, "bad MIC");
+        resp
+    }
+
+    fn ccs(resp: &[u8]) -> u16 {
+        u16::from_le_bytes([resp[CCS_OFFSET], resp[CCS_OFFSET + 1]])
+    }
+
+    // A latched flag is reported by each poll, up to and including the
+    // first with Clear Status set
+    #[test]
+    fn latch_clear() {
+        let flags = ChangeFlags::default();
+        assert_eq!(ccs(&respond(&flags, &poll(false), 0)), 0x01);
+
+        flags.latch(CCS_NAC);
+        assert_eq!(ccs(&respond(&flags, &poll(false), 0)), 0x01 | CCS_NAC);
+        assert_eq!(ccs(&respond(&flags, &poll(false), 0)), 0x01 | CCS_NAC);
+        assert_eq!(ccs(&respond(&flags, &poll(true), 0)), 0x01 | CCS_NAC);
+        assert_eq!(ccs(&respond(&flags, &poll(false), 0)), 0x01);
+    }
+
+    // Other responses are left unchanged, and don't clear the flags
+    #[test]
+    fn other_responses() {
+        let flags = ChangeFlags::default();
+        flags.latch(CCS_NAC);
+
+        // Controller Health Status Poll
+        let mut req = poll(true);
+        req[OPCODE_OFFSET] = 0x02;
+        nvmefault::update_mic(&mut req, true);
+        assert_eq!(ccs(&respond(&flags, &req, 0)), 0x01);
+
+        // a failed poll
+        assert_eq!(ccs(&respond(&flags, &poll(true), 0x04)), 0x01);
+
+        assert_eq!(ccs(&respond(&flags, &poll(false), 0)), 0x01 | CCS_NAC);
+    }
+}
@@ -0,0 +1,271 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Fault injection for NVMe-MI responses, for testing host MI drivers. Rules
+ * are given with --nvme-inject, or added from the monitor socket, in the
+ * form:
+ *
+ *   delay <ms> | drop <k> | status <code>  [mi|admin <opcode>] [count]
+ *
+ * A rule applies to requests with the given command type and opcode, or to
+ * all requests if none is given, and expires after `count` matching
+ * requests. Without a count, it stays in place until cleared. Where several
+ * rules match a request, the first one added applies.
+ *
+ * "delay" holds the response for the given time, "drop" discards every k-th
+ * matching response, and "status" replaces the response status. The command
+ * itself is always executed by the subsystem; only the response is altered,
+ * so a host that retries a dropped request sees the state left by the
+ * first attempt.
+ */
+
+// only used by the NVMe-MI device, with the nvme-mi feature
+#![cfg_attr(not(feature = "nvme-mi"), allow(dead_code))]
+
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/* NVMe-MI message header fields, following the message type byte */
+const NMIMT_SHIFT: u8 = 3;
+const NMIMT_MASK: u8 = 0x0f;
+const NMIMT_MI_COMMAND: u8 = 1;
+const NMIMT_ADMIN_COMMAND: u8 = 2;
+const OPCODE_OFFSET: usize = 3;
+const STATUS_OFFSET: usize = 3;
+
+/* message integrity check, a CRC-32C covering the message type byte */
+const MIC_LEN: usize = 4;
+const MSG_TYPE_NVME_IC: u8 = 0x84;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Delay(Duration),
+    /// discard every k-th matching response
+    Drop(u32),
+    /// replace the response status
+    Status(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandType {
+    Mi,
+    Admin,
+}
+
+/// A fault injection rule
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    /// matching requests; all requests if unset
+    pub command: Option<(CommandType, u8)>,
+    /// number of matching requests before the rule expires
+    pub count: Option<u32>,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split_whitespace().collect::<Vec<_>>();
+        let value = args.get(1).ok_or("expected a value for the action")?;
+        let action = match args[0] {
+            "delay" => Action::Delay(Duration::from_millis(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid delay {value}"))?,
+            )),
+            "drop" => match value.parse() {
+                Ok(k) if k > 0 => Action::Drop(k),
+                _ => return Err(format!("invalid drop interval {value}")),
+            },
+            "status" => Action::Status(crate::parse_int(value)?),
+            a => return Err(format!("unknown action {a}")),
+        };
+
+        let mut rest = &args[2..];
+        let command = match rest.first() {
+            Some(&t @ ("mi" | "admin")) => {
+                let typ = if t == "mi" {
+                    CommandType::Mi
+                } else {
+                    CommandType::Admin
+                };
+                let op = rest.get(1).ok_or("expected an opcode")?;
+                let op = crate::parse_int(op)?;
+                rest = &rest[2..];
+                Some((typ, op))
+            }
+            _ => None,
+        };
+
+        let count = match rest {
+            [] => None,
+            [c] => Some(c.parse().map_err(|_| format!("invalid count {c}"))?),
+            _ => return Err("too many arguments".to_string()),
+        };
+
+        Ok(Self {
+            action,
+            command,
+            count,
+        })
+    }
+}
+
+struct ActiveRule {
+    rule: Rule,
+    matched: u32,
+}
+
+#[derive(Default)]
+struct State {
+    rules: Vec<ActiveRule>,
+    delayed: u64,
+    dropped: u64,
+    status: u64,
+}
+
+/// Handle for injecting faults into NVMe-MI responses
+#[derive(Clone, Default)]
+pub struct NvmeFaults(Arc<Mutex<State>>);
+
+impl NvmeFaults {
+    pub fn inject(&self, rule: Rule) {
+        info!("NVMe-MI: injecting {rule:?}");
+        let mut state = self.0.lock().unwrap();
+        state.rules.push(ActiveRule { rule, matched: 0 });
+    }
+
+    /// Remove all rules
+    pub fn clear(&self) {
+        self.0.lock().unwrap().rules.clear();
+    }
+
+    /// Find the fault to apply to the response to `req`, a NVMe-MI message
+    /// following the message type byte. Counts the match against the rule.
+    pub fn take(&self, req: &[u8]) -> Option<Action> {
+        let (Some(hdr), Some(&op)) = (req.first(), req.get(OPCODE_OFFSET))
+        else {
+            return None;
+        };
+        let typ = match (hdr >> NMIMT_SHIFT) & NMIMT_MASK {
+            NMIMT_MI_COMMAND => Some(CommandType::Mi),
+            NMIMT_ADMIN_COMMAND => Some(CommandType::Admin),
+            _ => None,
+        };
+
+        let mut state = self.0.lock().unwrap();
+        let i = state.rules.iter().position(|r| {
+            r.rule.command.is_none_or(|c| Some(c.0) == typ && c.1 == op)
+        })?;
+        let r = &mut state.rules[i];
+        r.matched += 1;
+        let action = match r.rule.action {
+            Action::Drop(k) if r.matched % k > 0 => None,
+            a => Some(a),
+        };
+        if r.rule.count.is_some_and(|c| r.matched >= c) {
+            state.rules.remove(i);
+        }
+
+        match action {
+            Some(Action::Delay(_)) => state.delayed += 1,
+            Some(Action::Drop(_)) => state.dropped += 1,
+            Some(Action::Status(_)) => state.status += 1,
+            None => (),
+        }
+        action
+    }
+}
+
+impl std::fmt::Display for NvmeFaults {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.0.lock().unwrap();
+        write!(
+            f,
+            "{} rules, delayed {}, dropped {}, status replaced {}",
+            state.rules.len(),
+            state.delayed,
+            state.dropped,
+            state.status
+        )
+    }
+}
+
+/// CRC-32C, as used for the NVMe-MI message integrity check
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f63b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Recalculate the integrity check of a modified message, if it has one
+pub fn update_mic(msg: &mut [u8], ic: bool) {
+    if ic && msg.len() >= MIC_LEN {
+        let len = msg.len() - MIC_LEN;
+        let mut data = vec![MSG_TYPE_NVME_IC];
+        data.extend_from_slice(&msg[..len]);
+        msg[len..].copy_from_slice(&crc32c(&data).to_le_bytes());
+    }
+}
+
+// Replace the status of a response message, updating its integrity check
+fn set_status(msg: &mut [u8], ic: bool, status: u8) {
+    let Some(s) = msg.get_mut(STATUS_OFFSET) else {
+        return;
+    };
+    *s = status;
+    update_mic(msg, ic);
+}
+
+/// A response channel applying any injected fault to the response
+pub struct Injected<R> {
+    pub inner: R,
+    pub action: Option<Action>,
+}
+
+impl<R: mctp::AsyncRespChannel> mctp::AsyncRespChannel for Injected<R> {
+    type ReqChannel<'a>
+        = R::ReqChannel<'a>
+    where
+        Self: 'a;
+
+    async fn send_vectored(
+        &mut self,
+        ic: mctp::MsgIC,
+        bufs: &[&[u8]],
+    ) -> mctp::Result<()> {
+        let eid = self.inner.remote_eid();
+        match self.action {
+            None => self.inner.send_vectored(ic, bufs).await,
+            Some(Action::Delay(d)) => {
+                info!("NVMe-MI: delaying response to {eid} by {d:?}");
+                smol::Timer::after(d).await;
+                self.inner.send_vectored(ic, bufs).await
+            }
+            Some(Action::Drop(_)) => {
+                info!("NVMe-MI: dropping response to {eid}");
+                Ok(())
+            }
+            Some(Action::Status(status)) => {
+                info!("NVMe-MI: response to {eid} with status {status:#04x}");
+                let mut msg = bufs.concat();
+                set_status(&mut msg, ic.0, status);
+                self.inner.send_vectored(ic, &[&msg]).await
+            }
+        }
+    }
+
+    fn remote_eid(&self) -> mctp::Eid {
+        self.inner.remote_eid()
+    }
+
+    fn req_channel(&self) -> mctp::Result<Self::ReqChannel<'_>> {
+        self.inner.req_channel()
+    }
+}
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PCIe link attributes of NVMe-MI ports: the link speed, link width and
+ * maximum payload size.
+ *
+ * nvme-mi-dev's PciePort is created with a default link, and has no setters,
+ * so the attributes are applied to the Port Information data structure in
+ * Read NVMe-MI Data Structure responses. Ports are identified by their
+ * index, in the order they are added to the subsystem.
+ */
+
+use crate::nvmefault;
+
+/* Read NVMe-MI Data Structure request and response fields, following the
+ * message type byte */
+const NMIMT_SHIFT: u8 = 3;
+const NMIMT_MASK: u8 = 0x0f;
+const NMIMT_MI_COMMAND: u8 = 1;
+const MI_OPCODE_READ_DATA_STRUCTURE: u8 = 0x00;
+const OPCODE_OFFSET: usize = 3;
+const STATUS_OFFSET: usize = 3;
+// in DWORD0, following the controller ID
+const PORT_ID_OFFSET: usize = 9;
+const DTYP_OFFSET: usize = 10;
+const DTYP_PORT_INFO: u8 = 0x01;
+// response data, following the status and the management response
+const DATA_OFFSET: usize = 7;
+
+/* Port Information fields */
+const PORT_INFO_LEN: usize = 32;
+const PRTTYP_PCIE: u8 = 0x01;
+const PCIEMPS: usize = 8;
+const PCIESLSV: usize = 9;
+const PCIECLS: usize = 10;
+const PCIEMLW: usize = 11;
+const PCIENLW: usize = 12;
+
+const LINK_SPEEDS: [&str; 6] = ["gen1", "gen2", "gen3", "gen4", "gen5", "gen6"];
+const LINK_WIDTHS: [u8; 7] = [1, 2, 4, 8, 12, 16, 32];
+const PAYLOAD_SIZES: [u16; 6] = [128, 256, 512, 1024, 2048, 4096];
+
+/// PCIe link attributes of a port, replacing nvme-mi-dev's defaults where
+/// set. Each setter fails if the value can't be reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PcieLink {
+    /// link speed, as the PCIe generation
+    speed: Option<u8>,
+    /// negotiated link width, in lanes
+    width: Option<u8>,
+    /// maximum payload size, in bytes
+    mps: Option<u16>,
+}
+
+impl PcieLink {
+    /// Set the link speed, "gen1" to "gen6"
+    pub fn speed(mut self, speed: &str) -> Result<Self, String> {
+        let i = LINK_SPEEDS
+            .iter()
+            .position(|s| *s == speed)
+            .ok_or_else(|| format!("unsupported link-speed {speed}"))?;
+        self.speed = Some(i as u8 + 1);
+        Ok(self)
+    }
+
+    /// Set the negotiated link width
+    pub fn width(mut self, width: u8) -> Result<Self, String> {
+        if !LINK_WIDTHS.contains(&width) {
+            return Err(format!("unsupported link-width {width}"));
+        }
+        self.width = Some(width);
+        Ok(self)
+    }
+
+    /// Set the maximum payload size
+    pub fn mps(mut self, mps: u16) -> Result<Self, String> {
+        if !PAYLOAD_SIZES.contains(&mps) {
+            return Err(format!("unsupported mps {mps}"));
+        }
+        self.mps = Some(mps);
+        Ok(self)
+    }
+
+    fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+
+    // Apply to the Port Information of a PCIe port
+    fn apply(&self, info: &mut [u8]) {
+        if let Some(mps) = self.mps {
+            // 128 bytes, doubling with each value
+            info[PCIEMPS] = (mps.trailing_zeros() - 7) as u8;
+        }
+        if let Some(gen) = self.speed {
+            // the speeds supported, up to the current one
+            info[PCIESLSV] |= (1 << gen) - 1;
+            info[PCIECLS] = gen;
+        }
+        if let Some(width) = self.width {
+            info[PCIEMLW] = info[PCIEMLW].max(width);
+            info[PCIENLW] = width;
+        }
+    }
+}
+
+/// Apply `links`, indexed by port, to the response `resp` to `req`, if it
+/// is a successful read of the Port Information of a PCIe port. Both
+/// messages follow the message type byte.
+pub fn apply(links: &[PcieLink], req: &[u8], resp: &mut [u8], ic: bool) {
+    let nmimt = req.first().map(|h| (h >> NMIMT_SHIFT) & NMIMT_MASK);
+    if nmimt != Some(NMIMT_MI_COMMAND)
+        || req.get(OPCODE_OFFSET) != Some(&MI_OPCODE_READ_DATA_STRUCTURE)
+        || req.get(DTYP_OFFSET) != Some(&DTYP_PORT_INFO)
+    {
+        return;
+    }
+    let Some(link) = req
+        .get(PORT_ID_OFFSET)
+        .and_then(|p| links.get(*p as usize))
+        .filter(|l| l.is_set())
+    else {
+        return;
+    };
+    if resp.get(STATUS_OFFSET) != Some(&0) {
+        return;
+    }
+    let Some(info) = resp.get_mut(DATA_OFFSET..DATA_OFFSET + PORT_INFO_LEN)
+    else {
+        return;
+    };
+    if info[0] != PRTTYP_PCIE {
+        return;
+    }
+    link.apply(info);
+    nvmefault::update_mic(resp, ic);
+}
+
+/// A response channel applying the configured links to Port Information
+pub struct Linked<'l, 'r, R> {
+    pub inner: R,
+    pub links: &'l [PcieLink],
+    pub req: &'r [u8],
+}
+
+impl<R: mctp::AsyncRespChannel> mctp::AsyncRespChannel for Linked<'_, '_, R> {
+    type ReqChannel<'a>
+        = R::ReqChannel<'a>
+    where
+        Self: 'a;
+
+    async fn send_vectored(
+        &mut self,
+        ic: mctp::MsgIC,
+        bufs: &[&[u8]],
+    ) -> mctp::Result<()> {
+        let mut msg = bufs.concat();
+        apply(self.links, self.req, &mut msg, ic.0);
+        self.inner.send_vectored(ic, &[&msg]).await
+    }
+
+    fn remote_eid(&self) -> mctp::Eid {
+        self.inner.remote_eid()
+    }
+
+    fn req_channel(&self) -> mctp::Result<Self::ReqChannel<'_>> {
+        self.inner.req_channel()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read NVMe-MI Data Structure, for the Port Information of `port`
+    fn read_port_info(port: u8) -> Vec<u8> {
+        let mut req = vec![0x08, 0, 0, MI_OPCODE_READ_DATA_STRUCTURE];
+        req.resize(15 + 4, 0);
+        req[PORT_ID_OFFSET] = port;
+        req[DTYP_OFFSET] = DTYP_PORT_INFO;
+        nvmefault::update_mic(&mut req, true);
+        req
+    }
+
+    // A successful response with the Port Information of a PCIe port with
+    // the default link: 128 byte MPS, Gen1 x1
+    fn port_info_response() -> Vec<u8> {
+        let mut resp = vec![0x88, 0, 0, 0, PORT_INFO_LEN as u8, 0, 0];
+        let mut info = [0u8; PORT_INFO_LEN];
+        info[0] = PRTTYP_PCIE;
+        info[PCIESLSV] = 0b1;
+        info[PCIECLS] = 1;
+        info[PCIEMLW] = 1;
+        info[PCIENLW] = 1;
+        resp.extend_from_slice(&info);
+        resp.resize(resp.len() + 4, 0);
+        nvmefault::update_mic(&mut resp, true);
+        resp
+    }
+
+    // Gen4 x4, with a 256 byte MPS, is encoded in the PCIe port specific
+    // data, with a valid MIC
+    #[test]
+    fn port_info_encoding() {
+        let link = PcieLink::default()
+            .speed("gen4")
+            .and_then(|l| l.width(4))
+            .and_then(|l| l.mps(256))
+            .unwrap();
+        let links = [PcieLink::default(), link];
+
+        let mut resp = port_info_response();
+        apply(&links, &read_port_info(1), &mut resp, true);
+        let info = &resp[DATA_OFFSET..DATA_OFFSET + PORT_INFO_LEN];
+        assert_eq!(info[0], PRTTYP_PCIE);
+        // MPS, supported speeds, current speed, max and negotiated width
+        assert_eq!(info[PCIEMPS..=PCIENLW], [1, 0b1111, 4, 4, 4]);
+
+        let mut check = resp.clone();
+        nvmefault::update_mic(&mut check, true);
+        assert_eq!(resp, check, "bad MIC");
+
+        // a port with the default link, and a port with no link configured
+        for port in [0, 2] {
+            let mut resp = port_info_response();
+            apply(&links, &read_port_info(port), &mut resp, true);
+            assert_eq!(resp, port_info_response());
+        }
+    }
+
+    // Values that can't be reported in Port Information are refused
+    #[test]
+    fn unsupported_values() {
+        let link = PcieLink::default();
+        assert!(link.speed("gen7").is_err());
+        assert!(link.speed("4").is_err());
+        assert!(link.width(3).is_err());
+        assert!(link.width(0).is_err());
+        assert!(link.mps(64).is_err());
+        assert!(link.mps(300).is_err());
+        assert_eq!(link.mps(4096).map(|l| l.mps), Ok(Some(4096)));
+    }
+}
@@ -0,0 +1,205 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Admin command passthrough to a local NVMe device, with --nvme-passthrough.
+ *
+ * Identify, and Get Log Page for the SMART / Health Information log, are
+ * forwarded to the device with the Linux admin passthrough ioctl, and the
+ * completion returned as the NVMe-MI admin response. Only these read-only
+ * commands are forwarded; all others are handled by the emulated subsystem,
+ * whatever the configuration.
+ *
+ * The controller ID in the request is not translated: commands go to the
+ * controller behind the device node. The ioctl is run on a blocking thread,
+ * so a slow device doesn't stall the other listeners.
+ */
+
+// only used by the NVMe-MI device, with the nvme-mi feature
+#![cfg_attr(not(feature = "nvme-mi"), allow(dead_code))]
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+
+use crate::nvmefault::crc32c;
+
+/* NVMe-MI admin request layout, following the message type byte */
+const NMIMT_SHIFT: u8 = 3;
+const NMIMT_MASK: u8 = 0x0f;
+const NMIMT_ADMIN_COMMAND: u8 = 2;
+const ROR: u8 = 0x80;
+const OPCODE: usize = 3;
+const NSID: usize = 7;
+const DOFST: usize = 27;
+const DLEN: usize = 31;
+const CDW10: usize = 43;
+const CDW11: usize = 47;
+const ADMIN_REQ_LEN: usize = 67;
+
+/* message integrity check */
+const MIC_LEN: usize = 4;
+const MSG_TYPE_NVME_IC: u8 = 0x84;
+
+/* NVMe-MI response status */
+const MI_STATUS_SUCCESS: u8 = 0x00;
+const MI_STATUS_INTERNAL_ERROR: u8 = 0x02;
+const MI_STATUS_INVALID_PARAMETER: u8 = 0x04;
+
+/* forwarded admin commands */
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const LOG_SMART_HEALTH: u8 = 0x02;
+const IDENTIFY_LEN: usize = 4096;
+const MAX_DATA_LEN: usize = 4096;
+
+/* struct nvme_passthru_cmd, from linux/nvme_ioctl.h. Most fields are only
+ * read by the kernel. */
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct PassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/* _IOWR('N', 0x41, struct nvme_passthru_cmd) */
+const NVME_IOCTL_ADMIN_CMD: u64 = 0xc048_4e41;
+
+fn dword(req: &[u8], offset: usize) -> u32 {
+    // unwrap: offsets are within ADMIN_REQ_LEN, checked by the caller
+    u32::from_le_bytes(req[offset..offset + 4].try_into().unwrap())
+}
+
+/// A local NVMe device for admin command passthrough
+pub struct Passthrough {
+    dev: Arc<File>,
+}
+
+impl Passthrough {
+    pub fn open(path: &str) -> Result<Self> {
+        let dev = File::open(path)
+            .with_context(|| format!("Can't open NVMe device {path}"))?;
+        info!("NVMe-MI: passing Identify and SMART log requests to {path}");
+        Ok(Self { dev: Arc::new(dev) })
+    }
+
+    /// Build the response to `req`, a NVMe-MI message following the message
+    /// type byte, if it is an admin command to forward
+    pub async fn handle(&self, req: &[u8], ic: bool) -> Option<Vec<u8>> {
+        let body = if ic {
+            &req[..req.len().checked_sub(MIC_LEN)?]
+        } else {
+            req
+        };
+        if body.len() < ADMIN_REQ_LEN
+            || (body[0] >> NMIMT_SHIFT) & NMIMT_MASK != NMIMT_ADMIN_COMMAND
+        {
+            return None;
+        }
+
+        let opcode = body[OPCODE];
+        let cdw10 = dword(body, CDW10);
+        let cdw11 = dword(body, CDW11);
+        let len = match opcode {
+            ADMIN_IDENTIFY => IDENTIFY_LEN,
+            ADMIN_GET_LOG_PAGE if cdw10 as u8 == LOG_SMART_HEALTH => {
+                let numd = ((cdw11 & 0xffff) << 16) | (cdw10 >> 16);
+                (numd as usize + 1) * 4
+            }
+            _ => return None,
+        };
+
+        let mut resp = vec![body[0] | ROR, 0, 0];
+        let dofst = dword(body, DOFST) as usize;
+        let dlen = dword(body, DLEN) as usize;
+        let valid = len <= MAX_DATA_LEN
+            && dofst & 3 == 0
+            && dofst.checked_add(dlen).is_some_and(|end| end <= len);
+        if !valid {
+            debug!("NVMe-MI: invalid passthrough data range {dofst}+{dlen}");
+            resp.extend_from_slice(&[MI_STATUS_INVALID_PARAMETER, 0, 0, 0]);
+            return Some(finish(resp, ic));
+        }
+
+        let cmd = PassthruCmd {
+            opcode,
+            nsid: dword(body, NSID),
+            cdw10,
+            cdw11,
+            ..Default::default()
+        };
+        let dev = self.dev.clone();
+        let r = smol::unblock(move || admin_cmd(&dev, cmd, len)).await;
+
+        match r {
+            Ok((status, result, data)) => {
+                debug!(
+                    "NVMe-MI: passthrough opcode {opcode:#04x}, status \
+                     {status:#06x}"
+                );
+                resp.extend_from_slice(&[MI_STATUS_SUCCESS, 0, 0, 0]);
+                resp.extend_from_slice(&result.to_le_bytes());
+                resp.extend_from_slice(&0u32.to_le_bytes());
+                resp.extend_from_slice(&((status as u32) << 17).to_le_bytes());
+                if status == 0 {
+                    resp.extend_from_slice(&data[dofst..dofst + dlen]);
+                }
+            }
+            Err(e) => {
+                warn!("NVMe-MI: passthrough opcode {opcode:#04x} failed: {e}");
+                resp.extend_from_slice(&[MI_STATUS_INTERNAL_ERROR, 0, 0, 0]);
+            }
+        }
+        Some(finish(resp, ic))
+    }
+}
+
+// Append the message integrity check, if required
+fn finish(mut resp: Vec<u8>, ic: bool) -> Vec<u8> {
+    if ic {
+        let mut data = vec![MSG_TYPE_NVME_IC];
+        data.extend_from_slice(&resp);
+        resp.extend_from_slice(&crc32c(&data).to_le_bytes());
+    }
+    resp
+}
+
+// Issue an admin command, returning the NVMe status, the completion result
+// and the data
+fn admin_cmd(
+    dev: &File,
+    mut cmd: PassthruCmd,
+    len: usize,
+) -> std::io::Result<(u16, u32, Vec<u8>)> {
+    let mut data = vec![0u8; len];
+    cmd.addr = data.as_mut_ptr() as u64;
+    cmd.data_len = len as u32;
+
+    // SAFETY: cmd is a valid nvme_passthru_cmd, and addr points to data_len
+    // bytes, alive for the duration of the call
+    let rc = unsafe {
+        libc::ioctl(dev.as_raw_fd(), NVME_IOCTL_ADMIN_CMD as _, &mut cmd)
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // a positive return is the NVMe status field of the completion
+    Ok((rc as u16, cmd.result, data))
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Tracing of NVMe-MI exchanges. Each request is counted by command type and
+ * opcode, for the stats dump. With --trace-nvme, each exchange is also logged
+ * at debug level, with the decoded command and the response status and size.
+ * Only the message headers are decoded.
+ */
+
+// only used by the NVMe-MI device, with the nvme-mi feature
+#![cfg_attr(not(feature = "nvme-mi"), allow(dead_code))]
+
+use log::debug;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/* NVMe-MI message header fields, following the message type byte */
+const NMIMT_SHIFT: u8 = 3;
+const NMIMT_MASK: u8 = 0x0f;
+const OPCODE_OFFSET: usize = 3;
+const STATUS_OFFSET: usize = 3;
+
+/// NVMe-MI message type, from the NMIMT field
+fn message_type(nmimt: u8) -> &'static str {
+    match nmimt {
+        0 => "control",
+        1 => "MI",
+        2 => "admin",
+        4 => "PCIe",
+        _ => "unknown",
+    }
+}
+
+fn opcode_name(nmimt: u8, op: u8) -> &'static str {
+    match (nmimt, op) {
+        (1, 0x00) => "Read NVMe-MI Data Structure",
+        (1, 0x01) => "NVM Subsystem Health Status Poll",
+        (1, 0x02) => "Controller Health Status Poll",
+        (1, 0x03) => "Configuration Set",
+        (1, 0x04) => "Configuration Get",
+        (1, 0x05) => "VPD Read",
+        (1, 0x06) => "VPD Write",
+        (1, 0x07) => "Reset",
+        (1, 0x08) => "SES Receive",
+        (1, 0x09) => "SES Send",
+        (1, 0x0a) => "Management Endpoint Buffer Read",
+        (1, 0x0b) => "Management Endpoint Buffer Write",
+        (1, 0x0c) => "Shutdown",
+        (2, 0x02) => "Get Log Page",
+        (2, 0x06) => "Identify",
+        (2, 0x09) => "Set Features",
+        (2, 0x0a) => "Get Features",
+        (2, 0x0d) => "Namespace Management",
+        (2, 0x10) => "Firmware Commit",
+        (2, 0x11) => "Firmware Image Download",
+        (2, 0x15) => "Namespace Attachment",
+        (2, 0x80) => "Format NVM",
+        (2, 0x84) => "Sanitize",
+        _ => "unknown",
+    }
+}
+
+/// NVMe-MI request counters, and the trace setting
+#[derive(Default)]
+pub struct NvmeTrace {
+    enabled: bool,
+    // requests by (NMIMT, opcode)
+    requests: Mutex<BTreeMap<(u8, u8), u64>>,
+}
+
+impl NvmeTrace {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// Count a request, a NVMe-MI message following the message type byte,
+    /// logging it if tracing. Returns the decoded command, for the response.
+    pub fn request(&self, eid: mctp::Eid, req: &[u8]) -> Option<(u8, u8)> {
+        let (Some(hdr), Some(&op)) = (req.first(), req.get(OPCODE_OFFSET))
+        else {
+            if self.enabled {
+                debug!("NVMe-MI trace: short request from {eid}: {req:x?}");
+            }
+            return None;
+        };
+        let nmimt = (hdr >> NMIMT_SHIFT) & NMIMT_MASK;
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((nmimt, op))
+            .or_default() += 1;
+
+        if self.enabled {
+            debug!(
+                "NVMe-MI trace: {} request {op:#04x} ({}) from {eid}, {} bytes",
+                message_type(nmimt),
+                opcode_name(nmimt, op),
+                req.len()
+            );
+        }
+        Some((nmimt, op))
+    }
+
+    // Log a response to the command `cmd`
+    fn response(&self, eid: mctp::Eid, cmd: Option<(u8, u8)>, resp: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+        let status = resp.get(STATUS_OFFSET).copied().unwrap_or_default();
+        let (nmimt, op) = cmd.unwrap_or_default();
+        debug!(
+            "NVMe-MI trace: {} response {op:#04x} ({}) to {eid}, status \
+             {status:#04x}, {} bytes",
+            message_type(nmimt),
+            opcode_name(nmimt, op),
+            resp.len()
+        );
+    }
+}
+
+impl std::fmt::Display for NvmeTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let requests = self.requests.lock().unwrap();
+        if requests.is_empty() {
+            return write!(f, "no requests");
+        }
+        let mut sep = "";
+        for ((nmimt, op), count) in requests.iter() {
+            write!(f, "{sep}{} {op:#04x}: {count}", message_type(*nmimt))?;
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
+
+/// A response channel logging the response, if tracing
+pub struct Traced<'t, R> {
+    pub inner: R,
+    pub trace: &'t NvmeTrace,
+    /// the decoded request, from `NvmeTrace::request`
+    pub cmd: Option<(u8, u8)>,
+}
+
+impl<R: mctp::AsyncRespChannel> mctp::AsyncRespChannel for Traced<'_, R> {
+    type ReqChannel<'a>
+        = R::ReqChannel<'a>
+    where
+        Self: 'a;
+
+    async fn send_vectored(
+        &mut self,
+        ic: mctp::MsgIC,
+        bufs: &[&[u8]],
+    ) -> mctp::Result<()> {
+        if self.trace.enabled {
+            let resp = bufs.concat();
+            self.trace
+                .response(self.inner.remote_eid(), self.cmd, &resp);
+        }
+        self.inner.send_vectored(ic, bufs).await
+    }
+
+    fn remote_eid(&self) -> mctp::Eid {
+        self.inner.remote_eid()
+    }
+
+    fn req_channel(&self) -> mctp::Result<Self::ReqChannel<'_>> {
+        self.inner.req_channel()
+    }
+}
@@ -0,0 +1,405 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Packet capture, in pcapng format. Each port is recorded as a separate
+ * interface, with packets in the LINKTYPE_MCTP format: the MCTP packet,
+ * starting at the MCTP header.
+ *
+ * We can also read captures back, in either pcapng or the classic pcap
+ * format.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+const LINKTYPE_MCTP: u16 = 291;
+
+const BLOCK_SHB: u32 = 0x0a0d0d0a;
+const BLOCK_IDB: u32 = 0x00000001;
+const BLOCK_SPB: u32 = 0x00000003;
+const BLOCK_EPB: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+const PCAP_MAGIC_USEC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b23c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    // direction bits of epb_flags
+    fn flags(&self) -> u32 {
+        match self {
+            Self::In => 0x1,
+            Self::Out => 0x2,
+        }
+    }
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    last_flush: Instant,
+}
+
+// Build a pcapng block, with the type and length fields around `body`
+fn block(typ: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut b = Vec::with_capacity(len as usize);
+    b.extend_from_slice(&typ.to_le_bytes());
+    b.extend_from_slice(&len.to_le_bytes());
+    b.extend_from_slice(body);
+    b.extend_from_slice(&len.to_le_bytes());
+    b
+}
+
+impl Writer {
+    fn create(path: &str, ports: usize) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Can't create capture file {path}"))?;
+        let mut w = Self {
+            file: BufWriter::new(file),
+            last_flush: Instant::now(),
+        };
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // section length: unspecified
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        w.file.write_all(&block(BLOCK_SHB, &shb))?;
+
+        // one interface per port, with default microsecond timestamps
+        for _ in 0..ports {
+            let mut idb = Vec::new();
+            idb.extend_from_slice(&LINKTYPE_MCTP.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            // snaplen: unlimited
+            idb.extend_from_slice(&0u32.to_le_bytes());
+            w.file.write_all(&block(BLOCK_IDB, &idb))?;
+        }
+
+        w.file.flush()?;
+
+        Ok(w)
+    }
+
+    fn packet(
+        &mut self,
+        port: u8,
+        dir: Direction,
+        pkt: &[u8],
+    ) -> std::io::Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(pkt.len() + 40);
+        epb.extend_from_slice(&(port as u32).to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(pkt.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(pkt.len() as u32).to_le_bytes());
+        epb.extend_from_slice(pkt);
+        epb.resize(epb.len().next_multiple_of(4), 0);
+
+        epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+        epb.extend_from_slice(&4u16.to_le_bytes());
+        epb.extend_from_slice(&dir.flags().to_le_bytes());
+        epb.extend_from_slice(&OPT_ENDOFOPT.to_le_bytes());
+        epb.extend_from_slice(&0u16.to_le_bytes());
+
+        self.file.write_all(&block(BLOCK_EPB, &epb))?;
+
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.file.flush()?;
+            self.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+}
+
+/// Packet capture for all ports. Capture is disabled if no file is
+/// configured, or after any write error.
+pub struct Capture {
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Capture {
+    /// Start a capture to `path` for `ports` ports, or a disabled capture
+    /// if `path` is None.
+    pub fn new(path: Option<&str>, ports: usize) -> Result<Self> {
+        let writer = match path {
+            Some(p) => {
+                let w = Writer::create(p, ports)?;
+                info!("Capturing packets to {p}");
+                Some(w)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Record a packet on `port`
+    pub fn packet(&self, port: u8, dir: Direction, pkt: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(w) = writer.as_mut() else {
+            return;
+        };
+
+        if let Err(e) = w.packet(port, dir, pkt) {
+            warn!("Capture write failed ({e}), disabling capture");
+            *writer = None;
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Some(w) = self.writer.get_mut().unwrap().as_mut() {
+            let _ = w.file.flush();
+        }
+    }
+}
+
+/// A packet read from a capture file
+pub struct Record {
+    /// time since the epoch
+    pub ts: Duration,
+    /// direction, if recorded in the capture
+    pub dir: Option<Direction>,
+    pub data: Vec<u8>,
+}
+
+/// Packets read from a capture file, plus the number of records that could
+/// not be decoded as MCTP packets.
+pub struct CaptureFile {
+    pub records: Vec<Record>,
+    pub decode_errors: usize,
+}
+
+// Little- or big-endian field access for the capture formats
+#[derive(Clone, Copy)]
+struct Endian(bool);
+
+impl Endian {
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.0 {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.0 {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+}
+
+// Parse a pcapng if_tsresol value to the duration of one timestamp unit,
+// in nanoseconds
+fn tsresol_ns(v: u8) -> Option<u64> {
+    if v & 0x80 != 0 {
+        // power-of-two resolutions are not supported
+        return None;
+    }
+    10u64.checked_pow(9u32.checked_sub(v as u32)?)
+}
+
+fn read_pcapng(buf: &[u8]) -> Result<CaptureFile> {
+    let mut records = Vec::new();
+    let mut decode_errors = 0;
+    let mut e = Endian(false);
+    // linktype and timestamp unit (ns) for each interface in the section
+    let mut ifaces: Vec<(u16, Option<u64>)> = Vec::new();
+
+    let mut pos = 0;
+    while pos + 12 <= buf.len() {
+        let hdr = &buf[pos..];
+        let raw_typ = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+        if raw_typ == BLOCK_SHB {
+            e = Endian(Endian(false).u32(&hdr[8..]) != BYTE_ORDER_MAGIC);
+            ifaces.clear();
+        }
+        let typ = e.u32(hdr);
+        let len = e.u32(&hdr[4..]) as usize;
+        if len < 12 || len & 3 != 0 || pos + len > buf.len() {
+            bail!("Invalid block length {len} at offset {pos}");
+        }
+        let body = &buf[pos + 8..pos + len - 4];
+        pos += len;
+
+        match typ {
+            BLOCK_IDB if body.len() >= 8 => {
+                let linktype = e.u16(body);
+                let mut unit = Some(1000);
+                let mut opts = &body[8..];
+                while opts.len() >= 4 {
+                    let code = e.u16(opts);
+                    let olen = e.u16(&opts[2..]) as usize;
+                    let padded = olen.next_multiple_of(4);
+                    if code == OPT_ENDOFOPT || opts.len() < 4 + padded {
+                        break;
+                    }
+                    if code == OPT_IF_TSRESOL && olen >= 1 {
+                        unit = tsresol_ns(opts[4]);
+                    }
+                    opts = &opts[4 + padded..];
+                }
+                ifaces.push((linktype, unit));
+            }
+            BLOCK_EPB if body.len() >= 20 => {
+                let iface = e.u32(body) as usize;
+                let ts = ((e.u32(&body[4..]) as u64) << 32)
+                    | e.u32(&body[8..]) as u64;
+                let caplen = e.u32(&body[12..]) as usize;
+                let padded = caplen.next_multiple_of(4);
+                let Some(data) = body.get(20..20 + caplen) else {
+                    decode_errors += 1;
+                    continue;
+                };
+
+                let mut dir = None;
+                let mut opts = body.get(20 + padded..).unwrap_or_default();
+                while opts.len() >= 4 {
+                    let code = e.u16(opts);
+                    let olen = e.u16(&opts[2..]) as usize;
+                    if code == OPT_ENDOFOPT || opts.len() < 4 + olen {
+                        break;
+                    }
+                    if code == OPT_EPB_FLAGS && olen >= 4 {
+                        dir = match e.u32(&opts[4..]) & 0x3 {
+                            1 => Some(Direction::In),
+                            2 => Some(Direction::Out),
+                            _ => None,
+                        };
+                    }
+                    opts = opts
+                        .get(4 + olen.next_multiple_of(4)..)
+                        .unwrap_or_default();
+                }
+
+                let Some(&(linktype, Some(unit))) = ifaces.get(iface) else {
+                    decode_errors += 1;
+                    continue;
+                };
+                if linktype != LINKTYPE_MCTP || data.len() < 4 {
+                    decode_errors += 1;
+                    continue;
+                }
+
+                records.push(Record {
+                    ts: Duration::from_nanos(ts.saturating_mul(unit)),
+                    dir,
+                    data: data.to_vec(),
+                });
+            }
+            BLOCK_SPB => {
+                // no interface or timestamp information
+                decode_errors += 1;
+            }
+            _ => (),
+        }
+    }
+
+    Ok(CaptureFile {
+        records,
+        decode_errors,
+    })
+}
+
+fn read_pcap(buf: &[u8]) -> Result<CaptureFile> {
+    if buf.len() < 24 {
+        bail!("Short pcap header");
+    }
+    let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let (e, nsec) = match magic {
+        PCAP_MAGIC_USEC => (Endian(false), false),
+        PCAP_MAGIC_NSEC => (Endian(false), true),
+        m if m.swap_bytes() == PCAP_MAGIC_USEC => (Endian(true), false),
+        m if m.swap_bytes() == PCAP_MAGIC_NSEC => (Endian(true), true),
+        _ => bail!("Unknown capture file format"),
+    };
+    let linktype = e.u32(&buf[20..]) & 0xffff;
+    if linktype != LINKTYPE_MCTP as u32 {
+        bail!("Capture is not MCTP (link type {linktype})");
+    }
+
+    let mut records = Vec::new();
+    let mut decode_errors = 0;
+    let mut pos = 24;
+    while pos + 16 <= buf.len() {
+        let hdr = &buf[pos..];
+        let secs = e.u32(hdr) as u64;
+        let frac = e.u32(&hdr[4..]);
+        let caplen = e.u32(&hdr[8..]) as usize;
+        pos += 16;
+
+        let Some(data) = buf.get(pos..pos + caplen) else {
+            bail!("Truncated record at offset {pos}");
+        };
+        pos += caplen;
+
+        if data.len() < 4 {
+            decode_errors += 1;
+            continue;
+        }
+
+        let frac = if nsec {
+            Duration::from_nanos(frac as u64)
+        } else {
+            Duration::from_micros(frac as u64)
+        };
+        records.push(Record {
+            ts: Duration::from_secs(secs) + frac,
+            dir: None,
+            data: data.to_vec(),
+        });
+    }
+
+    Ok(CaptureFile {
+        records,
+        decode_errors,
+    })
+}
+
+/// Read all MCTP packets from a pcap or pcapng capture file
+pub fn read(path: &str) -> Result<CaptureFile> {
+    let buf = std::fs::read(path)
+        .with_context(|| format!("Can't read capture file {path}"))?;
+
+    if buf.len() < 4 {
+        bail!("Capture file {path} is empty");
+    }
+
+    let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if magic == BLOCK_SHB {
+        read_pcapng(&buf)
+    } else {
+        read_pcap(&buf)
+    }
+    .with_context(|| format!("Can't parse capture file {path}"))
+}
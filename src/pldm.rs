@@ -1,40 +1,122 @@
 // SPDX-License-Identifier: GPL-3.0
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use futures::{select, FutureExt};
 use log::{debug, info, warn};
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use mctp::Eid;
 use mctp_estack::{control::ControlEvent, router::Router};
 use pldm::{control::requester::negotiate_transfer_parameters, PldmError};
 use pldm_file::{
     client::{df_close, df_open, df_read_with},
-    proto::{DfCloseAttributes, DfOpenAttributes, FileIdentifier},
+    proto::{
+        DfCloseAttributes, DfOpenAttributes, FileDescriptor, FileIdentifier,
+    },
 };
 use pldm_platform::{proto::PdrRecord, requester as platrq};
 
-const MULTIPART_PART_SIZE: u16 = 512;
+use crate::events::{Event, EventKind};
+use crate::filewriter::FileWriter;
+use crate::pldmbase::Terminus;
+use crate::pldmupload;
+use crate::{PldmOptions, PldmStats};
 
-async fn pldm_control(chan: &mut impl mctp::AsyncReqChannel) -> Result<()> {
+/* the DSP0240 minimum part size, used if the peer won't negotiate */
+const MIN_PART_SIZE: u16 = 256;
+/* MultipartReceive response fields preceding the part data */
+const PART_OVERHEAD: usize = 18;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/* time for the bus owner to assign our TID, after our EID */
+const TID_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How file data is transferred, following NegotiateTransferParameters
+#[derive(Clone, Copy, Debug)]
+enum TransferMode {
+    /// the part size agreed with the peer
+    Negotiated(u16),
+    /// the peer rejected negotiation, so parts of the minimum size are
+    /// requested
+    Fallback,
+}
+
+impl TransferMode {
+    fn part_size(self) -> u16 {
+        match self {
+            TransferMode::Negotiated(size) => size,
+            TransferMode::Fallback => MIN_PART_SIZE,
+        }
+    }
+}
+
+impl std::fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferMode::Negotiated(size) => {
+                write!(f, "negotiated {size} byte parts")
+            }
+            TransferMode::Fallback => {
+                write!(f, "unnegotiated {MIN_PART_SIZE} byte parts")
+            }
+        }
+    }
+}
+
+async fn pldm_control(
+    chan: &mut impl mctp::AsyncReqChannel,
+    part_size: u16,
+) -> Result<TransferMode> {
     let req_types = [pldm_file::PLDM_TYPE_FILE_TRANSFER];
     let mut buf = [0u8];
 
-    let (size, neg_types) = negotiate_transfer_parameters(
-        chan,
-        &req_types,
-        &mut buf,
-        MULTIPART_PART_SIZE,
-    )
-    .await
-    .context("Negotiate transfer parameters failed")?;
+    let r =
+        negotiate_transfer_parameters(chan, &req_types, &mut buf, part_size)
+            .await
+            .context("Negotiate transfer parameters failed");
+
+    let mode = match r {
+        Ok((size, neg_types)) => {
+            debug!("Negotiated multipart size {size} for types {neg_types:?}");
+            TransferMode::Negotiated(size)
+        }
+        // no response is retried, as for the other requests
+        Err(e) if retryable(&e) => return Err(e),
+        // the command is unsupported, or our parameters were rejected
+        Err(e) => {
+            warn!("PLDM: {e:#}");
+            TransferMode::Fallback
+        }
+    };
+    info!("PLDM: file transfers use {mode}");
+    Ok(mode)
+}
 
-    debug!("Negotiated multipart size {size} for types {neg_types:?}");
+/// A file from the peer's File Descriptor PDRs
+struct PdrFile {
+    id: u16,
+    name: String,
+    size: usize,
+}
 
-    Ok(())
+impl PdrFile {
+    // Whether the file passes the --pldm-file-name and --pldm-file-id filters
+    fn selected(&self, opts: &PldmOptions) -> bool {
+        opts.file_name
+            .as_ref()
+            .is_none_or(|re| re.is_match(&self.name))
+            && opts.file_id.is_none_or(|id| id == self.id)
+    }
 }
 
 async fn pldm_pdr(
     chan: &mut impl mctp::AsyncReqChannel,
-) -> Result<(FileIdentifier, usize)> {
+) -> Result<Vec<PdrFile>> {
     // PDR Repository Info
     let pdr_info = platrq::get_pdr_repository_info(chan)
         .await
@@ -42,111 +124,1314 @@ async fn pldm_pdr(
 
     debug!("PDR Repository Info: {pdr_info:?}");
 
-    // File Descriptor PDR
+    // File Descriptor PDRs
     let mut pdrs = platrq::get_pdr(chan);
 
-    let file = loop {
-        match pdrs.next().await {
-            None => break None,
-            Some(Ok(PdrRecord::FileDescriptor(file))) => break Some(file),
-            Some(Ok(_)) => (),
-            Some(Err(e)) => {
+    let mut files = Vec::new();
+    while let Some(pdr) = pdrs.next().await {
+        match pdr {
+            Ok(PdrRecord::FileDescriptor(file)) => {
+                debug!("PDR: {file:?}");
+                files.push(PdrFile {
+                    id: file.file_identifier,
+                    name: String::from_utf8_lossy(&file.file_name).into(),
+                    size: file.file_max_size as usize,
+                });
+            }
+            Ok(_) => (),
+            Err(e) => {
                 debug!("Error reading PDR, skipping: {e}");
             }
         }
-    };
+    }
+
+    if files.is_empty() {
+        bail!("No File Descriptor PDR record found");
+    }
+
+    info!("PLDM: {} files:", files.len());
+    for f in &files {
+        info!("  {}: \"{}\", max size {}", f.id, f.name, f.size);
+    }
+
+    Ok(files)
+}
+
+// Create the output file, keeping any existing file unless overwriting
+fn create_output(path: &str, overwrite: bool) -> Result<File> {
+    let mut oo = OpenOptions::new();
+    oo.write(true);
+    if overwrite {
+        oo.create(true).truncate(true);
+    } else {
+        oo.create_new(true);
+    }
+    oo.open(path).with_context(|| {
+        if overwrite {
+            format!("Can't create {path}")
+        } else {
+            format!("Can't create {path}, use --pldm-overwrite to replace it")
+        }
+    })
+}
 
-    let file = file.context("No File Descriptor PDR record found")?;
+/// A file transfer, kept across session retries so that a retry resumes
+/// after the last complete chunk
+struct Transfer {
+    // bytes read, hashed and written so far
+    offset: usize,
+    hash: Sha256,
+    // written on a blocking thread, as the read callback can't wait
+    out: Option<FileWriter>,
+    // the output file, once created
+    path: Option<String>,
+    // time spent reading, for the throughput
+    elapsed: Duration,
+}
 
-    debug!("PDR: {file:?}");
+impl Transfer {
+    fn new() -> Self {
+        Self {
+            offset: 0,
+            hash: Sha256::new(),
+            out: None,
+            path: None,
+            elapsed: Duration::ZERO,
+        }
+    }
 
-    Ok((
-        FileIdentifier(file.file_identifier),
-        file.file_max_size as usize,
-    ))
+    // Abandon the transfer, removing its partial output file
+    async fn discard(mut self) {
+        if let Some(w) = self.out.take() {
+            let _ = w.finish().await;
+        }
+        let Some(path) = self.path else {
+            return;
+        };
+        match std::fs::remove_file(&path) {
+            Ok(()) => info!("PLDM: removed partial output {path}"),
+            Err(e) => warn!("PLDM: can't remove partial output {path}: {e}"),
+        }
+    }
 }
 
+// Transfer `file` in parts of `part_size`, writing it to `output` if set.
+// The file is read in `--pldm-read-chunk` sized DfReads, continuing from
+// the offset of its entry in `transfers`, which is removed once complete;
+// on failure, or if the transfer is cancelled, the entry holds the
+// progress for a retry. Returns the size and hash of the data. `open` holds
+// the file descriptor until it is closed, so a cancelled transfer can close
+// it.
 async fn pldm_file(
     chan: &mut impl mctp::AsyncReqChannel,
-    file: FileIdentifier,
-    size: usize,
-) -> Result<()> {
+    file: &PdrFile,
+    opts: &PldmOptions,
+    part_size: u16,
+    output: Option<&str>,
+    transfers: &mut HashMap<u16, Transfer>,
+    open: &mut Option<FileDescriptor>,
+) -> Result<(usize, String)> {
+    let t = transfers.entry(file.id).or_insert_with(Transfer::new);
+    if let (0, None, Some(path)) = (t.offset, &t.out, output) {
+        t.out = Some(FileWriter::new(create_output(path, opts.overwrite)?));
+        t.path = Some(path.to_string());
+    }
+
     let attrs = DfOpenAttributes::empty();
-    let fd = df_open(chan, file, attrs).await.context("DfOpen failed")?;
+    let fd = df_open(chan, FileIdentifier(file.id), attrs)
+        .await
+        .context("DfOpen failed")?;
 
     debug!("Open: {fd:?}");
+    *open = Some(fd);
 
-    let mut hash = Sha256::new();
-    let req_len = size;
-    let mut cur_len = 0usize;
-
-    debug!("Reading...");
-    let mut part = [0u8; { MULTIPART_PART_SIZE as usize + 18 }];
-    let res = df_read_with(chan, fd, 0, req_len, &mut part, |part| {
-        cur_len += part.len();
-        debug!("  {} bytes, {cur_len}/{req_len}", part.len());
-        if cur_len > req_len {
-            warn!("  data overflow!");
-            Err(PldmError::NoSpace)
-        } else {
-            hash.update(part);
+    let req_len = file.size;
+    let mut part = vec![0u8; part_size as usize + PART_OVERHEAD];
+    let mut res = Ok(());
+    let mut write_failed = false;
+
+    debug!("Reading from offset {}...", t.offset);
+    while t.offset < req_len {
+        let offset = t.offset;
+        let len = (req_len - offset).min(opts.read_chunk);
+        let mut chunk = Vec::with_capacity(len);
+        let start = Instant::now();
+        let r = df_read_with(chan, fd, offset, len, &mut part[..], |part| {
+            if chunk.len() + part.len() > len {
+                warn!("  data overflow!");
+                return Err(PldmError::NoSpace);
+            }
+            chunk.extend_from_slice(part);
+            debug!(
+                "  {} bytes, {}/{req_len}",
+                part.len(),
+                offset + chunk.len()
+            );
             Ok(())
+        })
+        .await;
+        t.elapsed += start.elapsed();
+
+        debug!("Read at {offset}: {r:?}");
+        if let Err(e) = r {
+            res = Err(e);
+            break;
         }
-    })
-    .await;
 
-    debug!("Read: {res:?}");
+        if let Some(w) = &t.out {
+            if !w.write(&chunk).await {
+                // the error is returned by finish(), after DfClose
+                write_failed = true;
+                break;
+            }
+        }
+        // only complete chunks are kept, so a retry rereads a failed one.
+        // Nothing is awaited between the write and this, so a cancelled
+        // transfer can't write a chunk without counting it.
+        t.hash.update(&chunk);
+        t.offset += chunk.len();
+        if chunk.len() < len {
+            // end of file, before the size declared by the PDR
+            break;
+        }
+    }
 
-    let hex = hex::encode(hash.finalize());
+    let attrs = DfCloseAttributes::empty();
+    let close = df_close(chan, fd, attrs).await;
+    *open = None;
 
-    info!("Transfer complete. {cur_len} bytes, sha256 {hex}");
+    debug!("Close: {close:?}");
 
-    let attrs = DfCloseAttributes::empty();
-    let res = df_close(chan, fd, attrs).await;
+    if res.is_err() && !write_failed {
+        // the data read so far is kept, for a retry to resume from
+        res.context("DfRead failed")?;
+    }
+
+    if let (Some(w), Some(path)) = (t.out.take(), output) {
+        w.finish()
+            .await
+            .with_context(|| format!("Writing {path} failed"))?;
+    }
 
-    debug!("Close: {res:?}");
+    let cur_len = t.offset;
+    let hex = hex::encode(std::mem::take(&mut t.hash).finalize());
+    let secs = t.elapsed.as_secs_f64();
+    transfers.remove(&file.id);
+    let rate = cur_len as f64 / 1024.0 / secs.max(f64::EPSILON);
 
-    Ok(())
+    info!(
+        "Transfer complete. {cur_len} bytes in {secs:.3}s, {rate:.1} KiB/s, \
+         sha256 {hex}"
+    );
+
+    if let Some(path) = output {
+        if cur_len < req_len {
+            warn!(
+                "Short transfer, {path} has {cur_len} of the {req_len} bytes \
+                 declared by the PDR"
+            );
+        } else {
+            info!("Wrote {cur_len} bytes to {path}");
+        }
+    }
+
+    Ok((cur_len, hex))
 }
 
-// Run one PLDM session: perform base setup, find our file in the PDR and
-// transfer it.
-async fn pldm_session(mut chan: impl mctp::AsyncReqChannel) -> Result<()> {
-    pldm_control(&mut chan)
-        .await
-        .context("PLDM control discovery failed")?;
+// Whether an error may pass on a retry: a timeout or transfer failure
+// talking to the peer, as when its responder is still starting up
+fn retryable(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        matches!(
+            c.downcast_ref::<mctp::Error>(),
+            Some(
+                mctp::Error::TimedOut
+                    | mctp::Error::RxFailure
+                    | mctp::Error::TxFailure
+            )
+        )
+    })
+}
 
-    let (file_desc, file_size) = pldm_pdr(&mut chan)
-        .await
-        .context("PLDM PDR query for file info failed")?;
+/// Progress of a session with one peer, kept across retries so that only
+/// the failed stage is run again
+#[derive(Default)]
+struct Session {
+    // set once transfer parameters have been negotiated, or refused
+    mode: Option<TransferMode>,
+    // the selected files, once the PDR has been read
+    files: Option<Vec<PdrFile>>,
+    // by file identifier: the size and hash, or the error for a failure
+    // that won't be retried
+    results: HashMap<u16, Result<(usize, String), String>>,
+    // the file open on the peer, during a transfer
+    open: Option<FileDescriptor>,
+    // by file identifier, transfers to resume after a retryable failure
+    // or an interruption
+    transfers: HashMap<u16, Transfer>,
+}
 
-    pldm_file(&mut chan, file_desc, file_size)
-        .await
-        .context("PLDM file transfer failed")?;
+impl Session {
+    // The progress kept when a session is restarted with the same peer:
+    // the completed files, and the transfers to resume
+    fn resume(self) -> Session {
+        Session {
+            results: self.results,
+            transfers: self.transfers,
+            ..Default::default()
+        }
+    }
+
+    // Abandon the transfers in progress, removing their partial output
+    async fn discard(self) {
+        for (_, t) in self.transfers {
+            t.discard().await;
+        }
+    }
+
+    fn summary(&self) {
+        let Some(files) = &self.files else {
+            return;
+        };
+        let mode = self.mode.map(|m| m.to_string()).unwrap_or_default();
+        info!("PLDM transfer summary, {mode}:");
+        for file in files {
+            match self.results.get(&file.id) {
+                Some(Ok((len, hex))) => {
+                    info!(
+                        "  {} \"{}\": {len} bytes, sha256 {hex}",
+                        file.id, file.name
+                    )
+                }
+                Some(Err(_)) => {
+                    info!("  {} \"{}\": failed", file.id, file.name)
+                }
+                None => info!("  {} \"{}\": incomplete", file.id, file.name),
+            }
+        }
+    }
+}
+
+// Run the remaining stages of a PLDM session: perform base setup, find the
+// files in the PDR and transfer each selected file, then upload the
+// --pldm-upload file if set. A retryable failure of any file is returned
+// once the other files have been transferred.
+async fn pldm_session(
+    chan: &mut impl mctp::AsyncReqChannel,
+    opts: &PldmOptions,
+    session: &mut Session,
+) -> Result<()> {
+    let mode = match session.mode {
+        Some(mode) => mode,
+        None => {
+            let mode = pldm_control(chan, opts.xfer_size)
+                .await
+                .context("PLDM control discovery failed")?;
+            *session.mode.insert(mode)
+        }
+    };
+
+    if session.files.is_none() {
+        let files = pldm_pdr(chan)
+            .await
+            .context("PLDM PDR query for file info failed")?;
+
+        let files = files
+            .into_iter()
+            .filter(|f| f.selected(opts))
+            .collect::<Vec<_>>();
+        if files.is_empty() {
+            bail!("No files match --pldm-file-name or --pldm-file-id");
+        }
+        session.files = Some(files);
+    }
+    let files = session.files.as_deref().unwrap_or_default();
+
+    // with more than one file, each is written to the output path with
+    // the file identifier appended
+    let mut retry = None;
+    for file in files {
+        if session.results.contains_key(&file.id) {
+            continue;
+        }
+        let output = opts.output.as_ref().map(|p| match files.len() {
+            1 => p.clone(),
+            _ => format!("{p}.{}", file.id),
+        });
+        info!("PLDM: transferring file {} \"{}\"", file.id, file.name);
+        let r = pldm_file(
+            chan,
+            file,
+            opts,
+            mode.part_size(),
+            output.as_deref(),
+            &mut session.transfers,
+            &mut session.open,
+        )
+        .await;
+        match r {
+            Ok(r) => {
+                session.results.insert(file.id, Ok(r));
+            }
+            Err(e) => {
+                warn!("PLDM transfer of file {} failed: {e:#}", file.id);
+                if retryable(&e) {
+                    retry.get_or_insert(e);
+                } else {
+                    // the output is kept, to show how far the transfer got
+                    session.transfers.remove(&file.id);
+                    session.results.insert(file.id, Err(format!("{e:#}")));
+                }
+            }
+        }
+    }
+
+    if let Some(e) = retry {
+        return Err(e);
+    }
 
+    session.summary();
+
+    if session.results.values().all(|r| r.is_err()) {
+        bail!("PLDM file transfers failed");
+    }
+
+    if let Some(path) = &opts.upload {
+        pldmupload::upload(chan, path, mode.part_size()).await?;
+    }
     Ok(())
 }
 
+// Run a session with the peer on `chan`, retrying retryable failures with
+// exponential backoff
+async fn pldm_retry(
+    chan: &mut impl mctp::AsyncReqChannel,
+    opts: &PldmOptions,
+    stats: &PldmStats,
+    session: &mut Session,
+) -> Result<()> {
+    let mut delay = opts.retry_delay;
+    let mut attempt = 1;
+    loop {
+        stats.attempts.fetch_add(1, Ordering::Relaxed);
+        let e = match pldm_session(chan, opts, session).await {
+            Ok(()) => {
+                stats.successes.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if attempt >= opts.retries || !retryable(&e) {
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+            session.summary();
+            return Err(e);
+        }
+        warn!(
+            "PLDM session attempt {attempt} failed, retrying in {delay:?}: \
+             {e:#}"
+        );
+        smol::Timer::after(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+// Run a session with the peer on `chan`, once the bus owner has assigned
+// our TID at or after `assigned`. Some bus owners refuse requests from a
+// terminus they have not yet assigned a TID.
+async fn pldm_start(
+    chan: &mut impl mctp::AsyncReqChannel,
+    assigned: Option<Instant>,
+    opts: &PldmOptions,
+    stats: &PldmStats,
+    session: &mut Session,
+    terminus: &Terminus,
+) -> Result<()> {
+    if terminus.wait_assigned(assigned, TID_TIMEOUT).await {
+        info!("PLDM: TID {} assigned", terminus.tid());
+    } else {
+        info!(
+            "PLDM: no TID assigned within {TID_TIMEOUT:?}, continuing with \
+             TID {}",
+            terminus.tid()
+        );
+    }
+    pldm_retry(chan, opts, stats, session).await
+}
+
+/// Run PLDM file transfer sessions with the bus owner, each time our EID is
+/// assigned. If a peer is set in `opts`, the first session is started with
+/// that peer immediately. A new EID assignment during a session restarts it
+/// with the new bus owner, after closing any file it left open. Transfers
+/// are resumed if the bus owner is unchanged, and otherwise their partial
+/// output files are removed.
+///
+/// Each session first waits for the bus owner to assign our TID with
+/// SetTID, continuing with the current TID if it does not.
+///
+/// In oneshot mode, this returns the result of the first completed session,
+/// or an error once the timeout expires.
 pub async fn pldm(
     router: &Router<'_>,
-    ctrl_ev_receiver: async_channel::Receiver<ControlEvent>,
-) -> std::io::Result<()> {
+    events: async_channel::Receiver<Arc<Event>>,
+    opts: PldmOptions,
+    stats: &PldmStats,
+    terminus: &Terminus,
+) -> Result<()> {
     info!("PLDM handler started");
+
+    // the session's peer, for reporting a timeout
+    let current = Cell::new(None);
+    let sessions =
+        pldm_sessions(router, events, &opts, stats, &current, terminus);
+    let Some(timeout) = opts.timeout else {
+        return sessions.await;
+    };
+
+    select!(
+        r = sessions.fuse() => r,
+        _ = smol::Timer::after(timeout).fuse() => match current.get() {
+            Some(peer) => bail!(
+                "PLDM session with {peer} timed out after {timeout:?}"
+            ),
+            None => bail!(
+                "PLDM timed out after {timeout:?} waiting for an EID \
+                 assignment"
+            ),
+        }
+    )
+}
+
+async fn pldm_sessions(
+    router: &Router<'_>,
+    events: async_channel::Receiver<Arc<Event>>,
+    opts: &PldmOptions,
+    stats: &PldmStats,
+    current: &Cell<Option<Eid>>,
+    terminus: &Terminus,
+) -> Result<()> {
+    // the peer, and the time it assigned our EID
+    let mut next_peer = opts.peer.map(|p| (p, None));
+    // the progress of an interrupted session, to resume with the same peer
+    let mut resume = None;
     loop {
-        let peer = loop {
-            let res = ctrl_ev_receiver.recv().await;
+        current.set(None);
+        let (peer, assigned) = if let Some(next) = next_peer.take() {
+            info!("PLDM: using peer {}", next.0);
+            next
+        } else {
+            loop {
+                let Ok(ev) = events.recv().await else {
+                    // no further events
+                    return futures::future::pending().await;
+                };
+
+                if let EventKind::Control(ControlEvent::SetEndpointId {
+                    bus_owner,
+                    ..
+                }) = &ev.event
+                {
+                    info!(
+                        "PLDM: new bus owner {bus_owner}, assigned {:.1?} ago",
+                        ev.time.elapsed()
+                    );
+                    break (*bus_owner, Some(ev.time));
+                };
+            }
+        };
+
+        current.set(Some(peer));
+
+        // the peer resetting our EID ends the session, and a new EID
+        // assignment restarts it with the new bus owner
+        let interrupt = async {
+            loop {
+                let Ok(ev) = events.recv().await else {
+                    return futures::future::pending().await;
+                };
+                match &ev.event {
+                    EventKind::EidReset { bus_owner, .. }
+                        if *bus_owner == peer =>
+                    {
+                        return None
+                    }
+                    EventKind::Control(ControlEvent::SetEndpointId {
+                        bus_owner,
+                        ..
+                    }) => return Some((*bus_owner, ev.time)),
+                    _ => (),
+                }
+            }
+        };
+
+        // the session's requests use their own channel, dropped with the
+        // session if it is interrupted, so a late response to a cancelled
+        // request can't be taken for the response to a later one
+        let mut session = resume.take().unwrap_or_default();
+        let run = async {
+            let mut chan = router.req(peer);
+            pldm_start(&mut chan, assigned, opts, stats, &mut session, terminus)
+                .await
+        };
+        let r = select!(
+            r = run.fuse() => futures::future::Either::Left(r),
+            r = interrupt.fuse() => futures::future::Either::Right(r),
+        );
+        let interrupted = match r {
+            futures::future::Either::Left(r) => {
+                match r {
+                    Ok(()) if opts.oneshot => return Ok(()),
+                    Err(e) if opts.oneshot => return Err(e),
+                    Ok(()) => (),
+                    Err(e) => {
+                        warn!("PLDM session failed: {e:#}");
+                        info!("Restarting wait for EID");
+                    }
+                }
+                // the next session starts any transfers again
+                session.discard().await;
+                continue;
+            }
+            futures::future::Either::Right(r) => r,
+        };
+
+        match interrupted {
+            None => info!("PLDM: EID reset by {peer}, ending session"),
+            Some((owner, time)) => {
+                info!(
+                    "PLDM: session with {peer} superseded by new bus owner \
+                     {owner}, restarting session"
+                );
+                next_peer = Some((owner, Some(time)));
+            }
+        }
+        if let Some(fd) = session.open.take() {
+            pldm_close_abandoned(router, peer, fd).await;
+        }
+        // a restart with the same peer resumes the transfers, otherwise
+        // their partial output is removed
+        match next_peer {
+            Some((owner, _)) if owner == peer => {
+                info!("PLDM: resuming transfers with {peer}");
+                resume = Some(session.resume());
+            }
+            _ => session.discard().await,
+        }
+    }
+}
+
+// Close a file left open on `peer` by an interrupted session. This is best
+// effort, as the peer may no longer be reachable at its EID.
+async fn pldm_close_abandoned(
+    router: &Router<'_>,
+    peer: Eid,
+    fd: FileDescriptor,
+) {
+    let mut chan = router.req(peer);
+    let attrs = DfCloseAttributes::empty();
+    select!(
+        r = df_close(&mut chan, fd, attrs).fuse() => {
+            debug!("PLDM: close of abandoned {fd:?} on {peer}: {r:?}")
+        }
+        _ = smol::Timer::after(CLOSE_TIMEOUT).fuse() => {
+            debug!("PLDM: no response closing abandoned {fd:?} on {peer}")
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pldmbase::{self, PldmBase, TypeResponder, PLDM_TYPE_BASE};
+    use crate::pldmplatform::PLDM_TYPE_PLATFORM;
+    use mctp::{AsyncListener, AsyncReqChannel, AsyncRespChannel};
+    use mctp_estack::control::MctpControl;
+    use mctp_estack::router::{Port, PortId, PortLookup, PortTop};
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    const REQUESTER_EID: Eid = Eid(8);
+    const RESPONDER_EID: Eid = Eid(9);
+
+    // Routes local packets out of the single port
+    struct OnePort;
+
+    impl PortLookup for OnePort {
+        fn by_eid(
+            &self,
+            _eid: Eid,
+            source_port: Option<PortId>,
+        ) -> (Option<PortId>, Option<usize>) {
+            match source_port {
+                None => (Some(PortId(0)), None),
+                Some(_) => (None, None),
+            }
+        }
+    }
+
+    // Deliver the packets sent from `port` to `peer`, as a loopback between
+    // two routers
+    async fn link(mut port: Port<'_>, router: &Router<'_>, peer: &Router<'_>) {
+        let start = Instant::now();
+        loop {
+            let (pkt, _dest) = port.outbound().await;
+            let ms = start.elapsed().as_millis() as u64;
+            let _ = router.update_time(ms).await;
+            let _ = peer.update_time(ms).await;
+            peer.inbound(pkt, PortId(0)).await;
+            port.outbound_done();
+        }
+    }
+
+    fn options() -> PldmOptions {
+        PldmOptions {
+            peer: None,
+            output: None,
+            overwrite: false,
+            file_name: None,
+            file_id: None,
+            retries: 1,
+            retry_delay: Duration::ZERO,
+            xfer_size: 512,
+            read_chunk: 65536,
+            oneshot: true,
+            timeout: None,
+            upload: None,
+        }
+    }
+
+    // A temporary file of `len` bytes, and a responder serving it as file 1,
+    // with the file transfer responder passed through `wrap`
+    fn serve(
+        name: &str,
+        len: u32,
+        wrap: impl FnOnce(Box<dyn TypeResponder>) -> Box<dyn TypeResponder>,
+    ) -> (PathBuf, Vec<u8>, PldmBase) {
+        let data = (0..len).map(|i| (i * 31 % 253) as u8).collect::<Vec<_>>();
+        let path = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-{name}", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let arg = format!("{name}={}", path.display());
+        let files = crate::pldmfile::load(&[arg]).unwrap();
+        let terminus = Arc::new(Terminus::new(pldmbase::DEFAULT_TID));
+        let platform = files.publish(None, terminus.clone());
+        let mut base = PldmBase::new(terminus);
+        base.register(platform.responder());
+        base.register(wrap(files.responder()));
+        (path, data, base)
+    }
+
+    // Transfer a file served with --serve-file by another router's
+    // responder, and compare the hashes
+    #[test]
+    fn serve_file_transfer() {
+        let (path, data, base) = serve("serve", 100_000, |r| r);
+
+        let lookup = OnePort;
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [req_top, resp_top] = &mut tops;
+        let mut requester = Router::new(REQUESTER_EID, &lookup, 0);
+        let mut responder = Router::new(RESPONDER_EID, &lookup, 0);
+        let req_port = requester.add_port(req_top).unwrap();
+        let resp_port = responder.add_port(resp_top).unwrap();
+        let req_port = requester.port(req_port).unwrap();
+        let resp_port = responder.port(resp_port).unwrap();
+
+        let opts = options();
+        let mut session = Session::default();
+        let run = async {
+            let mut chan = requester.req(RESPONDER_EID);
+            pldm_session(&mut chan, &opts, &mut session).await
+        };
+        smol::block_on(async {
+            select!(
+                r = run.fuse() => r.unwrap(),
+                _ = link(req_port, &requester, &responder).fuse() => {
+                    unreachable!()
+                }
+                _ = link(resp_port, &responder, &requester).fuse() => {
+                    unreachable!()
+                }
+                r = pldmbase::responder(&responder, base).fuse() => {
+                    panic!("responder failed: {r:?}")
+                }
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("transfer timed out")
+                }
+            )
+        });
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(session.mode, Some(TransferMode::Negotiated(512))));
+        let hash = hex::encode(Sha256::digest(&data));
+        let result = session.results.get(&1).unwrap().as_ref().unwrap();
+        assert_eq!(*result, (data.len(), hash));
+    }
+
+    // A file transfer responder recording the offset and length of each
+    // section read, and signalling once a number of sections have been read
+    struct Recording {
+        inner: Box<dyn TypeResponder>,
+        sections: Arc<Mutex<Vec<(u32, u32)>>>,
+        signal: Option<(usize, async_channel::Sender<()>)>,
+    }
+
+    impl TypeResponder for Recording {
+        fn typ(&self) -> u8 {
+            self.inner.typ()
+        }
+
+        fn versions(&self) -> &[u32] {
+            self.inner.versions()
+        }
+
+        fn commands(&self) -> &[u8] {
+            self.inner.commands()
+        }
+
+        fn command(
+            &mut self,
+            eid: Eid,
+            cmd: u8,
+            data: &[u8],
+        ) -> Result<Vec<u8>, u8> {
+            self.inner.command(eid, cmd, data)
+        }
+
+        fn multipart(&self) -> bool {
+            self.inner.multipart()
+        }
+
+        fn section(
+            &mut self,
+            eid: Eid,
+            context: u32,
+            offset: u32,
+            len: u32,
+        ) -> Result<Vec<u8>, u8> {
+            let mut sections = self.sections.lock().unwrap();
+            sections.push((offset, len));
+            if let Some((n, signal)) = &self.signal {
+                if sections.len() == *n {
+                    let _ = signal.try_send(());
+                }
+            }
+            self.inner.section(eid, context, offset, len)
+        }
+    }
+
+    // Answer control requests on `router`, passing on the events. Each EID
+    // assignment is followed by a SetTID, as from the bus owner.
+    async fn control(
+        router: &Router<'_>,
+        events: async_channel::Sender<Arc<Event>>,
+        tid: &mut PldmBase,
+    ) {
+        let mut l = router.listener(mctp::MCTP_TYPE_CONTROL).unwrap();
+        let mut c = MctpControl::new(router);
+        let mut buf = [0u8; 64];
+        loop {
+            let (_typ, _ic, msg, resp) = l.recv(&mut buf).await.unwrap();
+            let eid = resp.remote_eid();
+            if let Ok(Some(ev)) = c.handle_async(msg, resp).await {
+                let ev = Event {
+                    time: Instant::now(),
+                    event: EventKind::Control(ev),
+                };
+                events.send(Arc::new(ev)).await.unwrap();
+                tid.handle(eid, &[0x80, 0x00, 0x01, 2]).unwrap();
+            }
+        }
+    }
+
+    // A second EID assignment by the same bus owner, during a read, restarts
+    // the session, which resumes the transfer into the same output file
+    #[test]
+    fn restart_resumes_transfer() {
+        let (signal, interrupt) = async_channel::bounded(1);
+        let (path, data, base) = serve("restart", 100_000, |inner| {
+            Box::new(Recording {
+                inner,
+                sections: Default::default(),
+                signal: Some((2, signal)),
+            })
+        });
+        let out = path.with_extension("out");
+        let _ = std::fs::remove_file(&out);
+
+        let lookup = OnePort;
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [req_top, resp_top] = &mut tops;
+        let mut requester = Router::new(REQUESTER_EID, &lookup, 0);
+        let mut responder = Router::new(RESPONDER_EID, &lookup, 0);
+        let req_port = requester.add_port(req_top).unwrap();
+        let resp_port = responder.add_port(resp_top).unwrap();
+        let req_port = requester.port(req_port).unwrap();
+        let resp_port = responder.port(resp_port).unwrap();
+
+        // our TID, assigned before the first session
+        let terminus = Arc::new(Terminus::new(pldmbase::DEFAULT_TID));
+        let mut tid = PldmBase::new(terminus.clone());
+        tid.handle(RESPONDER_EID, &[0x80, 0x00, 0x01, 2]).unwrap();
 
-            if let Ok(ControlEvent::SetEndpointId { bus_owner, .. }) = res {
-                info!("PLDM: new bus owner {bus_owner}");
-                break bus_owner;
+        let opts = PldmOptions {
+            peer: Some(RESPONDER_EID),
+            output: Some(out.display().to_string()),
+            ..options()
+        };
+        let stats = PldmStats::default();
+        let current = Cell::new(None);
+        let (events_tx, events) = async_channel::bounded(4);
+        let sessions = pldm_sessions(
+            &requester, events, &opts, &stats, &current, &terminus,
+        );
+
+        // the bus owner assigns our EID again, partway through the read
+        let bus_owner = async {
+            interrupt.recv().await.unwrap();
+            let mut chan = responder.req(REQUESTER_EID);
+            let req = [0x80, 0x01, 0x00, REQUESTER_EID.0];
+            chan.send(mctp::MCTP_TYPE_CONTROL, &req).await.unwrap();
+            let mut buf = [0u8; 16];
+            chan.recv(&mut buf).await.unwrap();
+            futures::future::pending::<()>().await
+        };
+
+        smol::block_on(async {
+            select!(
+                r = sessions.fuse() => r.unwrap(),
+                _ = bus_owner.fuse() => (),
+                _ = control(&requester, events_tx, &mut tid).fuse() => (),
+                _ = link(req_port, &requester, &responder).fuse() => (),
+                _ = link(resp_port, &responder, &requester).fuse() => (),
+                r = pldmbase::responder(&responder, base).fuse() => {
+                    panic!("responder failed: {r:?}")
+                }
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("transfer timed out")
+                }
+            )
+        });
+        let written = std::fs::read(&out).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(out).unwrap();
+
+        // the interrupted session, then the restarted one
+        assert_eq!(stats.attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.successes.load(Ordering::Relaxed), 1);
+        assert!(written == data, "output differs from the served file");
+    }
+
+    // A peer answering each request with the next scripted response, and
+    // recording the requests. An empty response times out, and requests
+    // past the end of the script fail.
+    struct Scripted {
+        // the PLDM type and command expected, and the response from the
+        // completion code
+        script: VecDeque<(u8, u8, Vec<u8>)>,
+        // the PLDM type and command of each request, and when it was sent
+        sent: Vec<(u8, u8, Instant)>,
+        resp: Option<Vec<u8>>,
+    }
+
+    impl Scripted {
+        fn new(script: &[(u8, u8, &[u8])]) -> Self {
+            Self {
+                script: script
+                    .iter()
+                    .map(|(t, c, r)| (*t, *c, r.to_vec()))
+                    .collect(),
+                sent: Vec::new(),
+                resp: None,
+            }
+        }
+
+        fn commands(&self) -> Vec<(u8, u8)> {
+            self.sent.iter().map(|(t, c, _)| (*t, *c)).collect()
+        }
+    }
+
+    impl AsyncReqChannel for Scripted {
+        async fn send_vectored(
+            &mut self,
+            typ: mctp::MsgType,
+            _ic: mctp::MsgIC,
+            bufs: &[&[u8]],
+        ) -> mctp::Result<()> {
+            assert_eq!(typ, mctp::MCTP_TYPE_PLDM);
+            let req = bufs.concat();
+            let (typ, cmd) = (req[1] & 0x3f, req[2]);
+            self.sent.push((typ, cmd, Instant::now()));
+            let Some((t, c, data)) = self.script.pop_front() else {
+                return Err(mctp::Error::BadArgument);
+            };
+            assert_eq!((typ, cmd), (t, c), "unexpected request");
+            if data.is_empty() {
+                return Ok(());
+            }
+            // the request's instance ID and type, as a response
+            let mut resp = vec![req[0] & 0x1f, req[1], cmd];
+            resp.extend_from_slice(&data);
+            self.resp = Some(resp);
+            Ok(())
+        }
+
+        async fn recv<'f>(
+            &mut self,
+            buf: &'f mut [u8],
+        ) -> mctp::Result<(mctp::MsgType, mctp::MsgIC, &'f mut [u8])> {
+            let resp = self.resp.take().ok_or(mctp::Error::TimedOut)?;
+            let buf = buf.get_mut(..resp.len()).ok_or(mctp::Error::NoSpace)?;
+            buf.copy_from_slice(&resp);
+            Ok((mctp::MCTP_TYPE_PLDM, mctp::MsgIC(false), buf))
+        }
+
+        fn remote_eid(&self) -> Eid {
+            RESPONDER_EID
+        }
+    }
+
+    const NEGOTIATE_TRANSFER_PARAMETERS: u8 = 0x07;
+    const MULTIPART_RECEIVE: u8 = 0x09;
+    const GET_PDR_REPOSITORY_INFO: u8 = 0x50;
+    const PLDM_ERROR_INVALID_DATA: u8 = 0x02;
+    const PLDM_ERROR_UNSUPPORTED_PLDM_CMD: u8 = 0x05;
+
+    // The first request of a session, transfer parameter negotiation, waits
+    // for the bus owner to assign our TID
+    #[test]
+    fn tid_before_negotiation() {
+        let terminus = Arc::new(Terminus::new(pldmbase::DEFAULT_TID));
+        let mut base = PldmBase::new(terminus.clone());
+        // negotiation is refused, and the session ends at the PDR query
+        let mut chan = Scripted::new(&[(
+            PLDM_TYPE_BASE,
+            NEGOTIATE_TRANSFER_PARAMETERS,
+            &[PLDM_ERROR_UNSUPPORTED_PLDM_CMD],
+        )]);
+        let opts = options();
+        let stats = PldmStats::default();
+        let mut session = Session::default();
+
+        let run =
+            pldm_start(&mut chan, None, &opts, &stats, &mut session, &terminus);
+        let bus_owner = async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            // SetTID
+            base.handle(RESPONDER_EID, &[0x80, 0x00, 0x01, 2]).unwrap();
+            Instant::now()
+        };
+        let (r, set_tid) =
+            smol::block_on(futures::future::join(run, bus_owner));
+
+        assert!(r.is_err());
+        assert_eq!(terminus.tid(), 2);
+        assert_eq!(
+            chan.commands(),
+            [
+                (PLDM_TYPE_BASE, NEGOTIATE_TRANSFER_PARAMETERS),
+                (PLDM_TYPE_PLATFORM, GET_PDR_REPOSITORY_INFO),
+            ]
+        );
+        assert!(chan.sent[0].2 >= set_tid, "negotiated before SetTID");
+    }
+
+    fn negotiate(resp: &[u8]) -> Scripted {
+        Scripted::new(&[(PLDM_TYPE_BASE, NEGOTIATE_TRANSFER_PARAMETERS, resp)])
+    }
+
+    // A part size accepted by the peer is used
+    #[test]
+    fn negotiation_accepted() {
+        // 256 byte parts, for the file transfer type
+        let mut resp = vec![0x00, 0x00, 0x01];
+        resp.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 0]);
+        let mut chan = negotiate(&resp);
+        let mode = smol::block_on(pldm_control(&mut chan, 512)).unwrap();
+        assert!(matches!(mode, TransferMode::Negotiated(256)));
+    }
+
+    // A peer rejecting our parameters, or without the command, leaves
+    // transfers in parts of the minimum size
+    #[test]
+    fn negotiation_fallback() {
+        for cc in [PLDM_ERROR_INVALID_DATA, PLDM_ERROR_UNSUPPORTED_PLDM_CMD] {
+            let mut chan = negotiate(&[cc]);
+            let mode = smol::block_on(pldm_control(&mut chan, 512)).unwrap();
+            assert!(matches!(mode, TransferMode::Fallback), "cc {cc:#04x}");
+            assert_eq!(mode.part_size(), MIN_PART_SIZE);
+        }
+    }
+
+    // No response fails the negotiation, for the session to be retried
+    #[test]
+    fn negotiation_no_response() {
+        let mut chan = negotiate(&[]);
+        let e = smol::block_on(pldm_control(&mut chan, 512)).unwrap_err();
+        assert!(retryable(&e));
+    }
+
+    // A sensor crossing a threshold sends a PlatformEventMessage to the
+    // receiver set by SetEventReceiver, here a listener on the requester
+    #[test]
+    fn sensor_event() {
+        // any reading away from the nominal value crosses a threshold
+        let path = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-event.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[[sensor]]\nid = 1\nunit = \"celsius\"\nmin = 0\nmax = 100\n\
+             nominal = 50\nwarning-high = 60\nwarning-low = 40\n\
+             profile = \"sine\"\nperiod = 4\namplitude = 40\n",
+        )
+        .unwrap();
+        let terminus = Arc::new(Terminus::new(pldmbase::DEFAULT_TID));
+        let platform =
+            crate::pldmplatform::load(path.to_str().unwrap(), terminus.clone())
+                .unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut base = PldmBase::new(terminus);
+        base.register(platform.responder());
+
+        let lookup = OnePort;
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [req_top, resp_top] = &mut tops;
+        let mut requester = Router::new(REQUESTER_EID, &lookup, 0);
+        let mut responder = Router::new(RESPONDER_EID, &lookup, 0);
+        let req_port = requester.add_port(req_top).unwrap();
+        let resp_port = responder.add_port(resp_top).unwrap();
+        let req_port = requester.port(req_port).unwrap();
+        let resp_port = responder.port(resp_port).unwrap();
+
+        let run = async {
+            let mut l = requester.listener(mctp::MCTP_TYPE_PLDM).unwrap();
+            let mut chan = requester.req(RESPONDER_EID);
+            // SetEventReceiver in async mode, then SetNumericSensorEnable
+            // with events enabled
+            let receiver = [1, 0, REQUESTER_EID.0];
+            let enable = [1, 0, 0, 2];
+            for (cmd, data) in [(0x04, &receiver[..]), (0x10, &enable[..])] {
+                let mut req = vec![0x80, 0x02, cmd];
+                req.extend_from_slice(data);
+                chan.send(mctp::MCTP_TYPE_PLDM, &req).await.unwrap();
+                let mut buf = [0u8; 16];
+                let (_typ, _ic, resp) = chan.recv(&mut buf).await.unwrap();
+                assert_eq!(resp, [0x00, 0x02, cmd, 0x00]);
+            }
+
+            let mut buf = [0u8; 64];
+            let (_typ, _ic, msg, mut resp) = l.recv(&mut buf).await.unwrap();
+            let msg = msg.to_vec();
+            // success, with no further action
+            let reply = [msg[0] & 0x1f, 0x02, 0x0a, 0x00, 0x00];
+            resp.send(&reply).await.unwrap();
+            msg
+        };
+        let msg = smol::block_on(async {
+            select!(
+                r = run.fuse() => r,
+                _ = link(req_port, &requester, &responder).fuse() => {
+                    unreachable!()
+                }
+                _ = link(resp_port, &responder, &requester).fuse() => {
+                    unreachable!()
+                }
+                r = pldmbase::responder(&responder, base).fuse() => {
+                    panic!("responder failed: {r:?}")
+                }
+                _ = platform.events(&responder).fuse() => unreachable!(),
+                _ = smol::Timer::after(Duration::from_secs(10)).fuse() => {
+                    panic!("no event received")
+                }
+            )
+        });
+
+        // PlatformEventMessage, a sensor event from our TID, for a numeric
+        // sensor state change of sensor 1 from normal to a warning
+        assert_eq!(msg[0] & 0xe0, 0x80);
+        assert_eq!(
+            msg[1..9],
+            [0x02, 0x0a, 1, pldmbase::DEFAULT_TID, 0, 1, 0, 2]
+        );
+        assert!(matches!(msg[9], 0x05 | 0x08), "state {:#04x}", msg[9]);
+        assert_eq!(msg[10..12], [0x01, 5]);
+        assert_eq!(msg.len(), 16);
+    }
+
+    // A channel failing the `fail_at`th MultipartReceive request, and
+    // counting them
+    struct Flaky<C> {
+        inner: C,
+        receives: usize,
+        fail_at: usize,
+    }
+
+    impl<C: AsyncReqChannel> AsyncReqChannel for Flaky<C> {
+        async fn send_vectored(
+            &mut self,
+            typ: mctp::MsgType,
+            ic: mctp::MsgIC,
+            bufs: &[&[u8]],
+        ) -> mctp::Result<()> {
+            let req = bufs.concat();
+            if req[1] & 0x3f == PLDM_TYPE_BASE && req[2] == MULTIPART_RECEIVE {
+                self.receives += 1;
+                if self.receives == self.fail_at {
+                    return Err(mctp::Error::TxFailure);
+                }
+            }
+            self.inner.send_vectored(typ, ic, bufs).await
+        }
+
+        async fn recv<'f>(
+            &mut self,
+            buf: &'f mut [u8],
+        ) -> mctp::Result<(mctp::MsgType, mctp::MsgIC, &'f mut [u8])> {
+            self.inner.recv(buf).await
+        }
+
+        fn remote_eid(&self) -> Eid {
+            self.inner.remote_eid()
+        }
+    }
+
+    // Read file 1 from `base` with `opts`, through a channel failing its
+    // `fail_at`th MultipartReceive, if non-zero. Returns the number of
+    // MultipartReceive requests and the time taken.
+    fn read_file(
+        base: PldmBase,
+        opts: &PldmOptions,
+        stats: &PldmStats,
+        session: &mut Session,
+        fail_at: usize,
+    ) -> (usize, Duration) {
+        let lookup = OnePort;
+        let mut tops = [PortTop::new(), PortTop::new()];
+        let [req_top, resp_top] = &mut tops;
+        let mut requester = Router::new(REQUESTER_EID, &lookup, 0);
+        let mut responder = Router::new(RESPONDER_EID, &lookup, 0);
+        let req_port = requester.add_port(req_top).unwrap();
+        let resp_port = responder.add_port(resp_top).unwrap();
+        let req_port = requester.port(req_port).unwrap();
+        let resp_port = responder.port(resp_port).unwrap();
+
+        let start = Instant::now();
+        let run = async {
+            let mut chan = Flaky {
+                inner: requester.req(RESPONDER_EID),
+                receives: 0,
+                fail_at,
             };
+            pldm_retry(&mut chan, opts, stats, session).await.unwrap();
+            chan.receives
         };
+        let receives = smol::block_on(async {
+            select!(
+                r = run.fuse() => r,
+                _ = link(req_port, &requester, &responder).fuse() => {
+                    unreachable!()
+                }
+                _ = link(resp_port, &responder, &requester).fuse() => {
+                    unreachable!()
+                }
+                r = pldmbase::responder(&responder, base).fuse() => {
+                    panic!("responder failed: {r:?}")
+                }
+                _ = smol::Timer::after(Duration::from_secs(60)).fuse() => {
+                    panic!("transfer timed out")
+                }
+            )
+        });
+        (receives, start.elapsed())
+    }
+
+    // The file is read in --pldm-read-chunk DfReads of several parts, which
+    // needn't align with the parts, and a retry after a failed read resumes
+    // from the last complete chunk
+    #[test]
+    fn chunked_transfer_resumes() {
+        const CHUNK: u32 = 1500;
+        let sections = Arc::new(Mutex::new(Vec::new()));
+        let (path, data, base) = serve("chunks", 100_000, |inner| {
+            Box::new(Recording {
+                inner,
+                sections: sections.clone(),
+                signal: None,
+            })
+        });
+        let out = path.with_extension("out");
+        let _ = std::fs::remove_file(&out);
+
+        let opts = PldmOptions {
+            output: Some(out.display().to_string()),
+            retries: 2,
+            read_chunk: CHUNK as usize,
+            ..options()
+        };
+        let stats = PldmStats::default();
+        let mut session = Session::default();
+        // partway through the file
+        read_file(base, &opts, &stats, &mut session, 40);
+        let written = std::fs::read(&out).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(out).unwrap();
+
+        assert_eq!(stats.attempts.load(Ordering::Relaxed), 2);
+        let hash = hex::encode(Sha256::digest(&data));
+        let result = session.results.get(&1).unwrap().as_ref().unwrap();
+        assert_eq!(*result, (data.len(), hash));
+        assert!(written == data, "output differs from the served file");
+
+        // each chunk is read once, other than the one that failed
+        let sections = sections.lock().unwrap();
+        let chunks = (0..data.len() as u32).step_by(CHUNK as usize);
+        let mut offsets = sections.iter().map(|s| s.0).collect::<Vec<_>>();
+        assert!(offsets.len() <= chunks.len() + 1, "chunks reread");
+        assert!(sections.iter().all(|s| s.1 <= CHUNK));
+        offsets.dedup();
+        assert!(offsets.into_iter().eq(chunks));
+    }
+
+    // A 1 MiB file read in 256 and 4096 byte chunks, with 512 byte parts:
+    // the DfRead and MultipartReceive counts, and the throughput, which is
+    // printed for comparison with --nocapture
+    #[test]
+    fn read_chunk_benchmark() {
+        const LEN: u32 = 1 << 20;
+        for chunk in [256, 4096] {
+            let sections = Arc::new(Mutex::new(Vec::new()));
+            let (path, data, base) = serve("bench", LEN, |inner| {
+                Box::new(Recording {
+                    inner,
+                    sections: sections.clone(),
+                    signal: None,
+                })
+            });
+            let opts = PldmOptions {
+                read_chunk: chunk as usize,
+                ..options()
+            };
+            let stats = PldmStats::default();
+            let mut session = Session::default();
+            let (receives, time) =
+                read_file(base, &opts, &stats, &mut session, 0);
+            std::fs::remove_file(path).unwrap();
 
-        let chan = router.req(peer);
+            let hash = hex::encode(Sha256::digest(&data));
+            let result = session.results.get(&1).unwrap().as_ref().unwrap();
+            assert_eq!(*result, (data.len(), hash));
+            // one DfRead per chunk, each of at least one part per 512 bytes
+            let reads = sections.lock().unwrap().len();
+            assert_eq!(reads, (LEN / chunk) as usize);
+            assert!(receives >= reads * chunk.div_ceil(512) as usize);
 
-        if let Err(e) = pldm_session(chan).await {
-            warn!("PLDM session failed: {e}");
-            info!("Restarting wait for EID");
+            let rate = LEN as f64 / 1024.0 / time.as_secs_f64();
+            eprintln!(
+                "{chunk} byte chunks: {reads} DfReads, {receives} \
+                 MultipartReceive requests, {time:.3?}, {rate:.1} KiB/s"
+            );
         }
     }
 }
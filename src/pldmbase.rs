@@ -0,0 +1,516 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PLDM base (type 0) responder, answering SetTID, GetTID, GetPLDMVersion,
+ * GetPLDMTypes and GetPLDMCommands for the PLDM types registered with it.
+ * Requests for other types are dispatched to the responder registered for
+ * that type.
+ *
+ * Multipart transfers are negotiated by NegotiateTransferParameters, with
+ * a part size per requester, and read with MultipartReceive. The responder
+ * for the requested type provides the whole section on the first part, which
+ * is then sent in parts of the negotiated size, or of the DSP0240 minimum
+ * if the requester did not negotiate. The CRC-32 of the section follows the
+ * last part.
+ *
+ * This shares the PLDM message type with the file transfer requester. The
+ * listener only receives requests: the router delivers responses to our own
+ * requests to the requester's channel, by tag. Messages without the Rq bit
+ * are dropped regardless.
+ *
+ * The terminus ID is shared with the other responders, which report it, and
+ * with the file transfer requester, which waits for the bus owner's SetTID
+ * before starting a session.
+ */
+
+use log::{debug, info};
+use mctp::{AsyncListener, AsyncRespChannel, Eid};
+use mctp_estack::router::Router;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const PLDM_TYPE_BASE: u8 = 0x00;
+
+/* PLDM message header */
+const PLDM_RQ: u8 = 0x80;
+const PLDM_DATAGRAM: u8 = 0x40;
+const PLDM_IID_MASK: u8 = 0x1f;
+const PLDM_TYPE_MASK: u8 = 0x3f;
+
+/* base commands */
+const PLDM_SET_TID: u8 = 0x01;
+const PLDM_GET_TID: u8 = 0x02;
+const PLDM_GET_PLDM_VERSION: u8 = 0x03;
+const PLDM_GET_PLDM_TYPES: u8 = 0x04;
+const PLDM_GET_PLDM_COMMANDS: u8 = 0x05;
+const PLDM_NEGOTIATE_TRANSFER_PARAMETERS: u8 = 0x07;
+const PLDM_MULTIPART_RECEIVE: u8 = 0x09;
+
+/* completion codes */
+const PLDM_SUCCESS: u8 = 0x00;
+const PLDM_ERROR_INVALID_DATA: u8 = 0x02;
+const PLDM_ERROR_INVALID_LENGTH: u8 = 0x03;
+const PLDM_ERROR_UNSUPPORTED_PLDM_CMD: u8 = 0x05;
+const PLDM_ERROR_INVALID_PLDM_TYPE: u8 = 0x20;
+const PLDM_INVALID_DATA_TRANSFER_HANDLE: u8 = 0x80;
+const PLDM_INVALID_TRANSFER_OPERATION_FLAG: u8 = 0x81;
+const PLDM_INVALID_PLDM_TYPE_IN_REQUEST_DATA: u8 = 0x83;
+const PLDM_INVALID_PLDM_VERSION_IN_REQUEST_DATA: u8 = 0x84;
+const PLDM_INVALID_TRANSFER_CONTEXT: u8 = 0x86;
+
+/* MultipartReceive transfer operations and flags */
+const XFER_FIRST_PART: u8 = 0x00;
+const XFER_NEXT_PART: u8 = 0x01;
+const XFER_ABORT: u8 = 0x02;
+const XFER_COMPLETE: u8 = 0x03;
+const XFER_CURRENT_PART: u8 = 0x04;
+const XFER_START: u8 = 0x01;
+const XFER_MIDDLE: u8 = 0x02;
+const XFER_END: u8 = 0x04;
+const XFER_START_AND_END: u8 = 0x05;
+const XFER_ACK_COMPLETION: u8 = 0x08;
+
+/* multipart part sizes: the DSP0240 minimum, which applies until one is
+ * negotiated, and the largest we accept, keeping responses within a 1 KiB
+ * message */
+const MIN_PART_SIZE: u16 = 256;
+const MAX_PART_SIZE: u16 = 512;
+
+/* GetPLDMVersion transfer flags; the version data is always sent whole */
+const PLDM_GET_FIRSTPART: u8 = 0x01;
+const PLDM_START_AND_END: u8 = 0x05;
+
+/* DSP0240 1.1.0, as a ver32: major, minor, update, alpha */
+const PLDM_BASE_VERSION: u32 = 0xf1f1_f000;
+
+const MAX_REQUEST: usize = 256;
+
+/// Default terminus ID, until one is set by SetTID
+pub const DEFAULT_TID: u8 = 1;
+
+const BASE_VERSIONS: [u32; 1] = [PLDM_BASE_VERSION];
+const BASE_COMMANDS: [u8; 7] = [
+    PLDM_SET_TID,
+    PLDM_GET_TID,
+    PLDM_GET_PLDM_VERSION,
+    PLDM_GET_PLDM_TYPES,
+    PLDM_GET_PLDM_COMMANDS,
+    PLDM_NEGOTIATE_TRANSFER_PARAMETERS,
+    PLDM_MULTIPART_RECEIVE,
+];
+
+/// The terminus ID, shared by the PLDM responders and the requester
+pub struct Terminus {
+    tid: AtomicU8,
+    // when the TID was last set by SetTID
+    assigned: Mutex<Option<Instant>>,
+    // signalled on each SetTID
+    notify: (async_channel::Sender<()>, async_channel::Receiver<()>),
+}
+
+impl Terminus {
+    pub fn new(tid: u8) -> Self {
+        Self {
+            tid: AtomicU8::new(tid),
+            assigned: Mutex::new(None),
+            notify: async_channel::bounded(1),
+        }
+    }
+
+    pub fn tid(&self) -> u8 {
+        self.tid.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, tid: u8) {
+        self.tid.store(tid, Ordering::Relaxed);
+        *self.assigned.lock().unwrap() = Some(Instant::now());
+        let _ = self.notify.0.force_send(());
+    }
+
+    /// Wait up to `timeout` for a SetTID at or after `since`, or for any
+    /// SetTID if unset. Returns whether the TID was assigned.
+    #[cfg_attr(not(feature = "pldm"), allow(dead_code))]
+    pub async fn wait_assigned(
+        &self,
+        since: Option<Instant>,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let assigned = *self.assigned.lock().unwrap();
+            if assigned.is_some_and(|t| since.is_none_or(|s| t >= s)) {
+                return true;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            // a stale notification only causes another check
+            futures::future::select(
+                Box::pin(self.notify.1.recv()),
+                smol::Timer::after(left),
+            )
+            .await;
+        }
+    }
+}
+
+/// A responder for a PLDM type other than the base type
+pub trait TypeResponder {
+    fn typ(&self) -> u8;
+
+    /// supported versions, as ver32 values
+    fn versions(&self) -> &[u32];
+
+    /// supported command codes
+    fn commands(&self) -> &[u8];
+
+    /// Produces the response body following the completion code, or an
+    /// error completion code. `eid` is the requester's EID.
+    fn command(
+        &mut self,
+        eid: Eid,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8>;
+
+    /// Whether the type supports multipart transfers, reported by
+    /// NegotiateTransferParameters
+    fn multipart(&self) -> bool {
+        false
+    }
+
+    /// Produces the section of a MultipartReceive transfer: up to `len`
+    /// bytes at `offset` of the transfer context `context`, or an error
+    /// completion code
+    fn section(
+        &mut self,
+        _eid: Eid,
+        _context: u32,
+        _offset: u32,
+        _len: u32,
+    ) -> Result<Vec<u8>, u8> {
+        Err(PLDM_INVALID_PLDM_TYPE_IN_REQUEST_DATA)
+    }
+}
+
+/// A MultipartReceive transfer in progress
+struct Multipart {
+    typ: u8,
+    context: u32,
+    section: Vec<u8>,
+    // section offsets of the part last sent, and of the next part, or 0
+    // after the last part
+    current: usize,
+    next: usize,
+}
+
+/// The state of the base responder
+pub struct PldmBase {
+    terminus: Arc<Terminus>,
+    responders: Vec<Box<dyn TypeResponder>>,
+    // by requester, the negotiated part size, and the transfer in progress
+    part_sizes: HashMap<Eid, u16>,
+    transfers: HashMap<Eid, Multipart>,
+}
+
+// CRC-32, as used for the GetPLDMVersion version data integrity check
+fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Continue a CRC-32 from `crc` over `data`. The CRC starts as !0, and is
+/// inverted once all of the data is included.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+// A bitfield of `len` bytes, with the bit for each of `values` set
+fn bitfield(len: usize, values: impl Iterator<Item = u8>) -> Vec<u8> {
+    let mut bits = vec![0u8; len];
+    for v in values {
+        if let Some(b) = bits.get_mut(v as usize / 8) {
+            *b |= 1 << (v % 8);
+        }
+    }
+    bits
+}
+
+impl PldmBase {
+    /// A responder for the base type only, reporting the terminus ID of
+    /// `terminus`, which SetTID updates
+    pub fn new(terminus: Arc<Terminus>) -> Self {
+        Self {
+            terminus,
+            responders: Vec::new(),
+            part_sizes: HashMap::new(),
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Dispatch requests for the responder's type to it, and report the
+    /// type in GetPLDMTypes, GetPLDMVersion and GetPLDMCommands
+    pub fn register(&mut self, r: Box<dyn TypeResponder>) {
+        self.responders.push(r);
+    }
+
+    // The versions and commands supported for `typ`
+    fn find(&self, typ: u8) -> Option<(&[u32], &[u8])> {
+        if typ == PLDM_TYPE_BASE {
+            return Some((&BASE_VERSIONS[..], &BASE_COMMANDS[..]));
+        }
+        self.responders
+            .iter()
+            .find(|r| r.typ() == typ)
+            .map(|r| (r.versions(), r.commands()))
+    }
+
+    /// Build the response to a PLDM message from `eid`, or None if it is not
+    /// a request
+    pub fn handle(&mut self, eid: Eid, req: &[u8]) -> Option<Vec<u8>> {
+        let [hdr, typ, cmd, ref data @ ..] = *req else {
+            debug!("PLDM: short message, {} bytes", req.len());
+            return None;
+        };
+        if hdr & (PLDM_RQ | PLDM_DATAGRAM) != PLDM_RQ {
+            debug!("PLDM: dropping non-request message {req:x?}");
+            return None;
+        }
+
+        let typ = typ & PLDM_TYPE_MASK;
+        let mut resp = vec![hdr & PLDM_IID_MASK, typ, cmd];
+        let r = if typ == PLDM_TYPE_BASE {
+            self.base_command(eid, cmd, data)
+        } else {
+            match self.responders.iter_mut().find(|r| r.typ() == typ) {
+                Some(r) => r.command(eid, cmd, data),
+                None => Err(PLDM_ERROR_INVALID_PLDM_TYPE),
+            }
+        };
+        match r {
+            Ok(body) => {
+                resp.push(PLDM_SUCCESS);
+                resp.extend_from_slice(&body);
+            }
+            Err(cc) => {
+                debug!(
+                    "PLDM: type {typ} command {cmd:#04x} failed, cc {cc:#04x}"
+                );
+                resp.push(cc);
+            }
+        }
+        Some(resp)
+    }
+
+    // Handle a base command, returning the response following the
+    // completion code, or an error completion code
+    fn base_command(
+        &mut self,
+        eid: Eid,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        match cmd {
+            PLDM_SET_TID => {
+                let [tid] = *data else {
+                    return Err(PLDM_ERROR_INVALID_LENGTH);
+                };
+                if tid == 0x00 || tid == 0xff {
+                    return Err(PLDM_ERROR_INVALID_DATA);
+                }
+                info!("PLDM: TID set to {tid}");
+                self.terminus.set(tid);
+                Ok(vec![])
+            }
+            PLDM_GET_TID => Ok(vec![self.terminus.tid()]),
+            PLDM_GET_PLDM_TYPES => {
+                let types = self.responders.iter().map(|r| r.typ());
+                Ok(bitfield(8, std::iter::once(PLDM_TYPE_BASE).chain(types)))
+            }
+            PLDM_GET_PLDM_VERSION => {
+                let [h0, h1, h2, h3, op, typ] = *data else {
+                    return Err(PLDM_ERROR_INVALID_LENGTH);
+                };
+                if op != PLDM_GET_FIRSTPART {
+                    return Err(PLDM_INVALID_TRANSFER_OPERATION_FLAG);
+                }
+                if u32::from_le_bytes([h0, h1, h2, h3]) != 0 {
+                    return Err(PLDM_INVALID_DATA_TRANSFER_HANDLE);
+                }
+                let (supported, _) = self
+                    .find(typ)
+                    .ok_or(PLDM_INVALID_PLDM_TYPE_IN_REQUEST_DATA)?;
+
+                let mut versions = Vec::new();
+                for v in supported {
+                    versions.extend_from_slice(&v.to_le_bytes());
+                }
+                let mut body = 0u32.to_le_bytes().to_vec();
+                body.push(PLDM_START_AND_END);
+                body.extend_from_slice(&versions);
+                body.extend_from_slice(&crc32(&versions).to_le_bytes());
+                Ok(body)
+            }
+            PLDM_GET_PLDM_COMMANDS => {
+                let [typ, v0, v1, v2, v3] = *data else {
+                    return Err(PLDM_ERROR_INVALID_LENGTH);
+                };
+                let (versions, commands) = self
+                    .find(typ)
+                    .ok_or(PLDM_INVALID_PLDM_TYPE_IN_REQUEST_DATA)?;
+                let version = u32::from_le_bytes([v0, v1, v2, v3]);
+                if !versions.contains(&version) {
+                    return Err(PLDM_INVALID_PLDM_VERSION_IN_REQUEST_DATA);
+                }
+                Ok(bitfield(32, commands.iter().copied()))
+            }
+            PLDM_NEGOTIATE_TRANSFER_PARAMETERS => {
+                let [s0, s1, ref types @ ..] = *data else {
+                    return Err(PLDM_ERROR_INVALID_LENGTH);
+                };
+                if types.len() != 8 {
+                    return Err(PLDM_ERROR_INVALID_LENGTH);
+                }
+                let size = u16::from_le_bytes([s0, s1]);
+                if size < MIN_PART_SIZE {
+                    return Err(PLDM_ERROR_INVALID_DATA);
+                }
+                let size = size.min(MAX_PART_SIZE);
+                // the requested types that we can transfer in parts
+                let supported = self
+                    .responders
+                    .iter()
+                    .filter(|r| r.multipart())
+                    .map(|r| r.typ())
+                    .filter(|t| types[*t as usize / 8] & 1 << (t % 8) != 0);
+                let supported = bitfield(8, supported);
+
+                debug!("PLDM: part size {size} negotiated with {eid}");
+                self.part_sizes.insert(eid, size);
+                let mut body = size.to_le_bytes().to_vec();
+                body.extend_from_slice(&supported);
+                Ok(body)
+            }
+            PLDM_MULTIPART_RECEIVE => self.multipart_receive(eid, data),
+            _ => Err(PLDM_ERROR_UNSUPPORTED_PLDM_CMD),
+        }
+    }
+
+    fn multipart_receive(
+        &mut self,
+        eid: Eid,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        if data.len() != 18 {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        }
+        let field = |i: usize| {
+            u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]])
+        };
+        let (typ, op) = (data[0], data[1]);
+        let (context, handle) = (field(2), field(6));
+        let (offset, len) = (field(10), field(14));
+
+        match op {
+            XFER_FIRST_PART => {
+                let r = self
+                    .responders
+                    .iter_mut()
+                    .find(|r| r.typ() == typ && r.multipart())
+                    .ok_or(PLDM_INVALID_PLDM_TYPE_IN_REQUEST_DATA)?;
+                let section = r.section(eid, context, offset, len)?;
+                let t = Multipart {
+                    typ,
+                    context,
+                    section,
+                    current: 0,
+                    next: 0,
+                };
+                self.transfers.insert(eid, t);
+            }
+            XFER_NEXT_PART | XFER_CURRENT_PART => {
+                let t = self
+                    .transfers
+                    .get_mut(&eid)
+                    .filter(|t| t.typ == typ && t.context == context)
+                    .ok_or(PLDM_INVALID_TRANSFER_CONTEXT)?;
+                // the next part follows a part other than the last, and
+                // the current part is resent
+                let valid = match op {
+                    XFER_NEXT_PART => t.next != 0 && handle as usize == t.next,
+                    _ => handle as usize == t.current,
+                };
+                if !valid {
+                    return Err(PLDM_INVALID_DATA_TRANSFER_HANDLE);
+                }
+                t.current = handle as usize;
+            }
+            XFER_ABORT | XFER_COMPLETE => {
+                self.transfers.remove(&eid);
+                let mut body = vec![XFER_ACK_COMPLETION];
+                body.extend_from_slice(&0u32.to_le_bytes());
+                body.extend_from_slice(&0u32.to_le_bytes());
+                return Ok(body);
+            }
+            _ => return Err(PLDM_INVALID_TRANSFER_OPERATION_FLAG),
+        }
+
+        // unwrap: the transfer was found or started above
+        let t = self.transfers.get_mut(&eid).unwrap();
+        let size = self.part_sizes.get(&eid).copied().unwrap_or(MIN_PART_SIZE);
+        let start = t.current;
+        let end = (start + size as usize).min(t.section.len());
+        let flag = match (start == 0, end == t.section.len()) {
+            (true, true) => XFER_START_AND_END,
+            (true, false) => XFER_START,
+            (false, false) => XFER_MIDDLE,
+            (false, true) => XFER_END,
+        };
+        t.next = if flag & XFER_END != 0 { 0 } else { end };
+
+        let mut body = vec![flag];
+        body.extend_from_slice(&(t.next as u32).to_le_bytes());
+        body.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        body.extend_from_slice(&t.section[start..end]);
+        if flag & XFER_END != 0 {
+            body.extend_from_slice(&crc32(&t.section).to_le_bytes());
+        }
+        Ok(body)
+    }
+}
+
+/// Respond to PLDM requests, until the listener fails
+pub async fn responder(
+    router: &Router<'_>,
+    mut base: PldmBase,
+) -> std::io::Result<()> {
+    let mut l = router.listener(mctp::MCTP_TYPE_PLDM)?;
+    let mut buf = [0u8; MAX_REQUEST];
+
+    info!("PLDM responder listening, TID {}", base.terminus.tid());
+
+    loop {
+        let (_typ, _ic, msg, mut resp) = match l.recv(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("PLDM recv() failed: {e}");
+                continue;
+            }
+        };
+        let eid = resp.remote_eid();
+        debug!("PLDM request from {eid}: {msg:x?}");
+        let Some(reply) = base.handle(eid, msg) else {
+            continue;
+        };
+        if let Err(e) = resp.send(&reply).await {
+            debug!("PLDM reply failed: {e}");
+        }
+    }
+}
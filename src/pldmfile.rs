@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PLDM File Transfer (type 7) responder, serving local files given with
+ * --serve-file NAME=PATH.
+ *
+ * Each file has a File Descriptor PDR in the platform responder's PDR
+ * repository, with file identifiers following the order of the options,
+ * from 1, and the file's size at startup as its maximum size.
+ *
+ * DfOpen opens the local file, up to MAX_FDS times per file, and DfClose
+ * closes it. File data is read with MultipartReceive, handled by the base
+ * responder, with the file descriptor as the transfer context. Each
+ * descriptor keeps its file offset, so sequential reads don't seek.
+ * Sections are read synchronously, and are limited to MAX_SECTION bytes.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use mctp::Eid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::pldmbase::{Terminus, TypeResponder};
+use crate::pldmplatform::{self, Platform};
+
+pub const PLDM_TYPE_FILE_TRANSFER: u8 = 0x07;
+
+/* DSP0242 1.0.0 */
+const VERSIONS: [u32; 1] = [0xf1f0_f000];
+
+/* file transfer commands */
+const DF_OPEN: u8 = 0x01;
+const DF_CLOSE: u8 = 0x02;
+
+const COMMANDS: [u8; 2] = [DF_OPEN, DF_CLOSE];
+
+/* completion codes */
+const PLDM_ERROR: u8 = 0x01;
+const PLDM_ERROR_INVALID_LENGTH: u8 = 0x03;
+const PLDM_ERROR_UNSUPPORTED_PLDM_CMD: u8 = 0x05;
+const PLDM_FILE_INVALID_FILE_DESCRIPTOR: u8 = 0x80;
+const PLDM_FILE_INVALID_FILE_IDENTIFIER: u8 = 0x86;
+const PLDM_FILE_MAX_NUM_FDS_EXCEEDED: u8 = 0x88;
+const PLDM_FILE_UNABLE_TO_OPEN_FILE: u8 = 0x8a;
+/* MultipartReceive completion codes */
+const PLDM_INVALID_TRANSFER_CONTEXT: u8 = 0x86;
+const PLDM_INVALID_REQUESTED_SECTION_OFFSET: u8 = 0x87;
+const PLDM_INVALID_REQUESTED_SECTION_LENGTH: u8 = 0x88;
+
+/* File Descriptor PDR */
+const PDR_TYPE_FILE_DESCRIPTOR: u8 = 36;
+
+/* open descriptors allowed per file, reported in its PDR */
+const MAX_FDS: u8 = 4;
+/* largest section read for a MultipartReceive */
+const MAX_SECTION: u32 = 1024 * 1024;
+
+/// A file given with --serve-file
+struct ServedFile {
+    id: u16,
+    name: String,
+    path: PathBuf,
+    size: u32,
+}
+
+impl ServedFile {
+    // The File Descriptor PDR data
+    fn pdr(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        // terminus handle, file identifier, entity type, instance and
+        // container
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&self.id.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        // no superior directory
+        b.extend_from_slice(&0u16.to_le_bytes());
+        // file and OEM file classification, capabilities, version
+        b.extend_from_slice(&[0, 0]);
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&0u32.to_le_bytes());
+        b.extend_from_slice(&self.size.to_le_bytes());
+        b.push(MAX_FDS);
+        b.push(self.name.len() as u8);
+        b.extend_from_slice(self.name.as_bytes());
+        // no OEM file classification name
+        b.push(0);
+        b
+    }
+}
+
+/// An open file descriptor
+struct OpenFile {
+    // index into the served files
+    idx: usize,
+    file: File,
+    // the local file's offset, following the last read
+    offset: u64,
+}
+
+/// The file transfer responder, and its files
+pub struct FileServer {
+    files: Vec<ServedFile>,
+    open: HashMap<u16, OpenFile>,
+}
+
+impl FileServer {
+    /// Add the File Descriptor PDRs to the PDR repository of `platform`,
+    /// or of a new platform responder without sensors, reporting the
+    /// terminus ID of `terminus`
+    pub fn publish(
+        &self,
+        platform: Option<Platform>,
+        terminus: Arc<Terminus>,
+    ) -> Platform {
+        let platform =
+            platform.unwrap_or_else(|| pldmplatform::empty(terminus));
+        for f in &self.files {
+            platform.add_pdr(PDR_TYPE_FILE_DESCRIPTOR, &f.pdr());
+        }
+        platform
+    }
+
+    /// The responder, for registering with the base responder
+    pub fn responder(self) -> Box<dyn TypeResponder> {
+        Box::new(self)
+    }
+
+    fn df_open(&mut self, eid: Eid, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [i0, i1, _, _] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let id = u16::from_le_bytes([i0, i1]);
+        let idx = self
+            .files
+            .iter()
+            .position(|f| f.id == id)
+            .ok_or(PLDM_FILE_INVALID_FILE_IDENTIFIER)?;
+        let f = &self.files[idx];
+
+        let count = self.open.values().filter(|o| o.idx == idx).count();
+        if count >= MAX_FDS as usize {
+            return Err(PLDM_FILE_MAX_NUM_FDS_EXCEEDED);
+        }
+        let file = File::open(&f.path).map_err(|e| {
+            debug!("PLDM: can't open {}: {e}", f.path.display());
+            PLDM_FILE_UNABLE_TO_OPEN_FILE
+        })?;
+        let fd = (0..=u16::MAX)
+            .find(|fd| !self.open.contains_key(fd))
+            .ok_or(PLDM_FILE_UNABLE_TO_OPEN_FILE)?;
+
+        info!("PLDM: {eid} opened file {id} \"{}\" as fd {fd}", f.name);
+        let open = OpenFile {
+            idx,
+            file,
+            offset: 0,
+        };
+        self.open.insert(fd, open);
+        Ok(fd.to_le_bytes().to_vec())
+    }
+
+    fn df_close(&mut self, eid: Eid, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [f0, f1, _, _] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let fd = u16::from_le_bytes([f0, f1]);
+        let open = self
+            .open
+            .remove(&fd)
+            .ok_or(PLDM_FILE_INVALID_FILE_DESCRIPTOR)?;
+        info!(
+            "PLDM: {eid} closed fd {fd} of file {}",
+            self.files[open.idx].id
+        );
+        Ok(vec![])
+    }
+}
+
+impl TypeResponder for FileServer {
+    fn typ(&self) -> u8 {
+        PLDM_TYPE_FILE_TRANSFER
+    }
+
+    fn versions(&self) -> &[u32] {
+        &VERSIONS
+    }
+
+    fn commands(&self) -> &[u8] {
+        &COMMANDS
+    }
+
+    fn command(
+        &mut self,
+        eid: Eid,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        match cmd {
+            DF_OPEN => self.df_open(eid, data),
+            DF_CLOSE => self.df_close(eid, data),
+            _ => Err(PLDM_ERROR_UNSUPPORTED_PLDM_CMD),
+        }
+    }
+
+    fn multipart(&self) -> bool {
+        true
+    }
+
+    fn section(
+        &mut self,
+        _eid: Eid,
+        context: u32,
+        offset: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, u8> {
+        if len > MAX_SECTION {
+            return Err(PLDM_INVALID_REQUESTED_SECTION_LENGTH);
+        }
+        let open = u16::try_from(context)
+            .ok()
+            .and_then(|fd| self.open.get_mut(&fd))
+            .ok_or(PLDM_INVALID_TRANSFER_CONTEXT)?;
+
+        // the current size, as the file may have grown since startup
+        let size = open.file.metadata().map_err(|_| PLDM_ERROR)?.len();
+        let offset = offset as u64;
+        if len > 0 && offset >= size {
+            return Err(PLDM_INVALID_REQUESTED_SECTION_OFFSET);
+        }
+        if offset != open.offset {
+            open.file
+                .seek(SeekFrom::Start(offset))
+                .map_err(|_| PLDM_ERROR)?;
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        let r = (&mut open.file).take(len as u64).read_to_end(&mut data);
+        // the offset is unknown after a failed read
+        open.offset = match r {
+            Ok(n) => offset + n as u64,
+            Err(_) => u64::MAX,
+        };
+        r.map_err(|_| PLDM_ERROR)?;
+        debug!("PLDM: read {} bytes at {offset}, fd {context}", data.len());
+        Ok(data)
+    }
+}
+
+/// Check the files given as NAME=PATH, for serving by a responder
+pub fn load(args: &[String]) -> Result<FileServer> {
+    let mut files = Vec::new();
+    for (arg, id) in args.iter().zip(1..) {
+        let Some((name, path)) = arg.split_once('=') else {
+            bail!("Invalid --serve-file {arg}, expected NAME=PATH");
+        };
+        if name.is_empty() || name.len() > 255 || !name.is_ascii() {
+            bail!(
+                "Invalid --serve-file name \"{name}\", expected 1 to 255 \
+                 ASCII characters"
+            );
+        }
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("Can't serve {path}"))?;
+        if !meta.is_file() {
+            bail!("Can't serve {path}, not a regular file");
+        }
+        let size = u32::try_from(meta.len())
+            .with_context(|| format!("Can't serve {path}, too large"))?;
+        info!("PLDM: serving {path} as file {id} \"{name}\", {size} bytes");
+        files.push(ServedFile {
+            id,
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            size,
+        });
+    }
+    Ok(FileServer {
+        files,
+        open: HashMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pldmbase::PldmBase;
+
+    const REQUESTER: Eid = Eid(8);
+
+    /* base commands */
+    const NEGOTIATE_TRANSFER_PARAMETERS: u8 = 0x07;
+    const MULTIPART_RECEIVE: u8 = 0x09;
+
+    // A temporary file of `len` bytes, and a responder serving it as file 1
+    fn serve(name: &str, len: usize) -> (PathBuf, Vec<u8>, PldmBase) {
+        let data = (0..len).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let path = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-{name}", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let arg = format!("{name}={}", path.display());
+        let files = load(&[arg]).unwrap();
+        let mut base = PldmBase::new(Arc::new(Terminus::new(1)));
+        base.register(files.responder());
+        (path, data, base)
+    }
+
+    // Send a request, returning the completion code and response data
+    fn request(base: &mut PldmBase, typ: u8, cmd: u8, data: &[u8]) -> Vec<u8> {
+        let mut req = vec![0x80, typ, cmd];
+        req.extend_from_slice(data);
+        let resp = base.handle(REQUESTER, &req).unwrap();
+        assert_eq!(resp[..3], [0x00, typ, cmd]);
+        resp[3..].to_vec()
+    }
+
+    fn df_open(base: &mut PldmBase, id: u16) -> Vec<u8> {
+        let mut data = id.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0, 0]);
+        request(base, PLDM_TYPE_FILE_TRANSFER, DF_OPEN, &data)
+    }
+
+    fn receive(
+        base: &mut PldmBase,
+        op: u8,
+        fd: u16,
+        handle: u32,
+        offset: u32,
+        len: u32,
+    ) -> Vec<u8> {
+        let mut data = vec![PLDM_TYPE_FILE_TRANSFER, op];
+        for v in [fd as u32, handle, offset, len] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        request(base, 0, MULTIPART_RECEIVE, &data)
+    }
+
+    #[test]
+    fn open_errors() {
+        let (path, _, mut base) = serve("open", 16);
+
+        assert_eq!(df_open(&mut base, 2), [PLDM_FILE_INVALID_FILE_IDENTIFIER]);
+        let fds = (0..MAX_FDS)
+            .map(|_| {
+                let resp = df_open(&mut base, 1);
+                assert_eq!(resp[0], 0, "DfOpen failed");
+                u16::from_le_bytes([resp[1], resp[2]])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(df_open(&mut base, 1), [PLDM_FILE_MAX_NUM_FDS_EXCEEDED]);
+
+        // closing one allows another open
+        let mut close = fds[0].to_le_bytes().to_vec();
+        close.extend_from_slice(&[0, 0]);
+        let resp =
+            request(&mut base, PLDM_TYPE_FILE_TRANSFER, DF_CLOSE, &close);
+        assert_eq!(resp, [0]);
+        let resp =
+            request(&mut base, PLDM_TYPE_FILE_TRANSFER, DF_CLOSE, &close);
+        assert_eq!(resp, [PLDM_FILE_INVALID_FILE_DESCRIPTOR]);
+        assert_eq!(df_open(&mut base, 1)[0], 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn multipart_read() {
+        let (path, data, mut base) = serve("read", 1000);
+
+        // parts are sent at the negotiated size
+        let mut types = [0u8; 8];
+        types[0] = 1 << PLDM_TYPE_FILE_TRANSFER;
+        let mut req = 300u16.to_le_bytes().to_vec();
+        req.extend_from_slice(&types);
+        let resp = request(&mut base, 0, NEGOTIATE_TRANSFER_PARAMETERS, &req);
+        assert_eq!(resp[..3], [0, 44, 1]);
+        assert_eq!(resp[3], 1 << PLDM_TYPE_FILE_TRANSFER);
+
+        let resp = df_open(&mut base, 1);
+        assert_eq!(resp[0], 0);
+        let fd = u16::from_le_bytes([resp[1], resp[2]]);
+
+        let mut read = Vec::new();
+        let mut resp = receive(&mut base, 0, fd, 0, 0, 1000);
+        let mut flags = Vec::new();
+        loop {
+            assert_eq!(resp[0], 0);
+            let flag = resp[1];
+            let next = u32::from_le_bytes(resp[2..6].try_into().unwrap());
+            let len = u32::from_le_bytes(resp[6..10].try_into().unwrap());
+            read.extend_from_slice(&resp[10..10 + len as usize]);
+            flags.push((flag, len));
+            if flag & 0x04 != 0 {
+                // the CRC-32 of the whole section
+                let crc = &resp[10 + len as usize..];
+                assert_eq!(crc, 0x04da_8651u32.to_le_bytes());
+                break;
+            }
+            resp = receive(&mut base, 1, fd, next, 0, 1000);
+        }
+        assert_eq!(flags, [(0x01, 300), (0x02, 300), (0x02, 300), (0x04, 100)]);
+        assert_eq!(read, data);
+
+        // a part can't follow the last
+        let resp = receive(&mut base, 1, fd, 0, 0, 1000);
+        assert_eq!(resp, [0x80]);
+
+        // reads past the end of the file fail
+        let resp = receive(&mut base, 0, fd, 0, 1000, 16);
+        assert_eq!(resp, [PLDM_INVALID_REQUESTED_SECTION_OFFSET]);
+        let resp = receive(&mut base, 0, fd + 1, 0, 0, 16);
+        assert_eq!(resp, [PLDM_INVALID_TRANSFER_CONTEXT]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1268 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PLDM Firmware Update (type 5) firmware device, updated by an update agent
+ * (UA) on the bus. The device identity and components are given in a TOML
+ * file with --pldm-fw:
+ *
+ *   version = "1.0.0"          # active component image set version
+ *
+ *   [[descriptor]]
+ *   type = 0x0001              # DSP0267 descriptor type, IANA enterprise ID
+ *   value = "0000a015"         # hex, in the order sent
+ *
+ *   [[component]]
+ *   classification = 0x000a    # firmware
+ *   id = 1
+ *   index = 0                  # optional classification index
+ *   comparison-stamp = 0x100
+ *   version = "1.0.0"
+ *
+ * The UA drives the update: RequestUpdate, PassComponentTable for each
+ * component, then UpdateComponent. The device then requests the image
+ * with RequestFirmwareData, and reports TransferComplete, VerifyComplete
+ * and ApplyComplete, writing the image to component-CLASS-ID.bin in the
+ * --pldm-fw-dir directory. Applied components become pending, and are
+ * made active by ActivateFirmware, with self-contained activation.
+ *
+ * --pldm-fw-fail injects a failure into each component update: "verify"
+ * and "apply" report a failure of that step, and "stall" stops requesting
+ * data halfway through the image, until the UA cancels the update.
+ *
+ * Package data, device metadata and the update mode idle timeouts are not
+ * supported.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use mctp::{AsyncReqChannel, Eid};
+use mctp_estack::router::Router;
+use serde::Deserialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::filewriter::FileWriter;
+use crate::pldmbase::TypeResponder;
+
+pub const PLDM_TYPE_FW_UPDATE: u8 = 0x05;
+
+/* DSP0267 1.1.0 */
+const VERSIONS: [u32; 1] = [0xf1f1_f000];
+
+/* commands from the UA */
+const QUERY_DEVICE_IDENTIFIERS: u8 = 0x01;
+const GET_FIRMWARE_PARAMETERS: u8 = 0x02;
+const REQUEST_UPDATE: u8 = 0x10;
+const PASS_COMPONENT_TABLE: u8 = 0x13;
+const UPDATE_COMPONENT: u8 = 0x14;
+const ACTIVATE_FIRMWARE: u8 = 0x1a;
+const GET_STATUS: u8 = 0x1b;
+const CANCEL_UPDATE_COMPONENT: u8 = 0x1c;
+const CANCEL_UPDATE: u8 = 0x1d;
+
+/* commands to the UA */
+const REQUEST_FIRMWARE_DATA: u8 = 0x15;
+const TRANSFER_COMPLETE: u8 = 0x16;
+const VERIFY_COMPLETE: u8 = 0x17;
+const APPLY_COMPLETE: u8 = 0x18;
+
+const COMMANDS: [u8; 9] = [
+    QUERY_DEVICE_IDENTIFIERS,
+    GET_FIRMWARE_PARAMETERS,
+    REQUEST_UPDATE,
+    PASS_COMPONENT_TABLE,
+    UPDATE_COMPONENT,
+    ACTIVATE_FIRMWARE,
+    GET_STATUS,
+    CANCEL_UPDATE_COMPONENT,
+    CANCEL_UPDATE,
+];
+
+/* completion codes */
+const PLDM_ERROR_INVALID_DATA: u8 = 0x02;
+const PLDM_ERROR_INVALID_LENGTH: u8 = 0x03;
+const PLDM_ERROR_UNSUPPORTED_PLDM_CMD: u8 = 0x05;
+const PLDM_FWUP_NOT_IN_UPDATE_MODE: u8 = 0x80;
+const PLDM_FWUP_ALREADY_IN_UPDATE_MODE: u8 = 0x81;
+const PLDM_FWUP_INVALID_STATE_FOR_COMMAND: u8 = 0x84;
+const PLDM_FWUP_INCOMPLETE_UPDATE: u8 = 0x85;
+const PLDM_FWUP_BUSY_IN_BACKGROUND: u8 = 0x86;
+const PLDM_FWUP_CANCEL_PENDING: u8 = 0x87;
+const PLDM_FWUP_RETRY_REQUEST_FW_DATA: u8 = 0x89;
+
+/* PassComponentTable transfer flags */
+const TRANSFER_FLAG_END: u8 = 0x04;
+
+/* component responses, for PassComponentTable and UpdateComponent */
+const COMPONENT_CAN_BE_UPDATED: u8 = 0;
+const COMPONENT_CANNOT_BE_UPDATED: u8 = 1;
+const COMPONENT_OK: u8 = 0x00;
+const COMPARISON_STAMP_IDENTICAL: u8 = 0x01;
+const COMPARISON_STAMP_LOWER: u8 = 0x02;
+const COMPONENT_NOT_SUPPORTED: u8 = 0x06;
+
+/* UpdateComponent option flags */
+const FORCE_UPDATE: u32 = 1 << 0;
+
+/* component activation methods */
+const ACTIVATION_SELF_CONTAINED: u16 = 1 << 1;
+
+/* results reported to the UA */
+const TRANSFER_SUCCESS: u8 = 0x00;
+const TRANSFER_ERROR_FD_ABORTED: u8 = 0x03;
+const VERIFY_SUCCESS: u8 = 0x00;
+const VERIFY_ERROR_VERIFICATION_FAILURE: u8 = 0x01;
+const APPLY_SUCCESS: u8 = 0x00;
+const APPLY_FAILURE_MEMORY_ISSUE: u8 = 0x02;
+
+/* GetStatus auxiliary states and reason codes */
+const AUX_IN_PROGRESS: u8 = 0;
+const AUX_SUCCEEDED: u8 = 1;
+const AUX_FAILED: u8 = 2;
+const AUX_IDLE: u8 = 3;
+const AUX_STATUS_GENERIC_ERROR: u8 = 0x0a;
+const PROGRESS_NOT_SUPPORTED: u8 = 101;
+const REASON_INITIALIZATION: u8 = 0;
+const REASON_ACTIVATE_FIRMWARE: u8 = 1;
+const REASON_CANCEL_UPDATE: u8 = 2;
+
+const STRING_TYPE_ASCII: u8 = 1;
+
+/* the spec's minimum for the UA's maximum transfer size, and our limit */
+const MIN_TRANSFER_SIZE: u32 = 32;
+const MAX_TRANSFER_SIZE: u32 = 1024;
+const REQUEST_ATTEMPTS: u32 = 3;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Firmware device states, as reported by GetStatus
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FdState {
+    Idle = 0,
+    LearnComponents = 1,
+    ReadyXfer = 2,
+    Download = 3,
+    Verify = 4,
+    Apply = 5,
+    Activate = 6,
+}
+
+/// An injected failure, from --pldm-fw-fail
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fault {
+    Verify,
+    Apply,
+    Stall,
+}
+
+impl std::str::FromStr for Fault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "verify" => Ok(Fault::Verify),
+            "apply" => Ok(Fault::Apply),
+            "stall" => Ok(Fault::Stall),
+            _ => bail!("expected verify, apply or stall"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DescriptorConfig {
+    #[serde(rename = "type")]
+    typ: u16,
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ComponentConfig {
+    classification: u16,
+    id: u16,
+    #[serde(default)]
+    index: u8,
+    comparison_stamp: u32,
+    version: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    version: String,
+    #[serde(default, rename = "descriptor")]
+    descriptors: Vec<DescriptorConfig>,
+    #[serde(default, rename = "component")]
+    components: Vec<ComponentConfig>,
+}
+
+#[derive(Debug)]
+struct Component {
+    classification: u16,
+    id: u16,
+    index: u8,
+    stamp: u32,
+    version: String,
+    // applied, awaiting ActivateFirmware: comparison stamp and version
+    pending: Option<(u32, String)>,
+    // accepted in PassComponentTable, for this update
+    offered: bool,
+}
+
+impl Component {
+    // The response to a PassComponentTable or UpdateComponent offering an
+    // image with comparison stamp `stamp`
+    fn compatibility(&self, stamp: u32, force: bool) -> (u8, u8) {
+        if force || stamp > self.stamp {
+            (COMPONENT_CAN_BE_UPDATED, COMPONENT_OK)
+        } else if stamp == self.stamp {
+            (COMPONENT_CANNOT_BE_UPDATED, COMPARISON_STAMP_IDENTICAL)
+        } else {
+            (COMPONENT_CANNOT_BE_UPDATED, COMPARISON_STAMP_LOWER)
+        }
+    }
+}
+
+/// An update started by RequestUpdate
+struct Update {
+    ua: Eid,
+    // data per RequestFirmwareData
+    transfer_size: u32,
+    // component image set version
+    version: String,
+}
+
+/// A component image to request from the UA
+struct Transfer {
+    ua: Eid,
+    component: usize,
+    size: u32,
+    transfer_size: u32,
+    // comparison stamp and version of the image, pending once applied
+    stamp: u32,
+    version: String,
+    // the cancel count when the transfer started
+    generation: u32,
+}
+
+struct State {
+    descriptors: Vec<(u16, Vec<u8>)>,
+    version: String,
+    pending_version: Option<String>,
+    components: Vec<Component>,
+    fd: FdState,
+    previous: FdState,
+    update: Option<Update>,
+    // the component being transferred, verified or applied
+    current: Option<usize>,
+    progress: u8,
+    // result of the last component update
+    succeeded: Option<bool>,
+    reason: u8,
+    update_flags: u32,
+    // incremented by cancellation, ending the transfer in progress
+    generation: u32,
+}
+
+// A variable length string field: type, length, then the string
+fn parse_string(data: &[u8]) -> Result<String, u8> {
+    let [typ, len, ref s @ ..] = *data else {
+        return Err(PLDM_ERROR_INVALID_LENGTH);
+    };
+    if s.len() != len as usize {
+        return Err(PLDM_ERROR_INVALID_LENGTH);
+    }
+    if typ != STRING_TYPE_ASCII {
+        debug!("PLDM: firmware version string type {typ}");
+    }
+    Ok(String::from_utf8_lossy(s).into_owned())
+}
+
+fn push_string_header(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&[STRING_TYPE_ASCII, s.len() as u8]);
+}
+
+impl State {
+    fn enter(&mut self, next: FdState) {
+        debug!("PLDM: firmware device {:?} -> {next:?}", self.fd);
+        self.previous = self.fd;
+        self.fd = next;
+    }
+
+    fn require(&self, state: FdState) -> Result<(), u8> {
+        match self.fd {
+            s if s == state => Ok(()),
+            FdState::Idle => Err(PLDM_FWUP_NOT_IN_UPDATE_MODE),
+            _ => Err(PLDM_FWUP_INVALID_STATE_FOR_COMMAND),
+        }
+    }
+
+    fn find(&self, classification: u16, id: u16, index: u8) -> Option<usize> {
+        self.components.iter().position(|c| {
+            c.classification == classification && c.id == id && c.index == index
+        })
+    }
+
+    fn query_device_identifiers(&self) -> Vec<u8> {
+        let mut descriptors = Vec::new();
+        for (typ, value) in &self.descriptors {
+            descriptors.extend_from_slice(&typ.to_le_bytes());
+            descriptors.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            descriptors.extend_from_slice(value);
+        }
+        let mut body = (descriptors.len() as u32).to_le_bytes().to_vec();
+        body.push(self.descriptors.len() as u8);
+        body.extend_from_slice(&descriptors);
+        body
+    }
+
+    fn get_firmware_parameters(&self) -> Vec<u8> {
+        let pending = self.pending_version.as_deref().unwrap_or("");
+        let mut body = 0u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&(self.components.len() as u16).to_le_bytes());
+        push_string_header(&mut body, &self.version);
+        push_string_header(&mut body, pending);
+        body.extend_from_slice(self.version.as_bytes());
+        body.extend_from_slice(pending.as_bytes());
+
+        for c in &self.components {
+            let (pending_stamp, pending_version) = c
+                .pending
+                .as_ref()
+                .map_or((0, ""), |(s, v)| (*s, v.as_str()));
+            body.extend_from_slice(&c.classification.to_le_bytes());
+            body.extend_from_slice(&c.id.to_le_bytes());
+            body.push(c.index);
+            body.extend_from_slice(&c.stamp.to_le_bytes());
+            push_string_header(&mut body, &c.version);
+            // release dates are unknown
+            body.extend_from_slice(&[0; 8]);
+            body.extend_from_slice(&pending_stamp.to_le_bytes());
+            push_string_header(&mut body, pending_version);
+            body.extend_from_slice(&[0; 8]);
+            body.extend_from_slice(&ACTIVATION_SELF_CONTAINED.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(c.version.as_bytes());
+            body.extend_from_slice(pending_version.as_bytes());
+        }
+        body
+    }
+
+    fn request_update(&mut self, ua: Eid, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [t0, t1, t2, t3, _n0, _n1, _outstanding, p0, p1, ref version @ ..] =
+            *data
+        else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let version = parse_string(version)?;
+        if self.fd != FdState::Idle {
+            return Err(PLDM_FWUP_ALREADY_IN_UPDATE_MODE);
+        }
+        let transfer_size = u32::from_le_bytes([t0, t1, t2, t3]);
+        if transfer_size < MIN_TRANSFER_SIZE {
+            return Err(PLDM_ERROR_INVALID_DATA);
+        }
+        if u16::from_le_bytes([p0, p1]) != 0 {
+            debug!("PLDM: ignoring firmware package data");
+        }
+
+        info!("PLDM: firmware update to {version:?} from {ua}");
+        for c in self.components.iter_mut() {
+            c.offered = false;
+        }
+        self.update = Some(Update {
+            ua,
+            transfer_size: transfer_size.min(MAX_TRANSFER_SIZE),
+            version,
+        });
+        self.succeeded = None;
+        self.enter(FdState::LearnComponents);
+        // no device metadata, and no GetPackageData
+        Ok(vec![0, 0, 0])
+    }
+
+    fn pass_component_table(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [flag, c0, c1, i0, i1, index, s0, s1, s2, s3, ref version @ ..] =
+            *data
+        else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        parse_string(version)?;
+        self.require(FdState::LearnComponents)?;
+
+        let classification = u16::from_le_bytes([c0, c1]);
+        let id = u16::from_le_bytes([i0, i1]);
+        let stamp = u32::from_le_bytes([s0, s1, s2, s3]);
+        let resp = match self.find(classification, id, index) {
+            Some(idx) => {
+                let c = &mut self.components[idx];
+                let resp = c.compatibility(stamp, false);
+                c.offered = resp.1 == COMPONENT_OK;
+                resp
+            }
+            None => (COMPONENT_CANNOT_BE_UPDATED, COMPONENT_NOT_SUPPORTED),
+        };
+        debug!(
+            "PLDM: component {classification:#06x}/{id} offered, response \
+             {resp:?}"
+        );
+        if flag & TRANSFER_FLAG_END != 0 {
+            self.enter(FdState::ReadyXfer);
+        }
+        Ok(vec![resp.0, resp.1])
+    }
+
+    // Returns the response, and the transfer to start if the component
+    // will be updated
+    fn update_component(
+        &mut self,
+        data: &[u8],
+    ) -> Result<(Vec<u8>, Option<Transfer>), u8> {
+        let [c0, c1, i0, i1, index, ref rest @ ..] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let [s0, s1, s2, s3, z0, z1, z2, z3, ref rest @ ..] = *rest else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let [f0, f1, f2, f3, ref version @ ..] = *rest else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let version = parse_string(version)?;
+        self.require(FdState::ReadyXfer)?;
+
+        let classification = u16::from_le_bytes([c0, c1]);
+        let id = u16::from_le_bytes([i0, i1]);
+        let stamp = u32::from_le_bytes([s0, s1, s2, s3]);
+        let size = u32::from_le_bytes([z0, z1, z2, z3]);
+        // forced update is the only option we support
+        let flags = u32::from_le_bytes([f0, f1, f2, f3]) & FORCE_UPDATE;
+
+        // only components accepted in PassComponentTable may be updated
+        let idx = self
+            .find(classification, id, index)
+            .filter(|i| self.components[*i].offered);
+        let (resp, code) = match idx {
+            Some(i) => self.components[i].compatibility(stamp, flags != 0),
+            None => (COMPONENT_CANNOT_BE_UPDATED, COMPONENT_NOT_SUPPORTED),
+        };
+        let mut body = vec![resp, code];
+        body.extend_from_slice(&flags.to_le_bytes());
+        // no delay before RequestFirmwareData
+        body.extend_from_slice(&0u16.to_le_bytes());
+        let (Some(idx), Some(update), COMPONENT_OK) = (idx, &self.update, code)
+        else {
+            debug!("PLDM: not updating component {classification:#06x}/{id}");
+            return Ok((body, None));
+        };
+        info!(
+            "PLDM: updating component {classification:#06x}/{id} to \
+             {version:?}, {size} bytes"
+        );
+        let transfer = Transfer {
+            ua: update.ua,
+            component: idx,
+            size,
+            transfer_size: update.transfer_size,
+            stamp,
+            version,
+            generation: self.generation,
+        };
+        self.current = Some(idx);
+        self.progress = 0;
+        self.update_flags = flags;
+        self.enter(FdState::Download);
+        Ok((body, Some(transfer)))
+    }
+
+    fn activate_firmware(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [_self_contained] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        self.require(FdState::ReadyXfer)?;
+        if self.components.iter().all(|c| c.pending.is_none()) {
+            return Err(PLDM_FWUP_INCOMPLETE_UPDATE);
+        }
+
+        self.enter(FdState::Activate);
+        for c in self.components.iter_mut() {
+            if let Some((stamp, version)) = c.pending.take() {
+                info!(
+                    "PLDM: component {:#06x}/{} activated, version \
+                     {version:?}",
+                    c.classification, c.id
+                );
+                c.stamp = stamp;
+                c.version = version;
+            }
+        }
+        if let Some(version) = self.pending_version.take() {
+            self.version = version;
+        }
+        // activation is immediate
+        self.end(REASON_ACTIVATE_FIRMWARE);
+        Ok(0u16.to_le_bytes().to_vec())
+    }
+
+    fn get_status(&self) -> Vec<u8> {
+        let (aux, status) = match (self.fd, self.succeeded) {
+            (FdState::Download | FdState::Verify | FdState::Apply, _) => {
+                (AUX_IN_PROGRESS, 0)
+            }
+            (_, Some(true)) => (AUX_SUCCEEDED, 0),
+            (_, Some(false)) => (AUX_FAILED, AUX_STATUS_GENERIC_ERROR),
+            (_, None) => (AUX_IDLE, 0),
+        };
+        let progress = match self.fd {
+            FdState::Download => self.progress,
+            _ => PROGRESS_NOT_SUPPORTED,
+        };
+        let mut body = vec![
+            self.fd as u8,
+            self.previous as u8,
+            aux,
+            status,
+            progress,
+            self.reason,
+        ];
+        body.extend_from_slice(&self.update_flags.to_le_bytes());
+        body
+    }
+
+    // Abandon the component update in progress
+    fn cancel_component(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.current = None;
+        self.update_flags = 0;
+    }
+
+    fn cancel_update_component(&mut self) -> Result<Vec<u8>, u8> {
+        match self.fd {
+            FdState::Download | FdState::Verify | FdState::Apply => (),
+            FdState::Idle => return Err(PLDM_FWUP_NOT_IN_UPDATE_MODE),
+            _ => return Err(PLDM_FWUP_INVALID_STATE_FOR_COMMAND),
+        }
+        info!("PLDM: firmware component update cancelled");
+        self.cancel_component();
+        self.enter(FdState::ReadyXfer);
+        Ok(vec![])
+    }
+
+    fn cancel_update(&mut self) -> Result<Vec<u8>, u8> {
+        match self.fd {
+            FdState::Idle => return Err(PLDM_FWUP_NOT_IN_UPDATE_MODE),
+            FdState::Activate => {
+                return Err(PLDM_FWUP_INVALID_STATE_FOR_COMMAND)
+            }
+            _ => (),
+        }
+        info!("PLDM: firmware update cancelled");
+        self.cancel_component();
+        for c in self.components.iter_mut() {
+            c.pending = None;
+        }
+        self.pending_version = None;
+        self.end(REASON_CANCEL_UPDATE);
+        // all components remain functional, with no bitmap
+        let mut body = vec![0];
+        body.extend_from_slice(&0u64.to_le_bytes());
+        Ok(body)
+    }
+
+    // Leave update mode
+    fn end(&mut self, reason: u8) {
+        self.update = None;
+        self.reason = reason;
+        self.enter(FdState::Idle);
+    }
+
+    // Whether `t` is still the component update in progress
+    fn current(&self, t: &Transfer) -> bool {
+        self.generation == t.generation && self.current == Some(t.component)
+    }
+
+    // Record the result of a step of transfer `t`, entering `next` on
+    // success. Returns false if the transfer has been cancelled.
+    fn step(&mut self, t: &Transfer, ok: bool, next: FdState) -> bool {
+        if !self.current(t) {
+            return false;
+        }
+        if ok {
+            self.enter(next);
+        } else {
+            self.succeeded = Some(false);
+            self.current = None;
+            self.enter(FdState::ReadyXfer);
+        }
+        ok
+    }
+}
+
+/// The firmware device, for registering with the base responder and
+/// running its updates
+#[derive(Clone)]
+pub struct FirmwareDevice {
+    state: Arc<Mutex<State>>,
+    transfers: async_channel::Sender<Transfer>,
+    pending: async_channel::Receiver<Transfer>,
+    dir: PathBuf,
+    fault: Option<Fault>,
+}
+
+impl FirmwareDevice {
+    /// The responder, for registering with the base responder
+    pub fn responder(&self) -> Box<dyn TypeResponder> {
+        Box::new(self.clone())
+    }
+
+    /// Transfer, verify and apply components as they are updated by the UA
+    pub async fn run(self, router: &Router<'_>) {
+        let mut iid = 0u8;
+        while let Ok(t) = self.pending.recv().await {
+            let mut chan = router.req(t.ua);
+            self.update(&mut chan, &mut iid, &t).await;
+        }
+    }
+
+    async fn update(
+        &self,
+        chan: &mut impl AsyncReqChannel,
+        iid: &mut u8,
+        t: &Transfer,
+    ) {
+        let (classification, id) = {
+            let st = self.state.lock().unwrap();
+            let c = &st.components[t.component];
+            (c.classification, c.id)
+        };
+        let path = self
+            .dir
+            .join(format!("component-{classification:04x}-{id:04x}.bin"));
+
+        let Some(ok) = self.download(chan, iid, t, &path).await else {
+            return;
+        };
+        let result = if ok {
+            TRANSFER_SUCCESS
+        } else {
+            TRANSFER_ERROR_FD_ABORTED
+        };
+        if !self
+            .report(chan, iid, t, TRANSFER_COMPLETE, &[result])
+            .await
+            || !self.state.lock().unwrap().step(t, ok, FdState::Verify)
+        {
+            return;
+        }
+
+        let ok = self.fault != Some(Fault::Verify);
+        let result = if ok {
+            VERIFY_SUCCESS
+        } else {
+            VERIFY_ERROR_VERIFICATION_FAILURE
+        };
+        if !self.report(chan, iid, t, VERIFY_COMPLETE, &[result]).await
+            || !self.state.lock().unwrap().step(t, ok, FdState::Apply)
+        {
+            return;
+        }
+
+        let ok = self.fault != Some(Fault::Apply);
+        let result = if ok {
+            APPLY_SUCCESS
+        } else {
+            APPLY_FAILURE_MEMORY_ISSUE
+        };
+        // no change to the activation methods
+        let data = [result, 0, 0];
+        if !self.report(chan, iid, t, APPLY_COMPLETE, &data).await {
+            return;
+        }
+        let mut st = self.state.lock().unwrap();
+        if !st.step(t, ok, FdState::ReadyXfer) {
+            return;
+        }
+        info!(
+            "PLDM: component {classification:#06x}/{id} applied, version \
+             {:?}, written to {}",
+            t.version,
+            path.display()
+        );
+        st.components[t.component].pending = Some((t.stamp, t.version.clone()));
+        st.pending_version = st.update.as_ref().map(|u| u.version.clone());
+        st.succeeded = Some(true);
+        st.current = None;
+    }
+
+    // Request the image from the UA, writing it to `path`. Returns whether
+    // the image was received, or None if the transfer was cancelled.
+    async fn download(
+        &self,
+        chan: &mut impl AsyncReqChannel,
+        iid: &mut u8,
+        t: &Transfer,
+        path: &Path,
+    ) -> Option<bool> {
+        let file = match File::create(path) {
+            Ok(f) => FileWriter::new(f),
+            Err(e) => {
+                warn!("PLDM: can't create {}: {e}", path.display());
+                return Some(false);
+            }
+        };
+
+        let mut offset = 0u32;
+        let mut attempts = 0;
+        while offset < t.size {
+            if self.fault == Some(Fault::Stall) && offset >= t.size / 2 {
+                info!("PLDM: stalling firmware transfer at {offset}");
+                while self.state.lock().unwrap().current(t) {
+                    smol::Timer::after(RETRY_DELAY).await;
+                }
+                return None;
+            }
+
+            let len = t.transfer_size.min(t.size - offset);
+            let mut req = offset.to_le_bytes().to_vec();
+            req.extend_from_slice(&len.to_le_bytes());
+            let resp = request(chan, iid, REQUEST_FIRMWARE_DATA, &req).await;
+            if !self.state.lock().unwrap().current(t) {
+                return None;
+            }
+            match resp {
+                Some((0, data)) if data.len() == len as usize => {
+                    if !file.write(&data).await {
+                        // the error is reported by finish()
+                        break;
+                    }
+                    offset += len;
+                    attempts = 0;
+                    self.state.lock().unwrap().progress =
+                        (offset as u64 * 100 / t.size as u64) as u8;
+                }
+                Some((PLDM_FWUP_CANCEL_PENDING, _)) => {
+                    debug!("PLDM: UA cancelling the firmware transfer");
+                    return Some(false);
+                }
+                // retried, as is a missing response
+                Some((PLDM_FWUP_RETRY_REQUEST_FW_DATA, _)) | None
+                    if attempts + 1 < REQUEST_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    smol::Timer::after(RETRY_DELAY).await;
+                }
+                r => {
+                    warn!(
+                        "PLDM: RequestFirmwareData at {offset} failed: \
+                         {:?}",
+                        r.map(|(cc, _)| cc)
+                    );
+                    return Some(false);
+                }
+            }
+        }
+        if let Err(e) = file.finish().await {
+            warn!("PLDM: write to {} failed: {e}", path.display());
+            return Some(false);
+        }
+        Some(offset >= t.size)
+    }
+
+    // Send a TransferComplete, VerifyComplete or ApplyComplete. Returns
+    // false if the UA did not accept it, ending the component update.
+    async fn report(
+        &self,
+        chan: &mut impl AsyncReqChannel,
+        iid: &mut u8,
+        t: &Transfer,
+        cmd: u8,
+        data: &[u8],
+    ) -> bool {
+        debug!("PLDM: firmware command {cmd:#04x}, result {:#04x}", data[0]);
+        match request(chan, iid, cmd, data).await {
+            Some((0, _)) => true,
+            r => {
+                warn!(
+                    "PLDM: firmware command {cmd:#04x} failed: {:?}",
+                    r.map(|(cc, _)| cc)
+                );
+                self.state
+                    .lock()
+                    .unwrap()
+                    .step(t, false, FdState::ReadyXfer);
+                false
+            }
+        }
+    }
+}
+
+// Send a firmware update request to the UA, returning the completion code
+// and the response body following it, or None if there was no response
+async fn request(
+    chan: &mut impl AsyncReqChannel,
+    iid: &mut u8,
+    cmd: u8,
+    data: &[u8],
+) -> Option<(u8, Vec<u8>)> {
+    *iid = (*iid + 1) & 0x1f;
+    let mut req = vec![0x80 | *iid, PLDM_TYPE_FW_UPDATE, cmd];
+    req.extend_from_slice(data);
+    if let Err(e) = chan.send(mctp::MCTP_TYPE_PLDM, &req).await {
+        debug!("PLDM: firmware request {cmd:#04x} failed: {e}");
+        return None;
+    }
+
+    let mut buf = [0u8; MAX_TRANSFER_SIZE as usize + 4];
+    let resp = futures::future::select(
+        Box::pin(chan.recv(&mut buf)),
+        smol::Timer::after(RESPONSE_TIMEOUT),
+    )
+    .await;
+    let futures::future::Either::Left((Ok((_typ, _ic, msg)), _)) = resp else {
+        debug!("PLDM: no response to firmware request {cmd:#04x}");
+        return None;
+    };
+    match *msg {
+        [hdr, _, c, cc, ref rest @ ..] if hdr & 0x1f == *iid && c == cmd => {
+            Some((cc, rest.to_vec()))
+        }
+        _ => None,
+    }
+}
+
+impl TypeResponder for FirmwareDevice {
+    fn typ(&self) -> u8 {
+        PLDM_TYPE_FW_UPDATE
+    }
+
+    fn versions(&self) -> &[u32] {
+        &VERSIONS
+    }
+
+    fn commands(&self) -> &[u8] {
+        &COMMANDS
+    }
+
+    fn command(
+        &mut self,
+        eid: Eid,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        let mut st = self.state.lock().unwrap();
+        match cmd {
+            QUERY_DEVICE_IDENTIFIERS => Ok(st.query_device_identifiers()),
+            GET_FIRMWARE_PARAMETERS => Ok(st.get_firmware_parameters()),
+            REQUEST_UPDATE => st.request_update(eid, data),
+            PASS_COMPONENT_TABLE => st.pass_component_table(data),
+            UPDATE_COMPONENT => {
+                let (body, transfer) = st.update_component(data)?;
+                if let Some(t) = transfer {
+                    if self.transfers.try_send(t).is_err() {
+                        st.cancel_component();
+                        st.enter(FdState::ReadyXfer);
+                        return Err(PLDM_FWUP_BUSY_IN_BACKGROUND);
+                    }
+                }
+                Ok(body)
+            }
+            ACTIVATE_FIRMWARE => st.activate_firmware(data),
+            GET_STATUS => Ok(st.get_status()),
+            CANCEL_UPDATE_COMPONENT => st.cancel_update_component(),
+            CANCEL_UPDATE => st.cancel_update(),
+            _ => Err(PLDM_ERROR_UNSUPPORTED_PLDM_CMD),
+        }
+    }
+}
+
+// Validate the parsed config
+fn validate(config: Config) -> Result<State> {
+    if config.descriptors.is_empty() {
+        bail!("at least one descriptor is needed");
+    }
+    if config.version.len() > u8::MAX as usize {
+        bail!("version {:?} is too long", config.version);
+    }
+    let mut descriptors = Vec::new();
+    for d in config.descriptors {
+        let value = hex::decode(&d.value)
+            .with_context(|| format!("invalid descriptor {:?}", d.value))?;
+        if value.is_empty() || value.len() > u16::MAX as usize {
+            bail!("invalid descriptor length {}", value.len());
+        }
+        descriptors.push((d.typ, value));
+    }
+
+    let mut components: Vec<Component> = Vec::new();
+    for c in config.components {
+        if components.iter().any(|e| {
+            e.classification == c.classification
+                && e.id == c.id
+                && e.index == c.index
+        }) {
+            bail!("duplicate component {:#06x}/{}", c.classification, c.id);
+        }
+        if c.version.len() > u8::MAX as usize {
+            bail!("version {:?} is too long", c.version);
+        }
+        components.push(Component {
+            classification: c.classification,
+            id: c.id,
+            index: c.index,
+            stamp: c.comparison_stamp,
+            version: c.version,
+            pending: None,
+            offered: false,
+        });
+    }
+    if components.is_empty() {
+        bail!("at least one component is needed");
+    }
+
+    Ok(State {
+        descriptors,
+        version: config.version,
+        pending_version: None,
+        components,
+        fd: FdState::Idle,
+        previous: FdState::Idle,
+        update: None,
+        current: None,
+        progress: 0,
+        succeeded: None,
+        reason: REASON_INITIALIZATION,
+        update_flags: 0,
+        generation: 0,
+    })
+}
+
+/// Load a firmware device config, writing received images to `dir`, and
+/// injecting the failure `fault` if set
+pub fn load(
+    path: &str,
+    dir: &str,
+    fault: Option<&str>,
+) -> Result<FirmwareDevice> {
+    let fault = fault
+        .map(str::parse)
+        .transpose()
+        .context("invalid --pldm-fw-fail")?;
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Can't read PLDM firmware config {path}"))?;
+    let config: Config = toml::from_str(&text)
+        .with_context(|| format!("Invalid PLDM firmware config {path}"))?;
+    let state = validate(config)
+        .with_context(|| format!("Invalid PLDM firmware config {path}"))?;
+    if !Path::new(dir).is_dir() {
+        bail!("PLDM firmware directory {dir} does not exist");
+    }
+
+    info!(
+        "PLDM: firmware device, {} components, version {:?}",
+        state.components.len(),
+        state.version
+    );
+    let (transfers, pending) = async_channel::bounded(1);
+    Ok(FirmwareDevice {
+        state: Arc::new(Mutex::new(state)),
+        transfers,
+        pending,
+        dir: PathBuf::from(dir),
+        fault,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const UA: Eid = Eid(8);
+    const IMAGE_SIZE: usize = 200;
+
+    // An update agent serving a component image, and recording the
+    // requests it receives
+    struct Ua {
+        image: Vec<u8>,
+        requests: Vec<(u8, Vec<u8>)>,
+        // RequestFirmwareData requests served
+        served: Rc<Cell<usize>>,
+        resp: Option<Vec<u8>>,
+    }
+
+    impl Ua {
+        fn new() -> Self {
+            Self {
+                image: (0..IMAGE_SIZE).map(|i| (i * 11 % 251) as u8).collect(),
+                requests: Vec::new(),
+                served: Rc::default(),
+                resp: None,
+            }
+        }
+
+        // The commands received, and the offsets of the image requested
+        fn commands(&self) -> (Vec<u8>, Vec<u32>) {
+            let cmds = self.requests.iter().map(|(c, _)| *c).collect();
+            let offsets = self
+                .requests
+                .iter()
+                .filter(|(c, _)| *c == REQUEST_FIRMWARE_DATA)
+                .map(|(_, d)| u32::from_le_bytes(d[..4].try_into().unwrap()))
+                .collect();
+            (cmds, offsets)
+        }
+    }
+
+    impl AsyncReqChannel for Ua {
+        async fn send_vectored(
+            &mut self,
+            typ: mctp::MsgType,
+            _ic: mctp::MsgIC,
+            bufs: &[&[u8]],
+        ) -> mctp::Result<()> {
+            assert_eq!(typ, mctp::MCTP_TYPE_PLDM);
+            let req = bufs.concat();
+            assert_eq!(req[1], PLDM_TYPE_FW_UPDATE);
+            let (cmd, data) = (req[2], &req[3..]);
+            let mut resp = vec![req[0] & 0x1f, req[1], cmd, 0];
+            if cmd == REQUEST_FIRMWARE_DATA {
+                let u32_at = |i: usize| {
+                    u32::from_le_bytes(data[i..i + 4].try_into().unwrap())
+                };
+                let offset = u32_at(0) as usize;
+                let len = u32_at(4) as usize;
+                resp.extend_from_slice(&self.image[offset..offset + len]);
+                self.served.set(self.served.get() + 1);
+            }
+            self.requests.push((cmd, data.to_vec()));
+            self.resp = Some(resp);
+            Ok(())
+        }
+
+        async fn recv<'f>(
+            &mut self,
+            buf: &'f mut [u8],
+        ) -> mctp::Result<(mctp::MsgType, mctp::MsgIC, &'f mut [u8])> {
+            let resp = self.resp.take().ok_or(mctp::Error::TimedOut)?;
+            let buf = buf.get_mut(..resp.len()).ok_or(mctp::Error::NoSpace)?;
+            buf.copy_from_slice(&resp);
+            Ok((mctp::MCTP_TYPE_PLDM, mctp::MsgIC(false), buf))
+        }
+
+        fn remote_eid(&self) -> Eid {
+            UA
+        }
+    }
+
+    // A firmware device from the example config, writing images to a
+    // temporary directory
+    fn device(name: &str, fault: Option<&str>) -> (FirmwareDevice, PathBuf) {
+        let dir = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-fw-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config =
+            concat!(env!("CARGO_MANIFEST_DIR"), "/examples/pldm-fw.toml");
+        let dev = load(config, dir.to_str().unwrap(), fault).unwrap();
+        (dev, dir)
+    }
+
+    fn fd_state(dev: &FirmwareDevice) -> (FdState, FdState) {
+        let st = dev.state.lock().unwrap();
+        (st.fd, st.previous)
+    }
+
+    fn push_string(data: &mut Vec<u8>, s: &str) {
+        push_string_header(data, s);
+        data.extend_from_slice(s.as_bytes());
+    }
+
+    // Component 1, with comparison stamp `stamp`
+    fn component(stamp: u32) -> Vec<u8> {
+        let mut data = 0x000au16.to_le_bytes().to_vec();
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.push(0);
+        data.extend_from_slice(&stamp.to_le_bytes());
+        data
+    }
+
+    // RequestUpdate with 64 byte transfers, then PassComponentTable with
+    // component 1, returning the component response
+    fn start(dev: &mut FirmwareDevice, stamp: u32) -> Vec<u8> {
+        let mut data = 64u32.to_le_bytes().to_vec();
+        // one component, one outstanding request, no package data
+        data.extend_from_slice(&[1, 0, 1, 0, 0]);
+        push_string(&mut data, "2.0.0");
+        let r = dev.command(UA, REQUEST_UPDATE, &data);
+        assert_eq!(r, Ok(vec![0, 0, 0]));
+        assert_eq!(fd_state(dev).0, FdState::LearnComponents);
+        let r = dev.command(UA, REQUEST_UPDATE, &data);
+        assert_eq!(r, Err(PLDM_FWUP_ALREADY_IN_UPDATE_MODE));
+
+        let mut data = vec![TRANSFER_FLAG_END | 0x01];
+        data.extend_from_slice(&component(stamp));
+        push_string(&mut data, "1.1.0");
+        let r = dev.command(UA, PASS_COMPONENT_TABLE, &data).unwrap();
+        assert_eq!(fd_state(dev).0, FdState::ReadyXfer);
+        r
+    }
+
+    // UpdateComponent for component 1, returning the response and the
+    // transfer queued for the device to run
+    fn update_component(
+        dev: &mut FirmwareDevice,
+        stamp: u32,
+        flags: u32,
+    ) -> (Result<Vec<u8>, u8>, Option<Transfer>) {
+        let mut data = component(stamp);
+        data.extend_from_slice(&(IMAGE_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&flags.to_le_bytes());
+        push_string(&mut data, "1.1.0");
+        let r = dev.command(UA, UPDATE_COMPONENT, &data);
+        (r, dev.pending.try_recv().ok())
+    }
+
+    // A component update through transfer, verify and apply, then
+    // activation
+    #[test]
+    fn update_and_activate() {
+        let (mut dev, dir) = device("update", None);
+        let ids = dev.command(UA, QUERY_DEVICE_IDENTIFIERS, &[]).unwrap();
+        assert_eq!(ids[4], 2);
+        let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+        assert_eq!(r[..6], [0, 0, AUX_IDLE, 0, PROGRESS_NOT_SUPPORTED, 0]);
+        let (r, _) = update_component(&mut dev, 0x200, 0);
+        assert_eq!(r, Err(PLDM_FWUP_NOT_IN_UPDATE_MODE));
+
+        assert_eq!(start(&mut dev, 0x200), [COMPONENT_CAN_BE_UPDATED, 0]);
+        let (r, t) = update_component(&mut dev, 0x200, 0);
+        assert_eq!(r, Ok(vec![0, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(fd_state(&dev), (FdState::Download, FdState::ReadyXfer));
+        let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+        assert_eq!(r[..5], [3, 2, AUX_IN_PROGRESS, 0, 0]);
+
+        let mut ua = Ua::new();
+        smol::block_on(dev.update(&mut ua, &mut 0, &t.unwrap()));
+        let (cmds, offsets) = ua.commands();
+        assert_eq!(offsets, [0, 64, 128, 192]);
+        assert_eq!(
+            cmds[4..],
+            [TRANSFER_COMPLETE, VERIFY_COMPLETE, APPLY_COMPLETE]
+        );
+        assert!(ua.requests[4..].iter().all(|(_, d)| d[0] == 0));
+        let image = std::fs::read(dir.join("component-000a-0001.bin"));
+        assert_eq!(image.unwrap(), ua.image);
+        assert_eq!(fd_state(&dev), (FdState::ReadyXfer, FdState::Apply));
+        let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+        assert_eq!(r[2..4], [AUX_SUCCEEDED, 0]);
+
+        // the pending version follows the active one
+        let r = dev.command(UA, GET_FIRMWARE_PARAMETERS, &[]).unwrap();
+        assert_eq!(r[10..20], *b"1.0.02.0.0");
+
+        assert_eq!(dev.command(UA, ACTIVATE_FIRMWARE, &[1]), Ok(vec![0, 0]));
+        assert_eq!(fd_state(&dev), (FdState::Idle, FdState::Activate));
+        let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+        assert_eq!(r[5], REASON_ACTIVATE_FIRMWARE);
+        {
+            let st = dev.state.lock().unwrap();
+            assert_eq!(st.version, "2.0.0");
+            assert_eq!(st.components[0].stamp, 0x200);
+            assert_eq!(st.components[0].version, "1.1.0");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // An image with the same comparison stamp is refused unless forced,
+    // and cancelling leaves update mode
+    #[test]
+    fn update_refused() {
+        let (mut dev, dir) = device("refused", None);
+        let r = start(&mut dev, 0x100);
+        assert_eq!(
+            r,
+            [COMPONENT_CANNOT_BE_UPDATED, COMPARISON_STAMP_IDENTICAL]
+        );
+        // not accepted by PassComponentTable, so not updated even if forced
+        let (r, t) = update_component(&mut dev, 0x100, FORCE_UPDATE);
+        let r = r.unwrap();
+        assert_eq!(
+            r[..2],
+            [COMPONENT_CANNOT_BE_UPDATED, COMPONENT_NOT_SUPPORTED]
+        );
+        assert!(t.is_none());
+        assert_eq!(fd_state(&dev).0, FdState::ReadyXfer);
+
+        let r = dev.command(UA, ACTIVATE_FIRMWARE, &[1]);
+        assert_eq!(r, Err(PLDM_FWUP_INCOMPLETE_UPDATE));
+        let r = dev.command(UA, CANCEL_UPDATE_COMPONENT, &[]);
+        assert_eq!(r, Err(PLDM_FWUP_INVALID_STATE_FOR_COMMAND));
+        let r = dev.command(UA, CANCEL_UPDATE, &[]);
+        assert_eq!(r, Ok(vec![0; 9]));
+        assert_eq!(fd_state(&dev), (FdState::Idle, FdState::ReadyXfer));
+        let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+        assert_eq!(r[5], REASON_CANCEL_UPDATE);
+        let r = dev.command(UA, CANCEL_UPDATE, &[]);
+        assert_eq!(r, Err(PLDM_FWUP_NOT_IN_UPDATE_MODE));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Injected verify and apply failures end the component update at that
+    // step, leaving nothing to activate
+    #[test]
+    fn update_faults() {
+        let cases = [
+            ("verify", VERIFY_COMPLETE, VERIFY_ERROR_VERIFICATION_FAILURE),
+            ("apply", APPLY_COMPLETE, APPLY_FAILURE_MEMORY_ISSUE),
+        ];
+        for (fault, cmd, result) in cases {
+            let (mut dev, dir) = device(fault, Some(fault));
+            start(&mut dev, 0x200);
+            let (_, t) = update_component(&mut dev, 0x200, 0);
+
+            let mut ua = Ua::new();
+            smol::block_on(dev.update(&mut ua, &mut 0, &t.unwrap()));
+            let (last, data) = ua.requests.last().unwrap();
+            assert_eq!((*last, data[0]), (cmd, result), "{fault}");
+            assert_eq!(fd_state(&dev).0, FdState::ReadyXfer);
+            let r = dev.command(UA, GET_STATUS, &[]).unwrap();
+            assert_eq!(r[2..4], [AUX_FAILED, AUX_STATUS_GENERIC_ERROR]);
+            let r = dev.command(UA, ACTIVATE_FIRMWARE, &[1]);
+            assert_eq!(r, Err(PLDM_FWUP_INCOMPLETE_UPDATE));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    // A stalled transfer requests no more data until the UA cancels the
+    // component update
+    #[test]
+    fn stall_and_cancel() {
+        let (mut dev, dir) = device("stall", Some("stall"));
+        start(&mut dev, 0x200);
+        let (_, t) = update_component(&mut dev, 0x200, 0);
+
+        let mut ua = Ua::new();
+        let served = ua.served.clone();
+        let mut ctl = dev.clone();
+        let cancel = async {
+            // the requests before the halfway point
+            while served.get() < 2 {
+                smol::Timer::after(Duration::from_millis(10)).await;
+            }
+            assert_eq!(fd_state(&ctl).0, FdState::Download);
+            ctl.command(UA, CANCEL_UPDATE_COMPONENT, &[])
+        };
+        let (_, r) = smol::block_on(futures::future::join(
+            dev.update(&mut ua, &mut 0, &t.unwrap()),
+            cancel,
+        ));
+        assert_eq!(r, Ok(vec![]));
+
+        let (cmds, offsets) = ua.commands();
+        assert_eq!(offsets, [0, 64]);
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(fd_state(&dev), (FdState::ReadyXfer, FdState::Download));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1150 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PLDM Platform Monitoring and Control (type 2) responder, with simulated
+ * numeric sensors from a TOML file given with --pldm-sensors:
+ *
+ *   [[sensor]]
+ *   id = 1
+ *   unit = "celsius"       # celsius, volts, amps, watts or rpm
+ *   unit-modifier = 0      # optional, power of ten scaling the readings
+ *   min = 0                # readable range
+ *   max = 120
+ *   nominal = 35
+ *   warning-high = 70      # optional thresholds, each also with a -low
+ *   critical-high = 85
+ *   fatal-high = 100
+ *   profile = "sine"       # constant (default), sine or ramp
+ *   period = 60            # seconds, for sine and ramp
+ *   amplitude = 20         # for sine
+ *
+ * A sine sensor varies around its nominal value, and a ramp sensor rises
+ * from min to max and falls back over each period. Readings are computed
+ * when requested, from the time since startup.
+ *
+ * Each sensor has a Numeric Sensor PDR, with record handles following the
+ * order of the file. Readings and ranges are sint32 values. Records added
+ * by the other responders, such as the file transfer responder's File
+ * Descriptor PDRs, follow the sensors' records.
+ *
+ * Sensors are updated each second. Once a sensor's events are enabled by
+ * SetNumericSensorEnable, a change of its threshold state queues a numeric
+ * sensor state event. Up to MAX_EVENTS are retained, dropping the oldest,
+ * until they are delivered: by PlatformEventMessage to the receiver set by
+ * SetEventReceiver in async mode, or acknowledged through
+ * PollForPlatformEventMessage. Events queue while no receiver is set.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use mctp::{AsyncReqChannel, Eid};
+use mctp_estack::router::Router;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use toml::Spanned;
+
+use crate::pldmbase::{Terminus, TypeResponder};
+
+pub const PLDM_TYPE_PLATFORM: u8 = 0x02;
+
+/* DSP0248 1.2.0 */
+const VERSIONS: [u32; 1] = [0xf1f2_f000];
+
+/* platform commands */
+const SET_NUMERIC_SENSOR_ENABLE: u8 = 0x10;
+const GET_SENSOR_READING: u8 = 0x11;
+const GET_PDR_REPOSITORY_INFO: u8 = 0x50;
+const GET_PDR: u8 = 0x51;
+const SET_EVENT_RECEIVER: u8 = 0x04;
+const PLATFORM_EVENT_MESSAGE: u8 = 0x0a;
+const POLL_FOR_PLATFORM_EVENT_MESSAGE: u8 = 0x0b;
+
+const COMMANDS: [u8; 6] = [
+    SET_EVENT_RECEIVER,
+    POLL_FOR_PLATFORM_EVENT_MESSAGE,
+    SET_NUMERIC_SENSOR_ENABLE,
+    GET_SENSOR_READING,
+    GET_PDR_REPOSITORY_INFO,
+    GET_PDR,
+];
+
+/* completion codes */
+const PLDM_ERROR_INVALID_DATA: u8 = 0x02;
+const PLDM_ERROR_INVALID_LENGTH: u8 = 0x03;
+const PLDM_ERROR_UNSUPPORTED_PLDM_CMD: u8 = 0x05;
+const PLDM_PLATFORM_INVALID_SENSOR_ID: u8 = 0x80;
+const PLDM_PLATFORM_INVALID_PROTOCOL_TYPE: u8 = 0x80;
+const PLDM_PLATFORM_ENABLE_METHOD_NOT_SUPPORTED: u8 = 0x81;
+const PLDM_PLATFORM_INVALID_DATA_TRANSFER_HANDLE: u8 = 0x80;
+const PLDM_PLATFORM_INVALID_TRANSFER_OPERATION_FLAG: u8 = 0x81;
+const PLDM_PLATFORM_INVALID_RECORD_HANDLE: u8 = 0x82;
+
+/* GetPDR and PollForPlatformEventMessage transfer operation and transfer
+ * flags */
+const GET_NEXT_PART: u8 = 0x00;
+const GET_FIRST_PART: u8 = 0x01;
+const ACKNOWLEDGEMENT_ONLY: u8 = 0x02;
+const TRANSFER_START: u8 = 0x00;
+const TRANSFER_MIDDLE: u8 = 0x01;
+const TRANSFER_END: u8 = 0x04;
+const TRANSFER_START_AND_END: u8 = 0x05;
+
+/* Numeric Sensor PDR */
+const PDR_HEADER_VERSION: u8 = 0x01;
+const PDR_TYPE_NUMERIC_SENSOR: u8 = 2;
+const DATA_SIZE_SINT32: u8 = 5;
+const RANGE_FORMAT_SINT32: u8 = 5;
+
+/* sensor operational states */
+const SENSOR_ENABLED: u8 = 0;
+const SENSOR_UNAVAILABLE: u8 = 2;
+
+/* sensor event message enables */
+const NO_EVENT_GENERATION: u8 = 0;
+const EVENTS_ENABLED: u8 = 2;
+const STATE_EVENTS_ONLY_ENABLED: u8 = 4;
+
+/* SetEventReceiver global enables, and the MCTP transport protocol */
+const EVENT_DISABLE: u8 = 0;
+const EVENT_ENABLE_ASYNC: u8 = 1;
+const EVENT_ENABLE_POLLING: u8 = 2;
+const TRANSPORT_MCTP: u8 = 0;
+
+/* event messages */
+const EVENT_FORMAT_VERSION: u8 = 0x01;
+const EVENT_CLASS_SENSOR: u8 = 0x00;
+const NUMERIC_SENSOR_STATE: u8 = 0x02;
+/* PollForPlatformEventMessage event IDs for no event, and acknowledgement */
+const EVENT_ID_NONE: u16 = 0x0000;
+const EVENT_ID_ACK: u16 = 0xffff;
+
+const MAX_EVENTS: usize = 16;
+/* attempts at delivering an event before it is dropped */
+const EVENT_SEND_ATTEMPTS: u32 = 5;
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/* sensor states, for the present, previous and event states */
+const STATE_NORMAL: u8 = 0x01;
+const STATE_LOWER_WARNING: u8 = 0x05;
+const STATE_LOWER_CRITICAL: u8 = 0x06;
+const STATE_LOWER_FATAL: u8 = 0x07;
+const STATE_UPPER_WARNING: u8 = 0x08;
+const STATE_UPPER_CRITICAL: u8 = 0x09;
+const STATE_UPPER_FATAL: u8 = 0x0a;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Unit {
+    Celsius,
+    Volts,
+    Amps,
+    Watts,
+    Rpm,
+}
+
+impl Unit {
+    // DSP0248 base unit code
+    fn code(self) -> u8 {
+        match self {
+            Unit::Celsius => 2,
+            Unit::Volts => 5,
+            Unit::Amps => 6,
+            Unit::Watts => 7,
+            Unit::Rpm => 19,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Profile {
+    Constant,
+    Sine,
+    Ramp,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SensorConfig {
+    id: Spanned<u16>,
+    unit: Unit,
+    #[serde(default)]
+    unit_modifier: i8,
+    min: i32,
+    max: Spanned<i32>,
+    nominal: Spanned<i32>,
+    warning_high: Option<Spanned<i32>>,
+    critical_high: Option<Spanned<i32>>,
+    fatal_high: Option<Spanned<i32>>,
+    warning_low: Option<Spanned<i32>>,
+    critical_low: Option<Spanned<i32>>,
+    fatal_low: Option<Spanned<i32>>,
+    profile: Option<Spanned<Profile>>,
+    period: Option<Spanned<u32>>,
+    #[serde(default)]
+    amplitude: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default, rename = "sensor")]
+    sensors: Vec<SensorConfig>,
+}
+
+/// Thresholds, in order of increasing severity
+#[derive(Clone, Copy, Debug, Default)]
+struct Thresholds {
+    warning: Option<i32>,
+    critical: Option<i32>,
+    fatal: Option<i32>,
+}
+
+#[derive(Debug)]
+struct Sensor {
+    id: u16,
+    unit: Unit,
+    unit_modifier: i8,
+    min: i32,
+    max: i32,
+    nominal: i32,
+    high: Thresholds,
+    low: Thresholds,
+    profile: Profile,
+    // seconds
+    period: u32,
+    amplitude: i32,
+    op_state: u8,
+    event_enable: u8,
+    state: u8,
+    // the state before the last change
+    previous_state: u8,
+}
+
+impl Sensor {
+    // The simulated reading, `t` seconds after startup
+    fn value(&self, t: f64) -> i32 {
+        let phase = match self.profile {
+            Profile::Constant => return self.nominal,
+            _ => (t / self.period as f64).fract(),
+        };
+        let v = match self.profile {
+            Profile::Sine => {
+                self.nominal as f64
+                    + self.amplitude as f64
+                        * (phase * std::f64::consts::TAU).sin()
+            }
+            _ => {
+                let span = self.max as f64 - self.min as f64;
+                self.min as f64 + span * (1.0 - (2.0 * phase - 1.0).abs())
+            }
+        };
+        (v.round() as i32).clamp(self.min, self.max)
+    }
+
+    // The sensor state for a reading of `v`
+    fn state_of(&self, v: i32) -> u8 {
+        let upper = [
+            (self.high.fatal, STATE_UPPER_FATAL),
+            (self.high.critical, STATE_UPPER_CRITICAL),
+            (self.high.warning, STATE_UPPER_WARNING),
+        ];
+        let lower = [
+            (self.low.fatal, STATE_LOWER_FATAL),
+            (self.low.critical, STATE_LOWER_CRITICAL),
+            (self.low.warning, STATE_LOWER_WARNING),
+        ];
+        upper
+            .into_iter()
+            .find_map(|(t, s)| t.is_some_and(|t| v >= t).then_some(s))
+            .or_else(|| {
+                lower
+                    .into_iter()
+                    .find_map(|(t, s)| t.is_some_and(|t| v <= t).then_some(s))
+            })
+            .unwrap_or(STATE_NORMAL)
+    }
+
+    // The Numeric Sensor PDR, with record handle `handle`
+    fn pdr(&self, handle: u32) -> Vec<u8> {
+        let mut b = Vec::new();
+        // terminus handle, sensor ID, entity type, instance and container
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&self.id.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        // sensor init, no auxiliary names
+        b.extend_from_slice(&[0, 0]);
+        // base unit, unit modifier, rate unit, OEM unit handle
+        b.extend_from_slice(&[
+            self.unit.code(),
+            self.unit_modifier as u8,
+            0,
+            0,
+        ]);
+        // no auxiliary unit: unit, modifier, rate, rel, OEM unit handle
+        b.extend_from_slice(&[0, 0, 0, 0, 0]);
+        // linear, with sint32 readings
+        b.extend_from_slice(&[1, DATA_SIZE_SINT32]);
+        // resolution, offset, accuracy, plus and minus tolerance
+        b.extend_from_slice(&1.0f32.to_le_bytes());
+        b.extend_from_slice(&0.0f32.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        b.extend_from_slice(&[0, 0]);
+        // hysteresis
+        b.extend_from_slice(&0i32.to_le_bytes());
+        // supported thresholds, volatility
+        let supported = [
+            self.high.warning,
+            self.high.critical,
+            self.high.fatal,
+            self.low.warning,
+            self.low.critical,
+            self.low.fatal,
+        ]
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.is_some())
+        .fold(0u8, |acc, (i, _)| acc | 1 << i);
+        b.extend_from_slice(&[supported, 0]);
+        // state transition and update intervals, in seconds
+        b.extend_from_slice(&0.0f32.to_le_bytes());
+        b.extend_from_slice(&1.0f32.to_le_bytes());
+        b.extend_from_slice(&self.max.to_le_bytes());
+        b.extend_from_slice(&self.min.to_le_bytes());
+
+        // range fields: nominal, normal max and min, then the thresholds.
+        // Normal max and min are the warning thresholds, if set.
+        let normal_max = self.high.warning.unwrap_or(self.max);
+        let normal_min = self.low.warning.unwrap_or(self.min);
+        let ranges = [
+            (Some(self.nominal), Some(0)),
+            (Some(normal_max), Some(1)),
+            (Some(normal_min), Some(2)),
+            (self.high.warning, None),
+            (self.low.warning, None),
+            (self.high.critical, Some(3)),
+            (self.low.critical, Some(4)),
+            (self.high.fatal, Some(5)),
+            (self.low.fatal, Some(6)),
+        ];
+        let support = ranges
+            .iter()
+            .filter_map(|(v, bit)| v.and(*bit))
+            .fold(0u8, |acc, bit| acc | 1 << bit);
+        b.extend_from_slice(&[RANGE_FORMAT_SINT32, support]);
+        for (v, _) in ranges {
+            b.extend_from_slice(&v.unwrap_or(0).to_le_bytes());
+        }
+
+        pdr_record(handle, PDR_TYPE_NUMERIC_SENSOR, &b)
+    }
+}
+
+// A PDR of type `typ`, with record handle `handle` and data `body`
+fn pdr_record(handle: u32, typ: u8, body: &[u8]) -> Vec<u8> {
+    let mut pdr = Vec::new();
+    pdr.extend_from_slice(&handle.to_le_bytes());
+    pdr.extend_from_slice(&[PDR_HEADER_VERSION, typ]);
+    // record change number
+    pdr.extend_from_slice(&0u16.to_le_bytes());
+    pdr.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    pdr.extend_from_slice(body);
+    pdr
+}
+
+// CRC-8 with polynomial x^8 + x^2 + x + 1, for multipart GetPDR transfers
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// An event message, retained until delivered
+#[derive(Clone)]
+struct Event {
+    id: u16,
+    // sensor event data, following the event class
+    data: Vec<u8>,
+    // failed deliveries to the event receiver
+    attempts: u32,
+}
+
+/// The event receiver set by SetEventReceiver
+#[derive(Clone, Copy, Debug)]
+struct Receiver {
+    eid: Eid,
+    // EVENT_ENABLE_ASYNC or EVENT_ENABLE_POLLING
+    mode: u8,
+}
+
+struct State {
+    sensors: Vec<Sensor>,
+    // PDRs in record handle order, from 1
+    pdrs: Vec<Vec<u8>>,
+    start: Instant,
+    receiver: Option<Receiver>,
+    events: VecDeque<Event>,
+    last_event_id: u16,
+}
+
+impl State {
+    fn sensor_idx(&self, id: u16) -> Result<usize, u8> {
+        self.sensors
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or(PLDM_PLATFORM_INVALID_SENSOR_ID)
+    }
+
+    // Update a sensor's state from its current reading, queueing an event
+    // if the state changed and its events are enabled. Returns the reading.
+    fn update(&mut self, idx: usize) -> i32 {
+        let t = self.start.elapsed().as_secs_f64();
+        let s = &mut self.sensors[idx];
+        let v = s.value(t);
+        if s.op_state != SENSOR_ENABLED {
+            return v;
+        }
+        let state = s.state_of(v);
+        if state == s.state {
+            return v;
+        }
+        debug!(
+            "PLDM: sensor {} state {:#04x} -> {state:#04x}",
+            s.id, s.state
+        );
+        s.previous_state = s.state;
+        s.state = state;
+
+        if matches!(s.event_enable, EVENTS_ENABLED | STATE_EVENTS_ONLY_ENABLED)
+        {
+            let mut data = s.id.to_le_bytes().to_vec();
+            data.extend_from_slice(&[
+                NUMERIC_SENSOR_STATE,
+                state,
+                s.previous_state,
+                DATA_SIZE_SINT32,
+            ]);
+            data.extend_from_slice(&v.to_le_bytes());
+            self.queue(data);
+        }
+        v
+    }
+
+    fn queue(&mut self, data: Vec<u8>) {
+        if self.events.len() >= MAX_EVENTS {
+            if let Some(e) = self.events.pop_front() {
+                warn!("PLDM: event queue full, dropping event {}", e.id);
+            }
+        }
+        // event IDs 0x0000 and 0xffff are reserved
+        self.last_event_id = self.last_event_id % 0xfffe + 1;
+        self.events.push_back(Event {
+            id: self.last_event_id,
+            data,
+            attempts: 0,
+        });
+    }
+
+    // The receiver and the next event to send it, in async mode
+    fn next_delivery(&self) -> Option<(Eid, Event)> {
+        let r = self.receiver.filter(|r| r.mode == EVENT_ENABLE_ASYNC)?;
+        Some((r.eid, self.events.front()?.clone()))
+    }
+
+    // Record the result of sending event `id`
+    fn delivered(&mut self, id: u16, ok: bool) {
+        let Some(e) = self.events.front_mut().filter(|e| e.id == id) else {
+            // acknowledged by a poll meanwhile, or dropped
+            return;
+        };
+        if ok {
+            self.events.pop_front();
+            return;
+        }
+        e.attempts += 1;
+        if e.attempts >= EVENT_SEND_ATTEMPTS {
+            warn!("PLDM: event {id} not accepted by the receiver, dropping");
+            self.events.pop_front();
+        }
+    }
+
+    fn set_event_receiver(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let (enable, transport, eid) = match *data {
+            [enable, transport, eid] => (enable, transport, eid),
+            [enable, transport, eid, _, _] => (enable, transport, eid),
+            _ => return Err(PLDM_ERROR_INVALID_LENGTH),
+        };
+        if transport != TRANSPORT_MCTP {
+            return Err(PLDM_PLATFORM_INVALID_PROTOCOL_TYPE);
+        }
+        self.receiver = match enable {
+            EVENT_DISABLE => None,
+            EVENT_ENABLE_ASYNC | EVENT_ENABLE_POLLING => Some(Receiver {
+                eid: Eid(eid),
+                mode: enable,
+            }),
+            // async with keep-alive needs heartbeat events
+            _ => return Err(PLDM_PLATFORM_ENABLE_METHOD_NOT_SUPPORTED),
+        };
+        info!("PLDM: event receiver {:?}", self.receiver);
+        Ok(vec![])
+    }
+
+    fn poll_for_platform_event_message(
+        &mut self,
+        tid: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        let [version, op, h0, h1, h2, h3, a0, a1] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        if version != EVENT_FORMAT_VERSION {
+            return Err(PLDM_ERROR_INVALID_DATA);
+        }
+        let ack = u16::from_le_bytes([a0, a1]);
+        if self.events.front().is_some_and(|e| e.id == ack) {
+            self.events.pop_front();
+        }
+
+        let mut body = vec![tid];
+        match op {
+            ACKNOWLEDGEMENT_ONLY => {
+                body.extend_from_slice(&EVENT_ID_ACK.to_le_bytes());
+                return Ok(body);
+            }
+            GET_FIRST_PART => (),
+            // events always fit in a single part
+            GET_NEXT_PART => {
+                return Err(PLDM_PLATFORM_INVALID_DATA_TRANSFER_HANDLE)
+            }
+            _ => return Err(PLDM_PLATFORM_INVALID_TRANSFER_OPERATION_FLAG),
+        }
+        if u32::from_le_bytes([h0, h1, h2, h3]) != 0 {
+            return Err(PLDM_PLATFORM_INVALID_DATA_TRANSFER_HANDLE);
+        }
+
+        let Some(e) = self.events.front() else {
+            body.extend_from_slice(&EVENT_ID_NONE.to_le_bytes());
+            return Ok(body);
+        };
+        body.extend_from_slice(&e.id.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&[TRANSFER_START_AND_END, EVENT_CLASS_SENSOR]);
+        body.extend_from_slice(&(e.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&e.data);
+        Ok(body)
+    }
+
+    fn set_numeric_sensor_enable(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        let [i0, i1, op_state, event_enable] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        if op_state > SENSOR_UNAVAILABLE
+            || event_enable > STATE_EVENTS_ONLY_ENABLED
+        {
+            return Err(PLDM_ERROR_INVALID_DATA);
+        }
+        let idx = self.sensor_idx(u16::from_le_bytes([i0, i1]))?;
+        let s = &mut self.sensors[idx];
+        info!(
+            "PLDM: sensor {} operational state {op_state}, events \
+             {event_enable}",
+            s.id
+        );
+        s.op_state = op_state;
+        s.event_enable = event_enable;
+        Ok(vec![])
+    }
+
+    fn get_sensor_reading(&mut self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [i0, i1, _rearm] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let idx = self.sensor_idx(u16::from_le_bytes([i0, i1]))?;
+        let v = self.update(idx);
+        let s = &self.sensors[idx];
+
+        let mut body = vec![
+            DATA_SIZE_SINT32,
+            s.op_state,
+            s.event_enable,
+            s.state,
+            s.previous_state,
+            s.state,
+        ];
+        body.extend_from_slice(&v.to_le_bytes());
+        Ok(body)
+    }
+
+    fn get_pdr_repository_info(&self) -> Vec<u8> {
+        let size = self.pdrs.iter().map(|p| p.len()).sum::<usize>();
+        let largest = self.pdrs.iter().map(|p| p.len()).max().unwrap_or(0);
+
+        // available, with unspecified update and OEM update times
+        let mut body = vec![0u8; 1 + 13 + 13];
+        body.extend_from_slice(&(self.pdrs.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(size as u32).to_le_bytes());
+        body.extend_from_slice(&(largest as u32).to_le_bytes());
+        // data transfer handle timeout, in seconds
+        body.push(1);
+        body
+    }
+
+    fn get_pdr(&self, data: &[u8]) -> Result<Vec<u8>, u8> {
+        let [h0, h1, h2, h3, t0, t1, t2, t3, op, c0, c1, _, _] = *data else {
+            return Err(PLDM_ERROR_INVALID_LENGTH);
+        };
+        let handle = u32::from_le_bytes([h0, h1, h2, h3]);
+        let offset = u32::from_le_bytes([t0, t1, t2, t3]) as usize;
+        let count = u16::from_le_bytes([c0, c1]) as usize;
+
+        // record handle 0 is the first record
+        let idx = handle.saturating_sub(1) as usize;
+        let pdr = self
+            .pdrs
+            .get(idx)
+            .ok_or(PLDM_PLATFORM_INVALID_RECORD_HANDLE)?;
+        let offset = match op {
+            GET_FIRST_PART => 0,
+            GET_NEXT_PART if offset > 0 && offset < pdr.len() => offset,
+            GET_NEXT_PART => {
+                return Err(PLDM_PLATFORM_INVALID_DATA_TRANSFER_HANDLE)
+            }
+            _ => return Err(PLDM_PLATFORM_INVALID_TRANSFER_OPERATION_FLAG),
+        };
+        let end = (offset + count).min(pdr.len());
+
+        let next_record = if idx + 1 < self.pdrs.len() {
+            idx as u32 + 2
+        } else {
+            0
+        };
+        let (flag, next_offset) = match (offset == 0, end == pdr.len()) {
+            (true, true) => (TRANSFER_START_AND_END, 0),
+            (true, false) => (TRANSFER_START, end),
+            (false, false) => (TRANSFER_MIDDLE, end),
+            (false, true) => (TRANSFER_END, 0),
+        };
+
+        let mut body = next_record.to_le_bytes().to_vec();
+        body.extend_from_slice(&(next_offset as u32).to_le_bytes());
+        body.push(flag);
+        body.extend_from_slice(&((end - offset) as u16).to_le_bytes());
+        body.extend_from_slice(&pdr[offset..end]);
+        if flag == TRANSFER_END {
+            body.push(crc8(pdr));
+        }
+        Ok(body)
+    }
+}
+
+/// The platform monitoring responder, and its sensors and events
+#[derive(Clone)]
+pub struct Platform {
+    state: Arc<Mutex<State>>,
+    // our TID, reported in events
+    terminus: Arc<Terminus>,
+}
+
+impl Platform {
+    fn new(sensors: Vec<Sensor>, terminus: Arc<Terminus>) -> Self {
+        let pdrs = sensors
+            .iter()
+            .zip(1..)
+            .map(|(s, handle)| s.pdr(handle))
+            .collect();
+        let state = State {
+            sensors,
+            pdrs,
+            start: Instant::now(),
+            receiver: None,
+            events: VecDeque::new(),
+            last_event_id: 0,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            terminus,
+        }
+    }
+
+    /// The responder, for registering with the base responder
+    pub fn responder(&self) -> Box<dyn TypeResponder> {
+        Box::new(self.clone())
+    }
+
+    /// Add a PDR of type `typ` with data `body` to the repository, after
+    /// the existing records
+    pub fn add_pdr(&self, typ: u8, body: &[u8]) {
+        let mut st = self.state.lock().unwrap();
+        let handle = st.pdrs.len() as u32 + 1;
+        st.pdrs.push(pdr_record(handle, typ, body));
+    }
+
+    /// Update the sensors, delivering events to the event receiver in
+    /// async mode
+    pub async fn events(self, router: &Router<'_>) {
+        let mut iid = 0u8;
+        loop {
+            smol::Timer::after(UPDATE_INTERVAL).await;
+
+            // deliver queued events in order, until one fails
+            let mut next = {
+                let mut st = self.state.lock().unwrap();
+                for idx in 0..st.sensors.len() {
+                    st.update(idx);
+                }
+                st.next_delivery()
+            };
+            while let Some((eid, ev)) = next {
+                let tid = self.terminus.tid();
+                let ok = send_event(router, eid, tid, &mut iid, &ev).await;
+                let mut st = self.state.lock().unwrap();
+                st.delivered(ev.id, ok);
+                next = if ok { st.next_delivery() } else { None };
+            }
+        }
+    }
+}
+
+// Send an event to the receiver at `eid` in a PlatformEventMessage,
+// returning whether it was accepted
+async fn send_event(
+    router: &Router<'_>,
+    eid: Eid,
+    tid: u8,
+    iid: &mut u8,
+    ev: &Event,
+) -> bool {
+    *iid = (*iid + 1) & 0x1f;
+    let mut req = vec![
+        0x80 | *iid,
+        PLDM_TYPE_PLATFORM,
+        PLATFORM_EVENT_MESSAGE,
+        EVENT_FORMAT_VERSION,
+        tid,
+        EVENT_CLASS_SENSOR,
+    ];
+    req.extend_from_slice(&ev.data);
+
+    let mut chan = router.req(eid);
+    if let Err(e) = chan.send(mctp::MCTP_TYPE_PLDM, &req).await {
+        debug!("PLDM: event {} send failed: {e}", ev.id);
+        return false;
+    }
+    let mut buf = [0u8; 16];
+    let resp = futures::future::select(
+        Box::pin(chan.recv(&mut buf)),
+        smol::Timer::after(RESPONSE_TIMEOUT),
+    )
+    .await;
+    let futures::future::Either::Left((Ok((_typ, _ic, msg)), _)) = resp else {
+        debug!("PLDM: no response to event {}", ev.id);
+        return false;
+    };
+    match *msg {
+        [hdr, _, cmd, 0, ..]
+            if hdr & 0x1f == *iid && cmd == PLATFORM_EVENT_MESSAGE =>
+        {
+            debug!("PLDM: event {} delivered to {eid}", ev.id);
+            true
+        }
+        [_, _, _, cc, ..] => {
+            debug!("PLDM: event {} failed, cc {cc:#04x}", ev.id);
+            false
+        }
+        _ => false,
+    }
+}
+
+impl TypeResponder for Platform {
+    fn typ(&self) -> u8 {
+        PLDM_TYPE_PLATFORM
+    }
+
+    fn versions(&self) -> &[u32] {
+        &VERSIONS
+    }
+
+    fn commands(&self) -> &[u8] {
+        &COMMANDS
+    }
+
+    fn command(
+        &mut self,
+        _eid: Eid,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, u8> {
+        let tid = self.terminus.tid();
+        let mut st = self.state.lock().unwrap();
+        match cmd {
+            SET_EVENT_RECEIVER => st.set_event_receiver(data),
+            POLL_FOR_PLATFORM_EVENT_MESSAGE => {
+                st.poll_for_platform_event_message(tid, data)
+            }
+            SET_NUMERIC_SENSOR_ENABLE => st.set_numeric_sensor_enable(data),
+            GET_SENSOR_READING => st.get_sensor_reading(data),
+            GET_PDR_REPOSITORY_INFO => Ok(st.get_pdr_repository_info()),
+            GET_PDR => st.get_pdr(data),
+            _ => Err(PLDM_ERROR_UNSUPPORTED_PLDM_CMD),
+        }
+    }
+}
+
+// Check that thresholds lie within the readable range, and are ordered by
+// severity, away from the nominal value
+fn validate_thresholds(
+    s: &SensorConfig,
+    high: bool,
+) -> Result<Thresholds, (String, usize)> {
+    let (name, fields) = if high {
+        ("high", [&s.warning_high, &s.critical_high, &s.fatal_high])
+    } else {
+        ("low", [&s.warning_low, &s.critical_low, &s.fatal_low])
+    };
+    let nominal = *s.nominal.get_ref();
+    let (min, max) = (s.min, *s.max.get_ref());
+
+    let mut prev = nominal;
+    for t in fields.iter().copied().flatten() {
+        let v = *t.get_ref();
+        let ordered = if high { v > prev } else { v < prev };
+        if !(min..=max).contains(&v) {
+            return Err((
+                format!("{name} threshold {v} is outside the readable range"),
+                t.span().start,
+            ));
+        }
+        if !ordered {
+            return Err((
+                format!(
+                    "{name} thresholds must be ordered warning, critical, \
+                     fatal, away from the nominal value"
+                ),
+                t.span().start,
+            ));
+        }
+        prev = v;
+    }
+
+    let [warning, critical, fatal] =
+        fields.map(|t| t.as_ref().map(|t| *t.get_ref()));
+    Ok(Thresholds {
+        warning,
+        critical,
+        fatal,
+    })
+}
+
+// Validate the parsed config, returning the error message and the offset
+// of the offending value
+fn validate(config: Config) -> Result<Vec<Sensor>, (String, usize)> {
+    let mut sensors: Vec<Sensor> = Vec::new();
+    for s in &config.sensors {
+        let id = *s.id.get_ref();
+        if id == 0 || id == 0xffff {
+            return Err((format!("invalid sensor id {id}"), s.id.span().start));
+        }
+        if sensors.iter().any(|e| e.id == id) {
+            return Err((
+                format!("duplicate sensor id {id}"),
+                s.id.span().start,
+            ));
+        }
+        let (min, max) = (s.min, *s.max.get_ref());
+        if min > max {
+            return Err(("max is below min".to_string(), s.max.span().start));
+        }
+        let nominal = *s.nominal.get_ref();
+        if !(min..=max).contains(&nominal) {
+            return Err((
+                format!(
+                    "nominal value {nominal} is outside the readable range"
+                ),
+                s.nominal.span().start,
+            ));
+        }
+        let high = validate_thresholds(s, true)?;
+        let low = validate_thresholds(s, false)?;
+
+        let profile = s
+            .profile
+            .as_ref()
+            .map_or(Profile::Constant, |p| *p.get_ref());
+        let period = match (&s.period, &s.profile) {
+            (_, None) => 0,
+            (_, Some(_)) if profile == Profile::Constant => 0,
+            (Some(p), _) if *p.get_ref() > 0 => *p.get_ref(),
+            (Some(p), _) => {
+                return Err((
+                    "period must be positive".to_string(),
+                    p.span().start,
+                ))
+            }
+            (None, Some(p)) => {
+                return Err((
+                    "sine and ramp profiles need a period".to_string(),
+                    p.span().start,
+                ))
+            }
+        };
+
+        sensors.push(Sensor {
+            id,
+            unit: s.unit,
+            unit_modifier: s.unit_modifier,
+            min,
+            max,
+            nominal,
+            high,
+            low,
+            profile,
+            period,
+            amplitude: s.amplitude,
+            op_state: SENSOR_ENABLED,
+            event_enable: NO_EVENT_GENERATION,
+            state: STATE_NORMAL,
+            previous_state: STATE_NORMAL,
+        });
+    }
+    Ok(sensors)
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// A responder without sensors, for a PDR repository of other records,
+/// reporting the terminus ID of `terminus`
+pub fn empty(terminus: Arc<Terminus>) -> Platform {
+    Platform::new(Vec::new(), terminus)
+}
+
+/// Load and validate a sensor file. Errors include the line of the
+/// offending entry. Events report the terminus ID of `terminus`, shared
+/// with the base responder.
+pub fn load(path: &str, terminus: Arc<Terminus>) -> Result<Platform> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Can't read PLDM sensor config {path}"))?;
+
+    let config: Config = match toml::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => match e.span() {
+            Some(span) => {
+                bail!("{path}:{}: {}", line_of(&text, span.start), e.message())
+            }
+            None => bail!("{path}: {}", e.message()),
+        },
+    };
+
+    let sensors = validate(config).map_err(|(msg, offset)| {
+        anyhow::anyhow!("{path}:{}: {msg}", line_of(&text, offset))
+    })?;
+    info!("PLDM: {} numeric sensors", sensors.len());
+    Ok(Platform::new(sensors, terminus))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const EID: Eid = Eid(8);
+
+    // A platform responder loaded from the sensor config `text`
+    fn platform(name: &str, text: &str) -> Result<Platform> {
+        let path = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let p = load(path.to_str().unwrap(), Arc::new(Terminus::new(1)));
+        std::fs::remove_file(path).unwrap();
+        p
+    }
+
+    fn get_pdr(p: &mut Platform, handle: u32, xfer: u32, op: u8) -> Vec<u8> {
+        let mut req = handle.to_le_bytes().to_vec();
+        req.extend_from_slice(&xfer.to_le_bytes());
+        req.push(op);
+        req.extend_from_slice(&20u16.to_le_bytes());
+        req.extend_from_slice(&0u16.to_le_bytes());
+        p.command(EID, GET_PDR, &req).unwrap()
+    }
+
+    // The sensors' records are followed by those added later, and a
+    // record read in parts ends with its CRC-8
+    #[test]
+    fn pdr_repository() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let tid = Arc::new(Terminus::new(1));
+        let mut p = load(&format!("{path}/pldm-sensors.toml"), tid).unwrap();
+        p.add_pdr(36, &[0xaa; 30]);
+
+        let info = p.command(EID, GET_PDR_REPOSITORY_INFO, &[]).unwrap();
+        assert_eq!(info[27..31], 4u32.to_le_bytes());
+
+        let mut handle = 0;
+        let mut types = Vec::new();
+        loop {
+            let mut pdr = Vec::new();
+            let mut resp = get_pdr(&mut p, handle, 0, GET_FIRST_PART);
+            loop {
+                let count = u16::from_le_bytes([resp[9], resp[10]]) as usize;
+                pdr.extend_from_slice(&resp[11..11 + count]);
+                let xfer = u32::from_le_bytes(resp[4..8].try_into().unwrap());
+                if resp[8] == TRANSFER_START_AND_END {
+                    break;
+                }
+                if resp[8] == TRANSFER_END {
+                    assert_eq!(resp[11 + count], crc8(&pdr));
+                    break;
+                }
+                resp = get_pdr(&mut p, handle.max(1), xfer, GET_NEXT_PART);
+            }
+            assert_eq!(pdr[..4], handle.max(1).to_le_bytes());
+            types.push(pdr[5]);
+            handle = u32::from_le_bytes(resp[..4].try_into().unwrap());
+            if handle == 0 {
+                break;
+            }
+        }
+        assert_eq!(types, [2, 2, 2, 36]);
+    }
+
+    // Readings follow the ramp profile, and the state the thresholds
+    #[test]
+    fn sensor_states() {
+        let mut p = platform(
+            "states",
+            "[[sensor]]\nid = 7\nunit = \"watts\"\nmin = 0\nmax = 100\n\
+             nominal = 20\nwarning-high = 60\ncritical-high = 80\n\
+             profile = \"ramp\"\nperiod = 100\n",
+        )
+        .unwrap();
+
+        // 30 and 40 seconds into the ramp: 60 and 80 W
+        let mut reading = |secs| {
+            p.state.lock().unwrap().start = Instant::now()
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap();
+            p.command(EID, GET_SENSOR_READING, &[7, 0, 0]).unwrap()
+        };
+        let r = reading(30);
+        assert_eq!(r[3..6], [STATE_UPPER_WARNING, STATE_NORMAL, r[3]]);
+        assert_eq!(r[6..10], 60i32.to_le_bytes());
+        let r = reading(40);
+        assert_eq!(r[3..5], [STATE_UPPER_CRITICAL, STATE_UPPER_WARNING]);
+        assert_eq!(r[6..10], 80i32.to_le_bytes());
+
+        let err = p.command(EID, GET_SENSOR_READING, &[8, 0, 0]);
+        assert_eq!(err, Err(PLDM_PLATFORM_INVALID_SENSOR_ID));
+        // disabled, then enabled with events
+        let r = p.command(EID, SET_NUMERIC_SENSOR_ENABLE, &[7, 0, 1, 1]);
+        assert_eq!(r, Ok(vec![]));
+        let r = p.command(EID, SET_NUMERIC_SENSOR_ENABLE, &[7, 0, 0, 2]);
+        assert_eq!(r, Ok(vec![]));
+        let r = p.command(EID, SET_NUMERIC_SENSOR_ENABLE, &[7, 0, 0, 5]);
+        assert_eq!(r, Err(PLDM_ERROR_INVALID_DATA));
+    }
+
+    fn poll(p: &mut Platform, op: u8, ack: u16) -> Vec<u8> {
+        let mut req = vec![EVENT_FORMAT_VERSION, op, 0, 0, 0, 0];
+        req.extend_from_slice(&ack.to_le_bytes());
+        p.command(EID, POLL_FOR_PLATFORM_EVENT_MESSAGE, &req)
+            .unwrap()
+    }
+
+    // Events queue before a receiver is set, up to MAX_EVENTS, and are
+    // retrieved and acknowledged in order by polling
+    #[test]
+    fn poll_events() {
+        let mut p = platform(
+            "events",
+            "[[sensor]]\nid = 3\nunit = \"amps\"\nmin = 0\nmax = 100\n\
+             nominal = 20\nwarning-high = 50\nprofile = \"ramp\"\n\
+             period = 100\n",
+        )
+        .unwrap();
+        let r = p.command(EID, SET_NUMERIC_SENSOR_ENABLE, &[3, 0, 0, 4]);
+        assert_eq!(r, Ok(vec![]));
+
+        // 10 and 40 seconds into the ramp: 20 and 80 A, crossing the
+        // warning threshold up and down again
+        for i in 0..MAX_EVENTS + 2 {
+            let secs = if i % 2 == 0 { 40 } else { 10 };
+            let mut st = p.state.lock().unwrap();
+            st.start = Instant::now()
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap();
+            st.update(0);
+        }
+        assert_eq!(p.state.lock().unwrap().events.len(), MAX_EVENTS);
+
+        let r = p.command(EID, SET_EVENT_RECEIVER, &[2, 0, 8]);
+        assert_eq!(r, Ok(vec![]));
+        // in polling mode, nothing is delivered asynchronously
+        assert!(p.state.lock().unwrap().next_delivery().is_none());
+        let r = p.command(EID, SET_EVENT_RECEIVER, &[2, 1, 8]);
+        assert_eq!(r, Err(PLDM_PLATFORM_INVALID_PROTOCOL_TYPE));
+
+        // the two oldest events were dropped
+        let e = poll(&mut p, GET_FIRST_PART, 0);
+        assert_eq!(e[..3], [1, 3, 0]);
+        assert_eq!(e[7..9], [TRANSFER_START_AND_END, EVENT_CLASS_SENSOR]);
+        assert_eq!(e[9..13], 10u32.to_le_bytes());
+        assert_eq!(
+            e[13..19],
+            [
+                3,
+                0,
+                NUMERIC_SENSOR_STATE,
+                STATE_UPPER_WARNING,
+                STATE_NORMAL,
+                5
+            ]
+        );
+        assert_eq!(e[19..], 80i32.to_le_bytes());
+
+        // acknowledging event 3 moves on to event 4, and the next poll
+        // acknowledges that
+        let e = poll(&mut p, GET_FIRST_PART, 3);
+        assert_eq!(e[..3], [1, 4, 0]);
+        assert_eq!(e[16..18], [STATE_NORMAL, STATE_UPPER_WARNING]);
+        let e = poll(&mut p, ACKNOWLEDGEMENT_ONLY, 4);
+        assert_eq!(e, [1, 0xff, 0xff]);
+        assert_eq!(p.state.lock().unwrap().events.len(), MAX_EVENTS - 2);
+
+        // disabled, with events still queued
+        let r = p.command(EID, SET_EVENT_RECEIVER, &[0, 0, 0]);
+        assert_eq!(r, Ok(vec![]));
+        assert_eq!(poll(&mut p, GET_FIRST_PART, 0)[..3], [1, 5, 0]);
+    }
+
+    // Config errors report the line of the offending value
+    #[test]
+    fn config_errors() {
+        let sensor = "[[sensor]]\nid = 1\nunit = \"rpm\"\nmin = 0\nmax = 10\n\
+                      nominal = 5\n";
+        let e = platform("dup", &format!("{sensor}{sensor}")).err().unwrap();
+        assert!(e.to_string().ends_with(":8: duplicate sensor id 1"), "{e}");
+
+        let text = format!("{sensor}warning-high = 3\n");
+        let e = platform("order", &text).err().unwrap();
+        assert!(e.to_string().contains(":7: high thresholds"), "{e}");
+    }
+}
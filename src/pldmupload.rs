@@ -0,0 +1,705 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PLDM file upload, with --pldm-upload PATH: the local file is written to
+ * the first writable file in the peer's File Descriptor PDRs, those with
+ * the write capability bit set.
+ *
+ * The pldm-file client only reads files, so the requests are encoded here:
+ * GetPDR for the file capabilities, DfOpen and DfClose, and MultipartSend
+ * carrying the file data in parts of the negotiated size.
+ *
+ * Each MultipartSend response gives the handle of the next part the peer
+ * expects, as a file offset. If the peer accepted less than the whole part,
+ * the rest is resent from that offset, as is a part the peer asks for
+ * again, up to WRITE_RETRIES times without progress. A part rejected for
+ * its length is resent after negotiating a smaller part size. Once the file
+ * is closed, its size is checked by reading back across the end of the
+ * upload, with a new descriptor.
+ */
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::Either;
+use log::{debug, info};
+use mctp::AsyncReqChannel;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use crate::pldmbase::crc32_update;
+
+/* PLDM types */
+const PLDM_TYPE_BASE: u8 = 0x00;
+const PLDM_TYPE_PLATFORM: u8 = 0x02;
+const PLDM_TYPE_FILE_TRANSFER: u8 = 0x07;
+
+/* PLDM message header */
+const PLDM_RQ: u8 = 0x80;
+const PLDM_IID_MASK: u8 = 0x1f;
+
+/* commands */
+const NEGOTIATE_TRANSFER_PARAMETERS: u8 = 0x07;
+const MULTIPART_SEND: u8 = 0x08;
+const MULTIPART_RECEIVE: u8 = 0x09;
+const GET_PDR: u8 = 0x51;
+const DF_OPEN: u8 = 0x01;
+const DF_CLOSE: u8 = 0x02;
+
+/* completion codes */
+const PLDM_SUCCESS: u8 = 0x00;
+const PLDM_ERROR_INVALID_LENGTH: u8 = 0x03;
+
+/* multipart transfer operations and flags */
+const XFER_FIRST_PART: u8 = 0x00;
+const XFER_NEXT_PART: u8 = 0x01;
+const XFER_COMPLETE: u8 = 0x03;
+const XFER_CURRENT_PART: u8 = 0x04;
+const XFER_START: u8 = 0x01;
+const XFER_MIDDLE: u8 = 0x02;
+const XFER_END: u8 = 0x04;
+const XFER_START_AND_END: u8 = 0x05;
+
+/* GetPDR */
+const GET_PDR_NEXT_PART: u8 = 0x00;
+const GET_PDR_FIRST_PART: u8 = 0x01;
+const GET_PDR_COUNT: u16 = 256;
+const PDR_TRANSFER_END: u8 = 0x04;
+const PDR_HEADER_LEN: usize = 10;
+const PDR_TYPE_OFFSET: usize = 5;
+const PDR_TYPE_FILE_DESCRIPTOR: u8 = 36;
+
+/* File Descriptor PDR fields, following the common header */
+const FD_PDR_ID: usize = 2;
+const FD_PDR_CAPABILITIES: usize = 14;
+const FD_PDR_MAX_SIZE: usize = 20;
+const FD_PDR_NAME_LEN: usize = 25;
+const FILE_CAP_WRITE: u16 = 1 << 1;
+
+/* DfOpen attributes */
+const DF_OPEN_READ: u16 = 0;
+const DF_OPEN_WRITE: u16 = 1 << 1;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/* the DSP0240 minimum part size */
+const MIN_PART_SIZE: u16 = 256;
+/* resends of a part without the peer accepting more data */
+const WRITE_RETRIES: u32 = 3;
+/* bytes between progress logs */
+const PROGRESS_INTERVAL: u32 = 64 * 1024;
+
+/// A file in the peer's File Descriptor PDRs
+#[derive(Debug)]
+struct RemoteFile {
+    id: u16,
+    name: String,
+    capabilities: u16,
+    max_size: u32,
+}
+
+impl RemoteFile {
+    // Parse a File Descriptor PDR, including its common header
+    fn parse(pdr: &[u8]) -> Option<Self> {
+        let b = pdr.get(PDR_HEADER_LEN..)?;
+        let u16_at =
+            |i: usize| Some(u16::from_le_bytes([*b.get(i)?, b[i + 1]]));
+        let max_size = b.get(FD_PDR_MAX_SIZE..FD_PDR_MAX_SIZE + 4)?;
+        let name_len = *b.get(FD_PDR_NAME_LEN)? as usize;
+        let name =
+            b.get(FD_PDR_NAME_LEN + 1..FD_PDR_NAME_LEN + 1 + name_len)?;
+        Some(Self {
+            id: u16_at(FD_PDR_ID)?,
+            name: String::from_utf8_lossy(name).into(),
+            capabilities: u16_at(FD_PDR_CAPABILITIES)?,
+            max_size: u32::from_le_bytes(max_size.try_into().unwrap()),
+        })
+    }
+}
+
+/// A PLDM requester encoding its own requests
+struct Requester<'c, C> {
+    chan: &'c mut C,
+    iid: u8,
+}
+
+impl<C: AsyncReqChannel> Requester<'_, C> {
+    // Send a request, returning the response from the completion code
+    async fn request(
+        &mut self,
+        typ: u8,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.iid = (self.iid + 1) & PLDM_IID_MASK;
+        let hdr = [PLDM_RQ | self.iid, typ, cmd];
+        self.chan
+            .send_vectored(
+                mctp::MCTP_TYPE_PLDM,
+                mctp::MsgIC(false),
+                &[&hdr, data],
+            )
+            .await
+            .map_err(|e| anyhow!("Sending command {cmd:#04x} failed: {e}"))?;
+
+        let mut buf = [0u8; 1024];
+        let resp = futures::future::select(
+            Box::pin(self.chan.recv(&mut buf)),
+            smol::Timer::after(RESPONSE_TIMEOUT),
+        )
+        .await;
+        let resp = match resp {
+            Either::Left((Ok((_typ, _ic, resp)), _)) => resp,
+            Either::Left((Err(e), _)) => {
+                bail!("Receiving response to {cmd:#04x} failed: {e}")
+            }
+            Either::Right(_) => bail!("No response to command {cmd:#04x}"),
+        };
+        match *resp {
+            [iid, rtyp, rcmd, ref rest @ ..]
+                if iid == self.iid
+                    && rtyp & 0x3f == typ
+                    && rcmd == cmd
+                    && !rest.is_empty() =>
+            {
+                Ok(rest.to_vec())
+            }
+            _ => bail!("Bad response to command {cmd:#04x}: {resp:x?}"),
+        }
+    }
+
+    // Send a request that must succeed, returning the response following
+    // the completion code
+    async fn command(
+        &mut self,
+        typ: u8,
+        cmd: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let resp = self.request(typ, cmd, data).await?;
+        match resp[0] {
+            PLDM_SUCCESS => Ok(resp[1..].to_vec()),
+            cc => bail!("Command {cmd:#04x} failed, cc {cc:#04x}"),
+        }
+    }
+
+    // Read all of the peer's PDRs, returning the files
+    async fn files(&mut self) -> Result<Vec<RemoteFile>> {
+        let mut files = Vec::new();
+        let mut handle = 0u32;
+        loop {
+            let mut pdr = Vec::new();
+            let mut op = GET_PDR_FIRST_PART;
+            let mut xfer = 0u32;
+            let next = loop {
+                let mut req = handle.to_le_bytes().to_vec();
+                req.extend_from_slice(&xfer.to_le_bytes());
+                req.push(op);
+                req.extend_from_slice(&GET_PDR_COUNT.to_le_bytes());
+                // record change number
+                req.extend_from_slice(&0u16.to_le_bytes());
+                let resp = self
+                    .command(PLDM_TYPE_PLATFORM, GET_PDR, &req)
+                    .await
+                    .context("GetPDR failed")?;
+                let Some(data) = resp.get(11..) else {
+                    bail!("Short GetPDR response");
+                };
+                let next = u32::from_le_bytes(resp[0..4].try_into().unwrap());
+                xfer = u32::from_le_bytes(resp[4..8].try_into().unwrap());
+                let flag = resp[8];
+                let count = u16::from_le_bytes([resp[9], resp[10]]) as usize;
+                let Some(data) = data.get(..count) else {
+                    bail!("Short GetPDR response");
+                };
+                pdr.extend_from_slice(data);
+                // the CRC follows the last part of a multipart record
+                if flag == PDR_TRANSFER_END || xfer == 0 {
+                    break next;
+                }
+                op = GET_PDR_NEXT_PART;
+            };
+
+            if pdr.get(PDR_TYPE_OFFSET) == Some(&PDR_TYPE_FILE_DESCRIPTOR) {
+                match RemoteFile::parse(&pdr) {
+                    Some(f) => files.push(f),
+                    None => debug!("Bad File Descriptor PDR {pdr:x?}"),
+                }
+            }
+            if next == 0 {
+                return Ok(files);
+            }
+            handle = next;
+        }
+    }
+
+    async fn df_open(&mut self, id: u16, attrs: u16) -> Result<u16> {
+        let mut req = id.to_le_bytes().to_vec();
+        req.extend_from_slice(&attrs.to_le_bytes());
+        let resp = self
+            .command(PLDM_TYPE_FILE_TRANSFER, DF_OPEN, &req)
+            .await
+            .context("DfOpen failed")?;
+        let [f0, f1] = resp[..] else {
+            bail!("Bad DfOpen response {resp:x?}");
+        };
+        Ok(u16::from_le_bytes([f0, f1]))
+    }
+
+    async fn df_close(&mut self, fd: u16) -> Result<()> {
+        let mut req = fd.to_le_bytes().to_vec();
+        req.extend_from_slice(&0u16.to_le_bytes());
+        self.command(PLDM_TYPE_FILE_TRANSFER, DF_CLOSE, &req)
+            .await
+            .context("DfClose failed")?;
+        Ok(())
+    }
+
+    // Negotiate a part size of at most `size`, returning the peer's size
+    async fn negotiate(&mut self, size: u16) -> Result<u16> {
+        let mut req = size.to_le_bytes().to_vec();
+        let mut types = [0u8; 8];
+        types[0] = 1 << PLDM_TYPE_FILE_TRANSFER;
+        req.extend_from_slice(&types);
+        let resp = self
+            .command(PLDM_TYPE_BASE, NEGOTIATE_TRANSFER_PARAMETERS, &req)
+            .await
+            .context("Negotiate transfer parameters failed")?;
+        let [s0, s1, ..] = resp[..] else {
+            bail!("Bad NegotiateTransferParameters response {resp:x?}");
+        };
+        Ok(u16::from_le_bytes([s0, s1]))
+    }
+
+    // Send the data of `file`, `len` bytes, to `fd` in parts of `size`
+    async fn send(
+        &mut self,
+        fd: u16,
+        file: &mut File,
+        len: u32,
+        mut size: u16,
+    ) -> Result<()> {
+        // the next byte to send, and the extent of the CRC
+        let mut offset = 0u32;
+        let mut crc = !0u32;
+        let mut crc_end = 0u32;
+        let mut stalled = 0;
+        let mut progress = PROGRESS_INTERVAL;
+        let mut part = Vec::new();
+        loop {
+            let end = len.min(offset + size as u32);
+            part.resize((end - offset) as usize, 0);
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.read_exact(&mut part).context("Reading upload file")?;
+            if end > crc_end {
+                let new = (crc_end - offset) as usize;
+                crc = crc32_update(crc, &part[new..]);
+                crc_end = end;
+            }
+
+            let flag = match (offset == 0, end == len) {
+                (true, true) => XFER_START_AND_END,
+                (true, false) => XFER_START,
+                (false, false) => XFER_MIDDLE,
+                (false, true) => XFER_END,
+            };
+            let next = if end == len { 0 } else { end };
+            let mut req = vec![PLDM_TYPE_FILE_TRANSFER, flag];
+            // transfer context, handles, section offset and length, and
+            // the part
+            for v in [fd as u32, offset, next, 0, len, end - offset] {
+                req.extend_from_slice(&v.to_le_bytes());
+            }
+            req.extend_from_slice(&part);
+            if flag & XFER_END != 0 {
+                req.extend_from_slice(&(!crc).to_le_bytes());
+            }
+
+            let resp =
+                self.request(PLDM_TYPE_BASE, MULTIPART_SEND, &req).await?;
+            match resp[..] {
+                [PLDM_SUCCESS, op, h0, h1, h2, h3] => {
+                    let handle = u32::from_le_bytes([h0, h1, h2, h3]);
+                    let accepted = match op {
+                        XFER_COMPLETE if end == len => break,
+                        XFER_NEXT_PART if handle <= end => handle,
+                        XFER_CURRENT_PART => offset,
+                        _ => bail!(
+                            "Bad MultipartSend response, operation \
+                             {op:#04x}, handle {handle}"
+                        ),
+                    };
+                    if accepted < end {
+                        debug!(
+                            "PLDM: peer accepted {} of {} bytes at {offset}",
+                            accepted.saturating_sub(offset),
+                            end - offset
+                        );
+                    }
+                    if accepted > offset {
+                        stalled = 0;
+                    } else {
+                        stalled += 1;
+                        if stalled > WRITE_RETRIES {
+                            bail!("Peer accepted no data at {offset}");
+                        }
+                    }
+                    offset = accepted;
+                }
+                [PLDM_ERROR_INVALID_LENGTH] if size > MIN_PART_SIZE => {
+                    let want = (size / 2).max(MIN_PART_SIZE);
+                    let new = self.negotiate(want).await?.min(want);
+                    if new < MIN_PART_SIZE {
+                        bail!("Peer negotiated a {new} byte part size");
+                    }
+                    info!(
+                        "PLDM: peer rejected a {} byte part, now sending \
+                         {new} byte parts",
+                        end - offset
+                    );
+                    size = new;
+                }
+                [cc, ..] => bail!("MultipartSend failed, cc {cc:#04x}"),
+                [] => unreachable!(),
+            }
+
+            if offset >= progress {
+                info!("PLDM: uploaded {offset}/{len} bytes");
+                progress =
+                    offset - offset % PROGRESS_INTERVAL + PROGRESS_INTERVAL;
+            }
+        }
+        Ok(())
+    }
+
+    // Check that file `id` is `len` bytes, ending with `last`
+    async fn verify_size(
+        &mut self,
+        id: u16,
+        len: u32,
+        last: Option<u8>,
+    ) -> Result<()> {
+        let fd = self.df_open(id, DF_OPEN_READ).await?;
+        // the last byte, and the byte after it, if any
+        let mut req = vec![PLDM_TYPE_FILE_TRANSFER, XFER_FIRST_PART];
+        for v in [fd as u32, 0, len.saturating_sub(1), 2] {
+            req.extend_from_slice(&v.to_le_bytes());
+        }
+        let resp = self.request(PLDM_TYPE_BASE, MULTIPART_RECEIVE, &req).await;
+        self.df_close(fd).await?;
+
+        let resp = resp?;
+        let data = match resp.get(..10) {
+            Some([PLDM_SUCCESS, _flag, _, _, _, _, l0, l1, l2, l3]) => {
+                let n = u32::from_le_bytes([*l0, *l1, *l2, *l3]) as usize;
+                resp.get(10..10 + n).context("Short MultipartReceive")?
+            }
+            // nothing to read from an empty file
+            _ if len == 0 => &[],
+            _ => bail!("Reading back the upload failed: {resp:x?}"),
+        };
+        if data.first().copied() != last || data.len() > 1 {
+            bail!(
+                "Uploaded file size differs, read {data:x?} at offset {}",
+                len.saturating_sub(1)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Upload the file at `path` to the peer's first writable file, in parts
+/// of up to `part_size` bytes
+pub async fn upload(
+    chan: &mut impl AsyncReqChannel,
+    path: &str,
+    part_size: u16,
+) -> Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("Can't open {path}"))?;
+    let len = file.metadata()?.len();
+    let len = u32::try_from(len)
+        .with_context(|| format!("Can't upload {path}, too large"))?;
+
+    let mut req = Requester { chan, iid: 0 };
+    let files = req.files().await?;
+    let Some(remote) =
+        files.iter().find(|f| f.capabilities & FILE_CAP_WRITE != 0)
+    else {
+        bail!("No writable File Descriptor PDR found");
+    };
+    if len > remote.max_size {
+        bail!(
+            "Can't upload {len} bytes to file {} \"{}\", of at most {} bytes",
+            remote.id,
+            remote.name,
+            remote.max_size
+        );
+    }
+    info!(
+        "PLDM: uploading {path}, {len} bytes, to file {} \"{}\"",
+        remote.id, remote.name
+    );
+
+    let fd = req.df_open(remote.id, DF_OPEN_WRITE).await?;
+    let r = req.send(fd, &mut file, len, part_size).await;
+    // the descriptor is closed regardless
+    let closed = req.df_close(fd).await;
+    r.context("PLDM upload failed")?;
+    closed?;
+
+    let mut last = [0u8];
+    let last = if len > 0 {
+        file.seek(SeekFrom::Start(len as u64 - 1))?;
+        file.read_exact(&mut last)?;
+        Some(last[0])
+    } else {
+        None
+    };
+    req.verify_size(remote.id, len, last).await?;
+    info!("PLDM: upload complete, {len} bytes");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mctp::Eid;
+
+    // A peer with a read-only and a writable file, following the writes
+    // made with MultipartSend. Parts over `limit` bytes are rejected once
+    // `shrink_after` parts have been received, and part `partial` is only
+    // half accepted.
+    #[derive(Default)]
+    struct Peer {
+        pdrs: Vec<Vec<u8>>,
+        written: Vec<u8>,
+        max_size: u32,
+        limit: usize,
+        shrink_after: Option<usize>,
+        partial: Option<usize>,
+        // the offset and length of each part received
+        parts: Vec<(u32, usize)>,
+        crc: Option<u32>,
+        resp: Option<Vec<u8>>,
+    }
+
+    // A File Descriptor PDR for file `id`
+    fn file_pdr(handle: u32, id: u16, caps: u16, name: &str) -> Vec<u8> {
+        let mut b = vec![0u8; FD_PDR_NAME_LEN];
+        b[FD_PDR_ID..FD_PDR_ID + 2].copy_from_slice(&id.to_le_bytes());
+        b[FD_PDR_CAPABILITIES..FD_PDR_CAPABILITIES + 2]
+            .copy_from_slice(&caps.to_le_bytes());
+        b[FD_PDR_MAX_SIZE..FD_PDR_MAX_SIZE + 4]
+            .copy_from_slice(&100_000u32.to_le_bytes());
+        b.push(name.len() as u8);
+        b.extend_from_slice(name.as_bytes());
+
+        let mut pdr = handle.to_le_bytes().to_vec();
+        pdr.extend_from_slice(&[1, PDR_TYPE_FILE_DESCRIPTOR, 0, 0]);
+        pdr.extend_from_slice(&(b.len() as u16).to_le_bytes());
+        pdr.extend_from_slice(&b);
+        pdr
+    }
+
+    impl Peer {
+        fn new(limit: usize) -> Self {
+            Self {
+                pdrs: vec![
+                    file_pdr(1, 1, 0, "log"),
+                    file_pdr(2, 2, FILE_CAP_WRITE, "report"),
+                ],
+                max_size: 100_000,
+                limit,
+                ..Default::default()
+            }
+        }
+
+        // The response to a request, from the completion code
+        fn respond(&mut self, typ: u8, cmd: u8, data: &[u8]) -> Vec<u8> {
+            let u32_at = |i: usize| {
+                u32::from_le_bytes(data[i..i + 4].try_into().unwrap())
+            };
+            match (typ, cmd) {
+                (PLDM_TYPE_PLATFORM, GET_PDR) => {
+                    // whole records only
+                    let idx = u32_at(0).max(1) as usize - 1;
+                    let pdr = &self.pdrs[idx];
+                    let next = if idx + 1 < self.pdrs.len() {
+                        idx as u32 + 2
+                    } else {
+                        0
+                    };
+                    let mut r = vec![PLDM_SUCCESS];
+                    r.extend_from_slice(&next.to_le_bytes());
+                    r.extend_from_slice(&0u32.to_le_bytes());
+                    r.push(XFER_START_AND_END);
+                    r.extend_from_slice(&(pdr.len() as u16).to_le_bytes());
+                    r.extend_from_slice(pdr);
+                    r
+                }
+                (PLDM_TYPE_FILE_TRANSFER, DF_OPEN) => {
+                    assert_eq!(data[..2], 2u16.to_le_bytes(), "not writable");
+                    vec![PLDM_SUCCESS, 7, 0]
+                }
+                (PLDM_TYPE_FILE_TRANSFER, DF_CLOSE) => vec![PLDM_SUCCESS],
+                (PLDM_TYPE_BASE, NEGOTIATE_TRANSFER_PARAMETERS) => {
+                    let size = u16::from_le_bytes([data[0], data[1]]);
+                    let size = size.min(self.limit as u16);
+                    let mut r = vec![PLDM_SUCCESS];
+                    r.extend_from_slice(&size.to_le_bytes());
+                    r.extend_from_slice(&data[2..]);
+                    r
+                }
+                (PLDM_TYPE_BASE, MULTIPART_SEND) => self.multipart_send(data),
+                (PLDM_TYPE_BASE, MULTIPART_RECEIVE) => {
+                    let (offset, len) = (u32_at(10), u32_at(14));
+                    let start = (offset as usize).min(self.written.len());
+                    let end = (start + len as usize).min(self.written.len());
+                    let mut r = vec![PLDM_SUCCESS, XFER_START_AND_END];
+                    r.extend_from_slice(&0u32.to_le_bytes());
+                    r.extend_from_slice(&((end - start) as u32).to_le_bytes());
+                    r.extend_from_slice(&self.written[start..end]);
+                    r
+                }
+                _ => panic!("unexpected command {typ} {cmd:#04x}"),
+            }
+        }
+
+        fn multipart_send(&mut self, data: &[u8]) -> Vec<u8> {
+            let u32_at = |i: usize| {
+                u32::from_le_bytes(data[i..i + 4].try_into().unwrap())
+            };
+            let (flag, fd, handle) = (data[1], u32_at(2), u32_at(6));
+            let (len, part_len) = (u32_at(18), u32_at(22) as usize);
+            assert_eq!(fd, 7);
+            assert!(len <= self.max_size);
+            let part = &data[26..26 + part_len];
+            if self.shrink_after.is_some_and(|n| self.parts.len() >= n)
+                && part_len > self.limit
+            {
+                return vec![PLDM_ERROR_INVALID_LENGTH];
+            }
+            self.parts.push((handle, part_len));
+
+            // only the first half of the partial part is written
+            let n = match self.partial {
+                Some(p) if p == self.parts.len() => part_len / 2,
+                _ => part_len,
+            };
+            let offset = handle as usize;
+            self.written.truncate(offset);
+            self.written.extend_from_slice(&part[..n]);
+
+            let accepted = offset + n;
+            let mut r = vec![PLDM_SUCCESS];
+            if flag & XFER_END != 0 && accepted == len as usize {
+                let crc = data[26 + part_len..].try_into().unwrap();
+                self.crc = Some(u32::from_le_bytes(crc));
+                r.push(XFER_COMPLETE);
+                r.extend_from_slice(&0u32.to_le_bytes());
+            } else {
+                r.push(XFER_NEXT_PART);
+                r.extend_from_slice(&(accepted as u32).to_le_bytes());
+            }
+            r
+        }
+    }
+
+    impl AsyncReqChannel for Peer {
+        async fn send_vectored(
+            &mut self,
+            typ: mctp::MsgType,
+            _ic: mctp::MsgIC,
+            bufs: &[&[u8]],
+        ) -> mctp::Result<()> {
+            assert_eq!(typ, mctp::MCTP_TYPE_PLDM);
+            let req = bufs.concat();
+            let mut resp = vec![req[0] & PLDM_IID_MASK, req[1], req[2]];
+            resp.extend_from_slice(&self.respond(req[1], req[2], &req[3..]));
+            self.resp = Some(resp);
+            Ok(())
+        }
+
+        async fn recv<'f>(
+            &mut self,
+            buf: &'f mut [u8],
+        ) -> mctp::Result<(mctp::MsgType, mctp::MsgIC, &'f mut [u8])> {
+            let resp = self.resp.take().ok_or(mctp::Error::TimedOut)?;
+            let buf = buf.get_mut(..resp.len()).ok_or(mctp::Error::NoSpace)?;
+            buf.copy_from_slice(&resp);
+            Ok((mctp::MCTP_TYPE_PLDM, mctp::MsgIC(false), buf))
+        }
+
+        fn remote_eid(&self) -> Eid {
+            Eid(9)
+        }
+    }
+
+    // A temporary file of `len` bytes
+    fn local(name: &str, len: usize) -> (String, Vec<u8>) {
+        let data = (0..len).map(|i| (i * 13 % 251) as u8).collect::<Vec<_>>();
+        let path = std::env::temp_dir()
+            .join(format!("mctp-dev-{}-{name}", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        (path.display().to_string(), data)
+    }
+
+    // The file is written to the writable file in parts of the negotiated
+    // size, with the CRC-32 of the file after the last
+    #[test]
+    fn upload_parts() {
+        let (path, data) = local("upload", 1300);
+        let mut peer = Peer::new(512);
+        smol::block_on(upload(&mut peer, &path, 512)).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(peer.written == data, "upload differs");
+        assert_eq!(peer.parts, [(0, 512), (512, 512), (1024, 276)]);
+        assert_eq!(peer.crc, Some(!crc32_update(!0, &data)));
+    }
+
+    // A partly accepted part is resent from the peer's offset, and a
+    // rejected part size is renegotiated down
+    #[test]
+    fn upload_partial_and_shrink() {
+        let (path, data) = local("partial", 2000);
+        let mut peer = Peer::new(256);
+        peer.partial = Some(2);
+        peer.shrink_after = Some(3);
+        smol::block_on(upload(&mut peer, &path, 512)).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(peer.written == data, "upload differs");
+        assert_eq!(
+            peer.parts,
+            [
+                (0, 512),
+                // half accepted, then the rest resent
+                (512, 512),
+                (768, 512),
+                // the 512 byte part is rejected, and resent in 256 bytes
+                (1280, 256),
+                (1536, 256),
+                (1792, 208),
+            ]
+        );
+        assert_eq!(peer.crc, Some(!crc32_update(!0, &data)));
+    }
+
+    // Without a writable file, or with a file too large, nothing is sent
+    #[test]
+    fn upload_refused() {
+        let (path, _) = local("refused", 100);
+        let mut peer = Peer::new(512);
+        peer.pdrs.truncate(1);
+        let e = smol::block_on(upload(&mut peer, &path, 512)).unwrap_err();
+        assert!(e.to_string().contains("No writable"), "{e}");
+
+        let mut peer = Peer::new(512);
+        peer.pdrs[1] = file_pdr(2, 2, FILE_CAP_WRITE, "tiny");
+        let max = FD_PDR_MAX_SIZE + PDR_HEADER_LEN;
+        peer.pdrs[1][max..max + 4].copy_from_slice(&50u32.to_le_bytes());
+        let e = smol::block_on(upload(&mut peer, &path, 512)).unwrap_err();
+        assert!(e.to_string().contains("at most 50 bytes"), "{e}");
+        assert!(peer.parts.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* PTY allocation for the serial transport: we use the master side, and
+ * another program (eg., qemu, or a host MCTP stack) attaches to the slave.
+ */
+
+use anyhow::{bail, Context, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+
+pub struct Pty {
+    pub master: OwnedFd,
+    /* We keep the slave side open for the lifetime of the pty; without
+     * this, reads from the master would return EIO whenever no other
+     * process has the slave open, so the peer can open and close the
+     * device repeatedly without the transport failing.
+     */
+    pub slave: OwnedFd,
+    pub path: PathBuf,
+}
+
+impl Pty {
+    pub fn open() -> Result<Self> {
+        let mut master: libc::c_int = -1;
+        let mut slave: libc::c_int = -1;
+
+        // SAFETY: master and slave are valid pointers, other args are
+        // optional
+        let rc = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if rc < 0 {
+            bail!("Can't allocate pty: {}", std::io::Error::last_os_error());
+        }
+
+        // SAFETY: openpty has provided us with these fds
+        let (master, slave) = unsafe {
+            (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave))
+        };
+
+        // raw mode, so that the line discipline doesn't alter our framing
+        // SAFETY: termios is fully initialised by tcgetattr
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) < 0 {
+                bail!(
+                    "Can't get pty attributes: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+                bail!(
+                    "Can't set pty attributes: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+
+        let path =
+            std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd()))
+                .context("Can't find pty slave path")?;
+
+        Ok(Self {
+            master,
+            slave,
+            path,
+        })
+    }
+
+    /// Create a symlink at `link` to the slave device, replacing any
+    /// existing symlink.
+    pub fn link(&self, link: &str) -> Result<()> {
+        if let Ok(m) = std::fs::symlink_metadata(link) {
+            if m.file_type().is_symlink() {
+                std::fs::remove_file(link)
+                    .context("Can't remove existing link")?;
+            }
+        }
+        std::os::unix::fs::symlink(&self.path, link)
+            .with_context(|| format!("Can't create link {link}"))
+    }
+}
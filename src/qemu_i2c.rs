@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* MCTP over SMBus, attached to an emulated i2c bus in qemu via an external
+ * i2c socket.
+ *
+ * The socket carries i2c bus events, in either direction. Each event is a
+ * one-byte opcode, followed by an optional one-byte argument:
+ *
+ *   START_SEND <addr>   start a write transfer to 7-bit address <addr>
+ *   START_RECV <addr>   start a read transfer from 7-bit address <addr>
+ *   SEND <byte>         write a byte in the current transfer
+ *   RECV                read a byte in the current transfer; the target
+ *                       replies with a single data byte
+ *   STOP                end the current transfer
+ *
+ * Packets towards us arrive as a master write to our address. We transmit
+ * packets by mastering the bus ourselves, as the SMBus binding requires.
+ */
+
+use anyhow::{Context, Result};
+use futures::future::LocalBoxFuture;
+use futures::{select, AsyncReadExt, AsyncWriteExt, FutureExt};
+use log::{debug, info, trace, warn};
+use smol::Async;
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+
+use crate::i2c::{smbus_decode, smbus_encode};
+use crate::transport::{MctpTransport, BINDING_SMBUS};
+
+const OP_START_SEND: u8 = 0x01;
+const OP_START_RECV: u8 = 0x02;
+const OP_SEND: u8 = 0x03;
+const OP_RECV: u8 = 0x04;
+const OP_STOP: u8 = 0x05;
+
+// value returned for master reads; the MCTP binding only uses writes
+const READ_IDLE_BYTE: u8 = 0xff;
+
+#[derive(Debug)]
+enum BusState {
+    Idle,
+    // a master write to our address is in progress
+    Receiving(Vec<u8>),
+    // a master read from our address is in progress
+    Sending,
+    // a transfer to some other device is in progress
+    Other,
+}
+
+/* contains the i2c socket state, and handles async processing */
+pub struct MctpQemuI2cPort {
+    stream: Async<UnixStream>,
+    addr: u8,
+    state: BusState,
+    rx_buf: Vec<u8>,
+
+    /* i2c transfer interactions, connected to the higher-level objects */
+    xfer_tx_chan: async_channel::Receiver<Vec<u8>>,
+    xfer_rx_chan: async_channel::Sender<Vec<u8>>,
+}
+
+pub struct MctpQemuI2c {
+    addr: u8,
+
+    /* i2c addresses of peers, learnt from received packets */
+    peers: HashMap<u8, u8>,
+    default_peer: Option<u8>,
+
+    rx_cur: Vec<u8>,
+
+    xfer_tx_chan: async_channel::Sender<Vec<u8>>,
+    xfer_rx_chan: async_channel::Receiver<Vec<u8>>,
+
+    /* socket processing, until taken by background() */
+    port: Option<MctpQemuI2cPort>,
+}
+
+impl MctpQemuI2c {
+    /// Connect to a qemu i2c socket at `path`, as a device at 7-bit address
+    /// `addr`
+    pub fn new(path: &str, addr: u8, default_peer: Option<u8>) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Can't connect to {path}"))?;
+
+        let (xfer_out_sender, xfer_out_receiver) = async_channel::unbounded();
+        let (xfer_in_sender, xfer_in_receiver) = async_channel::unbounded();
+
+        let port = MctpQemuI2cPort {
+            stream: Async::new(stream)?,
+            addr,
+            state: BusState::Idle,
+            rx_buf: Vec::new(),
+            xfer_rx_chan: xfer_out_sender,
+            xfer_tx_chan: xfer_in_receiver,
+        };
+
+        Ok(Self {
+            addr,
+            peers: HashMap::new(),
+            default_peer,
+            rx_cur: Vec::new(),
+            xfer_tx_chan: xfer_in_sender,
+            xfer_rx_chan: xfer_out_receiver,
+            port: Some(port),
+        })
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        loop {
+            let xfer = self
+                .xfer_rx_chan
+                .recv()
+                .await
+                .or(Err(mctp::Error::RxFailure))?;
+
+            let (src_addr, pkt) = match smbus_decode(&xfer) {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!("i2c rx: invalid message: {e:?}");
+                    continue;
+                }
+            };
+
+            if let Some(&src_eid) = xfer.get(pkt.start + 2) {
+                self.peers.insert(src_eid, src_addr);
+            }
+
+            self.rx_cur = xfer[pkt].to_vec();
+            return Ok(&self.rx_cur);
+        }
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        if pkt.len() < 4 {
+            return Err(mctp::Error::BadArgument);
+        }
+
+        let dest_eid = pkt[1];
+        let Some(dest_addr) =
+            self.peers.get(&dest_eid).copied().or(self.default_peer)
+        else {
+            warn!("i2c tx: no known address for EID {dest_eid}, dropping");
+            return Err(mctp::Error::TxFailure);
+        };
+
+        let buf = smbus_encode(dest_addr, self.addr, pkt)?;
+
+        self.xfer_tx_chan
+            .send(buf)
+            .await
+            .or(Err(mctp::Error::TxFailure))
+    }
+}
+
+impl MctpQemuI2cPort {
+    async fn write_events(&mut self, events: &[u8]) -> mctp::Result<()> {
+        self.stream.write_all(events).await.map_err(|e| {
+            warn!("i2c socket write error {e:?}");
+            mctp::Error::TxFailure
+        })
+    }
+
+    // Handle one event from the bus. Returns the number of bytes consumed
+    // from `buf`, or None if the event is incomplete.
+    async fn handle_event(
+        &mut self,
+        buf: &[u8],
+    ) -> mctp::Result<Option<usize>> {
+        let Some(&op) = buf.first() else {
+            return Ok(None);
+        };
+        let arg = buf.get(1).copied();
+
+        let needs_arg = matches!(op, OP_START_SEND | OP_START_RECV | OP_SEND);
+        if needs_arg && arg.is_none() {
+            return Ok(None);
+        }
+
+        match (op, arg) {
+            (OP_START_SEND, Some(addr)) => {
+                self.state = if addr == self.addr {
+                    BusState::Receiving(vec![addr << 1])
+                } else {
+                    BusState::Other
+                };
+            }
+            (OP_START_RECV, Some(addr)) => {
+                self.state = if addr == self.addr {
+                    BusState::Sending
+                } else {
+                    BusState::Other
+                };
+            }
+            (OP_SEND, Some(b)) => {
+                if let BusState::Receiving(v) = &mut self.state {
+                    v.push(b);
+                }
+            }
+            (OP_RECV, _) => {
+                if let BusState::Sending = self.state {
+                    self.write_events(&[READ_IDLE_BYTE]).await?;
+                }
+            }
+            (OP_STOP, _) => {
+                let state = std::mem::replace(&mut self.state, BusState::Idle);
+                if let BusState::Receiving(v) = state {
+                    trace!("i2c rx: {v:02x?}");
+                    let _ = self.xfer_rx_chan.send(v).await;
+                }
+            }
+            _ => {
+                warn!("unknown i2c socket event {op:#04x}");
+                return Err(mctp::Error::RxFailure);
+            }
+        }
+
+        debug!("i2c state: {:?}", self.state);
+
+        Ok(Some(if needs_arg { 2 } else { 1 }))
+    }
+
+    async fn process_one(&mut self) -> mctp::Result<()> {
+        // we only master the bus when there is no other transfer in progress
+        let tx_fut = if matches!(self.state, BusState::Idle) {
+            futures::future::Either::Left(self.xfer_tx_chan.recv())
+        } else {
+            futures::future::Either::Right(futures::future::pending())
+        };
+
+        let mut buf = [0u8; 256];
+
+        select!(
+            r = self.stream.read(&mut buf).fuse() => {
+                let n = match r {
+                    Ok(0) => {
+                        info!("i2c socket closed");
+                        return Err(mctp::Error::RxFailure);
+                    }
+                    Ok(n) => n,
+                    Err(e) => {
+                        warn!("i2c socket read error {e:?}");
+                        return Err(mctp::Error::RxFailure);
+                    }
+                };
+                self.rx_buf.extend_from_slice(&buf[..n]);
+            }
+
+            r = tx_fut.fuse() => {
+                let Ok(xfer) = r else {
+                    return Err(mctp::Error::TxFailure);
+                };
+                trace!("i2c tx: {xfer:02x?}");
+                // address byte is sent as part of the start event
+                let dest = xfer[0] >> 1;
+                let mut events = vec![OP_START_SEND, dest];
+                for b in &xfer[1..] {
+                    events.extend_from_slice(&[OP_SEND, *b]);
+                }
+                events.push(OP_STOP);
+                self.write_events(&events).await?;
+            }
+        );
+
+        let mut pending = std::mem::take(&mut self.rx_buf);
+        let mut consumed = 0;
+        while let Some(n) = self.handle_event(&pending[consumed..]).await? {
+            consumed += n;
+        }
+        pending.drain(..consumed);
+        self.rx_buf = pending;
+
+        Ok(())
+    }
+
+    pub async fn process(&mut self) -> mctp::Result<()> {
+        loop {
+            self.process_one().await?
+        }
+    }
+}
+
+impl MctpTransport for MctpQemuI2c {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpQemuI2c::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpQemuI2c::send(self, pkt))
+    }
+
+    fn binding(&self) -> u8 {
+        BINDING_SMBUS
+    }
+
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        let mut port = self.port.take()?;
+        Some(Box::pin(async move { port.process().await }))
+    }
+}
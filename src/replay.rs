@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Replay transport: inbound packets are sourced from a capture file, and
+ * outbound packets are optionally compared against the packets sent in the
+ * original capture.
+ *
+ * Captures without direction information (eg. classic pcap files) are
+ * treated as all-inbound.
+ */
+
+use anyhow::Result;
+use futures::future::LocalBoxFuture;
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::pcap::{self, Direction};
+use crate::transport::MctpTransport;
+
+// time to wait for responses after the last inbound packet
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct MctpReplay {
+    inbound: VecDeque<pcap::Record>,
+    expected: Option<VecDeque<Vec<u8>>>,
+    realtime: bool,
+
+    last_ts: Option<Duration>,
+    next_at: Option<Instant>,
+    rx_cur: Vec<u8>,
+
+    replayed: usize,
+    sent: usize,
+    mismatches: usize,
+    decode_errors: usize,
+    done: bool,
+}
+
+impl MctpReplay {
+    /// Replay packets from the capture file at `path`. If `realtime` is set,
+    /// inbound packets are delayed according to their timestamps. If
+    /// `compare` is set, outbound packets are checked against those in the
+    /// capture.
+    pub fn new(path: &str, realtime: bool, compare: bool) -> Result<Self> {
+        let cap = pcap::read(path)?;
+
+        let has_dir = cap.records.iter().any(|r| r.dir.is_some());
+        if compare && !has_dir {
+            warn!("Capture has no packet directions, not comparing");
+        }
+
+        let mut inbound = VecDeque::new();
+        let mut expected = VecDeque::new();
+        for r in cap.records {
+            if r.dir == Some(Direction::Out) {
+                expected.push_back(r.data);
+            } else {
+                inbound.push_back(r);
+            }
+        }
+
+        info!(
+            "Replaying {} packets from {path}, {} responses to compare",
+            inbound.len(),
+            if compare && has_dir {
+                expected.len()
+            } else {
+                0
+            },
+        );
+
+        Ok(Self {
+            inbound,
+            expected: (compare && has_dir).then_some(expected),
+            realtime,
+            last_ts: None,
+            next_at: None,
+            rx_cur: Vec::new(),
+            replayed: 0,
+            sent: 0,
+            mismatches: 0,
+            decode_errors: cap.decode_errors,
+            done: false,
+        })
+    }
+
+    fn summary(&self) {
+        info!(
+            "Replay complete: {} packets replayed, {} sent, {} decode errors",
+            self.replayed, self.sent, self.decode_errors
+        );
+        if let Some(expected) = &self.expected {
+            info!(
+                "{} mismatched responses, {} expected responses not sent",
+                self.mismatches,
+                expected.len()
+            );
+        }
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        let Some(next) = self.inbound.front() else {
+            // allow the stack to respond to the final packets
+            smol::Timer::after(DRAIN_TIMEOUT).await;
+            self.done = true;
+            self.summary();
+            return Err(mctp::Error::RxFailure);
+        };
+
+        if self.realtime {
+            let delay = self
+                .last_ts
+                .map(|t| next.ts.saturating_sub(t))
+                .unwrap_or_default();
+            let at = *self.next_at.get_or_insert(Instant::now() + delay);
+            smol::Timer::at(at).await;
+        }
+
+        // unwrap: checked for an entry above
+        let r = self.inbound.pop_front().unwrap();
+        self.last_ts = Some(r.ts);
+        self.next_at = None;
+        self.replayed += 1;
+        self.rx_cur = r.data;
+        Ok(&self.rx_cur)
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        self.sent += 1;
+
+        let Some(expected) = &mut self.expected else {
+            debug!("replay tx: {pkt:02x?}");
+            return Ok(());
+        };
+
+        match expected.pop_front() {
+            Some(e) if e == pkt => (),
+            Some(e) => {
+                self.mismatches += 1;
+                warn!("replay tx mismatch: sent {pkt:02x?}, expected {e:02x?}");
+            }
+            None => {
+                self.mismatches += 1;
+                warn!("replay tx: unexpected packet {pkt:02x?}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl MctpTransport for MctpReplay {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpReplay::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpReplay::send(self, pkt))
+    }
+
+    fn closed(&self) -> bool {
+        self.done
+    }
+}
@@ -2,35 +2,828 @@
 //
 use anyhow::{Context, Result};
 use embedded_io_adapters::futures_03::FromFutures;
+use futures::future::{Either, LocalBoxFuture};
+use futures::{select, AsyncRead, AsyncWrite, Future, FutureExt};
+use log::{debug, info, warn};
 use mctp_estack::serial::MctpSerialHandler;
 use smol::Async;
+use std::os::fd::{AsFd, OwnedFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use crate::linkdump;
+use crate::pcap::Direction;
+use crate::transport::{MctpTransport, BINDING_SERIAL};
+use crate::tty::{self, TtyConfig, TtyRestore};
+
+/* baseline MTU for the serial binding */
+const SERIAL_MTU: usize = 251;
+
+const UNIX_CONNECT_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(4);
+
+const INVALID_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/* default limit on sending a single frame to a tty device */
+pub const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/* DSP0253 frame delimiter; escaped within frames */
+const FRAME_FLAG: u8 = 0x7e;
+
+/// Serial framing counters, for one port or for all serial-framed
+/// transports
+#[derive(Debug, Default)]
+pub struct SerialStats {
+    /// valid frames received and sent
+    pub rx_frames: AtomicU64,
+    pub tx_frames: AtomicU64,
+    /// bytes read from and written to the stream
+    pub rx_bytes: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    /// frames delimited in the received stream, whether valid or not
+    pub wire_frames: AtomicU64,
+    /// received bytes outside of any frame, discarded while resynchronising
+    pub discarded: AtomicU64,
+    /// frames dropped as the stream did not accept them in time
+    pub tx_timeouts: AtomicU64,
+}
+
+impl SerialStats {
+    const fn new() -> Self {
+        Self {
+            rx_frames: AtomicU64::new(0),
+            tx_frames: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            wire_frames: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+            tx_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Frames that were delimited in the stream, but not received: bad FCS,
+    /// invalid escapes or lengths
+    pub fn invalid_frames(&self) -> u64 {
+        let wire = self.wire_frames.load(Ordering::Relaxed);
+        wire.saturating_sub(self.rx_frames.load(Ordering::Relaxed))
+    }
+}
+
+impl std::fmt::Display for SerialStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let v = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        write!(
+            f,
+            "rx {} frames/{} bytes, tx {} frames/{} bytes, \
+             {} invalid frames, {} bytes discarded, {} tx timeouts",
+            v(&self.rx_frames),
+            v(&self.rx_bytes),
+            v(&self.tx_frames),
+            v(&self.tx_bytes),
+            self.invalid_frames(),
+            v(&self.discarded),
+            v(&self.tx_timeouts),
+        )
+    }
+}
+
+static STATS: SerialStats = SerialStats::new();
+
+/// Counters for the serial-framed transports, totalled over all ports
+pub fn stats() -> &'static SerialStats {
+    &STATS
+}
+
+/* Counters for one port. Updates are also added to the totals. */
+#[derive(Clone, Default)]
+struct PortStats(Arc<SerialStats>);
+
+impl PortStats {
+    fn add(&self, counter: fn(&SerialStats) -> &AtomicU64, n: usize) {
+        SerialStats::add(counter(&self.0), n);
+        SerialStats::add(counter(&STATS), n);
+    }
+}
+
+/// A byte stream that can carry MCTP serial framing: a tty, or any
+/// stream-oriented socket.
+pub trait SerialStream: AsyncRead + AsyncWrite + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Unpin> SerialStream for T {}
+
+/* Converts a zero-length read (ie., the peer closing the stream) into an
+ * error, so that the serial handler reports a failure rather than spinning on
+ * a closed connection.
+ */
+struct EofDetect {
+    inner: Box<dyn SerialStream>,
+    eof: bool,
+    /* whether we are within a frame of the received stream, and its length
+     * so far; used to count frames independently of the serial handler */
+    in_frame: bool,
+    frame_len: usize,
+    stats: PortStats,
+}
+
+impl EofDetect {
+    // Track frame delimiters in received data. A flag ends a non-empty
+    // frame, otherwise it starts a new one.
+    fn scan(&mut self, data: &[u8]) {
+        self.stats.add(|s| &s.rx_bytes, data.len());
+        for &b in data {
+            if b == FRAME_FLAG {
+                if self.in_frame && self.frame_len > 0 {
+                    self.stats.add(|s| &s.wire_frames, 1);
+                    self.in_frame = false;
+                } else {
+                    self.in_frame = true;
+                }
+                self.frame_len = 0;
+            } else if self.in_frame {
+                self.frame_len += 1;
+            } else {
+                self.stats.add(|s| &s.discarded, 1);
+            }
+        }
+    }
+}
+
+impl AsyncRead for EofDetect {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) if !buf.is_empty() => {
+                self.eof = true;
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "disconnected",
+                )))
+            }
+            Poll::Ready(Ok(n)) => {
+                linkdump::dump("serial", Direction::In, &buf[..n]);
+                self.scan(&buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+            r => r,
+        }
+    }
+}
+
+impl AsyncWrite for EofDetect {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            linkdump::dump("serial", Direction::Out, &buf[..n]);
+            self.stats.add(|s| &s.tx_bytes, n);
+        }
+        r
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/* Combines separate read and write streams into one bidirectional stream */
+struct Duplex<R, W> {
+    rx: R,
+    tx: W,
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Duplex<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.rx).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.tx).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.tx).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.tx).poll_close(cx)
+    }
+}
+
+/* A tty device that is reopened after a disconnect (eg., a USB-serial adapter
+ * being unplugged), retrying with exponential backoff. Reads wait for the
+ * device to reappear; writes are discarded while disconnected.
+ */
+struct ReconnectTty {
+    path: String,
+    config: TtyConfig,
+    file: Option<Async<std::fs::File>>,
+    /* original line settings of the current device */
+    #[allow(unused)]
+    restore: Option<TtyRestore>,
+    delay: Duration,
+    timer: Option<smol::Timer>,
+}
+
+// Open a tty device, and apply the line settings in `config`
+fn open_tty(
+    path: &str,
+    config: &TtyConfig,
+) -> Result<(Async<std::fs::File>, Option<TtyRestore>)> {
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(path)
+        .context("Can't open tty device")?;
+    let restore = tty::configure(f.as_fd(), config)?;
+    Ok((Async::new(f)?, restore))
+}
+
+impl ReconnectTty {
+    fn is_disconnect(e: &std::io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::ENODEV))
+    }
+
+    fn disconnect(&mut self, e: std::io::Error) {
+        warn!("{} disconnected ({e}), reconnecting", self.path);
+        self.file = None;
+        self.restore = None;
+        self.delay = RECONNECT_DELAY_MIN;
+    }
+
+    // Ready once the device is open
+    fn poll_reopen(&mut self, cx: &mut TaskContext<'_>) -> Poll<()> {
+        while self.file.is_none() {
+            let delay = self.delay;
+            let timer =
+                self.timer.get_or_insert_with(|| smol::Timer::after(delay));
+            if Pin::new(timer).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.timer = None;
+
+            info!("Reopening {}", self.path);
+            match open_tty(&self.path, &self.config) {
+                Ok((f, restore)) => {
+                    info!("Reconnected to {}", self.path);
+                    self.file = Some(f);
+                    self.restore = restore;
+                }
+                Err(e) => {
+                    self.delay = (self.delay * 2).min(RECONNECT_DELAY_MAX);
+                    info!(
+                        "Can't open {} ({e:#}), retrying in {:?}",
+                        self.path, self.delay
+                    );
+                }
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+impl AsyncRead for ReconnectTty {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.poll_reopen(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // unwrap: poll_reopen has opened the device
+            let file = self.file.as_mut().unwrap();
+            match Pin::new(file).poll_read(cx, buf) {
+                Poll::Ready(Err(e)) if Self::is_disconnect(&e) => {
+                    self.disconnect(e)
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ReconnectTty {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let Some(file) = self.file.as_mut() else {
+            debug!("{} disconnected, discarding", self.path);
+            return Poll::Ready(Ok(buf.len()));
+        };
+        match Pin::new(file).poll_write(cx, buf) {
+            Poll::Ready(Err(e)) if Self::is_disconnect(&e) => {
+                self.disconnect(e);
+                Poll::Ready(Ok(buf.len()))
+            }
+            r => r,
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.file.as_mut() {
+            Some(f) => Pin::new(f).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.file.as_mut() {
+            Some(f) => Pin::new(f).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
 
 #[allow(unused)]
 pub struct MctpSerial {
     mctpserial: MctpSerialHandler,
-    serial: FromFutures<Async<std::fs::File>>,
+    serial: FromFutures<EofDetect>,
+    pty_slave: Option<OwnedFd>,
+    /* original line settings of a tty device, restored on drop */
+    tty_restore: Option<TtyRestore>,
+    /* tty device for modem line status, and whether we have yet to see DSR
+     * asserted before receiving */
+    modem: Option<OwnedFd>,
+    wait_dsr: bool,
+    /* whether the peer closing the stream is a normal end of the session,
+     * rather than an error
+     */
+    close_ok: bool,
+    /* invalid frame count at the last warning, and when it was logged */
+    invalid_warned: (u64, Option<Instant>),
+    /* limit on sending a frame, if any */
+    send_timeout: Option<Duration>,
+    /* whether the last send timed out; we only warn on the first */
+    tx_stalled: bool,
 }
 
 impl MctpSerial {
-    pub fn new(tty: &str) -> Result<Self> {
-        let serial = std::fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(tty)
-            .context("Can't open tty device")?;
-        let serial = smol::Async::new(serial)?;
-        let serial = embedded_io_adapters::futures_03::FromFutures::new(serial);
+    /// Open a tty device, in raw mode with the line settings in `config`.
+    /// The original settings are restored when the transport is dropped.
+    pub fn new(tty: &str, config: &TtyConfig) -> Result<Self> {
+        let (serial, restore) = open_tty(tty, config)?;
+        let modem = match restore {
+            Some(_) => Some(serial.get_ref().as_fd().try_clone_to_owned()?),
+            None => None,
+        };
+
+        let mut s = Self::from_stream(serial);
+        s.tty_restore = restore;
+        s.send_timeout = Some(SEND_TIMEOUT);
+        s.modem = modem;
+        Ok(s)
+    }
+
+    /// Wait for the DSR line to be asserted before receiving. This has no
+    /// effect on streams other than tty devices opened by `new()`.
+    pub fn set_wait_dsr(&mut self, wait: bool) {
+        self.wait_dsr = wait;
+    }
+
+    /// Open a tty device, reopening it if the device is disconnected, rather
+    /// than failing. Packets in flight at the time of the disconnect are
+    /// lost.
+    pub fn reconnecting(tty: &str, config: &TtyConfig) -> Result<Self> {
+        let (file, restore) = open_tty(tty, config)?;
+        let stream = ReconnectTty {
+            path: tty.to_string(),
+            config: config.clone(),
+            file: Some(file),
+            restore,
+            delay: RECONNECT_DELAY_MIN,
+            timer: None,
+        };
+
+        let mut s = Self::from_stream(stream);
+        s.send_timeout = Some(SEND_TIMEOUT);
+        Ok(s)
+    }
+
+    /// Use an already-open tty (or tty-like) device
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
+        let serial = smol::Async::new(std::fs::File::from(fd))?;
+
+        Ok(Self::from_stream(serial))
+    }
+
+    /// Allocate a new pty, using the master side for the transport. Returns
+    /// the path to the slave device, for the peer to open. If `link` is
+    /// provided, a symlink to the slave device is created there.
+    pub fn pty(link: Option<&str>) -> Result<(Self, PathBuf)> {
+        let pty = crate::pty::Pty::open()?;
+
+        if let Some(link) = link {
+            pty.link(link)?;
+        }
+
+        let mut s = Self::from_fd(pty.master)?;
+        s.pty_slave = Some(pty.slave);
+        s.send_timeout = Some(SEND_TIMEOUT);
+
+        Ok((s, pty.path))
+    }
+
+    /// Connect to a TCP peer at `addr` (in `host:port` format), using the
+    /// MCTP serial framing over the stream.
+    pub fn tcp(addr: &str) -> Result<Self> {
+        let stream = std::net::TcpStream::connect(addr)
+            .with_context(|| format!("Can't connect to {addr}"))?;
+        let stream = Async::new(stream)?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to a unix stream socket at `path`, using the MCTP serial
+    /// framing over the stream. If the socket does not exist yet (or is not
+    /// yet accepting connections), retry up to `attempts` times.
+    pub fn unix(path: &str, attempts: u32) -> Result<Self> {
+        let mut attempt = 1;
+        let stream = loop {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(s) => break s,
+                Err(e)
+                    if attempt < attempts
+                        && matches!(
+                            e.kind(),
+                            std::io::ErrorKind::NotFound
+                                | std::io::ErrorKind::ConnectionRefused
+                        ) =>
+                {
+                    info!("{path} not available ({e}), retrying");
+                    std::thread::sleep(UNIX_CONNECT_DELAY);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Can't connect to {path}"))
+                }
+            }
+        };
+        let stream = Async::new(stream)?;
+
+        let mut s = Self::from_stream(stream);
+        s.close_ok = true;
+        Ok(s)
+    }
+
+    /// Create a vsock connection, using the MCTP serial framing over the
+    /// stream. If `listen` is set, we wait for a connection on `port`,
+    /// otherwise we connect to `cid:port`.
+    pub fn vsock(cid: u32, port: u32, listen: bool) -> Result<Self> {
+        let stream = if listen {
+            crate::vsock::listen(port)?
+        } else {
+            crate::vsock::connect(cid, port)?
+        };
+        let stream = Async::new(stream)?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Use a pair of named pipes, receiving from `rx` and transmitting on
+    /// `tx`, with MCTP serial framing.
+    pub fn fifo(rx: &str, tx: &str) -> Result<Self> {
+        let stream = crate::fifo::FifoPair::open(rx, tx)?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Use a socket passed by systemd socket activation, accepting a
+    /// connection if it is a listening socket.
+    pub fn activated(fd: OwnedFd) -> Result<Self> {
+        let stream = crate::systemd::stream(fd)?;
+        let stream = Async::new(stream)?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Use the process' stdin and stdout, with MCTP serial framing
+    pub fn stdio() -> Result<Self> {
+        // unbuffered access to the stdio fds, as the std handles may
+        // line-buffer our output
+        let stdin = std::io::stdin().as_fd().try_clone_to_owned()?;
+        let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+        let stream = Duplex {
+            rx: smol::Unblock::new(std::fs::File::from(stdin)),
+            tx: smol::Unblock::new(std::fs::File::from(stdout)),
+        };
+
+        let mut s = Self::from_stream(stream);
+        s.close_ok = true;
+        Ok(s)
+    }
+
+    pub fn from_stream(stream: impl SerialStream + 'static) -> Self {
+        let stream = EofDetect {
+            inner: Box::new(stream),
+            eof: false,
+            in_frame: false,
+            frame_len: 0,
+            stats: PortStats::default(),
+        };
+        let serial = embedded_io_adapters::futures_03::FromFutures::new(stream);
 
         let mctpserial = MctpSerialHandler::new();
 
-        Ok(Self { mctpserial, serial })
+        Self {
+            mctpserial,
+            serial,
+            pty_slave: None,
+            tty_restore: None,
+            modem: None,
+            wait_dsr: false,
+            close_ok: false,
+            invalid_warned: (0, None),
+            send_timeout: None,
+            tx_stalled: false,
+        }
+    }
+
+    /// Set the time allowed for sending a frame, or `None` to wait
+    /// indefinitely. Frames that can't be sent in time are dropped, so that
+    /// a peer that has stopped reading can't stall the port. TTY devices
+    /// default to `SEND_TIMEOUT`, other streams to no limit.
+    pub fn set_send_timeout(&mut self, timeout: Option<Duration>) {
+        self.send_timeout = timeout;
+    }
+
+    // Warn if further invalid frames have been received, at most once per
+    // INVALID_WARN_INTERVAL. A noisy link otherwise only shows up as upper
+    // layer timeouts.
+    fn check_invalid(&mut self) {
+        let invalid = self.stats().invalid_frames();
+        let (warned, at) = self.invalid_warned;
+        if invalid <= warned {
+            return;
+        }
+        let now = Instant::now();
+        if at.is_none_or(|t| now.duration_since(t) >= INVALID_WARN_INTERVAL) {
+            warn!(
+                "{} invalid serial frames received ({} total)",
+                invalid - warned,
+                invalid
+            );
+            self.invalid_warned = (invalid, Some(now));
+        }
     }
 
     pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
-        self.mctpserial.recv_async(&mut self.serial).await
+        if self.wait_dsr {
+            if let Some(fd) = &self.modem {
+                tty::wait_dsr(fd.as_fd()).await.map_err(|e| {
+                    warn!("Can't get modem status: {e}");
+                    mctp::Error::RxFailure
+                })?;
+            }
+            self.wait_dsr = false;
+        }
+        self.check_invalid();
+        let r = self.mctpserial.recv_async(&mut self.serial).await;
+        if r.is_ok() {
+            self.serial.inner().stats.add(|s| &s.rx_frames, 1);
+        }
+        r
     }
 
     pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
-        self.mctpserial.send_async(pkt, &mut self.serial).await
+        let stats = self.serial.inner().stats.clone();
+        let send = self.mctpserial.send_async(pkt, &mut self.serial);
+        let timeout = match self.send_timeout {
+            Some(t) => Either::Left(smol::Timer::after(t)),
+            None => Either::Right(futures::future::pending()),
+        };
+
+        // A partially sent frame is left unterminated; the peer discards it
+        // when it sees the start of the next frame.
+        select!(
+            r = send.fuse() => r?,
+            _ = timeout.fuse() => {
+                stats.add(|s| &s.tx_timeouts, 1);
+                if !self.tx_stalled {
+                    warn!("serial send timed out, dropping outbound frames");
+                    self.tx_stalled = true;
+                } else {
+                    debug!("serial send timed out");
+                }
+                return Err(mctp::Error::TxFailure);
+            }
+        );
+
+        if self.tx_stalled {
+            info!("serial send resumed");
+            self.tx_stalled = false;
+        }
+        stats.add(|s| &s.tx_frames, 1);
+        Ok(())
+    }
+
+    /// Framing counters for this port
+    pub fn stats(&self) -> &SerialStats {
+        &self.serial.inner().stats.0
+    }
+}
+
+impl MctpTransport for MctpSerial {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpSerial::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpSerial::send(self, pkt))
+    }
+
+    fn closed(&self) -> bool {
+        self.close_ok && self.serial.inner().eof
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(SERIAL_MTU)
+    }
+
+    fn binding(&self) -> u8 {
+        BINDING_SERIAL
+    }
+
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        // log modem status changes; this never completes, as losing the
+        // status isn't fatal to the transport
+        let fd = self.modem.as_ref()?.try_clone().ok()?;
+        Some(Box::pin(async move {
+            tty::monitor_modem(fd).await;
+            futures::future::pending().await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* an MCTP control Get Endpoint ID request */
+    const MCTP_PKT: [u8; 7] = [0x01, 0x08, 0x09, 0xc8, 0x00, 0x80, 0x02];
+
+    // A framed packet each way over a local TCP connection, then an error
+    // once the peer closes it
+    #[test]
+    fn tcp_round_trip() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut client = MctpSerial::tcp(&addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = MctpSerial::from_stream(Async::new(stream).unwrap());
+
+        smol::block_on(async {
+            client.send(&MCTP_PKT).await.unwrap();
+            assert_eq!(server.recv().await.unwrap(), &MCTP_PKT[..]);
+            server.send(&MCTP_PKT).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), &MCTP_PKT[..]);
+
+            drop(server);
+            assert!(client.recv().await.is_err());
+        });
+    }
+
+    // A framed packet each way over a vsock loopback connection, between
+    // the listen and connect roles
+    #[test]
+    fn vsock_round_trip() {
+        let port = 0x4d43_0000 + std::process::id() % 0x1_0000;
+        let listener = std::thread::spawn(move || {
+            MctpSerial::vsock(libc::VMADDR_CID_LOCAL, port, true).unwrap()
+        });
+
+        /* retry while the listener is starting up; any other failure means
+         * there's no vsock loopback here, so skip the test.
+         */
+        let mut client = loop {
+            match MctpSerial::vsock(libc::VMADDR_CID_LOCAL, port, false) {
+                Ok(c) => break c,
+                Err(e)
+                    if e.downcast_ref::<std::io::Error>().is_some_and(
+                        |e| {
+                            matches!(
+                                e.kind(),
+                                std::io::ErrorKind::ConnectionReset
+                                    | std::io::ErrorKind::ConnectionRefused
+                            )
+                        },
+                    ) && !listener.is_finished() =>
+                {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => {
+                    eprintln!("vsock loopback unavailable, skipping: {e:#}");
+                    return;
+                }
+            }
+        };
+        let mut server = listener.join().unwrap();
+
+        smol::block_on(async {
+            client.send(&MCTP_PKT).await.unwrap();
+            assert_eq!(server.recv().await.unwrap(), &MCTP_PKT[..]);
+            server.send(&MCTP_PKT).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), &MCTP_PKT[..]);
+        });
+    }
+
+    // A corrupt frame between two good ones is counted as invalid, and
+    // both good frames are received. Noise before the first frame is
+    // discarded.
+    #[test]
+    fn corrupt_frame() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        // a good frame, as framed by the transport
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut tx = MctpSerial::from_stream(Async::new(a).unwrap());
+        smol::block_on(tx.send(&MCTP_PKT)).unwrap();
+        drop(tx);
+        let mut good = Vec::new();
+        b.read_to_end(&mut good).unwrap();
+
+        // a payload byte changed, so the FCS doesn't match
+        let mut bad = good.clone();
+        bad[5] ^= 0x01;
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut rx = MctpSerial::from_stream(Async::new(a).unwrap());
+        let noise = [0x00, 0x55, 0xff];
+        b.write_all(&[&noise[..], &good, &bad, &good].concat())
+            .unwrap();
+
+        smol::block_on(async {
+            let mut received = 0;
+            for _ in 0..3 {
+                if let Ok(pkt) = rx.recv().await {
+                    assert_eq!(pkt, MCTP_PKT);
+                    received += 1;
+                    if received == 2 {
+                        break;
+                    }
+                }
+            }
+            assert_eq!(received, 2);
+        });
+
+        let stats = rx.stats();
+        assert_eq!(stats.rx_frames.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.invalid_frames(), 1);
+        assert_eq!(stats.discarded.load(Ordering::Relaxed), 3);
     }
 }
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Signal handling, through a signalfd.
+ *
+ * The signals are blocked, so that they are only delivered through the
+ * signalfd. The signal mask is inherited by new threads, so this needs to be
+ * set up before any other threads are started.
+ */
+
+use anyhow::{bail, Context, Result};
+use smol::Async;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+
+pub struct Signals {
+    fd: Async<File>,
+}
+
+impl Signals {
+    /// Block `signals`, and receive them through this object instead
+    pub fn new(signals: &[libc::c_int]) -> Result<Self> {
+        // SAFETY: the sigset is initialised by sigemptyset before use
+        let fd = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for s in signals {
+                libc::sigaddset(&mut set, *s);
+            }
+
+            let rc = libc::pthread_sigmask(
+                libc::SIG_BLOCK,
+                &set,
+                std::ptr::null_mut(),
+            );
+            if rc != 0 {
+                bail!("Can't block signals: {rc}");
+            }
+
+            libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Can't create signalfd");
+        }
+
+        // SAFETY: fd is a new signalfd, owned by us
+        let f = unsafe { File::from_raw_fd(fd) };
+
+        Ok(Self { fd: Async::new(f)? })
+    }
+
+    /// Wait for the next signal, returning its number
+    pub async fn recv(&self) -> std::io::Result<libc::c_int> {
+        let mut buf = [0u8; std::mem::size_of::<libc::signalfd_siginfo>()];
+        self.fd.read_with(|mut f| f.read_exact(&mut buf)).await?;
+        // ssi_signo is the first field of signalfd_siginfo
+        let signo = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        Ok(signo as libc::c_int)
+    }
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* systemd socket activation: adopt sockets passed to us by the service
+ * manager, as described in sd_listen_fds(3).
+ *
+ * Sockets may be either listening (Accept=no), in which case we accept a
+ * connection from them, or already connected (Accept=yes).
+ */
+
+use anyhow::{bail, Result};
+use log::info;
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+static LISTEN_FDS: Mutex<Option<VecDeque<OwnedFd>>> = Mutex::new(None);
+
+fn listen_fds() -> VecDeque<OwnedFd> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    // don't pass these on to any child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid != Some(std::process::id()) {
+        return VecDeque::new();
+    }
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: fds from LISTEN_FDS are passed to us by the service
+            // manager, and owned by this process
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                OwnedFd::from_raw_fd(fd)
+            }
+        })
+        .collect()
+}
+
+/// Take the next socket passed by systemd socket activation, if any. Each
+/// socket is only returned once, in the order they were passed.
+pub fn take_fd() -> Option<OwnedFd> {
+    let mut fds = LISTEN_FDS.lock().unwrap();
+    let fd = fds.get_or_insert_with(listen_fds).pop_front();
+    if let Some(fd) = &fd {
+        info!("Using socket fd {} from systemd", fd.as_raw_fd());
+    }
+    fd
+}
+
+/// Whether `fd` is a listening socket, rather than a connected stream
+pub fn is_listening(fd: &OwnedFd) -> Result<bool> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: val and len are valid for SO_ACCEPTCONN
+    let rc = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut val as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        bail!(
+            "Can't query activated socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(val != 0)
+}
+
+/// Get a connected stream from an activated socket, waiting for a connection
+/// if it's a listening socket.
+pub fn stream(fd: OwnedFd) -> Result<std::fs::File> {
+    if !is_listening(&fd)? {
+        return Ok(std::fs::File::from(fd));
+    }
+
+    info!("Waiting for connection on activated socket");
+
+    // SAFETY: valid fd, we don't need the peer address
+    let conn = unsafe {
+        libc::accept4(
+            fd.as_raw_fd(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if conn < 0 {
+        bail!(
+            "Can't accept connection: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    // SAFETY: we have just created this fd
+    let conn = unsafe { OwnedFd::from_raw_fd(conn) };
+
+    Ok(std::fs::File::from(conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::process::CommandExt;
+
+    // Run by socket_activation in a child process, with a connected socket
+    // on fd 3
+    #[test]
+    #[ignore]
+    fn activated_child() {
+        if std::env::var_os("LISTEN_FDS").is_none() {
+            return;
+        }
+        let fd = take_fd().expect("no activated socket");
+        assert!(take_fd().is_none());
+        let mut s = stream(fd).unwrap();
+        s.write_all(b"mctp").unwrap();
+    }
+
+    // A socketpair passed as fd 3 through LISTEN_FDS and LISTEN_PID is
+    // taken once, and usable as a stream
+    #[test]
+    fn socket_activation() {
+        let (mut ours, theirs) =
+            std::os::unix::net::UnixStream::pair().unwrap();
+        let theirs_fd = theirs.as_raw_fd();
+        let exe = std::env::current_exe().unwrap();
+
+        /* sh sets LISTEN_PID to its own pid, which the test binary keeps
+         * over the exec
+         */
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c")
+            .arg("LISTEN_PID=$$ exec \"$0\" \"$@\"")
+            .arg(exe)
+            .args(["--exact", "systemd::tests::activated_child", "--ignored"])
+            .env("LISTEN_FDS", "1");
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::dup2(theirs_fd, SD_LISTEN_FDS_START) < 0
+                    || libc::fcntl(SD_LISTEN_FDS_START, libc::F_SETFD, 0) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let out = cmd.output().unwrap();
+        drop(theirs);
+        assert!(out.status.success(), "{out:?}");
+        assert!(String::from_utf8_lossy(&out.stdout).contains("1 passed"));
+
+        let mut buf = Vec::new();
+        ours.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"mctp");
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Common interface for the MCTP transports. Transports pass MCTP packets
+ * (starting with the MCTP header) between the router and the binding.
+ */
+
+use futures::future::LocalBoxFuture;
+
+/* DSP0239 physical transport binding identifiers */
+pub const BINDING_SMBUS: u8 = 0x01;
+pub const BINDING_USB: u8 = 0x03;
+pub const BINDING_SERIAL: u8 = 0x05;
+pub const BINDING_VENDOR: u8 = 0xff;
+
+pub trait MctpTransport {
+    /// Receive the next inbound packet
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>>;
+
+    /// Send an outbound packet
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>>;
+
+    /// Whether the transport has been closed by the peer, as a normal end of
+    /// the session. Only checked after `recv()` has failed.
+    fn closed(&self) -> bool {
+        false
+    }
+
+    /// Maximum MCTP packet size that the transport can carry, if limited.
+    /// The router fragments outbound messages to fit.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// DSP0239 physical transport binding identifier, as reported in
+    /// routing table entries
+    fn binding(&self) -> u8 {
+        BINDING_VENDOR
+    }
+
+    /// Background processing required by the transport, to be run
+    /// concurrently with `recv()` and `send()`. This is only called once,
+    /// before the transport is started.
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        None
+    }
+}
+
+impl<T: MctpTransport + ?Sized> MctpTransport for Box<T> {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        (**self).recv()
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        (**self).send(pkt)
+    }
+
+    fn closed(&self) -> bool {
+        (**self).closed()
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        (**self).mtu()
+    }
+
+    fn binding(&self) -> u8 {
+        (**self).binding()
+    }
+
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        (**self).background()
+    }
+}
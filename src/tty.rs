@@ -0,0 +1,331 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Line settings for TTY devices used by the serial transport. A TTY may have
+ * been left with any settings by its previous user; echo, canonical mode or
+ * newline translation would all corrupt the serial framing, so we put the
+ * device into raw mode, and restore the original settings on close.
+ */
+
+use anyhow::{bail, Result};
+use log::{debug, info};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::time::Duration;
+
+const DSR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MODEM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* modem status lines logged on change */
+const MODEM_STATUS_LINES: &[(libc::c_int, &str)] = &[
+    (libc::TIOCM_CD, "carrier"),
+    (libc::TIOCM_CTS, "CTS"),
+    (libc::TIOCM_DSR, "DSR"),
+];
+
+/// Flow control for a TTY device
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlowControl {
+    #[default]
+    None,
+    RtsCts,
+}
+
+impl std::str::FromStr for FlowControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "rtscts" => Ok(Self::RtsCts),
+            _ => Err(format!(
+                "unknown flow control '{s}', expected none or rtscts"
+            )),
+        }
+    }
+}
+
+/// Line settings applied to a TTY device
+#[derive(Clone, Debug, Default)]
+pub struct TtyConfig {
+    /// baud rate; if unset, the current rate is kept
+    pub baud: Option<u32>,
+    pub flow: FlowControl,
+    /// state to set the DTR and RTS lines to; if unset, the line is left
+    /// unchanged
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+}
+
+/// The original settings of a configured TTY, restored on drop
+pub struct TtyRestore {
+    fd: OwnedFd,
+    termios: libc::termios,
+}
+
+impl Drop for TtyRestore {
+    fn drop(&mut self) {
+        // SAFETY: termios was populated by tcgetattr
+        let rc = unsafe {
+            libc::tcsetattr(self.fd.as_raw_fd(), libc::TCSANOW, &self.termios)
+        };
+        if rc < 0 {
+            debug!(
+                "Can't restore tty attributes: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+fn baud_speed(baud: u32) -> Option<libc::speed_t> {
+    let speed = match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        576000 => libc::B576000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1152000 => libc::B1152000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        2500000 => libc::B2500000,
+        3000000 => libc::B3000000,
+        3500000 => libc::B3500000,
+        4000000 => libc::B4000000,
+        _ => return None,
+    };
+    Some(speed)
+}
+
+/// Put `fd` into raw mode, with the line settings from `config`. Returns
+/// the original settings, to be restored when dropped, or `None` if `fd` is
+/// not a TTY (such as a pipe or socket), in which case it is left untouched.
+pub fn configure(
+    fd: BorrowedFd<'_>,
+    config: &TtyConfig,
+) -> Result<Option<TtyRestore>> {
+    let raw = fd.as_raw_fd();
+
+    // SAFETY: isatty has no memory safety requirements
+    if unsafe { libc::isatty(raw) } == 0 {
+        debug!("not a tty, skipping line configuration");
+        return Ok(None);
+    }
+
+    let speed = match config.baud {
+        Some(b) => match baud_speed(b) {
+            Some(s) => Some(s),
+            None => bail!("Unsupported baud rate {b}"),
+        },
+        None => None,
+    };
+
+    // SAFETY: termios is fully initialised by tcgetattr
+    let orig = unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(raw, &mut termios) < 0 {
+            bail!(
+                "Can't get tty attributes: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        termios
+    };
+
+    let mut termios = orig;
+    // SAFETY: termios is a valid, initialised struct
+    unsafe {
+        libc::cfmakeraw(&mut termios);
+        if let Some(speed) = speed {
+            libc::cfsetispeed(&mut termios, speed);
+            libc::cfsetospeed(&mut termios, speed);
+        }
+    }
+    // enable the receiver, and ignore modem control lines
+    termios.c_cflag |= libc::CREAD | libc::CLOCAL;
+    match config.flow {
+        FlowControl::None => termios.c_cflag &= !libc::CRTSCTS,
+        FlowControl::RtsCts => termios.c_cflag |= libc::CRTSCTS,
+    }
+
+    // SAFETY: termios is a valid, initialised struct
+    if unsafe { libc::tcsetattr(raw, libc::TCSANOW, &termios) } < 0 {
+        bail!(
+            "Can't set tty attributes: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    if config.dtr.is_some() || config.rts.is_some() {
+        set_modem_lines(fd, config)?;
+    }
+
+    Ok(Some(TtyRestore {
+        fd: fd.try_clone_to_owned()?,
+        termios: orig,
+    }))
+}
+
+fn modem_status(fd: BorrowedFd<'_>) -> std::io::Result<libc::c_int> {
+    let mut bits: libc::c_int = 0;
+    // SAFETY: bits is a valid pointer for TIOCMGET
+    let rc = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCMGET, &mut bits) };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(bits)
+}
+
+fn set_modem_lines(fd: BorrowedFd<'_>, config: &TtyConfig) -> Result<()> {
+    let mut bits = match modem_status(fd) {
+        Ok(b) => b,
+        Err(e) => bail!("Can't get modem lines: {e}"),
+    };
+    for (state, line) in
+        [(config.dtr, libc::TIOCM_DTR), (config.rts, libc::TIOCM_RTS)]
+    {
+        match state {
+            Some(true) => bits |= line,
+            Some(false) => bits &= !line,
+            None => (),
+        }
+    }
+
+    // SAFETY: bits is a valid pointer for TIOCMSET
+    let rc = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCMSET, &bits) };
+    if rc < 0 {
+        bail!("Can't set modem lines: {}", std::io::Error::last_os_error());
+    }
+    debug!("modem lines set to {bits:#x}");
+    Ok(())
+}
+
+/// Wait until the DSR line of a TTY is asserted
+pub async fn wait_dsr(fd: BorrowedFd<'_>) -> std::io::Result<()> {
+    let mut logged = false;
+    while modem_status(fd)? & libc::TIOCM_DSR == 0 {
+        if !logged {
+            info!("Waiting for DSR");
+            logged = true;
+        }
+        smol::Timer::after(DSR_POLL_INTERVAL).await;
+    }
+    if logged {
+        info!("DSR asserted");
+    }
+    Ok(())
+}
+
+/// Poll the modem status lines of a TTY, logging any changes. Polling stops
+/// if the status can no longer be read, such as when the device has been
+/// removed.
+pub async fn monitor_modem(fd: OwnedFd) {
+    let mut prev = None;
+    loop {
+        let bits = match modem_status(fd.as_fd()) {
+            Ok(b) => b,
+            Err(e) => {
+                debug!("Can't get modem status ({e}), stopping polling");
+                return;
+            }
+        };
+        for (line, name) in MODEM_STATUS_LINES {
+            let on = bits & line != 0;
+            if prev.is_none_or(|p| (p & line != 0) != on) {
+                info!("{name} {}", if on { "asserted" } else { "deasserted" });
+            }
+        }
+        prev = Some(bits);
+        smol::Timer::after(MODEM_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::FromRawFd;
+
+    fn attrs(fd: BorrowedFd<'_>) -> libc::termios {
+        // SAFETY: termios is fully initialised by tcgetattr
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            assert_eq!(libc::tcgetattr(fd.as_raw_fd(), &mut termios), 0);
+            termios
+        }
+    }
+
+    // A new PTY pair, as (master, slave)
+    fn pty() -> (OwnedFd, OwnedFd) {
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: the name, termios and winsize pointers may be null
+        let rc = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(rc, 0, "openpty: {}", std::io::Error::last_os_error());
+        // SAFETY: openpty returned two new fds
+        unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) }
+    }
+
+    // The slave side of a PTY is put into raw mode at the configured rate,
+    // and its original settings are restored on drop
+    #[test]
+    fn raw_mode() {
+        let (_master, slave) = pty();
+        let orig = attrs(slave.as_fd());
+        assert_ne!(orig.c_lflag & (libc::ECHO | libc::ICANON), 0);
+
+        let config = TtyConfig {
+            baud: Some(115200),
+            flow: FlowControl::None,
+            ..Default::default()
+        };
+        let restore = configure(slave.as_fd(), &config).unwrap().unwrap();
+
+        let t = attrs(slave.as_fd());
+        assert_eq!(t.c_lflag & (libc::ECHO | libc::ICANON | libc::ISIG), 0);
+        assert_eq!(t.c_iflag & (libc::ICRNL | libc::IXON), 0);
+        assert_eq!(t.c_oflag & libc::OPOST, 0);
+        assert_eq!(t.c_cflag & libc::CSIZE, libc::CS8);
+        assert_eq!(t.c_cflag & libc::CRTSCTS, 0);
+        // SAFETY: t is a valid, initialised struct
+        assert_eq!(unsafe { libc::cfgetospeed(&t) }, libc::B115200);
+
+        drop(restore);
+        let t = attrs(slave.as_fd());
+        assert_eq!(
+            (t.c_iflag, t.c_oflag, t.c_lflag),
+            (orig.c_iflag, orig.c_oflag, orig.c_lflag)
+        );
+    }
+
+    // A socket is left untouched
+    #[test]
+    fn not_a_tty() {
+        let (sock, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = sock.as_fd();
+        assert!(configure(fd, &TtyConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn unsupported_baud() {
+        let (_master, slave) = pty();
+        let config = TtyConfig {
+            baud: Some(12345),
+            ..Default::default()
+        };
+        assert!(configure(slave.as_fd(), &config).is_err());
+    }
+}
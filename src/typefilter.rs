@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Filtering of inbound messages by message type. The router silently holds
+ * messages of types that have no listener, so we drop them before they reach
+ * the router, logging and counting them by type. The filter also allows
+ * emulating a device that refuses particular message types.
+ *
+ * Only the first packet of a message carries its type. Dropping it is
+ * enough: the router discards the following packets, having no reassembly
+ * in progress for them.
+ */
+
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/* MCTP packet header fields */
+const HDR_LEN: usize = 4;
+const FLAG_SOM: u8 = 0x80;
+const MSG_TYPE_MASK: u8 = 0x7f;
+
+/// Parse a list of message types, as comma-separated numbers
+pub fn parse_types(s: &str) -> Result<Vec<u8>, String> {
+    s.split(',').map(|t| crate::parse_int(t.trim())).collect()
+}
+
+/// Inbound message type filter
+pub struct TypeFilter {
+    // types with a local listener
+    supported: Vec<u8>,
+    // if set, only these types are accepted
+    allow: Option<Vec<u8>>,
+    deny: Vec<u8>,
+    // dropped messages, by type
+    dropped: Mutex<BTreeMap<u8, u64>>,
+}
+
+impl TypeFilter {
+    pub fn new(
+        supported: Vec<u8>,
+        allow: Option<Vec<u8>>,
+        deny: Vec<u8>,
+    ) -> Self {
+        Self {
+            supported,
+            allow,
+            deny,
+            dropped: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Check an inbound packet for a local EID, returning false if it should
+    /// be dropped
+    pub fn accept(&self, pkt: &[u8]) -> bool {
+        let Some(&flags) = pkt.get(3) else {
+            return true;
+        };
+        if flags & FLAG_SOM == 0 {
+            return true;
+        }
+        let Some(typ) = pkt.get(HDR_LEN).map(|t| t & MSG_TYPE_MASK) else {
+            return true;
+        };
+
+        let reason = if self.deny.contains(&typ)
+            || self.allow.as_ref().is_some_and(|a| !a.contains(&typ))
+        {
+            "filtered"
+        } else if !self.supported.contains(&typ) {
+            "unsupported"
+        } else {
+            return true;
+        };
+
+        info!(
+            "Dropping {reason} message type {typ:#04x} from EID {}, \
+             first packet {} bytes",
+            pkt[2],
+            pkt.len() - HDR_LEN
+        );
+        *self.dropped.lock().unwrap().entry(typ).or_default() += 1;
+        false
+    }
+}
+
+impl std::fmt::Display for TypeFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let dropped = self.dropped.lock().unwrap();
+        if dropped.is_empty() {
+            return write!(f, "none dropped");
+        }
+        let mut sep = "";
+        for (typ, count) in dropped.iter() {
+            write!(f, "{sep}type {typ:#04x}: {count} dropped")?;
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
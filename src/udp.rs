@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* UDP transport: each datagram carries a single MCTP packet, with no further
+ * framing.
+ */
+
+use anyhow::{Context, Result};
+use futures::future::LocalBoxFuture;
+use log::{debug, trace, warn};
+use smol::Async;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::transport::MctpTransport;
+
+pub struct MctpUdp {
+    sock: Async<UdpSocket>,
+    peer: SocketAddr,
+    mtu: usize,
+    rx_buf: Vec<u8>,
+    rx_len: usize,
+}
+
+impl MctpUdp {
+    /// Create a UDP transport bound to `local`, exchanging packets with the
+    /// single peer at `remote`. `mtu` is the maximum datagram size, which
+    /// includes the MCTP packet header.
+    pub fn new(local: &str, remote: &str, mtu: usize) -> Result<Self> {
+        let peer = remote
+            .to_socket_addrs()
+            .with_context(|| format!("Can't resolve {remote}"))?
+            .next()
+            .with_context(|| format!("No addresses for {remote}"))?;
+
+        let sock = UdpSocket::bind(local)
+            .with_context(|| format!("Can't bind to {local}"))?;
+
+        Ok(Self {
+            sock: Async::new(sock)?,
+            peer,
+            mtu,
+            rx_buf: vec![0u8; mtu],
+            rx_len: 0,
+        })
+    }
+
+    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        loop {
+            let (len, addr) =
+                self.sock.recv_from(&mut self.rx_buf).await.map_err(|e| {
+                    warn!("UDP recv failed: {e}");
+                    mctp::Error::RxFailure
+                })?;
+
+            if addr != self.peer {
+                debug!("UDP rx: dropping datagram from unknown peer {addr}");
+                continue;
+            }
+
+            trace!("UDP rx: {:02x?}", &self.rx_buf[..len]);
+            self.rx_len = len;
+            return Ok(&self.rx_buf[..self.rx_len]);
+        }
+    }
+
+    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        if pkt.len() > self.mtu {
+            warn!(
+                "UDP tx: packet length {} exceeds MTU {}, dropping",
+                pkt.len(),
+                self.mtu
+            );
+            return Err(mctp::Error::NoSpace);
+        }
+
+        trace!("UDP tx: {pkt:02x?}");
+        self.sock.send_to(pkt, self.peer).await.map_err(|e| {
+            warn!("UDP send failed: {e}");
+            mctp::Error::TxFailure
+        })?;
+        Ok(())
+    }
+}
+
+impl MctpTransport for MctpUdp {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(MctpUdp::recv(self))
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(MctpUdp::send(self, pkt))
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(self.mtu)
+    }
+}
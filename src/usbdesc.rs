@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* USB standard descriptors, built at runtime and serialised to their wire
+ * format. Lengths and counts (bLength, wTotalLength, bNumInterfaces,
+ * bNumEndpoints) are computed during serialisation.
+ */
+
+pub const USB_DESC_TYPE_DEVICE: u8 = 1;
+pub const USB_DESC_TYPE_CONFIGURATION: u8 = 2;
+pub const USB_DESC_TYPE_STRING: u8 = 3;
+pub const USB_DESC_TYPE_INTERFACE: u8 = 4;
+pub const USB_DESC_TYPE_ENDPOINT: u8 = 5;
+pub const USB_DESC_TYPE_DEVICE_QUALIFIER: u8 = 6;
+pub const USB_DESC_TYPE_OTHER_SPEED_CONFIGURATION: u8 = 7;
+pub const USB_DESC_TYPE_BOS: u8 = 15;
+pub const USB_DESC_TYPE_DEVICE_CAPABILITY: u8 = 16;
+pub const USB_DESC_TYPE_SS_ENDPOINT_COMPANION: u8 = 48;
+
+pub const USB_ENDPOINT_XFER_BULK: u8 = 2;
+pub const USB_ENDPOINT_XFER_INT: u8 = 3;
+
+/// Device descriptor
+#[derive(Clone, Debug)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// ep0 max packet size; an exponent of 2 for SuperSpeed devices
+    pub max_packet0: u8,
+    pub vid: u16,
+    pub pid: u16,
+    pub device_version: u16,
+    pub manufacturer_idx: u8,
+    pub product_idx: u8,
+    pub serial_idx: u8,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![18, USB_DESC_TYPE_DEVICE];
+        v.extend_from_slice(&self.usb_version.to_le_bytes());
+        v.extend_from_slice(&[
+            self.class,
+            self.subclass,
+            self.protocol,
+            self.max_packet0,
+        ]);
+        v.extend_from_slice(&self.vid.to_le_bytes());
+        v.extend_from_slice(&self.pid.to_le_bytes());
+        v.extend_from_slice(&self.device_version.to_le_bytes());
+        v.extend_from_slice(&[
+            self.manufacturer_idx,
+            self.product_idx,
+            self.serial_idx,
+            self.num_configurations,
+        ]);
+        v
+    }
+
+    /// Device qualifier descriptor, describing this device at the other
+    /// speed
+    pub fn qualifier_bytes(&self) -> Vec<u8> {
+        let mut v = vec![10, USB_DESC_TYPE_DEVICE_QUALIFIER];
+        v.extend_from_slice(&self.usb_version.to_le_bytes());
+        v.extend_from_slice(&[
+            self.class,
+            self.subclass,
+            self.protocol,
+            self.max_packet0,
+            self.num_configurations,
+            0, /* bReserved */
+        ]);
+        v
+    }
+}
+
+/// Configuration descriptor, including its interfaces
+#[derive(Clone, Debug)]
+pub struct ConfigDescriptor {
+    /// configuration or other-speed configuration
+    pub desc_type: u8,
+    pub value: u8,
+    pub config_idx: u8,
+    pub attributes: u8,
+    /// in units of 2mA
+    pub max_power: u8,
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigDescriptor {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![
+            9,
+            self.desc_type,
+            0, /* wTotalLength, set below */
+            0,
+            self.interfaces.len() as u8,
+            self.value,
+            self.config_idx,
+            self.attributes,
+            self.max_power,
+        ];
+        for iface in &self.interfaces {
+            iface.write(&mut v);
+        }
+        let len = v.len() as u16;
+        v[2..4].copy_from_slice(&len.to_le_bytes());
+        v
+    }
+}
+
+/// Interface descriptor, including its endpoints
+#[derive(Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alt_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub interface_idx: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+impl InterfaceDescriptor {
+    /// Append the interface and endpoint descriptors to `v`
+    pub fn write(&self, v: &mut Vec<u8>) {
+        v.extend_from_slice(&[
+            9,
+            USB_DESC_TYPE_INTERFACE,
+            self.number,
+            self.alt_setting,
+            self.endpoints.len() as u8,
+            self.class,
+            self.subclass,
+            self.protocol,
+            self.interface_idx,
+        ]);
+        for ep in &self.endpoints {
+            ep.write(v);
+        }
+    }
+}
+
+/// Endpoint descriptor, with an optional SuperSpeed endpoint companion
+#[derive(Clone, Debug)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet: u16,
+    pub interval: u8,
+    /// append a SuperSpeed endpoint companion descriptor, with no bursts or
+    /// streams
+    pub ss_companion: bool,
+}
+
+impl EndpointDescriptor {
+    /// Append the endpoint (and companion) descriptors to `v`
+    pub fn write(&self, v: &mut Vec<u8>) {
+        v.extend_from_slice(&[
+            7,
+            USB_DESC_TYPE_ENDPOINT,
+            self.address,
+            self.attributes,
+        ]);
+        v.extend_from_slice(&self.max_packet.to_le_bytes());
+        v.push(self.interval);
+
+        if self.ss_companion {
+            /* only periodic endpoints reserve bandwidth */
+            let bytes_per_interval =
+                if self.attributes & 0x3 == USB_ENDPOINT_XFER_INT {
+                    self.max_packet
+                } else {
+                    0
+                };
+            v.extend_from_slice(&[
+                6,
+                USB_DESC_TYPE_SS_ENDPOINT_COMPANION,
+                0, /* bMaxBurst */
+                0, /* bmAttributes: no streams */
+            ]);
+            v.extend_from_slice(&bytes_per_interval.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* the hand-written descriptors these structs replaced */
+    #[rustfmt::skip]
+    const DEV_DESC: [u8; 18] = [
+        18, USB_DESC_TYPE_DEVICE,
+        0x00, 0x02, /* bcdUSB */
+        0x00, 0x00, 0x00, /* class, subclass, protocol */
+        0x40, /* bMaxPacketSize0 */
+        0x00, 0x00, /* idVendor */
+        0x00, 0x00, /* idProduct */
+        0x00, 0x00, /* bcdDevice */
+        0x01, 0x02, 0x03, /* string indices */
+        0x01, /* bNumConfigurations */
+    ];
+
+    #[rustfmt::skip]
+    const CONFIG_DESC: [u8; 9] = [
+        0x09, USB_DESC_TYPE_CONFIGURATION,
+        0x00, 0x00, /* wTotalLength, patched */
+        0x01, /* bNumInterfaces */
+        0x01, /* bConfigurationValue */
+        0x00, /* iConfiguration */
+        0x80, /* bmAttributes: bus powered */
+        0x01, /* bMaxPower: 2ma */
+    ];
+
+    #[rustfmt::skip]
+    const IFACE_DESC: [u8; 9] = [
+        0x09, USB_DESC_TYPE_INTERFACE,
+        0x00, /* bInterfaceNumber */
+        0x00, /* bAlternateSetting */
+        0x02, /* bNumEndpoints */
+        0x14, /* bInterfaceClass: MCTP */
+        0x00, /* bInterfaceSubClass */
+        0x01, /* bInterfaceProtocol: MCTP 1.0 */
+        0x04, /* iInterface */
+    ];
+
+    #[rustfmt::skip]
+    const EP_DESCS: [[u8; 7]; 2] = [
+        [0x07, USB_DESC_TYPE_ENDPOINT, 0x01, 0x02, 0x00, 0x02, 0],
+        [0x07, USB_DESC_TYPE_ENDPOINT, 0x81, 0x02, 0x00, 0x02, 0],
+    ];
+
+    fn endpoint(address: u8) -> EndpointDescriptor {
+        EndpointDescriptor {
+            address,
+            attributes: USB_ENDPOINT_XFER_BULK,
+            max_packet: 512,
+            interval: 0,
+            ss_companion: false,
+        }
+    }
+
+    fn interface() -> InterfaceDescriptor {
+        InterfaceDescriptor {
+            number: 0,
+            alt_setting: 0,
+            class: 0x14,
+            subclass: 0,
+            protocol: 1,
+            interface_idx: 4,
+            endpoints: vec![endpoint(0x01), endpoint(0x81)],
+        }
+    }
+
+    #[test]
+    fn device() {
+        let dev = DeviceDescriptor {
+            usb_version: 0x0200,
+            class: 0,
+            subclass: 0,
+            protocol: 0,
+            max_packet0: 64,
+            vid: 0,
+            pid: 0,
+            device_version: 0,
+            manufacturer_idx: 1,
+            product_idx: 2,
+            serial_idx: 3,
+            num_configurations: 1,
+        };
+        assert_eq!(dev.to_bytes(), DEV_DESC);
+    }
+
+    #[test]
+    fn endpoints() {
+        for (ep, expected) in interface().endpoints.iter().zip(EP_DESCS) {
+            let mut v = Vec::new();
+            ep.write(&mut v);
+            assert_eq!(v, expected);
+        }
+    }
+
+    #[test]
+    fn iface() {
+        let mut expected = IFACE_DESC.to_vec();
+        expected.extend(EP_DESCS.iter().flatten());
+
+        let mut v = Vec::new();
+        interface().write(&mut v);
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn config() {
+        let mut expected = CONFIG_DESC.to_vec();
+        expected.extend_from_slice(&IFACE_DESC);
+        expected.extend(EP_DESCS.iter().flatten());
+        let len = expected.len() as u16;
+        expected[2..4].copy_from_slice(&len.to_le_bytes());
+
+        let config = ConfigDescriptor {
+            desc_type: USB_DESC_TYPE_CONFIGURATION,
+            value: 1,
+            config_idx: 0,
+            attributes: 0x80,
+            max_power: 1,
+            interfaces: vec![interface()],
+        };
+        assert_eq!(config.to_bytes(), expected);
+    }
+}
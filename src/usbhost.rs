@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Minimal usbredir host, for testing the usbredir transport end-to-end.
+ *
+ * This plays the usbredirhost side of the protocol against a mctp-dev
+ * device: exchange hellos, fetch the descriptors, set the configuration,
+ * then send an MCTP control Get Endpoint ID request as a bulk OUT transfer,
+ * and wait for the response on the bulk IN endpoint.
+ *
+ * We advertise no usbredir capabilities, so packets use 32-bit ids and
+ * 16-bit bulk lengths, and can be encoded by hand.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, trace};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::usbdesc::{
+    USB_DESC_TYPE_CONFIGURATION, USB_DESC_TYPE_DEVICE, USB_DESC_TYPE_ENDPOINT,
+    USB_DESC_TYPE_INTERFACE, USB_ENDPOINT_XFER_BULK,
+};
+use crate::usbredir::{USB_CLASS_MCTP, USB_XFER_SIZE};
+
+/* usbredir packet types */
+const HELLO: u32 = 0;
+const DEVICE_CONNECT: u32 = 1;
+const DEVICE_DISCONNECT: u32 = 2;
+const SET_CONFIGURATION: u32 = 6;
+const CONFIGURATION_STATUS: u32 = 8;
+const CONTROL_PACKET: u32 = 100;
+const BULK_PACKET: u32 = 101;
+
+const STATUS_SUCCESS: u8 = 0;
+
+const USB_CTRL_GET_DESCRIPTOR: u8 = 6;
+const USB_DIR_IN: u8 = 0x80;
+
+const USB_HDR: [u8; 3] = [0x1a, 0xb4, 0x00];
+const USB_HDR_LEN: usize = 4;
+
+/* our EID, as the source of the request */
+const HOST_EID: u8 = 8;
+const MCTP_HDR_VER: u8 = 1;
+/* SOM, EOM, TO, tag 0 */
+const MCTP_FLAGS_REQ: u8 = 0xc8;
+const MCTP_CTRL_GET_EID: u8 = 0x02;
+const MCTP_CTRL_RQ: u8 = 0x80;
+
+/* IN transfers kept queued while waiting for the response */
+const IN_XFERS: u32 = 2;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Host {
+    sock: UnixStream,
+    next_id: u32,
+}
+
+impl Host {
+    fn id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn send(
+        &mut self,
+        typ: u32,
+        id: u32,
+        hdr: &[u8],
+        data: &[u8],
+    ) -> Result<()> {
+        let len = (hdr.len() + data.len()) as u32;
+        let mut pkt = Vec::with_capacity(12 + len as usize);
+        pkt.extend_from_slice(&typ.to_le_bytes());
+        pkt.extend_from_slice(&len.to_le_bytes());
+        pkt.extend_from_slice(&id.to_le_bytes());
+        pkt.extend_from_slice(hdr);
+        pkt.extend_from_slice(data);
+        self.sock.write_all(&pkt).context("usbredir write failed")
+    }
+
+    // Receive the next packet: type, id, and type header followed by data
+    fn recv(&mut self) -> Result<(u32, u32, Vec<u8>)> {
+        let mut hdr = [0u8; 12];
+        self.sock
+            .read_exact(&mut hdr)
+            .context("usbredir read failed")?;
+        let field = |i: usize| {
+            u32::from_le_bytes([hdr[i], hdr[i + 1], hdr[i + 2], hdr[i + 3]])
+        };
+        let (typ, len, id) = (field(0), field(4), field(8));
+        let mut payload = vec![0u8; len as usize];
+        self.sock
+            .read_exact(&mut payload)
+            .context("usbredir read failed")?;
+        trace!("usbredir packet type {typ} id {id}: {payload:02x?}");
+        if typ == DEVICE_DISCONNECT {
+            bail!("Device disconnected");
+        }
+        Ok((typ, id, payload))
+    }
+
+    // Receive packets until one of type `typ`, returning its id and payload
+    fn expect(&mut self, typ: u32) -> Result<(u32, Vec<u8>)> {
+        loop {
+            let (t, id, payload) = self.recv()?;
+            if t == typ {
+                return Ok((id, payload));
+            }
+            debug!("ignoring usbredir packet type {t}");
+        }
+    }
+
+    fn get_descriptor(&mut self, typ: u8, length: u16) -> Result<Vec<u8>> {
+        let id = self.id();
+        let mut hdr = vec![0, USB_CTRL_GET_DESCRIPTOR, USB_DIR_IN, 0];
+        hdr.extend_from_slice(&((typ as u16) << 8).to_le_bytes());
+        hdr.extend_from_slice(&0u16.to_le_bytes());
+        hdr.extend_from_slice(&length.to_le_bytes());
+        self.send(CONTROL_PACKET, id, &hdr, &[])?;
+
+        let (rid, resp) = self.expect(CONTROL_PACKET)?;
+        if rid != id || resp.len() < 10 {
+            bail!("Unexpected control response {rid}");
+        }
+        if resp[3] != STATUS_SUCCESS {
+            bail!("Descriptor type {typ} request failed, status {}", resp[3]);
+        }
+        let desc = resp[10..].to_vec();
+        if desc.len() < 2 || desc[1] != typ {
+            bail!("Invalid descriptor type {typ}: {desc:02x?}");
+        }
+        Ok(desc)
+    }
+
+    fn bulk(&mut self, ep: u8, length: usize, data: &[u8]) -> Result<u32> {
+        let id = self.id();
+        let mut hdr = vec![ep, 0];
+        hdr.extend_from_slice(&(length as u16).to_le_bytes());
+        hdr.extend_from_slice(&0u32.to_le_bytes());
+        self.send(BULK_PACKET, id, &hdr, data)?;
+        Ok(id)
+    }
+}
+
+/* Bulk endpoints of the MCTP interface */
+struct Endpoints {
+    config: u8,
+    ep_in: u8,
+    ep_out: u8,
+}
+
+// Find the MCTP interface's bulk endpoints in a configuration descriptor
+fn parse_config(desc: &[u8]) -> Result<Endpoints> {
+    if desc.len() < 9 || desc[0] < 9 {
+        bail!("Invalid configuration descriptor {desc:02x?}");
+    }
+    let mut eps = Endpoints {
+        config: desc[5],
+        ep_in: 0,
+        ep_out: 0,
+    };
+    let mut mctp = false;
+    let mut rest = &desc[desc[0] as usize..];
+    while rest.len() >= 2 {
+        let len = rest[0] as usize;
+        if len < 2 || len > rest.len() {
+            bail!("Invalid configuration descriptor {desc:02x?}");
+        }
+        let d = &rest[..len];
+        match d[1] {
+            USB_DESC_TYPE_INTERFACE if len >= 9 => {
+                mctp = d[5] == USB_CLASS_MCTP
+            }
+            USB_DESC_TYPE_ENDPOINT
+                if len >= 7 && mctp && d[3] & 3 == USB_ENDPOINT_XFER_BULK =>
+            {
+                if d[2] & USB_DIR_IN != 0 {
+                    eps.ep_in = d[2];
+                } else {
+                    eps.ep_out = d[2];
+                }
+            }
+            _ => (),
+        }
+        rest = &rest[len..];
+    }
+    if eps.ep_in == 0 || eps.ep_out == 0 {
+        bail!("No MCTP bulk endpoints in configuration");
+    }
+    Ok(eps)
+}
+
+/// Connect to the usbredir device on `sock`, and query its EID with a
+/// Get Endpoint ID request to `dest`. Fails if the response doesn't
+/// report `expect_eid`, when given.
+pub fn run(sock: UnixStream, dest: u8, expect_eid: Option<u8>) -> Result<()> {
+    sock.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut host = Host { sock, next_id: 0 };
+
+    let mut version = [0u8; 64];
+    let v = b"mctp-dev usb-host";
+    version[..v.len()].copy_from_slice(v);
+    host.send(HELLO, 0, &version, &[])?;
+    host.expect(DEVICE_CONNECT)
+        .context("No device connect from usbredir peer")?;
+
+    let dev = host.get_descriptor(USB_DESC_TYPE_DEVICE, 18)?;
+    if dev.len() != 18 {
+        bail!("Invalid device descriptor {dev:02x?}");
+    }
+    info!(
+        "Device {:04x}:{:04x}",
+        u16::from_le_bytes([dev[8], dev[9]]),
+        u16::from_le_bytes([dev[10], dev[11]])
+    );
+
+    let config = host.get_descriptor(USB_DESC_TYPE_CONFIGURATION, 9)?;
+    if config.len() < 9 {
+        bail!("Invalid configuration descriptor {config:02x?}");
+    }
+    let total = u16::from_le_bytes([config[2], config[3]]);
+    let config = host.get_descriptor(USB_DESC_TYPE_CONFIGURATION, total)?;
+    if config.len() != total as usize {
+        bail!("Configuration descriptor truncated: {config:02x?}");
+    }
+    let eps = parse_config(&config)?;
+    debug!(
+        "MCTP endpoints: OUT {:02x}, IN {:02x}",
+        eps.ep_out, eps.ep_in
+    );
+
+    let id = host.id();
+    host.send(SET_CONFIGURATION, id, &[eps.config], &[])?;
+    let (_, status) = host.expect(CONFIGURATION_STATUS)?;
+    if status[0] != STATUS_SUCCESS {
+        bail!("Set configuration failed, status {}", status[0]);
+    }
+
+    for _ in 0..IN_XFERS {
+        host.bulk(eps.ep_in, USB_XFER_SIZE, &[])?;
+    }
+
+    let req = [
+        MCTP_HDR_VER,
+        dest,
+        HOST_EID,
+        MCTP_FLAGS_REQ,
+        mctp::MCTP_TYPE_CONTROL.0,
+        MCTP_CTRL_RQ,
+        MCTP_CTRL_GET_EID,
+    ];
+    let mut out = USB_HDR.to_vec();
+    out.push((USB_HDR_LEN + req.len()) as u8);
+    out.extend_from_slice(&req);
+    let out_id = host.bulk(eps.ep_out, out.len(), &out)?;
+    info!("Sent Get Endpoint ID to EID {dest}");
+
+    // The device may send its own requests, such as Discovery Notify,
+    // before the response. Each completed IN transfer is requeued.
+    loop {
+        let (id, resp) = host.expect(BULK_PACKET)?;
+        if resp.len() < 8 {
+            bail!("Invalid bulk packet {resp:02x?}");
+        }
+        let status = resp[1];
+        if id == out_id {
+            if status != STATUS_SUCCESS {
+                bail!("OUT transfer failed, status {status}");
+            }
+            continue;
+        }
+        if status != STATUS_SUCCESS {
+            bail!("IN transfer failed, status {status}");
+        }
+        host.bulk(eps.ep_in, USB_XFER_SIZE, &[])?;
+
+        let data = &resp[8..];
+        let mut pkts = data;
+        while pkts.len() >= USB_HDR_LEN {
+            let len = pkts[3] as usize;
+            if pkts[..3] != USB_HDR || len < USB_HDR_LEN || len > pkts.len() {
+                bail!("Invalid MCTP USB framing {data:02x?}");
+            }
+            let pkt = &pkts[USB_HDR_LEN..len];
+            pkts = &pkts[len..];
+
+            // single-packet control response to us
+            if pkt.len() < 10
+                || pkt[1] != HOST_EID
+                || pkt[4] != mctp::MCTP_TYPE_CONTROL.0
+                || pkt[5] & MCTP_CTRL_RQ != 0
+                || pkt[6] != MCTP_CTRL_GET_EID
+            {
+                debug!("ignoring MCTP packet {pkt:02x?}");
+                continue;
+            }
+            let (cc, eid) = (pkt[7], pkt[8]);
+            if cc != 0 {
+                bail!("Get Endpoint ID failed, completion code {cc:#x}");
+            }
+            info!("Device EID {eid}, from EID {}", pkt[2]);
+            if let Some(e) = expect_eid.filter(|e| *e != eid) {
+                bail!("Expected EID {e}, device reports {eid}");
+            }
+            return Ok(());
+        }
+    }
+}
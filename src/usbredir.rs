@@ -1,64 +1,485 @@
 // SPDX-License-Identifier: GPL-3.0
 
 use anyhow::{Context, Result};
+use futures::future::LocalBoxFuture;
 use futures::{future, select, FutureExt};
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
+use mctp_estack::router::PortId;
 use mctp_estack::usb::MctpUsbHandler;
 use std::collections::VecDeque;
 use std::io::{Read as _, Write as _};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use usbredirparser::{self, Parser};
 
+use crate::linkdump;
+use crate::pcap::Direction;
+use crate::transport::{MctpTransport, BINDING_USB};
+use crate::usbdesc::{
+    ConfigDescriptor, DeviceDescriptor, EndpointDescriptor,
+    InterfaceDescriptor, USB_DESC_TYPE_BOS, USB_DESC_TYPE_CONFIGURATION,
+    USB_DESC_TYPE_DEVICE, USB_DESC_TYPE_DEVICE_CAPABILITY,
+    USB_DESC_TYPE_DEVICE_QUALIFIER, USB_DESC_TYPE_OTHER_SPEED_CONFIGURATION,
+    USB_DESC_TYPE_STRING, USB_ENDPOINT_XFER_BULK, USB_ENDPOINT_XFER_INT,
+};
+
 enum RedirRxOp {
     Submission(u64, usbredirparser::BulkPacket),
     Cancellation(u64),
+    Reset,
+    /// the host has started or stopped polling the interrupt endpoint
+    IntReceiving(bool),
+}
+
+/// Identity and configuration of the emulated USB device
+#[derive(Clone, Debug)]
+pub struct UsbDeviceInfo {
+    /// vendor ID. If unset, the descriptor reports 0x0000, and the usbredir
+    /// device connect reports 0xcc00.
+    pub vid: Option<u16>,
+    /// product ID, with the same defaults as `vid`
+    pub pid: Option<u16>,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+    pub speed: UsbSpeed,
+    /// number of OUT transfers queued before further transfers are failed
+    pub out_queue: usize,
+    /// injected transfer failures, shared with the monitor
+    pub faults: UsbFaults,
+    /// transfer counters, which may be shared between transports
+    pub stats: Arc<UsbStats>,
+    /// add an interrupt IN endpoint, used to notify the host that outbound
+    /// data is available
+    pub int_ep: bool,
+    /// validate the MCTP USB framing of OUT transfers before completing
+    /// them, failing transfers with invalid headers
+    pub strict: bool,
+    /// notified when the host configures the device
+    pub link_up: Option<LinkUp>,
+}
+
+/// Notification of a USB link coming up, identifying the port it belongs to
+#[derive(Clone, Debug)]
+pub struct LinkUp {
+    pub chan: async_channel::Sender<PortId>,
+    pub port: PortId,
+}
+
+impl LinkUp {
+    /// Notify that the link is up. The notification is dropped if the
+    /// channel is full.
+    pub fn notify(&self) {
+        let _ = self.chan.try_send(self.port);
+    }
+}
+
+/// Failure status for injected transfer faults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsbFault {
+    /// complete with a stall, and halt the endpoint until the host clears
+    /// the halt
+    Stall,
+    /// complete with an I/O error
+    Error,
+}
+
+/// Handle for injecting failures into bulk transfers, for testing host error
+/// paths
+#[derive(Clone, Debug, Default)]
+pub struct UsbFaults(Arc<Mutex<[Option<(UsbFault, u32)>; NUM_EPS]>>);
+
+impl UsbFaults {
+    /// Fail the next `count` transfers on the IN (`is_in`) or OUT endpoint
+    pub fn inject(&self, is_in: bool, fault: UsbFault, count: u32) {
+        let ep = if is_in { EP_ADDR_IN } else { EP_ADDR_OUT };
+        // unwrap: both addresses are valid
+        let i = ep_index(ep).unwrap();
+        self.0.lock().unwrap()[i] = (count > 0).then_some((fault, count));
+    }
+
+    /// Remove any pending faults
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = [None; NUM_EPS];
+    }
+
+    // Consume one pending fault for endpoint index `i`
+    fn take(&self, i: usize) -> Option<UsbFault> {
+        let mut faults = self.0.lock().unwrap();
+        let (fault, count) = faults[i].as_mut()?;
+        let fault = *fault;
+        *count -= 1;
+        if *count == 0 {
+            faults[i] = None;
+        }
+        Some(fault)
+    }
+}
+
+/// Speed of the emulated USB device
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UsbSpeed {
+    Full,
+    #[default]
+    High,
+    Super,
+}
+
+impl std::str::FromStr for UsbSpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "high" => Ok(Self::High),
+            "super" => Ok(Self::Super),
+            _ => Err(format!(
+                "unknown speed '{s}', expected full, high or super"
+            )),
+        }
+    }
+}
+
+impl UsbSpeed {
+    /// interrupt endpoint bInterval, for a 1ms polling interval
+    fn int_interval(&self) -> u8 {
+        match self {
+            Self::Full => 1,
+            /* 2^(4-1) microframes */
+            Self::High | Self::Super => 4,
+        }
+    }
+
+    /// bulk endpoint wMaxPacketSize
+    fn max_packet(&self) -> u16 {
+        match self {
+            Self::Full => FS_MAX_PACKET,
+            Self::High => HS_MAX_PACKET,
+            Self::Super => SS_MAX_PACKET,
+        }
+    }
+}
+
+impl Default for UsbDeviceInfo {
+    fn default() -> Self {
+        Self {
+            vid: None,
+            pid: None,
+            speed: UsbSpeed::default(),
+            manufacturer: "mctp-dev".to_string(),
+            product: "MCTP over USB device".to_string(),
+            serial: "sn0000".to_string(),
+            out_queue: OUT_QUEUE_DEPTH,
+            faults: UsbFaults::default(),
+            stats: Arc::default(),
+            int_ep: false,
+            strict: false,
+            link_up: None,
+        }
+    }
+}
+
+/// Transfer counters for the USB transports. These are always enabled, so
+/// are updated with relaxed atomics.
+#[derive(Debug, Default)]
+pub struct UsbStats {
+    /// OUT transfers received, and their total length
+    pub out_xfers: AtomicU64,
+    pub out_bytes: AtomicU64,
+    /// IN transfers completed with data, and their total length
+    pub in_xfers: AtomicU64,
+    pub in_bytes: AtomicU64,
+    /// control requests received
+    pub control: AtomicU64,
+    /// invalid MCTP USB headers or packets in received data
+    pub decode_errors: AtomicU64,
+    /// IN transfers cancelled by the host
+    pub cancelled: AtomicU64,
+    /// OUT transfers failed because the receive queue was full
+    pub out_dropped: AtomicU64,
+    /// IN transfer operations received while the IN queue was full
+    pub in_overflow: AtomicU64,
+}
+
+impl UsbStats {
+    pub(crate) fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for UsbStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let v = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        write!(
+            f,
+            "out {} xfers/{} bytes, in {} xfers/{} bytes, {} control, \
+             {} decode errors, {} cancelled, {} out dropped, \
+             {} in overflows",
+            v(&self.out_xfers),
+            v(&self.out_bytes),
+            v(&self.in_xfers),
+            v(&self.in_bytes),
+            v(&self.control),
+            v(&self.decode_errors),
+            v(&self.cancelled),
+            v(&self.out_dropped),
+            v(&self.in_overflow),
+        )
+    }
+}
+
+/* output from the parser, waiting for the socket to become writable */
+type WriteQueue = Arc<Mutex<VecDeque<u8>>>;
+
+/* IN transfer operations that did not fit in the channel to the port */
+type InBacklog = Arc<Mutex<VecDeque<RedirRxOp>>>;
+
+/* Received OUT transfers, queued directly for the framing layer. We keep a
+ * receiver too, so that a reset can discard the queued transfers, along
+ * with any partial packet that the framing layer holds.
+ */
+#[derive(Clone)]
+struct OutQueue {
+    sender: async_channel::Sender<Vec<u8>>,
+    receiver: async_channel::Receiver<Vec<u8>>,
+    reset: async_channel::Sender<()>,
+}
+
+impl OutQueue {
+    // Discard all received data. Transfers queued after this are kept.
+    fn reset(&self) {
+        let mut dropped = 0;
+        while self.receiver.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("reset, dropping {dropped} queued OUT transfers");
+        }
+        let _ = self.reset.try_send(());
+    }
 }
 
 struct UsbRedirHandler {
     stream: std::fs::File,
-    out_chan: async_channel::Sender<Vec<u8>>,
+    write_queue: WriteQueue,
+    out_queue: OutQueue,
     in_chan: async_channel::Sender<RedirRxOp>,
+    in_backlog: InBacklog,
+    info: UsbDeviceInfo,
+    /* current configuration value, 0 if unconfigured */
+    configuration: u8,
+    /* endpoint halt state, indexed by ep_index() */
+    halted: [bool; NUM_EPS],
+    /* framing state of OUT transfers, only tracked in strict mode */
+    out_framing: OutFraming,
+}
+
+/* Tracks MCTP USB packet boundaries across OUT transfers, to validate each
+ * header as it arrives. Packets, and their headers, may be split across
+ * transfers.
+ */
+#[derive(Default)]
+struct OutFraming {
+    /* bytes remaining of a packet started in a previous transfer */
+    remain: usize,
+    /* partial header from the end of the previous transfer */
+    hdr: Vec<u8>,
+}
+
+impl OutFraming {
+    // Check the headers in an OUT transfer. On failure, framing restarts
+    // at the next transfer.
+    fn check(&mut self, mut data: &[u8]) -> bool {
+        let n = self.remain.min(data.len());
+        self.remain -= n;
+        data = &data[n..];
+
+        while !data.is_empty() {
+            let n = (USB_HDR_LEN - self.hdr.len()).min(data.len());
+            self.hdr.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.hdr.len() < USB_HDR_LEN {
+                break;
+            }
+
+            let hdr = std::mem::take(&mut self.hdr);
+            let len = hdr[3] as usize;
+            if hdr[..2] != USB_HDR_ID || hdr[2] != 0 || len < USB_HDR_LEN {
+                trace!("invalid MCTP USB header {hdr:02x?}");
+                self.reset();
+                return false;
+            }
+
+            let body = len - USB_HDR_LEN;
+            let n = body.min(data.len());
+            self.remain = body - n;
+            data = &data[n..];
+        }
+        true
+    }
+
+    fn reset(&mut self) {
+        self.remain = 0;
+        self.hdr.clear();
+    }
 }
 
-const USB_CLASS_MCTP: u8 = 0x14;
+pub(crate) const USB_CLASS_MCTP: u8 = 0x14;
 const USB_PROTO_MCTP_V1: u8 = 1;
 
+const USB_CTRL_GET_STATUS: u8 = 0;
+const USB_CTRL_CLEAR_FEATURE: u8 = 1;
+const USB_CTRL_SET_FEATURE: u8 = 3;
 const USB_CTRL_GET_DESCRIPTOR: u8 = 6;
+const USB_CTRL_GET_CONFIGURATION: u8 = 8;
+const USB_CTRL_GET_INTERFACE: u8 = 10;
+const USB_CTRL_SET_INTERFACE: u8 = 11;
+
+const USB_REQTYPE_TYPE_MASK: u8 = 0x60;
+const USB_REQTYPE_TYPE_STANDARD: u8 = 0x00;
+const USB_REQTYPE_RECIP_MASK: u8 = 0x1f;
+const USB_REQTYPE_RECIP_DEVICE: u8 = 0;
+const USB_REQTYPE_RECIP_INTERFACE: u8 = 1;
+const USB_REQTYPE_RECIP_ENDPOINT: u8 = 2;
+
+const USB_FEATURE_ENDPOINT_HALT: u16 = 0;
 
 const EP_ADDR_OUT: u8 = 0x01;
 const EP_ADDR_IN: u8 = 0x81;
+/* optional interrupt IN endpoint, for message available notifications */
+const EP_ADDR_INT: u8 = 0x82;
+const INT_MAX_PACKET: u16 = 8;
+
+/* endpoints with halt state, see ep_index() */
+const NUM_EPS: usize = 3;
 
-const USB_XFER_SIZE: usize = 512;
+pub(crate) const USB_XFER_SIZE: usize = 512;
+
+const WRITE_QUEUE_MAX: usize = 1024 * 1024;
+
+const DECODE_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/* outbound packets waiting for IN transfers from the host */
+const TX_BACKLOG_MAX: usize = 256;
+
+/* default depth of the OUT transfer queue, and depth of the IN transfer
+ * operation channel */
+const OUT_QUEUE_DEPTH: usize = 64;
+const IN_QUEUE_DEPTH: usize = 64;
+
+/* MCTP over USB transport header: DMTF vendor ID, reserved, length */
+const USB_HDR_LEN: usize = 4;
+/* largest MCTP packet that fits the one-byte header length */
+pub(crate) const USB_MTU: usize = u8::MAX as usize - USB_HDR_LEN;
+const USB_HDR_ID: [u8; 2] = [0x1a, 0xb4];
+
+const REOPEN_DELAY_MIN: Duration = Duration::from_millis(250);
+const REOPEN_DELAY_MAX: Duration = Duration::from_secs(4);
+
+/* how we start a new usbredir session, after the peer disconnects */
+enum Reconnect {
+    /* reopen the device path */
+    Path(String),
+    /* accept a new connection on our listening socket */
+    Listen(smol::Async<UnixListener>),
+    /* fail on disconnect */
+    None,
+}
 
 /* contains the usbredir state, and handles async processing */
 pub struct MctpUsbRedirPort {
     parser: Pin<Box<usbredirparser::Parser>>,
     stream: smol::Async<std::fs::File>,
+    write_queue: WriteQueue,
     in_xfer_queue: VecDeque<(u64, usbredirparser::BulkPacket)>,
 
     /* usbredir interactions, connected to the usbredir handler. We use a
      * channel for this as the handler object gets stashed away within the
      * usbredirparser callback API
      */
-    redir_out_chan: async_channel::Receiver<Vec<u8>>,
     redir_in_chan: async_channel::Receiver<RedirRxOp>,
 
+    /* used to create handlers for new sessions: the sender for the above,
+     * and the queue for received OUT transfers, which go directly to the
+     * framing layer
+     */
+    out_queue: OutQueue,
+    redir_in_sender: async_channel::Sender<RedirRxOp>,
+    in_backlog: InBacklog,
+
+    reconnect: Reconnect,
+    info: UsbDeviceInfo,
+
     /* usb transfer interactions, connected to the higher-level objects */
     xfer_tx_chan: async_channel::Receiver<Vec<u8>>,
-    xfer_rx_chan: async_channel::Sender<Vec<u8>>,
+
+    /* an outbound packet that did not fit in the previous IN transfer, or
+     * that is waiting for the host to queue an IN transfer after an
+     * interrupt notification */
+    tx_pending: Option<Vec<u8>>,
+    /* the host is polling the interrupt endpoint, and whether we have
+     * notified it of the current outbound data */
+    int_receiving: bool,
+    int_notified: bool,
+
+    /* IN transfers completed, and the packets they carried */
+    in_xfers: u64,
+    in_packets: u64,
 }
 
-#[allow(unused)]
-pub struct MctpUsbRedir {
-    // mctp: mctp_estack::Stack,
-    // start_time: Instant,
-    mctpusb: MctpUsbHandler,
-    rx_buf: [u8; USB_XFER_SIZE],
-    rx_remain: std::ops::Range<usize>,
+/* MCTP-over-USB packet framing, exchanging USB transfers with a port
+ * implementation over a pair of channels
+ */
+pub(crate) struct MctpUsbXfer {
+    /* received data, which may hold partial packets */
+    rx_buf: Vec<u8>,
+    /* length of the packet last returned from rx_buf */
+    rx_consumed: usize,
 
     xfer_tx_chan: async_channel::Sender<Vec<u8>>,
     xfer_rx_chan: async_channel::Receiver<Vec<u8>>,
+    /* notified when partially received data is invalidated */
+    xfer_reset_chan: async_channel::Receiver<()>,
+
+    stats: Arc<UsbStats>,
+    /* decode errors since the last warning, and when it was logged */
+    decode_errors: u64,
+    decode_warned: Option<Instant>,
+}
+
+pub struct MctpUsbRedir {
+    xfer: MctpUsbXfer,
+
+    /* usbredir processing, until taken by background() */
+    port: Option<MctpUsbRedirPort>,
+}
+
+impl UsbRedirHandler {
+    // Pass an IN transfer operation to the port. Once the channel is full,
+    // operations are added to the backlog instead, preserving their order.
+    fn queue_in_op(&mut self, op: RedirRxOp) {
+        let mut backlog = self.in_backlog.lock().unwrap();
+        let op = if backlog.is_empty() {
+            match self.in_chan.try_send(op) {
+                Ok(()) => return,
+                Err(async_channel::TrySendError::Full(op)) => op,
+                Err(async_channel::TrySendError::Closed(_)) => {
+                    warn!("IN transfer channel closed");
+                    return;
+                }
+            }
+        } else {
+            op
+        };
+        UsbStats::add(&self.info.stats.in_overflow, 1);
+        backlog.push_back(op);
+    }
 }
 
 impl usbredirparser::ParserHandler for UsbRedirHandler {
@@ -68,7 +489,9 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
         buf: &mut [u8],
     ) -> std::io::Result<usize> {
         let res = self.stream.read(buf);
-        trace!("read:in:[{}]:{buf:x?}", buf.len());
+        if let Ok(n) = res {
+            trace!("read:in:[{n}]:{:x?}", &buf[..n]);
+        }
         match res {
             Ok(0) => Err(std::io::Error::new(
                 std::io::ErrorKind::BrokenPipe,
@@ -82,25 +505,44 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
         _parser: &Parser,
         buf: &[u8],
     ) -> std::io::Result<usize> {
-        self.stream.write(buf)
+        // queued for writing from MctpUsbRedirPort::flush(), as we may not
+        // block here
+        let mut queue = self.write_queue.lock().unwrap();
+        if queue.len() + buf.len() > WRITE_QUEUE_MAX {
+            return Err(std::io::Error::other("usbredir write queue full"));
+        }
+        queue.extend(buf);
+        Ok(buf.len())
     }
     fn hello(&mut self, parser: &Parser, _hello: &usbredirparser::Hello) {
+        log_peer_caps(parser);
         self.send_config(parser);
 
         let chdr = usbredirparser::DeviceConnect {
-            speed: usbredirparser::SPEED_HIGH,
+            speed: match self.info.speed {
+                UsbSpeed::Full => usbredirparser::SPEED_FULL,
+                UsbSpeed::High => usbredirparser::SPEED_HIGH,
+                UsbSpeed::Super => usbredirparser::SPEED_SUPER,
+            },
             device_class: 0,
             device_subclass: 0,
             device_protocol: 0,
-            vendor_id: 0xcc00,
-            product_id: 0xcc00,
+            vendor_id: self.info.vid.unwrap_or(0xcc00),
+            product_id: self.info.pid.unwrap_or(0xcc00),
             device_version_bcd: 0x0,
         };
         parser.send_device_connect(&chdr)
     }
 
     fn reset(&mut self, _parser: &Parser) {
-        debug!("reset");
+        debug!("device reset, configuration {} -> 0", self.configuration);
+        self.configuration = 0;
+        self.halted = [false; NUM_EPS];
+        self.out_framing.reset();
+        // ordered with the transfer operations, so that only the transfers
+        // from before the reset are discarded
+        self.out_queue.reset();
+        self.queue_in_op(RedirRxOp::Reset);
     }
 
     fn control_packet(
@@ -111,8 +553,37 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
         data: &[u8],
     ) {
         trace!("control packet {id} {pkt:x?}, data: {data:x?}");
-        if pkt.request == USB_CTRL_GET_DESCRIPTOR {
-            self.control_get_descriptor(parser, id, pkt)
+        UsbStats::add(&self.info.stats.control, 1);
+        if pkt.requesttype & USB_REQTYPE_TYPE_MASK != USB_REQTYPE_TYPE_STANDARD
+        {
+            debug!("unsupported control request type {:02x}", pkt.requesttype);
+            return Self::control_stall(parser, id, pkt);
+        }
+
+        match pkt.request {
+            USB_CTRL_GET_DESCRIPTOR => {
+                self.control_get_descriptor(parser, id, pkt)
+            }
+            USB_CTRL_GET_STATUS => self.control_get_status(parser, id, pkt),
+            USB_CTRL_CLEAR_FEATURE | USB_CTRL_SET_FEATURE => {
+                self.control_feature(parser, id, pkt)
+            }
+            USB_CTRL_GET_CONFIGURATION => {
+                Self::control_reply(parser, id, pkt, &[self.configuration])
+            }
+            USB_CTRL_GET_INTERFACE if pkt.index == 0 => {
+                Self::control_reply(parser, id, pkt, &[0])
+            }
+            USB_CTRL_SET_INTERFACE if pkt.index == 0 && pkt.value == 0 => {
+                Self::control_reply(parser, id, pkt, &[])
+            }
+            _ => {
+                debug!(
+                    "unsupported control request {:02x}:{:02x}",
+                    pkt.requesttype, pkt.request
+                );
+                Self::control_stall(parser, id, pkt)
+            }
         }
     }
 
@@ -124,21 +595,93 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
         data: &[u8],
     ) {
         trace!("bulk packet {id} {pkt:x?}, data[{}]: {data:x?}", data.len());
-        match pkt.endpoint {
-            EP_ADDR_IN => {
-                self.in_chan
-                    .send_blocking(RedirRxOp::Submission(id, *pkt))
-                    .expect("can't send to in channel");
+        if ep_index(pkt.endpoint).is_some_and(|i| self.halted[i]) {
+            debug!("bulk packet for halted ep {:02x}", pkt.endpoint);
+            let resp = usbredirparser::BulkPacket {
+                status: usbredirparser::STATUS_STALL,
+                length: 0,
+                length_high: 0,
+                ..*pkt
+            };
+            parser.send_bulk_packet(id, &resp, &[]);
+            return;
+        }
+
+        // Injected faults complete the transfer without queueing it. OUT
+        // data is discarded, so a partially transferred message is left
+        // for the router's reassembly timeout to clean up.
+        if let Some(i) = ep_index(pkt.endpoint) {
+            if let Some(fault) = self.info.faults.take(i) {
+                debug!("injecting {fault:?} on ep {:02x}", pkt.endpoint);
+                let status = match fault {
+                    UsbFault::Stall => {
+                        self.halted[i] = true;
+                        usbredirparser::STATUS_STALL
+                    }
+                    UsbFault::Error => usbredirparser::STATUS_IOERROR,
+                };
+                let resp = usbredirparser::BulkPacket {
+                    status,
+                    length: 0,
+                    length_high: 0,
+                    ..*pkt
+                };
+                parser.send_bulk_packet(id, &resp, &[]);
+                return;
             }
+        }
+
+        match pkt.endpoint {
+            EP_ADDR_IN => self.queue_in_op(RedirRxOp::Submission(id, *pkt)),
             EP_ADDR_OUT => {
-                let mut v = Vec::with_capacity(data.len());
-                v.extend_from_slice(data);
-                self.out_chan
-                    .send_blocking(v)
-                    .expect("can't send to out channel");
-                /* ack */
+                // data beyond the transfer length is not part of the
+                // transfer
+                let len = bulk_len(parser, pkt);
+                let data = if data.len() > len {
+                    debug!(
+                        "OUT transfer {id} has {} bytes, length {len}",
+                        data.len()
+                    );
+                    &data[..len]
+                } else {
+                    data
+                };
+
+                linkdump::dump("usb", Direction::In, data);
+
+                // In strict mode, the host learns of invalid framing through
+                // the transfer status. Otherwise, the data path resyncs on
+                // the next valid header, and the transfer succeeds.
+                if self.info.strict && !self.out_framing.check(data) {
+                    debug!("invalid framing in OUT transfer {id}");
+                    UsbStats::add(&self.info.stats.decode_errors, 1);
+                    let resp = usbredirparser::BulkPacket {
+                        status: usbredirparser::STATUS_IOERROR,
+                        length: 0,
+                        length_high: 0,
+                        ..*pkt
+                    };
+                    parser.send_bulk_packet(id, &resp, &[]);
+                    return;
+                }
+
+                // we can't wait for the port here, so fail the transfer if
+                // the queue is full. The host may retry.
+                let status = match self.out_queue.sender.try_send(data.to_vec())
+                {
+                    Ok(()) => {
+                        UsbStats::add(&self.info.stats.out_xfers, 1);
+                        UsbStats::add(&self.info.stats.out_bytes, data.len());
+                        usbredirparser::STATUS_SUCCESS
+                    }
+                    Err(e) => {
+                        debug!("can't queue OUT transfer {id}: {e}");
+                        UsbStats::add(&self.info.stats.out_dropped, 1);
+                        usbredirparser::STATUS_BABBLE
+                    }
+                };
                 let resp = usbredirparser::BulkPacket {
-                    status: 0,
+                    status,
                     length: 0,
                     length_high: 0,
                     ..*pkt
@@ -153,7 +696,46 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
 
     fn cancel_data_packet(&mut self, _parser: &Parser, id: u64) {
         debug!("cancel packet {id}");
-        let _ = self.in_chan.send_blocking(RedirRxOp::Cancellation(id));
+        self.queue_in_op(RedirRxOp::Cancellation(id));
+    }
+
+    fn start_interrupt_receiving(
+        &mut self,
+        parser: &Parser,
+        id: u64,
+        start: &usbredirparser::StartInterruptReceiving,
+    ) {
+        let ok = self.info.int_ep && start.endpoint == EP_ADDR_INT;
+        debug!("start interrupt receiving on ep {:02x}", start.endpoint);
+        if ok {
+            self.queue_in_op(RedirRxOp::IntReceiving(true));
+        }
+        let status = usbredirparser::InterruptReceivingStatus {
+            status: if ok {
+                usbredirparser::STATUS_SUCCESS
+            } else {
+                usbredirparser::STATUS_STALL
+            },
+            endpoint: start.endpoint,
+        };
+        parser.send_interrupt_receiving_status(id, &status);
+    }
+
+    fn stop_interrupt_receiving(
+        &mut self,
+        parser: &Parser,
+        id: u64,
+        stop: &usbredirparser::StopInterruptReceiving,
+    ) {
+        debug!("stop interrupt receiving on ep {:02x}", stop.endpoint);
+        if stop.endpoint == EP_ADDR_INT {
+            self.queue_in_op(RedirRxOp::IntReceiving(false));
+        }
+        let status = usbredirparser::InterruptReceivingStatus {
+            status: usbredirparser::STATUS_SUCCESS,
+            endpoint: stop.endpoint,
+        };
+        parser.send_interrupt_receiving_status(id, &status);
     }
 
     fn set_configuration(
@@ -169,151 +751,346 @@ impl usbredirparser::ParserHandler for UsbRedirHandler {
             status: 1,
         };
 
-        if cfg.configuration == 1 {
-            self.send_config(parser);
-            cfg_status.status = 0;
+        match cfg.configuration {
+            // unconfigured: the data endpoints are no longer active, so
+            // in-flight transfers are discarded, as for a reset
+            0 => {
+                if self.configuration != 0 {
+                    debug!("deconfigured, dropping queued transfers");
+                    self.out_queue.reset();
+                    self.queue_in_op(RedirRxOp::Reset);
+                }
+                self.configuration = 0;
+                self.out_framing.reset();
+                cfg_status.status = 0;
+            }
+            1 => {
+                self.send_config(parser);
+                self.configuration = cfg.configuration;
+                self.halted = [false; NUM_EPS];
+                cfg_status.status = 0;
+                if let Some(link_up) = &self.info.link_up {
+                    link_up.notify();
+                }
+            }
+            _ => (),
         }
 
         parser.send_configuration_status(id, &cfg_status)
     }
 }
 
-const USB_DESC_TYPE_DEVICE: u8 = 1;
-const USB_DESC_TYPE_CONFIGURATION: u8 = 2;
-const USB_DESC_TYPE_STRING: u8 = 3;
-const USB_DESC_TYPE_INTERFACE: u8 = 4;
-const USB_DESC_TYPE_ENDPOINT: u8 = 5;
+/* bulk wMaxPacketSize for full-, high- and SuperSpeed */
+const FS_MAX_PACKET: u16 = 64;
+const HS_MAX_PACKET: u16 = 512;
+const SS_MAX_PACKET: u16 = 1024;
 
-#[rustfmt::skip]
-const DEV_DESC : [u8; 18] = [
-    18, /* bLength */
-    USB_DESC_TYPE_DEVICE, /* bDescriptorTYpe */
-    0x00, 0x02, /* bcdUSB */
-    0x00, /* bDeviceClass */
-    0x00, /* bDeviceSubClass */
-    0x00, /* bDeviceProtocol */
-    0x40, /* bMaxPacketSize0 */
-    0x00, 0x00, /* idVendor */
-    0x00, 0x00, /* idProduct */
-    0x00, 0x00, /* bcdDevice */
-    0x01, /* iManufacturer */
-    0x02, /* iProduct */
-    0x03, /* iSerialNumber */
-    0x01, /* bNumConfigurations */
-];
+/* string index 4; indices 1 to 3 are from UsbDeviceInfo */
+pub(crate) const IFACE_STRING: &str = "MCTP over USB";
 
 #[rustfmt::skip]
-const CONFIG_DESC : [u8; 9] = [
-    0x09, /* bLength */
-    USB_DESC_TYPE_CONFIGURATION, /* bDescriptorType */
-    0x00, 0x00, /* wTotalLength */
-    0x01, /* bNumInterfaces */
-    0x01, /* bConfigurationValue */
-    0x00, /* iConfiguration */
-    0x80, /* bmAttributes: bus powered */
-    0x01, /* bMaxPower: 2ma */
+const STRING_LANGS : [u8; 4] = [
+    4, /* bLength */
+    USB_DESC_TYPE_STRING, /* bDescriptorType */
+    0x09, 0x04, /* en */
 ];
 
 #[rustfmt::skip]
-const IFACE_DESC :  [u8; 9] = [
-    0x09, /* bLength */
-    USB_DESC_TYPE_INTERFACE, /* bDescriptorType */
-    0x00, /* bInterfaceNumber */
-    0x00, /* bAlternateSetting */
-    0x02, /* bNumEndpoints */
-    USB_CLASS_MCTP, /* bInterfaceClass */
-    0x00, /* bInterfaceSubClass */
-    USB_PROTO_MCTP_V1, /* bInterfaceProtocol */
-    0x04, /* iInterface */
-];
+const BOS_DESC : [u8; 22] = [
+    0x05, /* bLength */
+    USB_DESC_TYPE_BOS, /* bDescriptorType */
+    22, 0x00, /* wTotalLength */
+    0x02, /* bNumDeviceCaps */
 
-#[rustfmt::skip]
-const EP_DESCS : [[u8; 7]; 2] = [
-    [
-        0x07, /* bLength */
-        USB_DESC_TYPE_ENDPOINT, /* bDescriptorType */
-        EP_ADDR_OUT, /* bEndpointAddress */
-        0x02, /* bmAttributes */
-        0x00, 0x02, /* wMaxPacketSize */
-        0 /* bInterval */
-    ],
-    [
-        0x07, /* bLength */
-        USB_DESC_TYPE_ENDPOINT, /* bDescriptorType */
-        EP_ADDR_IN, /* bEndpointAddress */
-        0x02, /* bmAttributes */
-        0x00, 0x02, /* wMaxPacketSize: 512 */
-        0 /* bInterval */
-    ],
+    /* USB 2.0 extension */
+    0x07, /* bLength */
+    USB_DESC_TYPE_DEVICE_CAPABILITY, /* bDescriptorType */
+    0x02, /* bDevCapabilityType */
+    0x02, 0x00, 0x00, 0x00, /* bmAttributes: LPM */
 
+    /* SuperSpeed USB device capability */
+    0x0a, /* bLength */
+    USB_DESC_TYPE_DEVICE_CAPABILITY, /* bDescriptorType */
+    0x03, /* bDevCapabilityType */
+    0x00, /* bmAttributes */
+    0x0e, 0x00, /* wSpeedsSupported: full, high, super */
+    0x01, /* bFunctionalitySupport: full */
+    0x0a, /* bU1DevExitLat */
+    0x20, 0x00, /* wU2DevExitLat */
 ];
 
-#[rustfmt::skip]
-const STRINGS : &[&str] = &[
-    "mctp-dev",
-    "MCTP over USB device",
-    "sn0000",
-    "MCTP over USB",
-];
+// Configuration descriptor, with the interface and endpoint descriptors,
+// for the bulk endpoints at `speed`. `desc_type` allows this to be used for
+// the other-speed configuration.
+fn config_desc(desc_type: u8, speed: UsbSpeed, int_ep: bool) -> Vec<u8> {
+    ConfigDescriptor {
+        desc_type,
+        value: 1,
+        config_idx: 0,
+        /* bus powered, 2mA */
+        attributes: 0x80,
+        max_power: 1,
+        interfaces: vec![mctp_interface(speed, int_ep)],
+    }
+    .to_bytes()
+}
 
-#[rustfmt::skip]
-const STRING_LANGS : [u8; 4] = [
-    4, /* bLength */
-    USB_DESC_TYPE_STRING, /* bDescriptorType */
-    0x09, 0x04, /* en */
-];
+/// The MCTP interface, with its bulk endpoints sized for `speed`, and an
+/// optional interrupt endpoint. The interface string is `IFACE_STRING`, at
+/// index 4.
+pub(crate) fn mctp_interface(
+    speed: UsbSpeed,
+    int_ep: bool,
+) -> InterfaceDescriptor {
+    let ep = |address| EndpointDescriptor {
+        address,
+        attributes: USB_ENDPOINT_XFER_BULK,
+        max_packet: speed.max_packet(),
+        interval: 0,
+        ss_companion: speed == UsbSpeed::Super,
+    };
+    let mut endpoints = vec![ep(EP_ADDR_OUT), ep(EP_ADDR_IN)];
+    if int_ep {
+        endpoints.push(EndpointDescriptor {
+            address: EP_ADDR_INT,
+            attributes: USB_ENDPOINT_XFER_INT,
+            max_packet: INT_MAX_PACKET,
+            interval: speed.int_interval(),
+            ss_companion: speed == UsbSpeed::Super,
+        });
+    }
+    InterfaceDescriptor {
+        number: 0,
+        alt_setting: 0,
+        class: USB_CLASS_MCTP,
+        subclass: 0,
+        protocol: USB_PROTO_MCTP_V1,
+        interface_idx: 4,
+        endpoints,
+    }
+}
+
+// Log the capabilities negotiated with the usbredir peer. The parser declares
+// our own capabilities, and handles the encoding of packet ids and device
+// versions to suit the peer; we only need to be aware of the bulk length
+// encoding.
+fn log_peer_caps(parser: &Parser) {
+    let caps = [
+        (
+            "connect_device_version",
+            parser.peer_has_cap(usbredirparser::CAP_CONNECT_DEVICE_VERSION),
+        ),
+        (
+            "ep_info_max_packet_size",
+            parser.peer_has_cap(usbredirparser::CAP_EP_INFO_MAX_PACKET_SIZE),
+        ),
+        (
+            "64bits_ids",
+            parser.peer_has_cap(usbredirparser::CAP_64BITS_IDS),
+        ),
+        (
+            "32bits_bulk_length",
+            parser.peer_has_cap(usbredirparser::CAP_32BITS_BULK_LENGTH),
+        ),
+    ];
+    let names = caps
+        .iter()
+        .filter_map(|(name, has)| has.then_some(*name))
+        .collect::<Vec<_>>();
+    info!("usbredir peer capabilities: [{}]", names.join(", "));
+}
+
+// Length of a bulk transfer. length_high is only valid if both sides support
+// 32-bit lengths.
+fn bulk_len(parser: &Parser, pkt: &usbredirparser::BulkPacket) -> usize {
+    let mut len = pkt.length as usize;
+    if parser.peer_has_cap(usbredirparser::CAP_32BITS_BULK_LENGTH) {
+        len |= (pkt.length_high as usize) << 16;
+    }
+    len
+}
+
+// Index into the endpoint state for a bulk endpoint address
+fn ep_index(addr: u8) -> Option<usize> {
+    match addr {
+        EP_ADDR_OUT => Some(0),
+        EP_ADDR_IN => Some(1),
+        EP_ADDR_INT => Some(2),
+        _ => None,
+    }
+}
 
 impl UsbRedirHandler {
+    // Complete a control transfer, with `data` for IN requests
+    fn control_reply(
+        parser: &Parser,
+        id: u64,
+        req: &usbredirparser::ControlPacket,
+        data: &[u8],
+    ) {
+        let data = &data[..data.len().min(req.length as usize)];
+        let resp = usbredirparser::ControlPacket {
+            status: usbredirparser::STATUS_SUCCESS,
+            length: data.len() as u16,
+            ..*req
+        };
+        parser.send_control_packet(id, &resp, data)
+    }
+
+    fn control_stall(
+        parser: &Parser,
+        id: u64,
+        req: &usbredirparser::ControlPacket,
+    ) {
+        let resp = usbredirparser::ControlPacket {
+            status: usbredirparser::STATUS_STALL,
+            length: 0,
+            ..*req
+        };
+        parser.send_control_packet(id, &resp, &[])
+    }
+
+    fn control_get_status(
+        &mut self,
+        parser: &Parser,
+        id: u64,
+        req: &usbredirparser::ControlPacket,
+    ) {
+        let status: u16 = match req.requesttype & USB_REQTYPE_RECIP_MASK {
+            /* bus powered, no remote wakeup */
+            USB_REQTYPE_RECIP_DEVICE => 0,
+            USB_REQTYPE_RECIP_INTERFACE if req.index == 0 => 0,
+            USB_REQTYPE_RECIP_ENDPOINT => {
+                match ep_index((req.index & 0xff) as u8) {
+                    Some(i) => self.halted[i] as u16,
+                    /* ep0 is never halted */
+                    None if req.index & 0x7f == 0 => 0,
+                    None => return Self::control_stall(parser, id, req),
+                }
+            }
+            _ => return Self::control_stall(parser, id, req),
+        };
+        Self::control_reply(parser, id, req, &status.to_le_bytes())
+    }
+
+    // SET_FEATURE and CLEAR_FEATURE. We only support ENDPOINT_HALT, on the
+    // bulk endpoints.
+    fn control_feature(
+        &mut self,
+        parser: &Parser,
+        id: u64,
+        req: &usbredirparser::ControlPacket,
+    ) {
+        let recip = req.requesttype & USB_REQTYPE_RECIP_MASK;
+        let ep = ep_index((req.index & 0xff) as u8);
+        match (recip, req.value, ep) {
+            (
+                USB_REQTYPE_RECIP_ENDPOINT,
+                USB_FEATURE_ENDPOINT_HALT,
+                Some(i),
+            ) => {
+                let halt = req.request == USB_CTRL_SET_FEATURE;
+                debug!("ep {:02x} halt: {halt}", req.index);
+                self.halted[i] = halt;
+                Self::control_reply(parser, id, req, &[])
+            }
+            _ => Self::control_stall(parser, id, req),
+        }
+    }
+
+    fn dev_desc(&self) -> DeviceDescriptor {
+        let super_speed = self.info.speed == UsbSpeed::Super;
+        DeviceDescriptor {
+            usb_version: if super_speed { 0x0300 } else { 0x0200 },
+            class: 0,
+            subclass: 0,
+            protocol: 0,
+            /* 512-byte ep0 for SuperSpeed, as 2^9 */
+            max_packet0: if super_speed { 9 } else { 64 },
+            vid: self.info.vid.unwrap_or(0),
+            pid: self.info.pid.unwrap_or(0),
+            device_version: 0,
+            manufacturer_idx: 1,
+            product_idx: 2,
+            serial_idx: 3,
+            num_configurations: 1,
+        }
+    }
+
+    fn string(&self, idx: usize) -> Option<&str> {
+        match idx {
+            1 => Some(&self.info.manufacturer),
+            2 => Some(&self.info.product),
+            3 => Some(&self.info.serial),
+            4 => Some(IFACE_STRING),
+            _ => None,
+        }
+    }
+
     fn control_get_descriptor(
         &mut self,
         parser: &Parser,
         id: u64,
         req: &usbredirparser::ControlPacket,
     ) {
-        let mut resp = usbredirparser::ControlPacket { ..*req };
         let (desc_type, desc_idx): (u8, u8) =
             (((req.value >> 8) & 0xff) as u8, ((req.value) & 0xff) as u8);
         trace!("desc request for type {desc_type:02x} idx {desc_idx:02x}");
         let mut v = Vec::new();
-        let mut data = match desc_type {
-            USB_DESC_TYPE_DEVICE => DEV_DESC.as_slice(),
+        let speed = self.info.speed;
+        let data = match desc_type {
+            USB_DESC_TYPE_DEVICE => {
+                v = self.dev_desc().to_bytes();
+                v.as_slice()
+            }
+            /* only a high-speed device has an other-speed configuration */
+            USB_DESC_TYPE_DEVICE_QUALIFIER if speed == UsbSpeed::High => {
+                v = self.dev_desc().qualifier_bytes();
+                v.as_slice()
+            }
+            USB_DESC_TYPE_BOS if speed == UsbSpeed::Super => {
+                BOS_DESC.as_slice()
+            }
             USB_DESC_TYPE_STRING => {
                 let s_idx = desc_idx as usize;
                 if s_idx == 0 {
                     STRING_LANGS.as_slice()
-                } else if s_idx - 1 < STRINGS.len() {
+                } else if let Some(s) = self.string(s_idx) {
                     v.extend_from_slice(&[0, USB_DESC_TYPE_STRING]);
-                    for b in STRINGS[s_idx - 1].encode_utf16() {
+                    for b in s.encode_utf16() {
                         v.extend_from_slice(&b.to_le_bytes());
                     }
                     v[0] = (v.len() & 0xff) as u8;
                     v.as_slice()
                 } else {
-                    &[]
+                    debug!("unknown string index {s_idx}");
+                    return Self::control_stall(parser, id, req);
                 }
             }
             USB_DESC_TYPE_CONFIGURATION => {
-                v.extend_from_slice(&CONFIG_DESC);
-                v.extend_from_slice(&IFACE_DESC);
-                v.extend_from_slice(&EP_DESCS[0]);
-                v.extend_from_slice(&EP_DESCS[1]);
-                /* set total length */
-                let len = v.len() as u16;
-                v[3] = ((len >> 8) & 0xff) as u8;
-                v[2] = ((len) & 0xff) as u8;
+                v = config_desc(
+                    USB_DESC_TYPE_CONFIGURATION,
+                    speed,
+                    self.info.int_ep,
+                );
+                v.as_slice()
+            }
+            USB_DESC_TYPE_OTHER_SPEED_CONFIGURATION
+                if speed == UsbSpeed::High =>
+            {
+                v = config_desc(
+                    USB_DESC_TYPE_OTHER_SPEED_CONFIGURATION,
+                    UsbSpeed::Full,
+                    self.info.int_ep,
+                );
                 v.as_slice()
             }
             _ => {
                 warn!("unsupported descriptor {desc_type:02x}");
-                &[]
+                return Self::control_stall(parser, id, req);
             }
         };
-        let req_len = req.length as usize;
-        if req_len < data.len() {
-            data = &data[..req_len];
-        }
-        resp.length = data.len() as u16;
-        parser.send_control_packet(id, &resp, data)
+        Self::control_reply(parser, id, req, data)
     }
 
     fn send_config(&mut self, parser: &Parser) {
@@ -344,89 +1121,321 @@ impl UsbRedirHandler {
         ep_info.max_packet_size[16] = 16;
         /* bulk in/out */
         ep_info.type_[1] = usbredirparser::TYPE_BULK;
-        ep_info.max_packet_size[1] = 512;
+        let max_packet = self.info.speed.max_packet();
+        ep_info.max_packet_size[1] = max_packet;
         ep_info.type_[17] = usbredirparser::TYPE_BULK;
-        ep_info.max_packet_size[17] = 512;
+        ep_info.max_packet_size[17] = max_packet;
+        /* interrupt in */
+        if self.info.int_ep {
+            ep_info.type_[18] = usbredirparser::TYPE_INTERRUPT;
+            ep_info.interval[18] = self.info.speed.int_interval();
+            ep_info.max_packet_size[18] = INT_MAX_PACKET;
+        }
         parser.send_ep_info(&ep_info);
     }
 }
 
 impl MctpUsbRedir {
-    pub fn new(path: &str) -> Result<(Self, MctpUsbRedirPort)> {
+    pub fn new(path: &str, info: UsbDeviceInfo) -> Result<Self> {
         let fd = std::fs::OpenOptions::new()
             .write(true)
             .read(true)
             .open(path)
             .context("Can't open tty device")?;
 
-        let fd2 = fd.try_clone()?;
-        let (redir_out_sender, redir_out_receiver) = async_channel::unbounded();
-        let (redir_in_sender, redir_in_receiver) = async_channel::unbounded();
+        Self::new_with_stream(fd, Reconnect::Path(path.to_string()), info)
+    }
 
-        let handler = UsbRedirHandler {
-            out_chan: redir_out_sender,
-            in_chan: redir_in_sender,
-            stream: fd,
-        };
-        let parser = usbredirparser::Parser::new(
-            handler,
-            usbredirparser::DeviceType::Host,
-        );
+    /// Create a usbredir transport by listening on a unix socket at `path`.
+    /// This waits for the first connection; once that session ends, the port
+    /// will accept further connections on the same socket.
+    pub fn listen(path: &str, info: UsbDeviceInfo) -> Result<Self> {
+        // remove any stale socket from a previous run
+        if let Ok(m) = std::fs::metadata(path) {
+            if m.file_type().is_socket() {
+                std::fs::remove_file(path)
+                    .context("Can't remove existing socket")?;
+            }
+        }
 
-        let (xfer_out_sender, xfer_out_receiver) = async_channel::unbounded();
-        let (xfer_in_sender, xfer_in_receiver) = async_channel::unbounded();
-        let port = MctpUsbRedirPort {
-            parser,
-            stream: smol::Async::new(fd2)?,
-            in_xfer_queue: VecDeque::new(),
-            redir_out_chan: redir_out_receiver,
-            redir_in_chan: redir_in_receiver,
-            xfer_rx_chan: xfer_out_sender,
-            xfer_tx_chan: xfer_in_receiver,
-        };
+        let listener =
+            UnixListener::bind(path).context("Can't bind to socket")?;
 
-        Ok((
-            Self {
-                mctpusb: MctpUsbHandler::new(),
-                rx_buf: [0u8; USB_XFER_SIZE],
-                rx_remain: std::ops::Range { start: 0, end: 0 },
-                xfer_tx_chan: xfer_in_sender,
-                xfer_rx_chan: xfer_out_receiver,
-            },
-            port,
-        ))
+        info!("Waiting for usbredir connection on {path}");
+        Self::from_listener(listener, info)
     }
 
-    pub async fn recv(&mut self) -> mctp::Result<&[u8]> {
-        if self.rx_remain.is_empty() {
-            let r = self
+    /// Create a usbredir transport from a socket passed by systemd socket
+    /// activation. A listening socket behaves as in `listen()`.
+    pub fn activated(fd: OwnedFd, info: UsbDeviceInfo) -> Result<Self> {
+        if crate::systemd::is_listening(&fd)? {
+            Self::from_listener(UnixListener::from(fd), info)
+        } else {
+            Self::new_with_stream(
+                std::fs::File::from(fd),
+                Reconnect::None,
+                info,
+            )
+        }
+    }
+
+    fn from_listener(
+        listener: UnixListener,
+        info: UsbDeviceInfo,
+    ) -> Result<Self> {
+        let (stream, _) =
+            listener.accept().context("Can't accept connection")?;
+        let fd = std::fs::File::from(OwnedFd::from(stream));
+
+        let listener = smol::Async::new(listener)?;
+        Self::new_with_stream(fd, Reconnect::Listen(listener), info)
+    }
+
+    fn new_with_stream(
+        fd: std::fs::File,
+        reconnect: Reconnect,
+        info: UsbDeviceInfo,
+    ) -> Result<Self> {
+        // OUT transfers are queued directly for the framing layer, so the
+        // queue depth bounds all received data
+        let (xfer, xfer_tx_chan, out_sender, reset_sender) =
+            MctpUsbXfer::bounded(info.out_queue.max(1), info.stats.clone());
+        let out_queue = OutQueue {
+            sender: out_sender,
+            receiver: xfer.xfer_rx_chan.clone(),
+            reset: reset_sender,
+        };
+        let (redir_in_sender, redir_in_receiver) =
+            async_channel::bounded(IN_QUEUE_DEPTH);
+
+        let write_queue = WriteQueue::default();
+        let in_backlog = InBacklog::default();
+        let (parser, stream) = MctpUsbRedirPort::new_session(
+            fd,
+            &write_queue,
+            &out_queue,
+            &redir_in_sender,
+            &in_backlog,
+            &info,
+        )?;
+
+        let port = MctpUsbRedirPort {
+            parser,
+            stream,
+            write_queue,
+            in_xfer_queue: VecDeque::new(),
+            redir_in_chan: redir_in_receiver,
+            out_queue,
+            redir_in_sender,
+            in_backlog,
+            reconnect,
+            info,
+            xfer_tx_chan,
+            tx_pending: None,
+            int_receiving: false,
+            int_notified: false,
+            in_xfers: 0,
+            in_packets: 0,
+        };
+
+        Ok(Self {
+            xfer,
+            port: Some(port),
+        })
+    }
+
+    /// Fail on a disconnect from the usbredir peer, rather than starting a
+    /// new session
+    pub fn disable_reconnect(&mut self) {
+        if let Some(port) = &mut self.port {
+            port.reconnect = Reconnect::None;
+        }
+    }
+}
+
+impl MctpUsbXfer {
+    /// Create the framing half of a USB transport. Also returns the port's
+    /// ends of the channels: outbound transfers to be sent on the IN
+    /// endpoint, a sender for transfers received on the OUT endpoint, and a
+    /// sender to notify a reset of the data path. Decode errors are counted
+    /// in `stats`.
+    pub(crate) fn new(
+        stats: Arc<UsbStats>,
+    ) -> (
+        Self,
+        async_channel::Receiver<Vec<u8>>,
+        async_channel::Sender<Vec<u8>>,
+        async_channel::Sender<()>,
+    ) {
+        Self::with_out_chan(async_channel::unbounded(), stats)
+    }
+
+    /// As new(), but with at most `depth` received transfers queued. The
+    /// port must not wait on a full queue.
+    pub(crate) fn bounded(
+        depth: usize,
+        stats: Arc<UsbStats>,
+    ) -> (
+        Self,
+        async_channel::Receiver<Vec<u8>>,
+        async_channel::Sender<Vec<u8>>,
+        async_channel::Sender<()>,
+    ) {
+        Self::with_out_chan(async_channel::bounded(depth), stats)
+    }
+
+    fn with_out_chan(
+        (xfer_out_sender, xfer_out_receiver): (
+            async_channel::Sender<Vec<u8>>,
+            async_channel::Receiver<Vec<u8>>,
+        ),
+        stats: Arc<UsbStats>,
+    ) -> (
+        Self,
+        async_channel::Receiver<Vec<u8>>,
+        async_channel::Sender<Vec<u8>>,
+        async_channel::Sender<()>,
+    ) {
+        let (xfer_in_sender, xfer_in_receiver) = async_channel::unbounded();
+        let (xfer_reset_sender, xfer_reset_receiver) =
+            async_channel::bounded(1);
+        let xfer = Self {
+            rx_buf: Vec::with_capacity(USB_XFER_SIZE * 2),
+            rx_consumed: 0,
+            xfer_tx_chan: xfer_in_sender,
+            xfer_rx_chan: xfer_out_receiver,
+            xfer_reset_chan: xfer_reset_receiver,
+            stats,
+            decode_errors: 0,
+            decode_warned: None,
+        };
+        (xfer, xfer_in_receiver, xfer_out_sender, xfer_reset_sender)
+    }
+
+    // Count a decode error. Corrupt data may produce an error per byte, so
+    // only warn once per DECODE_WARN_INTERVAL.
+    fn decode_error(&mut self) {
+        UsbStats::add(&self.stats.decode_errors, 1);
+        self.decode_errors += 1;
+
+        let now = Instant::now();
+        if self
+            .decode_warned
+            .is_none_or(|t| now.duration_since(t) >= DECODE_WARN_INTERVAL)
+        {
+            warn!("{} invalid MCTP USB headers received", self.decode_errors);
+            self.decode_warned = Some(now);
+            self.decode_errors = 0;
+        }
+    }
+
+    // Find the length of the next complete packet at the start of rx_buf,
+    // discarding any padding or corrupt data before it. Returns None if more
+    // data is required.
+    fn next_packet(&mut self) -> Option<usize> {
+        loop {
+            // resynchronise on the next possible header
+            let start = self
+                .rx_buf
+                .windows(USB_HDR_ID.len())
+                .position(|w| w == USB_HDR_ID)
+                .unwrap_or_else(|| {
+                    // keep a trailing byte that may begin a header
+                    let n = self.rx_buf.len();
+                    if self.rx_buf.last() == Some(&USB_HDR_ID[0]) {
+                        n - 1
+                    } else {
+                        n
+                    }
+                });
+            if start > 0 {
+                if self.rx_buf[..start].iter().any(|b| *b != 0) {
+                    debug!("discarding {start} bytes of invalid rx data");
+                }
+                self.rx_buf.drain(..start);
+            }
+
+            if self.rx_buf.len() < USB_HDR_LEN {
+                return None;
+            }
+
+            let len = self.rx_buf[3] as usize;
+            if len < USB_HDR_LEN || self.rx_buf[2] != 0 {
+                trace!("invalid MCTP USB header {:02x?}", &self.rx_buf[..4]);
+                self.decode_error();
+                self.rx_buf.drain(..1);
+                continue;
+            }
+
+            if self.rx_buf.len() < len {
+                return None;
+            }
+
+            if MctpUsbHandler::decode(&self.rx_buf[..len]).is_err() {
+                trace!("invalid MCTP USB packet");
+                self.decode_error();
+                self.rx_buf.drain(..1);
+                continue;
+            }
+
+            return Some(len);
+        }
+    }
+
+    /// Receive a MCTP packet. Packets may be split across transfers, and
+    /// transfers may contain multiple packets, or padding.
+    pub(crate) async fn recv(&mut self) -> mctp::Result<&[u8]> {
+        self.rx_buf.drain(..self.rx_consumed);
+        self.rx_consumed = 0;
+
+        let len = loop {
+            if let Some(len) = self.next_packet() {
+                break len;
+            }
+
+            let r = self
                 .xfer_rx_chan
                 .recv()
                 .await
                 .or(Err(mctp::Error::RxFailure))?;
-            let len = r.len();
-            if len > self.rx_buf.len() {
-                return Err(mctp::Error::RxFailure);
+            // the reset is notified after discarding the queued transfers,
+            // and before any later transfers are queued. So this only
+            // discards a partial packet from before the reset.
+            if self.xfer_reset_chan.try_recv().is_ok()
+                && !self.rx_buf.is_empty()
+            {
+                debug!(
+                    "reset, discarding {} bytes of rx data",
+                    self.rx_buf.len()
+                );
+                self.rx_buf.clear();
             }
-            self.rx_buf.split_at_mut(len).0.clone_from_slice(&r);
-            self.rx_remain = std::ops::Range { start: 0, end: len };
+            self.rx_buf.extend_from_slice(&r);
+        };
+
+        let (pkt, _) = MctpUsbHandler::decode(&self.rx_buf[..len])
+            .or(Err(mctp::Error::RxFailure))?;
+        self.rx_consumed = len;
+        Ok(pkt)
+    }
+
+    pub(crate) async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
+        // the host isn't taking IN transfers (eg. while suspended), so drop
+        // rather than queueing indefinitely
+        if self.xfer_tx_chan.len() >= TX_BACKLOG_MAX {
+            warn!("outbound USB queue full, dropping packet");
+            return Ok(());
         }
 
-        let data = &self.rx_buf[self.rx_remain.clone()];
-        match MctpUsbHandler::decode(data) {
-            Ok((pkt, rem)) => {
-                self.rx_remain.start = self.rx_remain.end - rem.len();
-                Ok(pkt)
-            }
-            Err(_) => {
-                self.rx_remain = std::ops::Range { start: 0, end: 0 };
-                Err(mctp::Error::RxFailure)
-            }
+        if pkt.len() > USB_MTU {
+            warn!(
+                "USB tx: packet length {} exceeds MTU {USB_MTU}, dropping",
+                pkt.len()
+            );
+            return Err(mctp::Error::NoSpace);
         }
-    }
 
-    pub async fn send(&mut self, pkt: &[u8]) -> mctp::Result<()> {
-        let total = pkt.len().checked_add(4).ok_or(mctp::Error::NoSpace)?;
+        let total = pkt.len() + USB_HDR_LEN;
         let mut tx_buf = Vec::with_capacity(total);
         let hdr = MctpUsbHandler::header(pkt.len())?;
         tx_buf.extend_from_slice(&hdr);
@@ -441,14 +1450,38 @@ impl MctpUsbRedir {
 
 impl MctpUsbRedirPort {
     async fn process_one(&mut self) -> mctp::Result<()> {
+        // Apply any submissions and cancellations from the last read before
+        // selecting a transfer to complete, so that we never complete an IN
+        // transfer that the host has already cancelled.
+        self.drain_in_ops();
+        self.flush()?;
+
+        if !self.in_xfer_queue.is_empty() {
+            if let Some(xfer) = self.tx_pending.take() {
+                self.complete_in(xfer);
+                return self.flush();
+            }
+        }
+
         // we only poll on the tx future (outgoing USB transfers from the MCTP
-        // stack) if we have a usbredir IN transfer queued and ready to go.
-        let tx_fut = if self.in_xfer_queue.is_empty() {
+        // stack) if we have a usbredir IN transfer queued and ready to go, or
+        // we can notify the host of outbound data on the interrupt endpoint.
+        let notify = self.int_receiving
+            && !self.int_notified
+            && self.tx_pending.is_none();
+        let tx_fut = if self.in_xfer_queue.is_empty() && !notify {
             future::Either::Left(future::pending())
         } else {
             future::Either::Right(self.xfer_tx_chan.recv())
         };
 
+        // only wait for the socket to be writable if we have queued output
+        let write_fut = if self.write_queue.lock().unwrap().is_empty() {
+            future::Either::Left(future::pending())
+        } else {
+            future::Either::Right(self.stream.writable())
+        };
+
         select!(
             // socket activity
             r = self.stream.readable().fuse() => {
@@ -464,38 +1497,31 @@ impl MctpUsbRedirPort {
                 }
             },
 
-            // tx from redir
-            r = self.redir_out_chan.recv().fuse() => {
-                if let Ok(xfer) = r {
-                    let _ = self.xfer_rx_chan.send(xfer).await;
+            // queued output, written by flush() below
+            r = write_fut.fuse() => {
+                if let Err(e) = r {
+                    warn!("io error {e:?}");
+                    return Err(mctp::Error::TxFailure);
                 }
             }
 
             // rx from redir
             r = self.redir_in_chan.recv().fuse() => {
-                match r {
-                    Ok(RedirRxOp::Submission(id, pkt)) => {
-                        self.in_xfer_queue.push_back((id, pkt));
-                    }
-                    Ok(RedirRxOp::Cancellation(id)) => {
-                        self.cancel(id);
-                    }
-                    _ => (),
+                if let Ok(op) = r {
+                    self.redir_in_op(op);
                 }
             }
 
             // tx from MCTP stack
             r = tx_fut.fuse() => {
                 if let Ok(xfer) = r {
-                    // unwrap(): we have already confirmed we have an entry in
-                    // the in_xfer_queue
-                    let (id, mut pkt) = self.in_xfer_queue.pop_front().unwrap();
-
-                    pkt.status = usbredirparser::STATUS_SUCCESS;
-                    pkt.length = xfer.len() as u16;
-
-                    trace!("tx xfer: {xfer:02x?}");
-                    self.parser.send_bulk_packet(id, &pkt, &xfer);
+                    if self.in_xfer_queue.is_empty() {
+                        // held until the host queues an IN transfer
+                        self.tx_pending = Some(xfer);
+                        self.notify_int();
+                    } else {
+                        self.complete_in(xfer);
+                    }
                 } else {
                     warn!("tx/xfer failure: {r:?}");
                     return Err(mctp::Error::TxFailure);
@@ -504,11 +1530,90 @@ impl MctpUsbRedirPort {
             }
         );
 
+        self.flush()
+    }
+
+    // Complete the first queued IN transfer with `xfer`, plus as many further
+    // ready packets as will fit in the host's transfer. Packets are only
+    // split across transfers if the host's transfer is too small for a
+    // single packet; the remainder is sent in the next transfer.
+    fn complete_in(&mut self, mut xfer: Vec<u8>) {
+        // unwrap(): callers have confirmed we have an entry in the
+        // in_xfer_queue
+        let (id, mut pkt) = self.in_xfer_queue.pop_front().unwrap();
+        let max = bulk_len(&self.parser, &pkt);
+
+        if xfer.len() > max {
+            debug!(
+                "IN transfer of {} bytes exceeds host length {max}, splitting",
+                xfer.len()
+            );
+            self.tx_pending = Some(xfer.split_off(max));
+        }
+
+        let mut count = 1;
+        while self.tx_pending.is_none() {
+            let Ok(next) = self.xfer_tx_chan.try_recv() else {
+                break;
+            };
+            if xfer.len() + next.len() > max {
+                self.tx_pending = Some(next);
+                break;
+            }
+            xfer.extend_from_slice(&next);
+            count += 1;
+        }
+
+        self.in_xfers += 1;
+        self.in_packets += count;
+        if count > 1 {
+            debug!(
+                "batched {count} packets in IN transfer, {:.2} packets per \
+                 transfer",
+                self.in_packets as f64 / self.in_xfers as f64
+            );
+        }
+
+        pkt.status = usbredirparser::STATUS_SUCCESS;
+        pkt.length = (xfer.len() & 0xffff) as u16;
+        pkt.length_high = (xfer.len() >> 16) as u16;
+
+        // further data will need a new notification
+        self.int_notified = false;
+
+        UsbStats::add(&self.info.stats.in_xfers, 1);
+        UsbStats::add(&self.info.stats.in_bytes, xfer.len());
+
+        trace!("tx xfer: {xfer:02x?}");
+        linkdump::dump("usb", Direction::Out, &xfer);
+        self.parser.send_bulk_packet(id, &pkt, &xfer);
+    }
+
+    // Move the parser's output to the write queue, then write as much of the
+    // queue as the socket will currently accept
+    fn flush(&mut self) -> mctp::Result<()> {
         while self.parser.has_data_to_write() != 0 {
             let res = self.parser.do_write();
             if let Err(e) = res {
                 warn!("write error {e:?}");
-                break;
+                return Err(mctp::Error::TxFailure);
+            }
+        }
+
+        let mut queue = self.write_queue.lock().unwrap();
+        let mut stream = self.stream.get_ref();
+        while !queue.is_empty() {
+            let (data, _) = queue.as_slices();
+            match stream.write(data) {
+                Ok(0) => return Err(mctp::Error::TxFailure),
+                Ok(n) => {
+                    queue.drain(..n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("write error {e:?}");
+                    return Err(mctp::Error::TxFailure);
+                }
             }
         }
         Ok(())
@@ -516,10 +1621,200 @@ impl MctpUsbRedirPort {
 
     pub async fn process(&mut self) -> mctp::Result<()> {
         loop {
-            self.process_one().await?
+            let res = self.process_one().await;
+            if let Err(e) = res {
+                if matches!(self.reconnect, Reconnect::None) {
+                    return Err(e);
+                }
+                info!("usbredir session ended, reconnecting");
+                self.reconnect().await?;
+            }
+        }
+    }
+
+    // Create a parser and handler for a new usbredir session on `fd`
+    fn new_session(
+        fd: std::fs::File,
+        write_queue: &WriteQueue,
+        out_queue: &OutQueue,
+        in_chan: &async_channel::Sender<RedirRxOp>,
+        in_backlog: &InBacklog,
+        info: &UsbDeviceInfo,
+    ) -> Result<(Pin<Box<Parser>>, smol::Async<std::fs::File>)> {
+        let fd2 = fd.try_clone()?;
+
+        let handler = UsbRedirHandler {
+            out_queue: out_queue.clone(),
+            in_chan: in_chan.clone(),
+            in_backlog: in_backlog.clone(),
+            stream: fd,
+            write_queue: write_queue.clone(),
+            info: info.clone(),
+            configuration: 0,
+            halted: [false; NUM_EPS],
+            out_framing: OutFraming::default(),
+        };
+        let parser = usbredirparser::Parser::new(
+            handler,
+            usbredirparser::DeviceType::Host,
+        );
+
+        Ok((parser, smol::Async::new(fd2)?))
+    }
+
+    // Open a new connection to the usbredir peer, by the method we were
+    // created with. Device paths are retried until they can be reopened.
+    async fn open_stream(&self) -> mctp::Result<std::fs::File> {
+        match &self.reconnect {
+            Reconnect::Listen(listener) => {
+                let stream = listener
+                    .accept()
+                    .await
+                    .and_then(|(s, _)| s.into_inner())
+                    .map_err(|e| {
+                        warn!("accept failed: {e:?}");
+                        mctp::Error::RxFailure
+                    })?;
+                Ok(std::fs::File::from(OwnedFd::from(stream)))
+            }
+            Reconnect::Path(path) => {
+                let mut delay = REOPEN_DELAY_MIN;
+                loop {
+                    smol::Timer::after(delay).await;
+                    info!("Reopening {path}");
+                    let r = std::fs::OpenOptions::new()
+                        .write(true)
+                        .read(true)
+                        .open(path);
+                    match r {
+                        Ok(f) => break Ok(f),
+                        Err(e) => info!("Can't open {path}: {e}"),
+                    }
+                    delay = (delay * 2).min(REOPEN_DELAY_MAX);
+                }
+            }
+            Reconnect::None => Err(mctp::Error::RxFailure),
+        }
+    }
+
+    // Start a fresh usbredir session on a new connection. The hello /
+    // device connect handshake is re-run by the new parser.
+    async fn reconnect(&mut self) -> mctp::Result<()> {
+        let fd = self.open_stream().await?;
+
+        // output queued for the previous session is no longer valid
+        self.write_queue.lock().unwrap().clear();
+
+        let (parser, stream) = Self::new_session(
+            fd,
+            &self.write_queue,
+            &self.out_queue,
+            &self.redir_in_sender,
+            &self.in_backlog,
+            &self.info,
+        )
+        .map_err(|e| {
+            warn!("can't create usbredir session: {e:?}");
+            mctp::Error::RxFailure
+        })?;
+
+        info!("New usbredir session");
+
+        // transfers queued by the previous session are no longer valid
+        self.in_xfer_queue.clear();
+        let backlog = std::mem::take(&mut *self.in_backlog.lock().unwrap());
+        for r in std::iter::from_fn(|| self.redir_in_chan.try_recv().ok())
+            .chain(backlog)
+        {
+            if let RedirRxOp::Submission(id, _) = r {
+                debug!("dropping stale IN transfer {id}");
+            }
+        }
+
+        // outbound packets for the previous session's host are dropped; the
+        // new host will re-enumerate
+        let mut dropped = self.tx_pending.take().map_or(0, |_| 1);
+        while self.xfer_tx_chan.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("dropped {dropped} outbound packets from previous session");
+        }
+        // as is any data received from the previous session
+        self.out_queue.reset();
+
+        self.parser = parser;
+        self.stream = stream;
+        self.flush()
+    }
+
+    // Take all pending IN transfer operations from the handler: those in the
+    // channel, then any that overflowed into the backlog
+    fn drain_in_ops(&mut self) {
+        while let Ok(op) = self.redir_in_chan.try_recv() {
+            self.redir_in_op(op);
+        }
+        let backlog = std::mem::take(&mut *self.in_backlog.lock().unwrap());
+        for op in backlog {
+            self.redir_in_op(op);
+        }
+    }
+
+    fn redir_in_op(&mut self, op: RedirRxOp) {
+        match op {
+            RedirRxOp::Submission(id, pkt) => {
+                self.in_xfer_queue.push_back((id, pkt));
+            }
+            RedirRxOp::Cancellation(id) => self.cancel(id),
+            RedirRxOp::Reset => self.bus_reset(),
+            RedirRxOp::IntReceiving(r) => {
+                self.int_receiving = r;
+                self.int_notified = false;
+            }
         }
     }
 
+    // Notify the host that outbound data is available, with a one-byte
+    // packet on the interrupt endpoint
+    fn notify_int(&mut self) {
+        trace!("notifying outbound data on interrupt endpoint");
+        let pkt = usbredirparser::InterruptPacket {
+            endpoint: EP_ADDR_INT,
+            status: usbredirparser::STATUS_SUCCESS,
+            length: 1,
+        };
+        self.parser.send_interrupt_packet(0, &pkt, &[1]);
+        self.int_notified = true;
+    }
+
+    // A reset or deconfiguration invalidates all in-flight transfers: the
+    // host no longer expects completions for queued IN transfers, and any
+    // partially sent or received packet is lost. Outbound packets queued
+    // for the old configuration are dropped too, as a link bounce would.
+    fn bus_reset(&mut self) {
+        debug!(
+            "data path reset, dropping {} queued IN transfers",
+            self.in_xfer_queue.len()
+        );
+        self.in_xfer_queue.clear();
+        // the host restarts interrupt polling after a reset
+        self.int_receiving = false;
+        self.int_notified = false;
+        let mut dropped = self.tx_pending.take().map_or(0, |_| 1);
+        while self.xfer_tx_chan.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("dropped {dropped} outbound packets");
+        }
+        // The router has no interface for discarding partial reassembly
+        // state for a port; incomplete inbound messages are dropped once
+        // their reassembly times out.
+        info!("USB reset, incomplete messages will expire by timeout");
+    }
+
+    // Remove a queued IN transfer, and complete it as cancelled. Transfers
+    // that we have already completed need no response.
     fn cancel(&mut self, id: u64) {
         let res = self.in_xfer_queue.iter().enumerate().find_map(|(i, e)| {
             if e.0 == id {
@@ -528,10 +1823,726 @@ impl MctpUsbRedirPort {
                 None
             }
         });
-        if let Some(idx) = res {
-            self.in_xfer_queue.remove(idx);
-        } else {
+        let Some((_, pkt)) = res.and_then(|idx| self.in_xfer_queue.remove(idx))
+        else {
             debug!("cancellation for unknown id {id}");
+            return;
+        };
+        UsbStats::add(&self.info.stats.cancelled, 1);
+
+        let resp = usbredirparser::BulkPacket {
+            status: usbredirparser::STATUS_CANCELLED,
+            length: 0,
+            length_high: 0,
+            ..pkt
+        };
+        self.parser.send_bulk_packet(id, &resp, &[]);
+    }
+}
+
+impl Drop for MctpUsbRedirPort {
+    // Notify the peer that the device has gone, rather than leaving it to
+    // detect the closed connection
+    fn drop(&mut self) {
+        debug!("sending usbredir device disconnect");
+        self.parser.send_device_disconnect();
+        // best effort; we can't wait for the socket to become writable
+        let _ = self.flush();
+    }
+}
+
+impl MctpTransport for MctpUsbRedir {
+    fn recv(&mut self) -> LocalBoxFuture<'_, mctp::Result<&[u8]>> {
+        Box::pin(self.xfer.recv())
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        pkt: &'a [u8],
+    ) -> LocalBoxFuture<'a, mctp::Result<()>> {
+        Box::pin(self.xfer.send(pkt))
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(USB_MTU)
+    }
+
+    fn binding(&self) -> u8 {
+        BINDING_USB
+    }
+
+    fn background(
+        &mut self,
+    ) -> Option<LocalBoxFuture<'static, mctp::Result<()>>> {
+        let mut port = self.port.take()?;
+        Some(Box::pin(async move { port.process().await }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::future::Future;
+    use std::os::unix::net::UnixStream;
+
+    /* usbredir packet types */
+    const HELLO: u32 = 0;
+    const DEVICE_CONNECT: u32 = 1;
+    const RESET: u32 = 3;
+    const SET_CONFIGURATION: u32 = 6;
+    const CONFIGURATION_STATUS: u32 = 8;
+    const CANCEL_DATA_PACKET: u32 = 21;
+    const CONTROL_PACKET: u32 = 100;
+    const BULK_PACKET: u32 = 101;
+
+    const SCRIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /* an MCTP control Get Endpoint ID request */
+    const MCTP_PKT: [u8; 7] = [0x01, 0x08, 0x09, 0xc8, 0x00, 0x80, 0x02];
+
+    /* peer capability bits, as advertised in our hello */
+    const CAP_32BITS_BULK_LENGTH: u32 = 1 << 6;
+
+    /* A scripted usbredir peer, in the role of the USB host. By default it
+     * advertises no capabilities, so packets use 32-bit ids and 16-bit bulk
+     * lengths.
+     */
+    struct Peer {
+        stream: smol::Async<UnixStream>,
+        caps: u32,
+    }
+
+    impl Peer {
+        async fn send(&mut self, typ: u32, id: u32, hdr: &[u8], data: &[u8]) {
+            let len = (hdr.len() + data.len()) as u32;
+            let mut pkt = Vec::new();
+            pkt.extend_from_slice(&typ.to_le_bytes());
+            pkt.extend_from_slice(&len.to_le_bytes());
+            pkt.extend_from_slice(&id.to_le_bytes());
+            pkt.extend_from_slice(hdr);
+            pkt.extend_from_slice(data);
+            self.stream.write_all(&pkt).await.unwrap();
+        }
+
+        // The next packet's type, id, and type header with data
+        async fn recv(&mut self) -> (u32, u32, Vec<u8>) {
+            let mut hdr = [0u8; 12];
+            self.stream.read_exact(&mut hdr).await.unwrap();
+            let field = |i: usize| {
+                u32::from_le_bytes(hdr[i..i + 4].try_into().unwrap())
+            };
+            let mut payload = vec![0u8; field(4) as usize];
+            self.stream.read_exact(&mut payload).await.unwrap();
+            (field(0), field(8), payload)
+        }
+
+        // Skip packets until one of type `typ`
+        async fn expect(&mut self, typ: u32) -> (u32, Vec<u8>) {
+            loop {
+                let (t, id, payload) = self.recv().await;
+                if t == typ {
+                    return (id, payload);
+                }
+            }
+        }
+
+        // Exchange hellos, and wait for the device to connect
+        async fn connect(&mut self) {
+            let mut version = [0u8; 64];
+            version[..9].copy_from_slice(b"test-host");
+            self.send(HELLO, 0, &version, &self.caps.to_le_bytes())
+                .await;
+            self.expect(DEVICE_CONNECT).await;
+        }
+
+        // A standard device IN control request, returning the status and
+        // data of the reply
+        async fn control_in(
+            &mut self,
+            id: u32,
+            request: u8,
+            value: u16,
+            length: u16,
+        ) -> (u8, Vec<u8>) {
+            let mut hdr = vec![0x80, request, 0x80, 0];
+            hdr.extend_from_slice(&value.to_le_bytes());
+            hdr.extend_from_slice(&0u16.to_le_bytes());
+            hdr.extend_from_slice(&length.to_le_bytes());
+            self.send(CONTROL_PACKET, id, &hdr, &[]).await;
+
+            let (rid, resp) = self.expect(CONTROL_PACKET).await;
+            assert_eq!(rid, id);
+            (resp[3], resp[10..].to_vec())
+        }
+
+        async fn get_descriptor(
+            &mut self,
+            id: u32,
+            typ: u8,
+            idx: u8,
+            length: u16,
+        ) -> (u8, Vec<u8>) {
+            let value = (typ as u16) << 8 | idx as u16;
+            self.control_in(id, USB_CTRL_GET_DESCRIPTOR, value, length)
+                .await
+        }
+
+        // Read the device and configuration descriptors, and set
+        // configuration 1, as a host does on enumeration. Uses ids from
+        // `id` to `id + 2`.
+        async fn enumerate(&mut self, id: u32) {
+            let types = [USB_DESC_TYPE_DEVICE, USB_DESC_TYPE_CONFIGURATION];
+            for (id, typ) in (id..).zip(types) {
+                let (status, desc) = self.get_descriptor(id, typ, 0, 255).await;
+                assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+                assert_eq!(desc[1], typ);
+            }
+            assert_eq!(self.set_configuration(id + 2, 1).await, 0);
+        }
+
+        // Returns the configuration status
+        async fn set_configuration(&mut self, id: u32, config: u8) -> u8 {
+            self.send(SET_CONFIGURATION, id, &[config], &[]).await;
+            let (rid, resp) = self.expect(CONFIGURATION_STATUS).await;
+            assert_eq!((rid, resp[1]), (id, config));
+            resp[0]
+        }
+
+        // Submit a bulk transfer, with `data` for OUT transfers
+        async fn bulk(&mut self, id: u32, ep: u8, length: usize, data: &[u8]) {
+            let mut hdr = vec![ep, 0];
+            hdr.extend_from_slice(&(length as u16).to_le_bytes());
+            hdr.extend_from_slice(&0u32.to_le_bytes());
+            if self.caps & CAP_32BITS_BULK_LENGTH != 0 {
+                hdr.extend_from_slice(&((length >> 16) as u16).to_le_bytes());
+            }
+            self.send(BULK_PACKET, id, &hdr, data).await;
+        }
+
+        // The next bulk transfer completion: id, status and IN data. The
+        // reported length must match the data.
+        async fn bulk_reply(&mut self) -> (u32, u8, Vec<u8>) {
+            let (id, resp) = self.expect(BULK_PACKET).await;
+            let mut len = u16::from_le_bytes([resp[2], resp[3]]) as usize;
+            let data = if self.caps & CAP_32BITS_BULK_LENGTH != 0 {
+                len |= (u16::from_le_bytes([resp[8], resp[9]]) as usize) << 16;
+                &resp[10..]
+            } else {
+                &resp[8..]
+            };
+            assert_eq!(len, data.len());
+            (id, resp[1], data.to_vec())
+        }
+
+        async fn cancel(&mut self, id: u32) {
+            self.send(CANCEL_DATA_PACKET, id, &[], &[]).await;
+        }
+    }
+
+    // `mctp` in MCTP over USB framing
+    fn usb_packet(mctp: &[u8]) -> Vec<u8> {
+        let mut v = USB_HDR_ID.to_vec();
+        v.extend_from_slice(&[0, (mctp.len() + USB_HDR_LEN) as u8]);
+        v.extend_from_slice(mctp);
+        v
+    }
+
+    // Run `script` against a device created with `info`, over a socketpair.
+    // The device's port is processed alongside the script, and must not
+    // exit before it.
+    fn run<F>(info: UsbDeviceInfo, script: impl FnOnce(Peer, MctpUsbRedir) -> F)
+    where
+        F: Future<Output = ()>,
+    {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut redir =
+            MctpUsbRedir::activated(OwnedFd::from(ours), info).unwrap();
+        let port = redir.background().unwrap();
+        let peer = Peer {
+            stream: smol::Async::new(theirs).unwrap(),
+            caps: 0,
+        };
+
+        let script = future::select(
+            Box::pin(script(peer, redir)),
+            smol::Timer::after(SCRIPT_TIMEOUT),
+        );
+        match smol::block_on(future::select(port, script)) {
+            future::Either::Left((r, _)) => {
+                panic!("usbredir port exited: {r:?}")
+            }
+            future::Either::Right((future::Either::Right(_), _)) => {
+                panic!("timed out")
+            }
+            future::Either::Right((future::Either::Left(_), _)) => (),
+        }
+    }
+
+    // A packet split across two transfers at every byte boundary is only
+    // returned once complete, including after padding and a stray header
+    // byte
+    #[test]
+    fn next_packet_split() {
+        let pkt = usb_packet(&MCTP_PKT);
+        for prefix in [&[][..], &[0x00, 0x1a, 0x00]] {
+            let data = [prefix, &pkt].concat();
+            for split in 0..=data.len() {
+                let (mut xfer, _, _, _) = MctpUsbXfer::new(Arc::default());
+                xfer.rx_buf.extend_from_slice(&data[..split]);
+                if split < data.len() {
+                    assert_eq!(xfer.next_packet(), None, "split {split}");
+                }
+                xfer.rx_buf.extend_from_slice(&data[split..]);
+                assert_eq!(xfer.next_packet(), Some(pkt.len()));
+                assert_eq!(xfer.rx_buf, pkt);
+            }
+        }
+    }
+
+    // Transfers holding several packets, and packets spanning transfers,
+    // are received in order
+    #[test]
+    fn recv_spanning_transfers() {
+        let pkt = usb_packet(&MCTP_PKT);
+        let data = [&pkt[..], &pkt, &pkt].concat();
+        let (mut xfer, _, out_tx, _) = MctpUsbXfer::new(Arc::default());
+        for x in data.chunks(pkt.len() + 3) {
+            out_tx.try_send(x.to_vec()).unwrap();
         }
+        smol::block_on(async {
+            for _ in 0..3 {
+                assert_eq!(xfer.recv().await.unwrap(), MCTP_PKT);
+            }
+        });
+    }
+
+    // Ready packets share an IN transfer. A packet that doesn't fit in the
+    // rest of the transfer is sent whole in the next one.
+    #[test]
+    fn in_batching() {
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            let pkt = usb_packet(&MCTP_PKT);
+            for _ in 0..3 {
+                redir.xfer.send(&MCTP_PKT).await.unwrap();
+            }
+            peer.bulk(1, EP_ADDR_IN, pkt.len() * 2 + 3, &[]).await;
+            peer.bulk(2, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+
+            let (id, status, data) = peer.bulk_reply().await;
+            assert_eq!((id, status), (1, usbredirparser::STATUS_SUCCESS));
+            assert_eq!(data, [&pkt[..], &pkt].concat());
+            let (id, status, data) = peer.bulk_reply().await;
+            assert_eq!((id, status), (2, usbredirparser::STATUS_SUCCESS));
+            assert_eq!(data, pkt);
+        });
+    }
+
+    // A 4 KiB NVMe-MI response, as 64-byte packets, takes 10 IN transfers
+    // of USB_XFER_SIZE rather than one for each of its 65 packets
+    #[test]
+    fn in_batching_4k() {
+        let msg = vec![0x04; 4112];
+        let pkts = msg
+            .chunks(64)
+            .map(|c| [&[0x01, 0x09, 0x08, 0x00], c].concat())
+            .collect::<Vec<_>>();
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            for p in &pkts {
+                redir.xfer.send(p).await.unwrap();
+            }
+            let expected =
+                pkts.iter().flat_map(|p| usb_packet(p)).collect::<Vec<_>>();
+
+            let mut data = Vec::new();
+            let mut xfers = 0;
+            while data.len() < expected.len() {
+                peer.bulk(xfers, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+                let (_, _, d) = peer.bulk_reply().await;
+                assert!(d.len() <= USB_XFER_SIZE);
+                data.extend(d);
+                xfers += 1;
+            }
+            assert_eq!(pkts.len(), 65);
+            assert_eq!(xfers, 10);
+            assert_eq!(data, expected);
+        });
+    }
+
+    // An outbound transfer larger than the host's buffer is split, with the
+    // remainder completing the following transfers
+    #[test]
+    fn in_short_host_buffer() {
+        run(UsbDeviceInfo::default(), |mut peer, redir| async move {
+            peer.connect().await;
+
+            /* the port doesn't interpret outbound transfers, so this stands
+             * in for a 512-byte MCTP packet
+             */
+            let xfer = (0..512).map(|i| i as u8).collect::<Vec<_>>();
+            redir.xfer.xfer_tx_chan.send(xfer.clone()).await.unwrap();
+
+            let mut data = Vec::new();
+            for id in 0..6 {
+                peer.bulk(id, EP_ADDR_IN, 100, &[]).await;
+                let (rid, status, d) = peer.bulk_reply().await;
+                assert_eq!((rid, status), (id, usbredirparser::STATUS_SUCCESS));
+                assert_eq!(d.len(), if id < 5 { 100 } else { 12 });
+                data.extend(d);
+            }
+            assert_eq!(data, xfer);
+        });
+    }
+
+    // A 128 KiB host buffer is filled past 64 KiB, with the reply length
+    // carried in both length and length_high
+    #[test]
+    fn in_large_host_buffer() {
+        run(UsbDeviceInfo::default(), |mut peer, redir| async move {
+            peer.caps = CAP_32BITS_BULK_LENGTH;
+            peer.connect().await;
+
+            let mut mctp = vec![0x01, 0x09, 0x08, 0xc0];
+            mctp.resize(251, 0xa5);
+            let pkt = usb_packet(&mctp);
+            for _ in 0..600 {
+                redir.xfer.xfer_tx_chan.send(pkt.clone()).await.unwrap();
+            }
+
+            // 514 whole packets fit in 128 KiB
+            peer.bulk(1, EP_ADDR_IN, 128 * 1024, &[]).await;
+            let (_, status, data) = peer.bulk_reply().await;
+            assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+            assert_eq!(data.len(), 514 * pkt.len());
+            assert!(data.len() > 0xffff);
+            assert!(data.chunks(pkt.len()).all(|c| c == pkt));
+
+            peer.bulk(2, EP_ADDR_IN, 128 * 1024, &[]).await;
+            let (_, _, data) = peer.bulk_reply().await;
+            assert_eq!(data.len(), 86 * pkt.len());
+        });
+    }
+
+    // A flood of OUT transfers while the MCTP stack isn't receiving doesn't
+    // block the session. Transfers beyond the queue depth are failed back
+    // to the host, and only the queued transfers are held.
+    #[test]
+    fn out_flood() {
+        let info = UsbDeviceInfo {
+            out_queue: 16,
+            ..Default::default()
+        };
+        let stats = info.stats.clone();
+        run(info, |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            let data = usb_packet(&MCTP_PKT);
+            for id in 0..1000 {
+                peer.bulk(id, EP_ADDR_OUT, data.len(), &data).await;
+            }
+            let (mut ok, mut failed) = (0, 0);
+            for _ in 0..1000 {
+                match peer.bulk_reply().await.1 {
+                    usbredirparser::STATUS_SUCCESS => ok += 1,
+                    usbredirparser::STATUS_BABBLE => failed += 1,
+                    s => panic!("unexpected OUT status {s}"),
+                }
+            }
+            assert_eq!((ok, failed), (16, 984));
+            assert_eq!(redir.xfer.xfer_rx_chan.len(), 16);
+            assert_eq!(stats.out_xfers.load(Ordering::Relaxed), 16);
+            assert_eq!(stats.out_dropped.load(Ordering::Relaxed), 984);
+
+            for _ in 0..16 {
+                assert_eq!(redir.xfer.recv().await.unwrap(), MCTP_PKT);
+            }
+        });
+    }
+
+    // IN transfers submitted beyond the channel depth are kept, and
+    // completed in order
+    #[test]
+    fn in_flood() {
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            let len = usb_packet(&MCTP_PKT).len();
+            for id in 0..200 {
+                peer.bulk(id, EP_ADDR_IN, len, &[]).await;
+            }
+            for _ in 0..200 {
+                redir.xfer.send(&MCTP_PKT).await.unwrap();
+            }
+            for id in 0..200 {
+                let (rid, status, _) = peer.bulk_reply().await;
+                assert_eq!((rid, status), (id, usbredirparser::STATUS_SUCCESS));
+            }
+        });
+    }
+
+    // A reset discards a partially received packet, and the IN transfers
+    // queued before it
+    #[test]
+    fn reset_flush() {
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            let data = usb_packet(&MCTP_PKT);
+            peer.bulk(1, EP_ADDR_OUT, 5, &data[..5]).await;
+            peer.bulk(2, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+            peer.send(RESET, 0, &[], &[]).await;
+            peer.bulk(3, EP_ADDR_OUT, data.len(), &data).await;
+            peer.bulk(4, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+
+            // only the packet sent after the reset is received
+            assert_eq!(redir.xfer.recv().await.unwrap(), MCTP_PKT);
+
+            // and only the IN transfer submitted after the reset completes
+            redir.xfer.send(&MCTP_PKT).await.unwrap();
+            for expected_id in [1, 3] {
+                let (id, status, _) = peer.bulk_reply().await;
+                assert_eq!(
+                    (id, status),
+                    (expected_id, usbredirparser::STATUS_SUCCESS)
+                );
+            }
+            let (id, _, in_data) = peer.bulk_reply().await;
+            assert_eq!((id, in_data), (4, data));
+        });
+    }
+
+    // Outbound packets are dropped, rather than queued without limit, while
+    // the host isn't taking IN transfers
+    #[test]
+    fn tx_backlog() {
+        let (mut xfer, tx_chan, _, _) = MctpUsbXfer::new(Arc::default());
+        smol::block_on(async {
+            for _ in 0..TX_BACKLOG_MAX + 10 {
+                xfer.send(&MCTP_PKT).await.unwrap();
+            }
+        });
+        assert_eq!(tx_chan.len(), TX_BACKLOG_MAX);
+    }
+
+    // Deconfiguring discards in-flight transfers as a reset does, and the
+    // device is usable again once reconfigured
+    #[test]
+    fn reconfigure() {
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+            assert_eq!(peer.set_configuration(1, 1).await, 0);
+
+            let data = usb_packet(&MCTP_PKT);
+            peer.bulk(2, EP_ADDR_OUT, 5, &data[..5]).await;
+            peer.bulk(3, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+            assert_eq!(peer.set_configuration(4, 0).await, 0);
+            assert_eq!(peer.set_configuration(5, 1).await, 0);
+            peer.bulk(6, EP_ADDR_OUT, data.len(), &data).await;
+            peer.bulk(7, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+
+            // only the packet sent after reconfiguration is received
+            assert_eq!(redir.xfer.recv().await.unwrap(), MCTP_PKT);
+
+            // and only the IN transfer submitted after it completes
+            redir.xfer.send(&MCTP_PKT).await.unwrap();
+            for expected_id in [2, 6] {
+                let (id, status, _) = peer.bulk_reply().await;
+                assert_eq!(
+                    (id, status),
+                    (expected_id, usbredirparser::STATUS_SUCCESS)
+                );
+            }
+            let (id, _, in_data) = peer.bulk_reply().await;
+            assert_eq!((id, in_data), (7, data));
+        });
+    }
+
+    // After a reset part way through an inbound message, the host
+    // re-enumerates the device, and a full message is then received
+    #[test]
+    fn reset_reenumerate() {
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+            peer.enumerate(1).await;
+
+            let data = usb_packet(&MCTP_PKT);
+            peer.bulk(4, EP_ADDR_OUT, 5, &data[..5]).await;
+            let (id, status, _) = peer.bulk_reply().await;
+            assert_eq!((id, status), (4, usbredirparser::STATUS_SUCCESS));
+
+            peer.send(RESET, 0, &[], &[]).await;
+            let (status, config) =
+                peer.control_in(5, USB_CTRL_GET_CONFIGURATION, 0, 1).await;
+            assert_eq!((status, config), (0, vec![0]));
+            peer.enumerate(6).await;
+
+            peer.bulk(9, EP_ADDR_OUT, data.len(), &data).await;
+            assert_eq!(redir.xfer.recv().await.unwrap(), MCTP_PKT);
+            peer.bulk(10, EP_ADDR_IN, USB_XFER_SIZE, &[]).await;
+            redir.xfer.send(&MCTP_PKT).await.unwrap();
+            for expected_id in [9, 10] {
+                let (id, status, _) = peer.bulk_reply().await;
+                assert_eq!(
+                    (id, status),
+                    (expected_id, usbredirparser::STATUS_SUCCESS)
+                );
+            }
+        });
+    }
+
+    // Descriptor contents, truncated to the requested length
+    #[test]
+    fn descriptors() {
+        let info = UsbDeviceInfo {
+            vid: Some(0x1234),
+            pid: Some(0x5678),
+            ..Default::default()
+        };
+        let speed = info.speed;
+        run(info, |mut peer, redir| async move {
+            // keep the device's channels open
+            let _redir = redir;
+            peer.connect().await;
+
+            let (status, dev) =
+                peer.get_descriptor(1, USB_DESC_TYPE_DEVICE, 0, 18).await;
+            assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+            assert_eq!(dev.len(), 18);
+            assert_eq!(dev[1], USB_DESC_TYPE_DEVICE);
+            assert_eq!(dev[8..12], [0x34, 0x12, 0x78, 0x56]);
+
+            let (status, config) = peer
+                .get_descriptor(2, USB_DESC_TYPE_CONFIGURATION, 0, 255)
+                .await;
+            assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+            let expected =
+                config_desc(USB_DESC_TYPE_CONFIGURATION, speed, false);
+            assert_eq!(config, expected);
+
+            // truncated to the request length
+            let (_, short) = peer
+                .get_descriptor(3, USB_DESC_TYPE_CONFIGURATION, 0, 9)
+                .await;
+            assert_eq!(short, expected[..9]);
+
+            let (status, iface) =
+                peer.get_descriptor(4, USB_DESC_TYPE_STRING, 4, 255).await;
+            assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+            let name = IFACE_STRING.encode_utf16().collect::<Vec<_>>();
+            assert_eq!(iface.len(), 2 + name.len() * 2);
+
+            let (status, _) =
+                peer.get_descriptor(5, USB_DESC_TYPE_STRING, 9, 255).await;
+            assert_eq!(status, usbredirparser::STATUS_STALL);
+        });
+    }
+
+    // OUT transfers are acknowledged once queued, and only configuration
+    // 1 is accepted
+    #[test]
+    fn out_ack() {
+        let info = UsbDeviceInfo::default();
+        let stats = info.stats.clone();
+        run(info, |mut peer, mut redir| async move {
+            peer.connect().await;
+            assert_eq!(peer.set_configuration(1, 1).await, 0);
+            assert_ne!(peer.set_configuration(2, 2).await, 0);
+
+            let data = usb_packet(&MCTP_PKT);
+            peer.bulk(3, EP_ADDR_OUT, data.len(), &data).await;
+            let (id, status, _) = peer.bulk_reply().await;
+            assert_eq!((id, status), (3, usbredirparser::STATUS_SUCCESS));
+
+            assert_eq!(redir.xfer.recv().await.unwrap(), &MCTP_PKT[..]);
+            assert_eq!(stats.out_xfers.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    // IN transfers complete in submission order, and a cancelled transfer
+    // is completed as cancelled without taking a packet
+    #[test]
+    fn in_completion_order() {
+        let pkts = [
+            [0x01, 0x09, 0x08, 0xc0, 0x00, 0x00, 0x01],
+            [0x01, 0x09, 0x08, 0xc0, 0x00, 0x00, 0x02],
+        ];
+        run(UsbDeviceInfo::default(), |mut peer, mut redir| async move {
+            peer.connect().await;
+            peer.set_configuration(1, 1).await;
+
+            // each transfer only has room for one packet
+            let len = usb_packet(&pkts[0]).len();
+            for id in 10..13 {
+                peer.bulk(id, EP_ADDR_IN, len, &[]).await;
+            }
+            peer.cancel(11).await;
+            let (id, status, _) = peer.bulk_reply().await;
+            assert_eq!((id, status), (11, usbredirparser::STATUS_CANCELLED));
+
+            for pkt in &pkts {
+                redir.xfer.send(pkt).await.unwrap();
+            }
+
+            // completed in submission order, skipping the cancelled transfer
+            for (expected_id, pkt) in [10, 12].into_iter().zip(&pkts) {
+                let (id, status, data) = peer.bulk_reply().await;
+                assert_eq!((id, status), (expected_id, 0));
+                assert_eq!(data, usb_packet(pkt));
+            }
+        });
+    }
+
+    // An invalid header in an OUT transfer is counted and skipped, and the
+    // following packet received
+    #[test]
+    fn decode_error() {
+        let info = UsbDeviceInfo::default();
+        let stats = info.stats.clone();
+        run(info, |mut peer, mut redir| async move {
+            peer.connect().await;
+            peer.set_configuration(1, 1).await;
+
+            // a header with an invalid length, then a valid packet
+            let mut data = vec![0x1a, 0xb4, 0x00, 0x02];
+            data.extend(usb_packet(&MCTP_PKT));
+            peer.bulk(2, EP_ADDR_OUT, data.len(), &data).await;
+            let (_, status, _) = peer.bulk_reply().await;
+            assert_eq!(status, usbredirparser::STATUS_SUCCESS);
+
+            assert_eq!(redir.xfer.recv().await.unwrap(), &MCTP_PKT[..]);
+            assert_eq!(stats.decode_errors.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    // In strict mode, a transfer with an invalid header is failed, and a
+    // packet split across transfers is accepted
+    #[test]
+    fn strict_framing() {
+        let info = UsbDeviceInfo {
+            strict: true,
+            ..Default::default()
+        };
+        run(info, |mut peer, mut redir| async move {
+            peer.connect().await;
+
+            let data = usb_packet(&MCTP_PKT);
+            let bad = [&[0x1a, 0xb4, 0x00, 0x02][..], &data].concat();
+            peer.bulk(1, EP_ADDR_OUT, bad.len(), &bad).await;
+            peer.bulk(2, EP_ADDR_OUT, 2, &data[..2]).await;
+            peer.bulk(3, EP_ADDR_OUT, data.len() - 2, &data[2..]).await;
+            for (expected_id, expected_status) in [
+                (1, usbredirparser::STATUS_IOERROR),
+                (2, usbredirparser::STATUS_SUCCESS),
+                (3, usbredirparser::STATUS_SUCCESS),
+            ] {
+                let (id, status, _) = peer.bulk_reply().await;
+                assert_eq!((id, status), (expected_id, expected_status));
+            }
+            assert_eq!(redir.xfer.recv().await.unwrap(), MCTP_PKT);
+        });
     }
 }
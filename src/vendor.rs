@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* Vendor-defined MCTP control commands. Each vendor ID set registered here
+ * is reported by Get Vendor Defined Message Support, and control requests
+ * with a command code in the vendor range are passed to the registered
+ * handler for that code.
+ */
+
+use log::debug;
+use std::ops::RangeInclusive;
+
+use crate::{
+    MCTP_CTRL_CC_ERROR_INVALID_DATA, MCTP_CTRL_CC_ERROR_UNSUPPORTED_CMD,
+    MCTP_CTRL_CC_SUCCESS,
+};
+
+pub const MCTP_CTRL_CMD_GET_VENDOR_MESSAGE_SUPPORT: u8 = 0x06;
+
+/// Control command codes dispatched to vendor handlers, from the range
+/// reserved by DSP0236 for future use
+pub const VENDOR_CONTROL_CMDS: RangeInclusive<u8> = 0x80..=0xef;
+
+/* vendor ID format for a PCI vendor ID */
+const VENDOR_ID_FORMAT_PCI: u8 = 0x00;
+
+/* built-in example, answering EXAMPLE_CMD_ECHO */
+const EXAMPLE_VENDOR_ID: u16 = 0xccde;
+const EXAMPLE_VERSION: u16 = 0x0001;
+const EXAMPLE_CMD_ECHO: u8 = 0x82;
+
+/// Produces the response body following the completion code, or an error
+/// completion code
+pub type Handler = fn(&[u8]) -> Result<Vec<u8>, u8>;
+
+/// A vendor ID set, and the commands it implements
+pub struct VendorSet {
+    /// PCI vendor ID
+    pub vendor_id: u16,
+    /// vendor-defined command set version
+    pub version: u16,
+    pub commands: Vec<(u8, Handler)>,
+}
+
+/// The vendor ID sets registered with the control task
+#[derive(Default)]
+pub struct VendorControl {
+    sets: Vec<VendorSet>,
+}
+
+impl VendorControl {
+    pub fn register(&mut self, set: VendorSet) {
+        self.sets.push(set);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Build the response to a control request, if it is a vendor-defined
+    /// command or Get Vendor Defined Message Support. Other requests are
+    /// left to the standard control handler.
+    pub fn handle(&self, req: &[u8]) -> Option<Vec<u8>> {
+        let cmd = req[1];
+        let mut resp = vec![req[0] & 0x1f, cmd];
+
+        if cmd == MCTP_CTRL_CMD_GET_VENDOR_MESSAGE_SUPPORT {
+            let selector = req.get(2).map(|s| *s as usize);
+            let set = selector.and_then(|s| Some((s, self.sets.get(s)?)));
+            let Some((selector, set)) = set else {
+                resp.push(MCTP_CTRL_CC_ERROR_INVALID_DATA);
+                return Some(resp);
+            };
+            let next = if selector + 1 < self.sets.len() {
+                selector as u8 + 1
+            } else {
+                0xff
+            };
+            resp.extend_from_slice(&[
+                MCTP_CTRL_CC_SUCCESS,
+                next,
+                VENDOR_ID_FORMAT_PCI,
+            ]);
+            resp.extend_from_slice(&set.vendor_id.to_be_bytes());
+            resp.extend_from_slice(&set.version.to_be_bytes());
+            return Some(resp);
+        }
+
+        if !VENDOR_CONTROL_CMDS.contains(&cmd) {
+            return None;
+        }
+
+        let handler = self
+            .sets
+            .iter()
+            .flat_map(|s| &s.commands)
+            .find(|(c, _)| *c == cmd)
+            .map(|(_, h)| h);
+        let Some(handler) = handler else {
+            debug!("Unsupported vendor control command {cmd:#04x}");
+            resp.push(MCTP_CTRL_CC_ERROR_UNSUPPORTED_CMD);
+            return Some(resp);
+        };
+
+        match handler(&req[2..]) {
+            Ok(body) => {
+                resp.push(MCTP_CTRL_CC_SUCCESS);
+                resp.extend_from_slice(&body);
+            }
+            Err(cc) => resp.push(cc),
+        }
+        Some(resp)
+    }
+}
+
+// Echo the request payload, preceded by the command set version
+fn example_echo(payload: &[u8]) -> Result<Vec<u8>, u8> {
+    let mut body = vec![EXAMPLE_VERSION as u8];
+    body.extend_from_slice(payload);
+    Ok(body)
+}
+
+/// The built-in example vendor ID set, enabled with `--vendor-control`
+pub fn example() -> VendorSet {
+    VendorSet {
+        vendor_id: EXAMPLE_VENDOR_ID,
+        version: EXAMPLE_VERSION,
+        commands: vec![(EXAMPLE_CMD_ECHO, example_echo)],
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* vsock stream setup, for carrying the MCTP serial framing between a VM
+ * guest and its host.
+ */
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+fn socket() -> Result<OwnedFd> {
+    // SAFETY: plain socket() call, fd checked below
+    let fd = unsafe {
+        libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+    };
+    if fd < 0 {
+        bail!(
+            "Can't create vsock socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // SAFETY: we have just created this fd
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockaddr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    libc::sockaddr_vm {
+        svm_family: libc::AF_VSOCK as libc::sa_family_t,
+        svm_reserved1: 0,
+        svm_port: port,
+        svm_cid: cid,
+        svm_zero: [0; 4],
+    }
+}
+
+const SOCKADDR_VM_LEN: libc::socklen_t =
+    std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+/// Connect to a vsock listener at `cid:port`
+pub fn connect(cid: u32, port: u32) -> Result<std::fs::File> {
+    let fd = socket()?;
+    let addr = sockaddr(cid, port);
+
+    // SAFETY: addr is a valid sockaddr_vm for the provided length
+    let rc = unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            SOCKADDR_VM_LEN,
+        )
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Can't connect to vsock {cid}:{port}"));
+    }
+
+    Ok(std::fs::File::from(fd))
+}
+
+/// Listen on vsock `port`, and wait for a single connection
+pub fn listen(port: u32) -> Result<std::fs::File> {
+    let fd = socket()?;
+    let addr = sockaddr(libc::VMADDR_CID_ANY, port);
+
+    // SAFETY: addr is a valid sockaddr_vm for the provided length
+    let rc = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            SOCKADDR_VM_LEN,
+        )
+    };
+    if rc < 0 {
+        bail!(
+            "Can't bind to vsock port {port}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    // SAFETY: valid fd
+    let rc = unsafe { libc::listen(fd.as_raw_fd(), 1) };
+    if rc < 0 {
+        bail!("Can't listen on vsock: {}", std::io::Error::last_os_error());
+    }
+
+    info!("Waiting for vsock connection on port {port}");
+
+    // SAFETY: valid fd, we don't need the peer address
+    let conn = unsafe {
+        libc::accept4(
+            fd.as_raw_fd(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if conn < 0 {
+        bail!(
+            "Can't accept vsock connection: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    // SAFETY: we have just created this fd
+    let conn = unsafe { OwnedFd::from_raw_fd(conn) };
+
+    Ok(std::fs::File::from(conn))
+}
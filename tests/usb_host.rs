@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: GPL-3.0
+
+/* End-to-end test of the usb transport: a mctp-dev device and a mctp-dev
+ * usb-host, each given one end of a socketpair as a systemd activated
+ * socket.
+ */
+
+#![cfg(feature = "usb-host")]
+
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+const MCTP_DEV: &str = env!("CARGO_BIN_EXE_mctp-dev");
+
+// A mctp-dev command with `sock` passed as fd 3. The shell sets LISTEN_PID
+// to its own pid, which mctp-dev keeps across the exec.
+fn activated(sock: &UnixStream, args: &[&str]) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg("export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$0\" \"$@\"")
+        .arg(MCTP_DEV)
+        .args(args)
+        .stdout(Stdio::null());
+
+    let fd = sock.as_raw_fd();
+    // SAFETY: only async-signal-safe calls between fork and exec; fd stays
+    // open in the parent until the child is spawned
+    unsafe {
+        cmd.pre_exec(move || {
+            let rc = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if rc < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    cmd
+}
+
+#[test]
+fn get_endpoint_id() {
+    let (dev_sock, host_sock) = UnixStream::pair().unwrap();
+
+    let mut dev = activated(&dev_sock, &["usb", "unused"]).spawn().unwrap();
+    let host =
+        activated(&host_sock, &["usb-host", "--expect-eid", "0", "unused"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+    // only the children hold the sockets, so each sees the other exit
+    drop((dev_sock, host_sock));
+
+    let out = host.wait_with_output().unwrap();
+    let _ = dev.kill();
+    dev.wait().unwrap();
+
+    assert!(
+        out.status.success(),
+        "usb-host failed: {}\n{}",
+        out.status,
+        String::from_utf8_lossy(&out.stdout)
+    );
+}